@@ -0,0 +1,97 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use project_blueberry::color::{BlendMode, Color};
+use project_blueberry::image_buffer::{CamBuffer, ImageBuffer, SingleImageBuffer};
+use project_blueberry::raster::{self, TileRenderer};
+
+const WIDTH : usize = 1280;
+const HEIGHT : usize = 720;
+
+/// A sprite with an opaque middle, a soft edge and transparent corners, like most art.
+fn sprite(size : usize) -> SingleImageBuffer {
+    let center = size as f64 / 2.0;
+    let colors = (0..size * size).map(|i| {
+        let (x, y) = ((i % size) as f64 + 0.5, (i / size) as f64 + 0.5);
+        let distance = ((x - center).powi(2) + (y - center).powi(2)).sqrt() / center;
+        let alpha = ((1.2 - distance) * 5.0).clamp(0.0, 1.0);
+        Color((x * 4.0) as u8, (y * 4.0) as u8, 128, (alpha * 255.0) as u8)
+    }).collect();
+    SingleImageBuffer::from_colors(colors, size, size)
+}
+
+fn background() -> CamBuffer {
+    let mut buffer = CamBuffer::new(WIDTH, HEIGHT);
+    buffer.get_buffer_mut().iter_mut().enumerate().for_each(|(i, c)| *c = Color(i as u8, (i / WIDTH) as u8, 64, 255));
+    buffer
+}
+
+fn blend(c : &mut Criterion) {
+    let modes = [BlendMode::Replace, BlendMode::Alpha, BlendMode::Add, BlendMode::Multiply];
+    let src : Vec<Color> = (0..WIDTH).map(|i| Color(i as u8, 255 - i as u8, 90, (i * 7) as u8)).collect();
+    let dst : Vec<Color> = (0..WIDTH).map(|i| Color(30, i as u8, 200, 255)).collect();
+
+    let mut group = c.benchmark_group("blend");
+    group.throughput(Throughput::Elements(WIDTH as u64));
+    for mode in modes {
+        group.bench_with_input(BenchmarkId::new("per_pixel", format!("{:?}", mode)), &mode, |b, &mode| {
+            let mut row = dst.clone();
+            b.iter(|| row.iter_mut().zip(&src).for_each(|(d, s)| d.blend_with(*s, mode)))
+        });
+        group.bench_with_input(BenchmarkId::new("span", format!("{:?}", mode)), &mode, |b, &mode| {
+            let mut row = dst.clone();
+            b.iter(|| raster::blend_span(black_box(&mut row), &src, mode))
+        });
+    }
+    group.finish();
+}
+
+fn blit(c : &mut Criterion) {
+    let sprite = sprite(64);
+    let mut group = c.benchmark_group("blit");
+    group.throughput(Throughput::Elements(64 * 64 * 200));
+    group.bench_function("alpha_64x64_x200", |b| {
+        let mut target = background();
+        b.iter(|| for i in 0..200 {
+            sprite.blit(&mut target, (i * 37 % WIDTH) as i32 - 32, (i * 53 % HEIGHT) as i32 - 32, BlendMode::Alpha)
+        })
+    });
+    group.finish();
+}
+
+fn tiles(c : &mut Criterion) {
+    let sprite = sprite(64);
+    let mut renderer = TileRenderer::new();
+    for i in 0..2000 {
+        renderer.blit(sprite.as_view(), (i * 37 % WIDTH) as i32 - 32, (i * 53 % HEIGHT) as i32 - 32, BlendMode::Alpha);
+    }
+
+    let mut group = c.benchmark_group("tiles");
+    group.throughput(Throughput::Elements(64 * 64 * 2000));
+    group.bench_function("serial", |b| {
+        let mut target = background();
+        b.iter(|| renderer.render_serial(&mut target))
+    });
+    for rows in [8, 16, 64] {
+        group.bench_with_input(BenchmarkId::new("parallel", rows), &rows, |b, &rows| {
+            let mut renderer = TileRenderer::new().with_tile_height(rows);
+            for i in 0..2000 {
+                renderer.blit(sprite.as_view(), (i * 37 % WIDTH) as i32 - 32, (i * 53 % HEIGHT) as i32 - 32, BlendMode::Alpha);
+            }
+            let mut target = background();
+            b.iter(|| renderer.render(&mut target))
+        });
+    }
+    group.finish();
+}
+
+fn dump(c : &mut Criterion) {
+    let buffer = background();
+    let mut bytes = vec![0u8; WIDTH * HEIGHT * 4];
+    let mut group = c.benchmark_group("dump");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("1280x720", |b| b.iter(|| buffer.dump(black_box(&mut bytes))));
+    group.finish();
+}
+
+criterion_group!(benches, blend, blit, tiles, dump);
+criterion_main!(benches);
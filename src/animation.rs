@@ -1,73 +1,142 @@
-use crate::buffer::{BufferAtlas, Buffer};
-use crate::math::{Transform, Vec2};
+use crate::math::Vec2;
 use crate::input::InputInfo;
 use imgui::Ui;
 use crate::frame::FrameInfo;
 use crate::comps::object::GameComponent;
 use std::any::Any;
-use crate::image_buffer::CamBuffer;
+use crate::image_buffer::{CamBuffer, AtlasImageBuffer, ImageBuffer, ImageSource, ImageView, DrawParams};
+use crate::assets::{missing_texture, Assets, Handle};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io;
+use std::path::Path;
+use crate::comps::transform::TransformComponent;
+
+/// Where an animation's frames come from. Shared sheets are read through `Assets` on every
+/// draw, so a reloaded sheet shows up on the next frame.
+enum Frames {
+    Owned(AtlasImageBuffer),
+    Shared(Rc<RefCell<Assets>>, Handle<AtlasImageBuffer>)
+}
 
 pub struct Animation {
-    buffer_atlas: BufferAtlas,
+    frames: Frames,
     current_frame : u32,
-    total_frames : u32,
     fps : f64,
     elapsed_time : f64
 }
 
 impl Animation {
-    pub fn new(buffer_atlas : BufferAtlas, fps : f64) -> Animation {
-        let total_frames = buffer_atlas.len() as u32;
+    pub fn new(atlas : AtlasImageBuffer, fps : f64) -> Animation {
+        Animation::with_frames(Frames::Owned(atlas), fps)
+    }
+
+    /// Plays every frame of an image file. Animated files such as GIFs keep their own frame
+    /// delays; `fps` is used for stills.
+    pub fn load(path : &Path, fps : f64) -> io::Result<Animation> {
+        Ok(Animation::new(AtlasImageBuffer::load_animated(path)?, fps))
+    }
+
+    /// Plays the regions of a sheet loaded with `Assets::load_sheet`.
+    pub fn from_asset(assets : Rc<RefCell<Assets>>, sheet : Handle<AtlasImageBuffer>, fps : f64) -> Animation {
+        Animation::with_frames(Frames::Shared(assets, sheet), fps)
+    }
+
+    fn with_frames(frames : Frames, fps : f64) -> Animation {
         Animation {
-            buffer_atlas,
+            frames,
             current_frame: 0,
-            total_frames,
             fps,
             elapsed_time: 0.0
         }
     }
 
-    pub fn get_frame(&self) -> &Buffer {
-        self.buffer_atlas.get_buffer(self.current_frame as usize)
+    pub fn get_total_frames(&self) -> u32 {
+        match &self.frames {
+            Frames::Owned(atlas) => atlas.len() as u32,
+            Frames::Shared(assets, sheet) => assets.borrow().get(*sheet).len() as u32
+        }
+    }
+
+    /// How long the current frame is shown: its own delay if the sheet has one, else `fps`.
+    fn frame_time(&self) -> f64 {
+        let index = self.current_frame as usize;
+        let delay = match &self.frames {
+            Frames::Owned(atlas) => atlas.get_delay(index),
+            Frames::Shared(assets, sheet) => assets.borrow().get(*sheet).get_delay(index)
+        };
+        delay.unwrap_or(self.fps)
     }
 
     pub fn update(&mut self, delta : f64) {
         self.elapsed_time += delta;
-        if self.elapsed_time >= self.fps {
+        if self.elapsed_time >= self.frame_time() {
             self.increment_frame();
             self.elapsed_time = 0.0;
         }
     }
 
     fn increment_frame(&mut self) {
-        self.current_frame = if self.current_frame + 1 < self.total_frames {self.current_frame + 1} else {0}
+        self.current_frame = if self.current_frame + 1 < self.get_total_frames() {self.current_frame + 1} else {0}
+    }
+}
+
+/// The current frame.
+impl ImageSource for Animation {
+    fn get_size(&self) -> (usize, usize) {
+        self.with_view(|frame| frame.get_dimensions())
+    }
+
+    /// A sheet that lost frames on reload wraps around, and one with no frames at all draws the
+    /// missing texture instead of panicking.
+    fn with_view<R>(&self, f : impl FnOnce(ImageView) -> R) -> R {
+        let index = self.current_frame as usize;
+        match &self.frames {
+            Frames::Owned(atlas) if atlas.is_empty() => f(missing_texture().as_view()),
+            Frames::Owned(atlas) => f(atlas.get(index % atlas.len())),
+            Frames::Shared(assets, sheet) => {
+                let assets = assets.borrow();
+                let atlas = assets.get(*sheet);
+                if atlas.is_empty() {
+                    f(assets.get(assets.get_missing()).as_view())
+                } else {
+                    f(atlas.get(index % atlas.len()))
+                }
+            }
+        }
     }
 }
 
 
 
 pub struct AnimationComponent {
-    animation : Animation,
-    transform : Transform
+    animation : Animation
 }
 
 impl AnimationComponent {
     pub fn new(animation : Animation) -> AnimationComponent {
         AnimationComponent {
-            animation,
-            transform : Transform::from(0, 0)
+            animation
         }
     }
 }
 
 impl GameComponent for AnimationComponent {
-    fn update(&mut self, frame_info: &FrameInfo, input_info: &InputInfo) {
+    fn update(&mut self, frame_info: &FrameInfo, input_info: &InputInfo, transform: Option<&mut TransformComponent>) {
         self.animation.update(frame_info.update_delta)
     }
 
-    fn render(&mut self, main_buffer: &mut CamBuffer) {
-        let (x, y) = self.transform.get_xy();
-        //self.animation.get_frame().blit(main_buffer, x, y);
+    fn render(&mut self, main_buffer: &mut CamBuffer, frame_info: &FrameInfo, transform: Option<&TransformComponent>) {
+        let (cam_x, cam_y) = main_buffer.get_offset().get_xy();
+        let mut params = DrawParams::at(-cam_x as f64, -cam_y as f64);
+
+        if let Some(t) = transform {
+            params.pos.add_vec(&t.interpolated_pos(frame_info));
+            params.scale = t.interpolated_scale(frame_info);
+            params.rotation = t.interpolated_rotation(frame_info);
+        }
+
+        self.animation.with_view(|frame| frame.draw(main_buffer, &params));
     }
 
     fn object_debug(&mut self, ui: &Ui) {
@@ -77,4 +146,57 @@ impl GameComponent for AnimationComponent {
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::image_buffer::SingleImageBuffer;
+    use std::fs;
+
+    fn strip() -> AtlasImageBuffer {
+        let frames : Vec<SingleImageBuffer> = (0..3).map(|i| SingleImageBuffer::from_colors(vec![Color(i, 0, 0, 255); 4], 2, 2)).collect();
+        AtlasImageBuffer::from_frames(&frames)
+    }
+
+    fn first_pixel(animation : &Animation) -> Color {
+        animation.with_view(|frame| frame.get_pixel(0, 0))
+    }
+
+    #[test]
+    fn frames_advance_and_wrap() {
+        let mut animation = Animation::new(strip(), 0.5);
+        assert_eq!(first_pixel(&animation), Color(0, 0, 0, 255));
+        animation.update(0.25);
+        assert_eq!(first_pixel(&animation), Color(0, 0, 0, 255));
+        animation.update(0.25);
+        assert_eq!(first_pixel(&animation), Color(1, 0, 0, 255));
+        animation.update(0.5);
+        animation.update(0.5);
+        assert_eq!(first_pixel(&animation), Color(0, 0, 0, 255));
+
+        // A frame index left over from a longer sheet wraps instead of panicking.
+        animation.current_frame = 4;
+        assert_eq!(first_pixel(&animation), Color(1, 0, 0, 255));
+    }
+
+    #[test]
+    fn sheets_without_regions_draw_the_missing_texture() {
+        let animation = Animation::new(AtlasImageBuffer::new(SingleImageBuffer::new(4, 4)), 0.5);
+        assert_eq!(animation.get_size(), (16, 16));
+        assert_eq!(first_pixel(&animation), Color(255, 0, 255, 255));
+
+        // A still loaded as a sheet has no regions until some are added.
+        let dir = std::env::temp_dir().join(format!("blueberry_animation_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        SingleImageBuffer::new(4, 4).save(&dir.join("still.png")).unwrap();
+        let assets = Assets::shared();
+        assets.borrow_mut().set_roots(vec![dir.clone()]);
+        let sheet = assets.borrow_mut().load_sheet("still.png");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let animation = Animation::from_asset(assets.clone(), sheet.unwrap(), 0.5);
+        assert_eq!(animation.get_size(), (16, 16));
+        assert_eq!(first_pixel(&animation), Color(255, 0, 255, 255));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::atlas::PackedAtlas;
+use crate::color::Color;
+use crate::image_buffer::{AtlasImageBuffer, ImageBuffer, ImageSource, ImageView, SingleImageBuffer, PATH_TO_SPRITES};
+use crate::image_loader::{self, Frame};
+use log::{error, info};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime};
+
+/// How long a reload stays in `Assets::get_recent_reloads`.
+const RECENT_RELOAD_SECONDS : f64 = 4.0;
+
+/// A typed index into `Assets`. Handles are cheap to copy and stay valid for the registry's
+/// lifetime.
+pub struct Handle<T> {
+    index : usize,
+    marker : PhantomData<fn() -> T>
+}
+
+impl<T> Handle<T> {
+    fn new(index : usize) -> Handle<T> {
+        Handle { index, marker : PhantomData }
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other : &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.index.hash(state)
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({})", self.index)
+    }
+}
+
+#[derive(Debug)]
+pub enum AssetError {
+    /// No root had a file by that name.
+    NotFound { name : String, roots : Vec<PathBuf> },
+    /// The file exists but couldn't be read or decoded.
+    Invalid { path : PathBuf, error : io::Error }
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::NotFound { name, roots } => {
+                let roots : Vec<String> = roots.iter().map(|r| r.display().to_string()).collect();
+                write!(f, "Couldn't find '{}' in any asset root ({})", name, roots.join(", "))
+            }
+            AssetError::Invalid { path, error } => write!(f, "Couldn't load '{}': {}", path.display(), error)
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+/// Types `Assets` can hand out handles for.
+pub trait Asset : Sized {
+    fn storage(assets : &Assets) -> &Vec<Self>;
+
+    fn storage_mut(assets : &mut Assets) -> &mut Vec<Self>;
+}
+
+impl Asset for SingleImageBuffer {
+    fn storage(assets : &Assets) -> &Vec<Self> {
+        &assets.images
+    }
+
+    fn storage_mut(assets : &mut Assets) -> &mut Vec<Self> {
+        &mut assets.images
+    }
+}
+
+impl Asset for AtlasImageBuffer {
+    fn storage(assets : &Assets) -> &Vec<Self> {
+        &assets.sheets
+    }
+
+    fn storage_mut(assets : &mut Assets) -> &mut Vec<Self> {
+        &mut assets.sheets
+    }
+}
+
+impl Asset for PackedAtlas {
+    fn storage(assets : &Assets) -> &Vec<Self> {
+        &assets.atlases
+    }
+
+    fn storage_mut(assets : &mut Assets) -> &mut Vec<Self> {
+        &mut assets.atlases
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum AssetKind {
+    Image,
+    Sheet,
+    Atlas
+}
+
+/// A loaded file and the newest modification time seen across the files it came from.
+struct Watched {
+    kind : AssetKind,
+    index : usize,
+    name : String,
+    paths : Vec<PathBuf>,
+    modified : Option<SystemTime>
+}
+
+/// One asset that changed on disk.
+#[derive(Debug, Clone)]
+pub struct Reload {
+    pub name : String,
+    /// Why the new version was rejected. The old one stays in use.
+    pub error : Option<String>,
+    /// Ids of images whose pixels were replaced, for invalidating caches keyed by them.
+    pub image_ids : Vec<u64>,
+    pub at : Instant
+}
+
+/// Loads every asset once and hands out handles to it. Names are resolved against each root in
+/// order, and loading the same file twice returns the first handle.
+///
+/// While `watch` is set, `poll` checks the files behind every loaded asset and reloads the ones
+/// that changed in place, so everything holding a handle picks up the new version.
+pub struct Assets {
+    roots : Vec<PathBuf>,
+    images : Vec<SingleImageBuffer>,
+    sheets : Vec<AtlasImageBuffer>,
+    atlases : Vec<PackedAtlas>,
+    loaded : HashMap<(AssetKind, PathBuf), usize>,
+    watched : Vec<Watched>,
+    color_keys : HashMap<String, Color>,
+    missing : Handle<SingleImageBuffer>,
+    pub watch : bool,
+    pub poll_interval : f64,
+    next_poll : f64,
+    recent : Vec<Reload>
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets {
+            roots : vec![PathBuf::from(PATH_TO_SPRITES)],
+            images : vec![missing_texture()],
+            sheets : Vec::new(),
+            atlases : Vec::new(),
+            loaded : HashMap::new(),
+            watched : Vec::new(),
+            color_keys : HashMap::new(),
+            missing : Handle::new(0),
+            watch : true,
+            poll_interval : 0.5,
+            next_poll : 0.0,
+            recent : Vec::new()
+        }
+    }
+
+    pub fn shared() -> Rc<RefCell<Assets>> {
+        Rc::new(RefCell::new(Assets::new()))
+    }
+
+    /// Replaces the folders names are looked up in.
+    pub fn set_roots(&mut self, roots : Vec<PathBuf>) {
+        self.roots = roots;
+    }
+
+    /// Adds a folder searched after the existing ones.
+    pub fn add_root(&mut self, root : &Path) {
+        self.roots.push(root.to_path_buf());
+    }
+
+    pub fn get_roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// The first root that has a file called `name`. Absolute names are used as they are.
+    pub fn resolve(&self, name : &str) -> Result<PathBuf, AssetError> {
+        self.roots.iter()
+            .map(|root| root.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| AssetError::NotFound { name : String::from(name), roots : self.roots.clone() })
+    }
+
+    pub fn load_image(&mut self, name : &str) -> Result<Handle<SingleImageBuffer>, AssetError> {
+        let path = self.resolve(name)?;
+        if let Some(index) = self.find(AssetKind::Image, &path) {
+            return Ok(Handle::new(index))
+        }
+
+        let image = self.decode(name, &path)?;
+        self.images.push(image);
+        Ok(Handle::new(self.watch_file(AssetKind::Image, self.images.len() - 1, name, vec![path])))
+    }
+
+    /// Like `load_image`, but logs the error and hands back the missing texture instead.
+    pub fn image(&mut self, name : &str) -> Handle<SingleImageBuffer> {
+        self.load_image(name).unwrap_or_else(|e| {
+            error!("{}", e);
+            self.missing
+        })
+    }
+
+    /// Loads an image as a sheet without any regions yet. Regions added through `get_mut` are
+    /// kept when the image is reloaded, clipped to its new size. Animated files instead get a
+    /// region and delay per frame, both replaced on reload.
+    pub fn load_sheet(&mut self, name : &str) -> Result<Handle<AtlasImageBuffer>, AssetError> {
+        let path = self.resolve(name)?;
+        if let Some(index) = self.find(AssetKind::Sheet, &path) {
+            return Ok(Handle::new(index))
+        }
+
+        let mut frames = self.decode_frames(name, &path)?;
+        let sheet = if frames.len() > 1 {
+            AtlasImageBuffer::animated(frames)
+        } else {
+            AtlasImageBuffer::new(frames.swap_remove(0).image)
+        };
+        self.sheets.push(sheet);
+        Ok(Handle::new(self.watch_file(AssetKind::Sheet, self.sheets.len() - 1, name, vec![path])))
+    }
+
+    /// Loads a sheet written by `PackedAtlas::save`, `name` being the path without extension.
+    pub fn load_atlas(&mut self, name : &str) -> Result<Handle<PackedAtlas>, AssetError> {
+        let image_path = self.resolve(&format!("{}.png", name))?;
+        let manifest_path = self.resolve(&format!("{}.ron", name))?;
+        if let Some(index) = self.find(AssetKind::Atlas, &manifest_path) {
+            return Ok(Handle::new(index))
+        }
+
+        let atlas = load_atlas(&image_path, &manifest_path)?;
+        self.atlases.push(atlas);
+        Ok(Handle::new(self.watch_file(AssetKind::Atlas, self.atlases.len() - 1, name, vec![manifest_path, image_path])))
+    }
+
+    pub fn get<T : Asset>(&self, handle : Handle<T>) -> &T {
+        &T::storage(self)[handle.index]
+    }
+
+    pub fn get_mut<T : Asset>(&mut self, handle : Handle<T>) -> &mut T {
+        &mut T::storage_mut(self)[handle.index]
+    }
+
+    /// The checkerboard drawn in place of images that failed to load.
+    pub fn get_missing(&self) -> Handle<SingleImageBuffer> {
+        self.missing
+    }
+
+    /// How many files are loaded and watched.
+    pub fn len(&self) -> usize {
+        self.watched.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watched.is_empty()
+    }
+
+    /// Reloads that happened in the last few seconds, oldest first.
+    pub fn get_recent_reloads(&self) -> &[Reload] {
+        &self.recent
+    }
+
+    /// Checks for changed files once every `poll_interval` seconds of `elapsed` and reloads them.
+    pub fn poll(&mut self, elapsed : f64) -> Vec<Reload> {
+        self.recent.retain(|r| r.at.elapsed().as_secs_f64() < RECENT_RELOAD_SECONDS);
+        if !self.watch || elapsed < self.next_poll {
+            return Vec::new()
+        }
+        self.next_poll = elapsed + self.poll_interval;
+        self.reload_changed()
+    }
+
+    /// Reloads every asset whose files are newer than when they were last loaded.
+    pub fn reload_changed(&mut self) -> Vec<Reload> {
+        let mut reloads = Vec::new();
+
+        for i in 0..self.watched.len() {
+            let modified = newest_modified(&self.watched[i].paths);
+            if modified.is_none() || modified <= self.watched[i].modified { continue }
+            self.watched[i].modified = modified;
+
+            let reload = self.reload(i);
+            match &reload.error {
+                Some(e) => error!("Keeping the old '{}': {}", reload.name, e),
+                None => info!("Reloaded '{}'", reload.name)
+            }
+            self.recent.push(reload.clone());
+            reloads.push(reload);
+        }
+
+        reloads
+    }
+
+    fn reload(&mut self, watched : usize) -> Reload {
+        let Watched { kind, index, ref name, ref paths, .. } = self.watched[watched];
+        let mut reload = Reload { name : name.clone(), error : None, image_ids : Vec::new(), at : Instant::now() };
+
+        let result = match kind {
+            AssetKind::Image => self.decode(name, &paths[0]).map(|image| {
+                let (width, height) = image.get_dimensions();
+                let target = &mut self.images[index];
+                target.set_buffer(image.get_buffer(), width, height);
+                reload.image_ids.push(target.get_id());
+            }),
+            AssetKind::Sheet => self.decode_frames(name, &paths[0]).map(|mut frames| {
+                let target = &mut self.sheets[index];
+                if frames.len() > 1 || target.is_animated() {
+                    target.set_animation(frames);
+                } else {
+                    target.set_image(frames.swap_remove(0).image);
+                }
+                reload.image_ids.push(target.get_image().get_id());
+            }),
+            AssetKind::Atlas => load_atlas(&paths[1], &paths[0]).map(|atlas| {
+                let old = std::mem::replace(&mut self.atlases[index], atlas);
+                reload.image_ids.push(old.get_atlas().get_image().get_id());
+            })
+        };
+
+        reload.error = result.err().map(|e| e.to_string());
+        reload
+    }
+
+    /// Makes pixels of `key`'s color transparent in the image called `name`, for legacy sprites
+    /// without alpha. Set it before the first load; it is applied again on every reload.
+    pub fn set_color_key(&mut self, name : &str, key : Color) {
+        self.color_keys.insert(String::from(name), key);
+    }
+
+    fn decode(&self, name : &str, path : &Path) -> Result<SingleImageBuffer, AssetError> {
+        Ok(self.decode_frames(name, path)?.swap_remove(0).image)
+    }
+
+    fn decode_frames(&self, name : &str, path : &Path) -> Result<Vec<Frame>, AssetError> {
+        let mut frames = image_loader::load_frames(path).map_err(|error| AssetError::Invalid { path : path.to_path_buf(), error })?;
+        if let Some(&key) = self.color_keys.get(name) {
+            frames.iter_mut().for_each(|f| f.image.apply_color_key(key));
+        }
+        Ok(frames)
+    }
+
+    fn find(&self, kind : AssetKind, path : &Path) -> Option<usize> {
+        self.loaded.get(&(kind, path.to_path_buf())).map(|&w| self.watched[w].index)
+    }
+
+    fn watch_file(&mut self, kind : AssetKind, index : usize, name : &str, paths : Vec<PathBuf>) -> usize {
+        self.loaded.insert((kind, paths[0].clone()), self.watched.len());
+        let modified = newest_modified(&paths);
+        self.watched.push(Watched { kind, index, name : String::from(name), paths, modified });
+        index
+    }
+}
+
+fn load_atlas(image_path : &Path, manifest_path : &Path) -> Result<PackedAtlas, AssetError> {
+    PackedAtlas::load(image_path, manifest_path).map_err(|error| AssetError::Invalid { path : manifest_path.to_path_buf(), error })
+}
+
+fn newest_modified(paths : &[PathBuf]) -> Option<SystemTime> {
+    paths.iter().filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok()).max()
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Assets::new()
+    }
+}
+
+/// The magenta and black checkerboard `Assets` hands out for images that failed to load.
+pub fn missing_texture() -> SingleImageBuffer {
+    let mut image = SingleImageBuffer::new(16, 16);
+    for y in 0..16 {
+        for x in 0..16 {
+            let color = if (x / 4 + y / 4) % 2 == 0 { Color(255, 0, 255, 255) } else { Color(0, 0, 0, 255) };
+            image.set_pixel(color, x, y);
+        }
+    }
+    image
+}
+
+/// An image owned by a shared `Assets`, for components that draw it.
+pub struct AssetImage {
+    assets : Rc<RefCell<Assets>>,
+    handle : Handle<SingleImageBuffer>
+}
+
+impl AssetImage {
+    pub fn new(assets : Rc<RefCell<Assets>>, handle : Handle<SingleImageBuffer>) -> AssetImage {
+        AssetImage { assets, handle }
+    }
+
+    pub fn get_handle(&self) -> Handle<SingleImageBuffer> {
+        self.handle
+    }
+}
+
+impl ImageSource for AssetImage {
+    fn get_size(&self) -> (usize, usize) {
+        self.assets.borrow().get(self.handle).get_dimensions()
+    }
+
+    fn with_view<R>(&self, f : impl FnOnce(ImageView) -> R) -> R {
+        f(self.assets.borrow().get(self.handle).as_view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder under the temp dir holding `files`, removed when dropped.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(test : &str, files : &[(&str, SingleImageBuffer)]) -> Root {
+            let dir = std::env::temp_dir().join(format!("blueberry_assets_{}_{}", test, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            for (name, image) in files {
+                image.save(&dir.join(name)).unwrap();
+            }
+            Root(dir)
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn filled(width : usize, height : usize, color : Color) -> SingleImageBuffer {
+        SingleImageBuffer::from_colors(vec![color; width * height], width, height)
+    }
+
+    #[test]
+    fn loading_a_file_twice_gives_the_same_handle() {
+        let root = Root::new("dedup", &[("a.png", filled(2, 2, Color(1, 2, 3, 255))), ("b.png", filled(3, 1, Color(4, 5, 6, 255)))]);
+        let mut assets = Assets::new();
+        assets.set_roots(vec![root.0.clone()]);
+
+        let a = assets.load_image("a.png").unwrap();
+        let b = assets.load_image("b.png").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(assets.load_image("a.png").unwrap(), a);
+        assert_eq!(assets.image("b.png"), b);
+        assert_eq!(assets.len(), 2);
+        assert_eq!(assets.get(a).get_pixel(1, 1), Color(1, 2, 3, 255));
+        assert_eq!(assets.get(b).get_dimensions(), (3, 1));
+
+        // The same file as a sheet is a separate asset, but again only loaded once.
+        let sheet = assets.load_sheet("a.png").unwrap();
+        assert_eq!(assets.load_sheet("a.png").unwrap(), sheet);
+        assert_eq!(assets.len(), 3);
+    }
+
+    #[test]
+    fn later_roots_are_searched_when_earlier_ones_miss() {
+        let first = Root::new("first", &[("a.png", filled(1, 1, Color(10, 0, 0, 255)))]);
+        let second = Root::new("second", &[("a.png", filled(1, 1, Color(20, 0, 0, 255))), ("b.png", filled(1, 1, Color(30, 0, 0, 255)))]);
+        let mut assets = Assets::new();
+        assets.set_roots(vec![first.0.clone()]);
+        assets.add_root(&second.0);
+
+        assert_eq!(assets.resolve("a.png").unwrap(), first.0.join("a.png"));
+        let b = assets.load_image("b.png").unwrap();
+        assert_eq!(assets.get(b).get_pixel(0, 0), Color(30, 0, 0, 255));
+    }
+
+    #[test]
+    fn color_keys_apply_to_images_loaded_by_that_name() {
+        let image = || SingleImageBuffer::from_colors(vec![Color(255, 0, 255, 255), Color(1, 1, 1, 255)], 2, 1);
+        let root = Root::new("color_key", &[("keyed.png", image()), ("plain.png", image())]);
+        let mut assets = Assets::new();
+        assets.set_roots(vec![root.0.clone()]);
+        assets.set_color_key("keyed.png", Color(255, 0, 255, 0));
+
+        let keyed = assets.load_image("keyed.png").unwrap();
+        let plain = assets.load_image("plain.png").unwrap();
+        assert_eq!(assets.get(keyed).get_buffer(), &vec![Color::CLEAR, Color(1, 1, 1, 255)]);
+        assert_eq!(assets.get(plain).get_pixel(0, 0), Color(255, 0, 255, 255));
+    }
+
+    #[test]
+    fn missing_and_broken_files_fall_back_to_the_missing_texture() {
+        let root = Root::new("missing", &[]);
+        fs::write(root.0.join("broken.png"), b"not a png").unwrap();
+        let mut assets = Assets::new();
+        assets.set_roots(vec![root.0.clone()]);
+
+        assert!(matches!(assets.load_image("nope.png"), Err(AssetError::NotFound { .. })));
+        assert!(matches!(assets.load_image("broken.png"), Err(AssetError::Invalid { .. })));
+        let missing = assets.get_missing();
+        assert_eq!(assets.image("nope.png"), missing);
+        assert_eq!(assets.image("broken.png"), missing);
+        assert!(assets.is_empty());
+
+        let texture = assets.get(missing);
+        assert_eq!(texture.get_dimensions(), (16, 16));
+        assert_eq!(texture.get_pixel(0, 0), Color(255, 0, 255, 255));
+        assert_eq!(texture.get_pixel(4, 0), Color(0, 0, 0, 255));
+        assert_eq!(texture.get_pixel(4, 4), Color(255, 0, 255, 255));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::image_buffer::{AtlasImageBuffer, ImageBuffer, ImageView, SingleImageBuffer};
+use crate::math::Rect;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where one packed sprite ended up. `rect` is its trimmed pixels inside the sheet and `offset`
+/// is where those pixels sat in the original image, so drawing a region at `offset` puts it back
+/// where the untrimmed sprite would have been.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AtlasRegion {
+    pub name : String,
+    pub rect : Rect,
+    pub offset : (i32, i32),
+    pub source_size : (usize, usize)
+}
+
+/// The named regions of a packed sheet, saved next to the sheet's png as ron.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AtlasManifest {
+    pub width : usize,
+    pub height : usize,
+    pub regions : Vec<AtlasRegion>
+}
+
+impl AtlasManifest {
+    pub fn load(path : &Path) -> io::Result<AtlasManifest> {
+        let contents = fs::read_to_string(path)?;
+        ron::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
+
+/// A sheet built by `AtlasBuilder` or loaded from disk. Every sprite is a view into the one
+/// decoded image.
+pub struct PackedAtlas {
+    atlas : AtlasImageBuffer,
+    manifest : AtlasManifest,
+    names : HashMap<String, usize>
+}
+
+impl PackedAtlas {
+    fn new(image : SingleImageBuffer, manifest : AtlasManifest) -> PackedAtlas {
+        let mut atlas = AtlasImageBuffer::new(image);
+        let mut names = HashMap::new();
+
+        for (index, region) in manifest.regions.iter().enumerate() {
+            let rect = region.rect;
+            atlas.add(rect.x as usize, rect.y as usize, rect.width as usize, rect.height as usize);
+            names.insert(region.name.clone(), index);
+        }
+
+        PackedAtlas { atlas, manifest, names }
+    }
+
+    /// Loads a sheet and manifest written by `save`.
+    pub fn load(image_path : &Path, manifest_path : &Path) -> io::Result<PackedAtlas> {
+        let image = SingleImageBuffer::load(image_path)?;
+        let manifest = AtlasManifest::load(manifest_path)?;
+        if image.get_dimensions() != (manifest.width, manifest.height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Atlas image doesn't match the size in its manifest"))
+        }
+        Ok(PackedAtlas::new(image, manifest))
+    }
+
+    pub fn save(&self, image_path : &Path, manifest_path : &Path) -> io::Result<()> {
+        self.atlas.get_image().save(image_path)?;
+        self.manifest.save(manifest_path)
+    }
+
+    pub fn get(&self, name : &str) -> Option<ImageView<'_>> {
+        self.index_of(name).map(|index| self.atlas.get(index))
+    }
+
+    pub fn get_region(&self, name : &str) -> Option<&AtlasRegion> {
+        self.index_of(name).map(|index| &self.manifest.regions[index])
+    }
+
+    /// The index of `name` in the underlying `AtlasImageBuffer`.
+    pub fn index_of(&self, name : &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+
+    pub fn get_atlas(&self) -> &AtlasImageBuffer {
+        &self.atlas
+    }
+
+    pub fn get_manifest(&self) -> &AtlasManifest {
+        &self.manifest
+    }
+
+    pub fn len(&self) -> usize {
+        self.manifest.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifest.regions.is_empty()
+    }
+}
+
+/// Packs loose images into one sheet with max-rects, best short side fit. The sheet starts at the
+/// smallest power of two square that could hold everything and grows until it all fits.
+pub struct AtlasBuilder {
+    sprites : Vec<(String, SingleImageBuffer)>,
+    padding : usize,
+    trim : bool,
+    max_size : usize
+}
+
+impl AtlasBuilder {
+    pub fn new() -> AtlasBuilder {
+        AtlasBuilder {
+            sprites : Vec::new(),
+            padding : 1,
+            trim : true,
+            max_size : 4096
+        }
+    }
+
+    /// Empty pixels kept between sprites so filtering or rounding never bleeds a neighbour in.
+    pub fn with_padding(mut self, padding : usize) -> AtlasBuilder {
+        self.padding = padding;
+        self
+    }
+
+    /// Whether fully transparent borders are cut off before packing.
+    pub fn with_trim(mut self, trim : bool) -> AtlasBuilder {
+        self.trim = trim;
+        self
+    }
+
+    /// The largest width or height the sheet may grow to.
+    pub fn with_max_size(mut self, max_size : usize) -> AtlasBuilder {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn add(&mut self, name : &str, image : SingleImageBuffer) {
+        self.sprites.push((String::from(name), image));
+    }
+
+    /// Adds every png directly inside `dir`, named by file stem.
+    pub fn add_dir(&mut self, dir : &Path) -> io::Result<()> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png")) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            self.add(&name, SingleImageBuffer::load(&path)?);
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> io::Result<PackedAtlas> {
+        let trimmed : Vec<Rect> = self.sprites.iter()
+            .map(|(_, image)| if self.trim { opaque_bounds(image) } else { full_bounds(image) })
+            .collect();
+
+        // Largest first packs tighter; ties are broken by name so builds are reproducible.
+        let mut order : Vec<usize> = (0..self.sprites.len()).collect();
+        order.sort_by(|&a, &b| {
+            let side = |i : usize| trimmed[i].width.max(trimmed[i].height);
+            side(b).cmp(&side(a)).then_with(|| self.sprites[a].0.cmp(&self.sprites[b].0))
+        });
+
+        let padding = self.padding as i32;
+        let area : i32 = trimmed.iter().map(|r| (r.width + padding) * (r.height + padding)).sum();
+        let longest = trimmed.iter().map(|r| r.width.max(r.height)).max().unwrap_or(0) as usize;
+        let mut width = ((area as f64).sqrt().ceil() as usize).max(longest).max(1).next_power_of_two();
+        let mut height = width;
+
+        while width <= self.max_size && height <= self.max_size {
+            if let Some(placed) = self.pack(&order, &trimmed, width, height) {
+                return Ok(self.compose(&trimmed, &placed, width, height))
+            }
+            if width <= height { width *= 2 } else { height *= 2 }
+        }
+
+        let message = format!("{} sprites don't fit in a {}x{} atlas", self.sprites.len(), self.max_size, self.max_size);
+        Err(io::Error::other(message))
+    }
+
+    /// Places every sprite in `order`, or gives up if one doesn't fit.
+    fn pack(&self, order : &[usize], trimmed : &[Rect], width : usize, height : usize) -> Option<Vec<Rect>> {
+        let padding = self.padding as i32;
+        // The bin is padded on the far edges too, so the last sprite's padding may hang off the sheet.
+        let mut bin = MaxRects::new(width as i32 + padding, height as i32 + padding);
+        let mut placed = vec![Rect::default(); trimmed.len()];
+
+        for &i in order {
+            let rect = trimmed[i];
+            if rect.is_empty() { continue }
+            let slot = bin.insert(rect.width + padding, rect.height + padding)?;
+            placed[i] = Rect::new(slot.x, slot.y, rect.width, rect.height);
+        }
+
+        Some(placed)
+    }
+
+    fn compose(&self, trimmed : &[Rect], placed : &[Rect], width : usize, height : usize) -> PackedAtlas {
+        let mut sheet = SingleImageBuffer::new(width, height);
+        let mut manifest = AtlasManifest { width, height, regions : Vec::new() };
+
+        for (i, (name, image)) in self.sprites.iter().enumerate() {
+            let (src, dst) = (trimmed[i], placed[i]);
+            let view = image.view(src.x as usize, src.y as usize, src.width as usize, src.height as usize);
+            for y in 0..view.get_height() {
+                for x in 0..view.get_width() {
+                    sheet.set_pixel(view.get_pixel(x, y), dst.x as usize + x, dst.y as usize + y);
+                }
+            }
+
+            manifest.regions.push(AtlasRegion {
+                name : name.clone(),
+                rect : dst,
+                offset : (src.x, src.y),
+                source_size : image.get_dimensions()
+            });
+        }
+
+        PackedAtlas::new(sheet, manifest)
+    }
+}
+
+impl Default for AtlasBuilder {
+    fn default() -> Self {
+        AtlasBuilder::new()
+    }
+}
+
+fn full_bounds(image : &SingleImageBuffer) -> Rect {
+    let (width, height) = image.get_dimensions();
+    Rect::new(0, 0, width as i32, height as i32)
+}
+
+/// The smallest rectangle holding every pixel that isn't fully transparent.
+fn opaque_bounds(image : &SingleImageBuffer) -> Rect {
+    let (width, height) = image.get_dimensions();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (width, height, 0, 0);
+
+    for y in 0..height {
+        for x in 0..width {
+            if image.get_pixel(x, y).3 == 0 { continue }
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x + 1);
+            max_y = max_y.max(y + 1);
+        }
+    }
+
+    if min_x >= max_x { return Rect::default() }
+    Rect::new(min_x as i32, min_y as i32, (max_x - min_x) as i32, (max_y - min_y) as i32)
+}
+
+/// The free space of a bin as a set of possibly overlapping maximal rectangles.
+struct MaxRects {
+    free : Vec<Rect>
+}
+
+impl MaxRects {
+    fn new(width : i32, height : i32) -> MaxRects {
+        MaxRects { free : vec![Rect::new(0, 0, width, height)] }
+    }
+
+    fn insert(&mut self, width : i32, height : i32) -> Option<Rect> {
+        let best = self.free.iter()
+            .filter(|f| f.width >= width && f.height >= height)
+            .min_by_key(|f| {
+                let (left_x, left_y) = (f.width - width, f.height - height);
+                (left_x.min(left_y), left_x.max(left_y), f.y, f.x)
+            })?;
+        let placed = Rect::new(best.x, best.y, width, height);
+
+        let mut free = Vec::with_capacity(self.free.len() + 4);
+        for f in self.free.drain(..) {
+            if f.intersect(&placed).is_empty() {
+                free.push(f);
+                continue
+            }
+            if placed.x > f.x { free.push(Rect::new(f.x, f.y, placed.x - f.x, f.height)) }
+            if placed.right() < f.right() { free.push(Rect::new(placed.right(), f.y, f.right() - placed.right(), f.height)) }
+            if placed.y > f.y { free.push(Rect::new(f.x, f.y, f.width, placed.y - f.y)) }
+            if placed.bottom() < f.bottom() { free.push(Rect::new(f.x, placed.bottom(), f.width, f.bottom() - placed.bottom())) }
+        }
+
+        // Drop rectangles that another one fully covers.
+        let contains = |outer : &Rect, inner : &Rect| outer.intersect(inner) == *inner;
+        let mut pruned : Vec<Rect> = Vec::with_capacity(free.len());
+        for (i, f) in free.iter().enumerate() {
+            let covered = free.iter().enumerate().any(|(j, g)| i != j && contains(g, f) && (g != f || j < i));
+            if !covered { pruned.push(*f) }
+        }
+        self.free = pruned;
+
+        Some(placed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+
+    /// A `width` by `height` image that is transparent except for `opaque`, where every pixel
+    /// encodes its own position and `tag`.
+    fn sprite(width : usize, height : usize, opaque : Rect, tag : u8) -> SingleImageBuffer {
+        let mut image = SingleImageBuffer::new(width, height);
+        for y in opaque.y..opaque.bottom() {
+            for x in opaque.x..opaque.right() {
+                image.set_pixel(Color(tag, x as u8, y as u8, 255), x as usize, y as usize);
+            }
+        }
+        image
+    }
+
+    fn builder(padding : usize) -> AtlasBuilder {
+        let mut builder = AtlasBuilder::new().with_padding(padding);
+        builder.add("square", sprite(5, 5, Rect::new(0, 0, 5, 5), 1));
+        builder.add("tall", sprite(3, 9, Rect::new(0, 0, 3, 9), 2));
+        builder.add("wide", sprite(7, 2, Rect::new(0, 0, 7, 2), 3));
+        builder.add("trimmed", sprite(6, 5, Rect::new(3, 1, 2, 3), 4));
+        builder.add("dot", sprite(1, 1, Rect::new(0, 0, 1, 1), 5));
+        builder
+    }
+
+    /// Every region's pixels match the opaque pixels of the sprite it came from.
+    fn assert_pixels(atlas : &PackedAtlas) {
+        for region in &atlas.get_manifest().regions {
+            let view = atlas.get(&region.name).unwrap();
+            assert_eq!(view.get_dimensions(), (region.rect.width as usize, region.rect.height as usize));
+            for y in 0..view.get_height() {
+                for x in 0..view.get_width() {
+                    let (sx, sy) = (x as i32 + region.offset.0, y as i32 + region.offset.1);
+                    let Color(_, px, py, a) = view.get_pixel(x, y);
+                    assert_eq!((px as i32, py as i32, a), (sx, sy, 255), "{} at {}, {}", region.name, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packed_sprites_keep_their_padding_apart() {
+        for padding in [0, 1, 3] {
+            let atlas = builder(padding).build().unwrap();
+            let manifest = atlas.get_manifest();
+            let sheet = Rect::new(0, 0, manifest.width as i32, manifest.height as i32);
+            let padded = |r : Rect| Rect::new(r.x, r.y, r.width + padding as i32, r.height + padding as i32);
+
+            for (i, a) in manifest.regions.iter().enumerate() {
+                assert_eq!(sheet.intersect(&a.rect), a.rect, "{} is off the sheet", a.name);
+                for b in &manifest.regions[i + 1..] {
+                    assert!(padded(a.rect).intersect(&padded(b.rect)).is_empty(), "{} and {} with padding {}", a.name, b.name, padding);
+                }
+            }
+            assert_pixels(&atlas);
+        }
+    }
+
+    #[test]
+    fn trimming_records_where_the_pixels_were() {
+        let atlas = builder(1).build().unwrap();
+        let region = atlas.get_region("trimmed").unwrap();
+        assert_eq!((region.rect.width, region.rect.height), (2, 3));
+        assert_eq!(region.offset, (3, 1));
+        assert_eq!(region.source_size, (6, 5));
+
+        let atlas = builder(1).with_trim(false).build().unwrap();
+        let region = atlas.get_region("trimmed").unwrap();
+        assert_eq!((region.rect.width, region.rect.height), (6, 5));
+        assert_eq!(region.offset, (0, 0));
+        assert_eq!(atlas.get("trimmed").unwrap().get_pixel(3, 1), Color(4, 3, 1, 255));
+        assert_eq!(atlas.get("trimmed").unwrap().get_pixel(0, 0), Color::CLEAR);
+    }
+
+    #[test]
+    fn sprites_that_cannot_fit_are_an_error() {
+        assert!(builder(1).with_max_size(8).build().is_err());
+        assert!(AtlasBuilder::new().build().unwrap().is_empty());
+    }
+
+    #[test]
+    fn saved_atlases_load_back_the_same() {
+        let atlas = builder(2).build().unwrap();
+        let dir = std::env::temp_dir();
+        let image_path = dir.join(format!("blueberry_atlas_{}.png", std::process::id()));
+        let manifest_path = dir.join(format!("blueberry_atlas_{}.ron", std::process::id()));
+        atlas.save(&image_path, &manifest_path).unwrap();
+        let loaded = PackedAtlas::load(&image_path, &manifest_path);
+
+        // A manifest for a different sheet size is rejected.
+        let mut manifest = AtlasManifest::load(&manifest_path).unwrap();
+        manifest.width += 1;
+        manifest.save(&manifest_path).unwrap();
+        let mismatched = PackedAtlas::load(&image_path, &manifest_path);
+        fs::remove_file(&image_path).unwrap();
+        fs::remove_file(&manifest_path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert!(mismatched.is_err());
+        assert_eq!(loaded.len(), atlas.len());
+        for region in &atlas.get_manifest().regions {
+            let other = loaded.get_region(&region.name).unwrap();
+            assert_eq!((other.rect, other.offset, other.source_size), (region.rect, region.offset, region.source_size));
+            assert_eq!(loaded.index_of(&region.name), atlas.index_of(&region.name));
+        }
+        assert_pixels(&loaded);
+    }
+}
@@ -1,19 +1,13 @@
-use std::path::{Path, PathBuf};
-use std::fs::File;
-use std::io::BufWriter;
-use png::{OutputInfo, Reader};
-use crate::math::{Vec2i, Vec2, Vec2u};
-
-const PATH_TO_SPRITES : &str = "./assets/sprites/";
+#![allow(deprecated)]
 
-fn read_from_file(filename : &str) -> (OutputInfo, Reader<File>) {
-    let mut path = PathBuf::from(PATH_TO_SPRITES);
-    path.push(filename);
-    let file = File::open(path).unwrap();
-    let decoder = png::Decoder::new(file);
-    decoder.read_info().expect("Unable to encode image! File may be corrupt or not a png!")
-}
+use std::io::BufWriter;
+use crate::math::{Vec2i, Vec2, Rect};
+use crate::color::{Color, BlendMode};
+use crate::image_buffer::{SingleImageBuffer, AtlasImageBuffer, ImageBuffer, clip_blit};
 
+/// The original byte-per-channel image. Kept only so old code can convert into the
+/// `image_buffer` types through `From`; nothing in the engine draws with it anymore.
+#[deprecated(note = "use `SingleImageBuffer` and `ImageView` from `image_buffer` instead")]
 #[derive(Debug, Clone)]
 pub struct Buffer {
     width : u32,
@@ -22,6 +16,7 @@ pub struct Buffer {
     buffer : Vec<u8>
 }
 
+#[deprecated(note = "use the `ImageBuffer` trait instead")]
 pub trait BufferProvider {
     fn get_buffer(&self) -> &Buffer;
 
@@ -60,74 +55,68 @@ impl Buffer {
     }
 
     pub fn from_png(filepath : &str) -> Buffer {
-        let (info, mut reader) = read_from_file(filepath);
-
-        let mut frame = vec![0; info.buffer_size()];
-        reader.next_frame(&mut frame).unwrap();
-
-        Buffer::from(frame, info.width, info.height)
+        let image = SingleImageBuffer::from(filepath);
+        let (width, height) = image.get_dimensions();
+        let bytes = image.get_buffer().iter().flat_map(|c| [c.0, c.1, c.2, c.3]).collect();
+        Buffer::from(bytes, width as u32, height as u32)
     }
 
     pub fn from_png_atlas(filepath : &str, x : u32, y : u32, width : u32, height : u32) -> Buffer {
-        let (info, mut reader) = read_from_file(filepath);
-
-        //println!("{:?}", info.bit_depth);
-
-        let mut frame = vec![0; info.buffer_size()];
-        reader.next_frame(&mut frame).unwrap();
+        Buffer::from_png(filepath).sub_buffer(x, y, width, height)
+    }
 
+    /// Copies a `width` by `height` rectangle starting at `x`, `y` into a new buffer.
+    pub fn sub_buffer(&self, x : u32, y : u32, width : u32, height : u32) -> Buffer {
         let mut buffer = Buffer::new(width, height);
 
         for i in 0..width {
             for j in 0..height {
-                let index : usize = ((x + i) * 4 + ((y + j) * info.width * 4)) as usize;
-                buffer.set_pixel(i, j,
-                                 frame[index],
-                                 frame[index + 1],
-                                 frame[index + 2],
-                                 frame[index + 3])
+                let (r, g, b, a) = self.get_pixel(x + i, y + j);
+                buffer.set_pixel(i, j, r, g, b, a)
             }
         }
 
         buffer
     }
 
-    fn calc_index(&self, x : u32, y : u32) -> usize {
-        ((x * 4 + (y * self.width * 4)) as usize).clamp(0, self.buffer.len().saturating_sub(1))
+    /// Where the first byte of the pixel at `x`, `y` is, or `None` outside the buffer.
+    fn calc_index(&self, x : u32, y : u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((x as usize + y as usize * self.width as usize) * 4)
+        } else {
+            Option::None
+        }
     }
 
     pub fn contains(&self, x : i32, y : i32) -> bool {
-        x > 0 && x < self.width as i32 && y > 0 && y < self.height as i32
+        x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32
     }
 
+    /// Ignored outside the buffer.
     pub fn set_pixel(&mut self, x : u32, y : u32, r : u8, g : u8, b : u8, a : u8) {
-        let index = self.calc_index(x, y);
-
-        self.buffer[index] = r;
-        self.buffer[index + 1] = g;
-        self.buffer[index + 2] = b;
-        self.buffer[index + 3] = a;
+        if let Some(index) = self.calc_index(x, y) {
+            self.buffer[index..index + 4].copy_from_slice(&[r, g, b, a]);
+        }
     }
 
     pub fn blend_pixel(&mut self, x : u32, y : u32, r : u8, g : u8, b : u8, a : u8) {
-        if x < self.width && y < self.height {
-            let index = self.calc_index(x, y);
-            let alpha : f32 = a as f32 / 255.0;
-
-            let rd = ((r as f32) * (alpha)) as u8;
-            let gd = ((g as f32) * (alpha)) as u8;
-            let bd = ((b as f32) * (alpha)) as u8;
+        self.blend_pixel_with(x, y, Color(r, g, b, a), BlendMode::Alpha)
+    }
 
-            self.buffer[index] = self.buffer[index].saturating_add(rd);
-            self.buffer[index + 1] = self.buffer[index + 1].saturating_add(gd);
-            self.buffer[index + 2] = self.buffer[index + 2].saturating_add(bd);
-            self.buffer[index + 3] = self.buffer[index + 3].saturating_add(a);
+    pub fn blend_pixel_with(&mut self, x : u32, y : u32, color : Color, mode : BlendMode) {
+        if let Some(index) = self.calc_index(x, y) {
+            let dst = Color(self.buffer[index], self.buffer[index + 1], self.buffer[index + 2], self.buffer[index + 3]);
+            let Color(r, g, b, a) = Color::blended(dst, color, mode);
+            self.buffer[index..index + 4].copy_from_slice(&[r, g, b, a]);
         }
     }
 
+    /// The pixel at `x`, `y`, transparent outside the buffer.
     pub fn get_pixel(&self, x : u32, y : u32) -> (u8, u8, u8, u8) {
-        let index = self.calc_index(x, y);
-        (self.buffer[index], self.buffer[index + 1], self.buffer[index + 2], self.buffer[index + 3])
+        match self.calc_index(x, y) {
+            Some(index) => (self.buffer[index], self.buffer[index + 1], self.buffer[index + 2], self.buffer[index + 3]),
+            None => (0, 0, 0, 0)
+        }
     }
 
     pub fn set_offset(&mut self, x : i32, y : i32) {
@@ -139,21 +128,33 @@ impl Buffer {
     }
 
     pub fn blit(&self, other: &mut Buffer, x : i32, y : i32) {
-        for i in 0..self.width {
-            for j in 0..self.height {
-                if other.contains(x + i as i32, y + j as i32) {
-                    let (r, g, b, a) = self.get_pixel(i, j);
-                    let (ox, oy) = self.offset.get_xy();
-                    other.blend_pixel((ox + x + i as i32) as u32, (oy + y + j as i32) as u32, r, g, b, a);
-                }
+        self.blit_with(other, x, y, BlendMode::Alpha)
+    }
+
+    /// Blends this buffer into `other` with its top left corner at `x`, `y` plus its offset.
+    pub fn blit_with(&self, other: &mut Buffer, x : i32, y : i32, mode : BlendMode) {
+        let (ox, oy) = self.offset.get_xy();
+        self.blit_rect(Rect::new(0, 0, self.width as i32, self.height as i32), other, x + ox, y + oy, mode)
+    }
+
+    /// Blends the `src` rectangle of this buffer into `other` with its top left corner at `x`,
+    /// `y`, ignoring the offset. Anything outside either buffer is skipped.
+    pub fn blit_rect(&self, src : Rect, other : &mut Buffer, x : i32, y : i32, mode : BlendMode) {
+        let bounds = Rect::new(0, 0, other.width as i32, other.height as i32);
+        let (src, x, y) = match clip_blit(src, (self.width as usize, self.height as usize), x, y, bounds) {
+            Some(clipped) => clipped,
+            None => return
+        };
+        for j in 0..src.height {
+            for i in 0..src.width {
+                let (r, g, b, a) = self.get_pixel((src.x + i) as u32, (src.y + j) as u32);
+                other.blend_pixel_with((x + i) as u32, (y + j) as u32, Color(r, g, b, a), mode);
             }
         }
     }
 
     pub fn dump(&self, arr : &mut [u8]) {
-        for (i, v) in self.buffer.iter().enumerate() {
-            arr[i] = *v;
-        }
+        arr[..self.buffer.len()].copy_from_slice(&self.buffer);
     }
 
     pub fn clear(&mut self) {
@@ -162,6 +163,7 @@ impl Buffer {
     }
 }
 
+/// Builds a `BufferAtlas`. Deprecated along with it.
 #[macro_export]
 macro_rules! buffer_atlas {
     ( $n:literal | $({$x:expr, $y:expr, $w:expr, $h:expr}),* ) => {
@@ -182,21 +184,25 @@ macro_rules! buffer_atlas {
     }
 }
 
+/// A sheet cut into copied frames. Convert it with `AtlasImageBuffer::from`, or load the sheet
+/// through `Assets` or `AtlasImageBuffer` directly so the frames stay views into one image.
+#[deprecated(note = "use `AtlasImageBuffer` instead")]
 pub struct BufferAtlas {
     buffers: Vec<Buffer>,
-    file : String,
+    sheet : Buffer,
     texture_width : u32,
     texture_height : u32
 }
 
 impl BufferAtlas {
+    /// Decodes the sheet once up front; `add` and `slice` copy out of it.
     pub fn new(filename : &str) -> BufferAtlas {
-        let (info, _) = read_from_file(filename);
+        let sheet = Buffer::from_png(filename);
         BufferAtlas {
             buffers: Vec::new(),
-            file: String::from(filename),
-            texture_width: info.width,
-            texture_height: info.height
+            texture_width: sheet.width,
+            texture_height: sheet.height,
+            sheet
         }
     }
 
@@ -214,7 +220,7 @@ impl BufferAtlas {
     }
 
     pub fn add(&mut self, x : u32, y : u32, width : u32, height : u32) {
-        self.buffers.push(Buffer::from_png_atlas(self.file.as_str(), x, y, width, height));
+        self.buffers.push(self.sheet.sub_buffer(x, y, width, height));
     }
 
     pub fn get_buffer(&self, index : usize) -> &Buffer {
@@ -224,4 +230,93 @@ impl BufferAtlas {
     pub fn len(&self) -> usize {
         self.buffers.len()
     }
-}
\ No newline at end of file
+}
+
+impl From<&Buffer> for SingleImageBuffer {
+    fn from(buffer : &Buffer) -> SingleImageBuffer {
+        let colors = buffer.buffer.chunks_exact(4).map(|c| Color(c[0], c[1], c[2], c[3])).collect();
+        SingleImageBuffer::from_colors(colors, buffer.width as usize, buffer.height as usize)
+    }
+}
+
+/// Lays the frames out side by side in one strip, in the same order.
+impl From<&BufferAtlas> for AtlasImageBuffer {
+    fn from(atlas : &BufferAtlas) -> AtlasImageBuffer {
+        let frames : Vec<SingleImageBuffer> = atlas.buffers.iter().map(|b| b.into()).collect();
+        AtlasImageBuffer::from_frames(&frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(width : u32, height : u32, base : u8) -> Buffer {
+        let mut buffer = Buffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                buffer.set_pixel(x, y, base, x as u8, y as u8, 255);
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn contains_includes_edges_and_corners() {
+        let buffer = Buffer::new(4, 3);
+        for y in -2..5 {
+            for x in -2..6 {
+                assert_eq!(buffer.contains(x, y), (0..4).contains(&x) && (0..3).contains(&y), "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_pixels_are_ignored() {
+        let mut buffer = numbered(3, 2, 10);
+        let before = buffer.clone();
+        for (x, y) in [(3, 0), (0, 2), (3, 1), (2, 2), (u32::MAX, u32::MAX)] {
+            buffer.set_pixel(x, y, 1, 2, 3, 255);
+            buffer.blend_pixel_with(x, y, Color(1, 2, 3, 255), BlendMode::Replace);
+            assert_eq!(buffer.get_pixel(x, y), (0, 0, 0, 0));
+        }
+        assert_eq!(buffer.buffer, before.buffer);
+        assert_eq!(buffer.get_pixel(2, 1), (10, 2, 1, 255));
+    }
+
+    #[test]
+    fn blit_matches_reference_at_every_position() {
+        for (ox, oy) in [(0, 0), (1, -1), (-3, 2)] {
+            let mut source = numbered(3, 2, 200);
+            source.set_offset(ox, oy);
+            for y in -5..7 {
+                for x in -6..8 {
+                    let mut actual = numbered(5, 4, 0);
+                    source.blit_with(&mut actual, x, y, BlendMode::Replace);
+
+                    let mut expected = numbered(5, 4, 0);
+                    for j in 0..2 {
+                        for i in 0..3 {
+                            let (dx, dy) = (x + ox + i, y + oy + j);
+                            if expected.contains(dx, dy) {
+                                expected.set_pixel(dx as u32, dy as u32, 200, i as u8, j as u8, 255);
+                            }
+                        }
+                    }
+                    assert_eq!(actual.buffer, expected.buffer, "offset {}, {} at {}, {}", ox, oy, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blit_rect_clips_source_to_buffer() {
+        let source = numbered(3, 3, 50);
+        let mut dest = Buffer::new(3, 3);
+        source.blit_rect(Rect::new(-1, 1, 3, 5), &mut dest, 0, 0, BlendMode::Replace);
+        assert_eq!(dest.get_pixel(0, 0), (0, 0, 0, 0));
+        assert_eq!(dest.get_pixel(1, 0), (50, 0, 1, 255));
+        assert_eq!(dest.get_pixel(2, 1), (50, 1, 2, 255));
+        assert_eq!(dest.get_pixel(1, 2), (0, 0, 0, 0));
+    }
+}
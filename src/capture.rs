@@ -0,0 +1,341 @@
+use crate::color::Color;
+use crate::image_buffer::{CamBuffer, ImageBuffer, ImageView, SingleImageBuffer};
+use game_loop::winit::event::VirtualKeyCode;
+use log::{error, info};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SCREENSHOT_KEY : VirtualKeyCode = VirtualKeyCode::F12;
+pub const RECORD_KEY : VirtualKeyCode = VirtualKeyCode::F10;
+
+/// Browsers slow GIFs with shorter delays right down, so faster frames are dropped instead.
+const MIN_GIF_DELAY : u16 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CaptureFormat {
+    Gif,
+    PngSequence
+}
+
+impl CaptureFormat {
+    /// GIF for `.gif` paths, a directory of numbered pngs for anything else.
+    pub fn for_path(path : &Path) -> CaptureFormat {
+        match path.extension() {
+            Some(e) if e.eq_ignore_ascii_case("gif") => CaptureFormat::Gif,
+            _ => CaptureFormat::PngSequence
+        }
+    }
+}
+
+/// Copies the game's pixels, without the imgui overlay, at `scale` times the native resolution.
+/// Transparent pixels come out black, as they look on screen.
+pub fn grab(source : ImageView, scale : usize) -> SingleImageBuffer {
+    let scale = scale.max(1);
+    let (width, height) = source.get_dimensions();
+    let mut image = SingleImageBuffer::new(width * scale, height * scale);
+
+    for y in 0..height * scale {
+        for x in 0..width * scale {
+            let Color(r, g, b, _) = source.get_pixel(x / scale, y / scale);
+            image.set_pixel(Color(r, g, b, 255), x, y);
+        }
+    }
+    image
+}
+
+/// `grab` of the screen as shown, with palette indices looked up.
+fn grab_screen(buffer : &CamBuffer, scale : usize) -> SingleImageBuffer {
+    let pixels = buffer.resolved();
+    let (width, height) = buffer.get_dimensions();
+    grab(ImageView::new(buffer.get_id(), &pixels, width, height), scale)
+}
+
+/// Saves what `buffer` holds right now as a png.
+pub fn screenshot(buffer : &CamBuffer, path : &Path, scale : usize) -> io::Result<()> {
+    grab_screen(buffer, scale).save(path)
+}
+
+struct Recording {
+    path : PathBuf,
+    format : CaptureFormat,
+    scale : usize,
+    /// Native resolution frames and when each started, in seconds since recording began.
+    frames : Vec<(SingleImageBuffer, f64)>,
+    elapsed : f64,
+    remaining : Option<usize>
+}
+
+impl Recording {
+    fn push(&mut self, buffer : &CamBuffer, delta : f64) {
+        let start = self.elapsed;
+        self.elapsed += delta;
+
+        // Still scenes would otherwise fill memory with identical frames.
+        let frame = grab_screen(buffer, 1);
+        if self.frames.last().is_some_and(|(last, _)| last.get_buffer() == frame.get_buffer()) { return }
+        self.frames.push((frame, start));
+    }
+
+    fn save(&self) -> io::Result<()> {
+        match self.format {
+            CaptureFormat::Gif => self.save_gif(),
+            CaptureFormat::PngSequence => self.save_pngs()
+        }
+    }
+
+    fn save_pngs(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        for (i, (frame, _)) in self.frames.iter().enumerate() {
+            grab(frame.as_view(), self.scale).save(&self.path.join(format!("frame_{:05}.png", i)))?;
+        }
+        Ok(())
+    }
+
+    fn save_gif(&self) -> io::Result<()> {
+        let gif_error = |e : gif::EncodingError| io::Error::new(io::ErrorKind::InvalidData, e);
+        let (width, height) = match self.frames.first() {
+            Some((frame, _)) => frame.get_dimensions(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No frames were captured"))
+        };
+        let (width, height) = (width * self.scale, height * self.scale);
+        if width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The capture is too large for a GIF"))
+        }
+
+        // Frames start on whole centiseconds; one that would show for less than the minimum is
+        // dropped and its time goes to the frame before it.
+        let centis = |seconds : f64| (seconds * 100.0).round() as u64;
+        let mut shown : Vec<(&SingleImageBuffer, u64)> = Vec::new();
+        for (frame, start) in &self.frames {
+            let start = centis(*start);
+            if shown.last().is_some_and(|&(_, last)| start < last + MIN_GIF_DELAY as u64) { continue }
+            shown.push((frame, start));
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = BufWriter::new(File::create(&self.path)?);
+        let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &[]).map_err(gif_error)?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+
+        let end = centis(self.elapsed);
+        for (i, &(frame, start)) in shown.iter().enumerate() {
+            let next = shown.get(i + 1).map_or(end, |&(_, s)| s);
+            let mut rgba : Vec<u8> = grab(frame.as_view(), self.scale).get_buffer().iter()
+                .flat_map(|c| [c.0, c.1, c.2, c.3])
+                .collect();
+            let mut gif_frame = gif::Frame::from_rgba_speed(width as u16, height as u16, &mut rgba, 10);
+            gif_frame.delay = next.saturating_sub(start).clamp(MIN_GIF_DELAY as u64, u16::MAX as u64) as u16;
+            encoder.write_frame(&gif_frame).map_err(gif_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Screenshots and clip recording of the `CamBuffer`. Requests are served at the end of the next
+/// rendered frame, once the scene is drawn and before it goes to the window.
+pub struct Capture {
+    /// Where screenshots and recordings without an explicit path are written.
+    pub directory : PathBuf,
+    /// Integer upscale applied to saved images. 1 keeps the native resolution.
+    pub scale : usize,
+    pub format : CaptureFormat,
+    screenshot_requested : bool,
+    recording : Option<Recording>,
+    taken : u32
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture {
+            directory : PathBuf::from("./captures"),
+            scale : 1,
+            format : CaptureFormat::Gif,
+            screenshot_requested : false,
+            recording : Option::None,
+            taken : 0
+        }
+    }
+
+    /// Saves a png of the next rendered frame into `directory`.
+    pub fn request_screenshot(&mut self) {
+        self.screenshot_requested = true;
+    }
+
+    /// Records every rendered frame into `directory` until `stop_recording`.
+    pub fn start_recording(&mut self) {
+        let extension = match self.format {
+            CaptureFormat::Gif => "gif",
+            CaptureFormat::PngSequence => ""
+        };
+        let path = self.next_path("clip", extension);
+        self.start_recording_to(path, self.format);
+    }
+
+    /// Records into `path`, replacing any recording in progress without saving it.
+    pub fn start_recording_to(&mut self, path : PathBuf, format : CaptureFormat) {
+        self.recording = Some(Recording { path, format, scale : self.scale, frames : Vec::new(), elapsed : 0.0, remaining : Option::None });
+    }
+
+    /// Records the next `frames` rendered frames and saves them on its own.
+    pub fn record_frames(&mut self, frames : usize) {
+        self.start_recording();
+        if let Some(recording) = &mut self.recording {
+            recording.remaining = Some(frames);
+        }
+    }
+
+    /// Ends the recording and writes it out. Does nothing if none is running.
+    pub fn stop_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            match recording.save() {
+                Ok(_) => info!("Saved {} captured frames to {}", recording.frames.len(), recording.path.display()),
+                Err(e) => error!("Unable to save capture {}: {}", recording.path.display(), e)
+            }
+        }
+    }
+
+    pub fn toggle_recording(&mut self) {
+        if self.is_recording() { self.stop_recording() } else { self.start_recording() }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Frames kept so far by the running recording. Repeated frames only count once.
+    pub fn get_frame_count(&self) -> usize {
+        self.recording.as_ref().map_or(0, |r| r.frames.len())
+    }
+
+    /// Seconds recorded so far.
+    pub fn get_recorded_time(&self) -> f64 {
+        self.recording.as_ref().map_or(0.0, |r| r.elapsed)
+    }
+
+    /// Takes any requested screenshot and feeds the recording. `delta` is how long this frame
+    /// stays on screen.
+    pub fn capture(&mut self, buffer : &CamBuffer, delta : f64) {
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            let path = self.next_path("screenshot", "png");
+            let result = fs::create_dir_all(&self.directory).and_then(|_| screenshot(buffer, &path, self.scale));
+            match result {
+                Ok(_) => info!("Saved screenshot {}", path.display()),
+                Err(e) => error!("Unable to save screenshot {}: {}", path.display(), e)
+            }
+        }
+
+        let finished = match &mut self.recording {
+            Some(recording) => {
+                recording.push(buffer, delta);
+                recording.remaining = recording.remaining.map(|r| r.saturating_sub(1));
+                recording.remaining == Some(0)
+            }
+            None => false
+        };
+        if finished {
+            self.stop_recording();
+        }
+    }
+
+    /// A file name in `directory` that won't collide with earlier captures.
+    fn next_path(&mut self, prefix : &str, extension : &str) -> PathBuf {
+        self.taken += 1;
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.directory.join(format!("{}_{}_{:03}", prefix, seconds, self.taken)).with_extension(extension)
+    }
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader;
+
+    fn screen(colors : [Color; 2]) -> CamBuffer {
+        let mut buffer = CamBuffer::new(2, 1);
+        buffer.set_pixel(colors[0], 0, 0);
+        buffer.set_pixel(colors[1], 1, 0);
+        buffer
+    }
+
+    fn temp_path(name : &str) -> PathBuf {
+        std::env::temp_dir().join(format!("blueberry_capture_{}_{}", name, std::process::id()))
+    }
+
+    const RED : Color = Color(255, 0, 0, 255);
+    const BLUE : Color = Color(0, 0, 255, 255);
+    const GREEN : Color = Color(0, 255, 0, 255);
+
+    #[test]
+    fn grab_scales_up_and_drops_alpha() {
+        let image = grab(screen([Color(1, 2, 3, 0), RED]).as_view(), 2);
+        assert_eq!(image.get_dimensions(), (4, 2));
+        let dark = Color(1, 2, 3, 255);
+        assert_eq!(image.get_buffer(), &vec![dark, dark, RED, RED, dark, dark, RED, RED]);
+    }
+
+    #[test]
+    fn repeated_frames_are_only_kept_once() {
+        let mut capture = Capture::new();
+        capture.start_recording_to(temp_path("unused.gif"), CaptureFormat::Gif);
+        for colors in [[RED, BLUE], [RED, BLUE], [BLUE, RED], [RED, BLUE], [RED, BLUE]] {
+            capture.capture(&screen(colors), 0.1);
+        }
+        assert_eq!(capture.get_frame_count(), 3);
+        assert!((capture.get_recorded_time() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gif_delays_add_up_the_time_of_dropped_frames() {
+        let path = temp_path("delays.gif");
+        let mut capture = Capture::new();
+        capture.start_recording_to(path.clone(), CaptureFormat::Gif);
+        // The repeat extends the first frame, and the green frame is too short to show.
+        for (colors, delta) in [([RED, RED], 0.1), ([RED, RED], 0.1), ([BLUE, BLUE], 0.01), ([GREEN, GREEN], 0.05), ([RED, BLUE], 0.2)] {
+            capture.capture(&screen(colors), delta);
+        }
+        capture.stop_recording();
+
+        let frames = image_loader::load_frames(&path);
+        fs::remove_file(&path).unwrap();
+        let frames = frames.unwrap();
+        let delays : Vec<f64> = frames.iter().map(|f| f.delay.unwrap()).collect();
+        assert_eq!(delays, vec![0.2, 0.06, 0.2]);
+        let firsts : Vec<Color> = frames.iter().map(|f| f.image.get_pixel(0, 0)).collect();
+        assert_eq!(firsts, vec![RED, BLUE, RED]);
+        assert_eq!(frames[2].image.get_pixel(1, 0), BLUE);
+    }
+
+    #[test]
+    fn recording_a_set_number_of_frames_saves_on_its_own() {
+        let mut capture = Capture::new();
+        capture.directory = temp_path("sequence");
+        capture.format = CaptureFormat::PngSequence;
+        capture.scale = 3;
+        capture.record_frames(2);
+        capture.capture(&screen([RED, BLUE]), 0.1);
+        assert!(capture.is_recording());
+        capture.capture(&screen([BLUE, RED]), 0.1);
+        assert!(!capture.is_recording());
+
+        let clips : Vec<PathBuf> = fs::read_dir(&capture.directory).unwrap().map(|e| e.unwrap().path()).collect();
+        let frames = fs::read_dir(&clips[0]).map(|d| d.count());
+        let second = SingleImageBuffer::load(&clips[0].join("frame_00001.png"));
+        fs::remove_dir_all(&capture.directory).unwrap();
+        assert_eq!(clips.len(), 1);
+        assert_eq!(frames.unwrap(), 2);
+        let second = second.unwrap();
+        assert_eq!(second.get_dimensions(), (6, 3));
+        assert_eq!(second.get_pixel(0, 0), BLUE);
+    }
+}
@@ -1,24 +1,242 @@
-#[derive(Copy, Clone, Eq, PartialEq)]
+use serde::{Serialize, Deserialize};
+use bytemuck::{Pod, Zeroable};
+
+/// RGBA, 8 bits per channel. Laid out as four bytes so pixel slices can be copied as raw bytes.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Pod, Zeroable)]
 pub struct Color(pub u8, pub u8, pub u8, pub u8);
 
+/// How a source color is combined with the color already in the destination. `sa` is the
+/// source alpha in `[0, 1]`, `s` and `d` are the source and destination channels.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Serialize, Deserialize)]
+pub enum BlendMode {
+    /// `d = s`, alpha included.
+    Replace,
+    /// Straight alpha "over": `d = s * sa + d * da * (1 - sa)`, divided by the resulting alpha.
+    #[default]
+    Alpha,
+    /// "Over" for colors whose channels are already multiplied by their alpha: `d = s + d * (1 - sa)`.
+    PremultipliedAlpha,
+    /// `d = d + s * sa`, saturating. Alpha is added too.
+    Add,
+    /// `d = d * s`, faded in by `sa`.
+    Multiply,
+    /// `d = 1 - (1 - d) * (1 - s)`, faded in by `sa`.
+    Screen,
+    /// `d = d - s * sa`, saturating. Destination alpha is kept.
+    Subtract
+}
+
+fn to_u8(value : f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+/// `numerator / denominator`, rounded to nearest and saturated to a channel.
+fn div_round(numerator : u32, denominator : u32) -> u8 {
+    ((2 * numerator + denominator) / (2 * denominator)).min(255) as u8
+}
+
 impl Color {
     pub const CLEAR : Color = Color(0, 0, 0, 0);
 
+    /// Component-wise linear interpolation, `t = 0.0` gives `a` and `t = 1.0` gives `b`.
+    pub fn lerp(a : Color, b : Color, t : f64) -> Color {
+        let mix = |a : u8, b : u8| to_u8(a as f64 + (b as f64 - a as f64) * t);
+        Color(mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2), mix(a.3, b.3))
+    }
+
+    pub fn premultiply(&self) -> Color {
+        let alpha = self.3 as f64 / 255.0;
+        Color(to_u8(self.0 as f64 * alpha), to_u8(self.1 as f64 * alpha), to_u8(self.2 as f64 * alpha), self.3)
+    }
+
+    pub fn unpremultiply(&self) -> Color {
+        if self.3 == 0 { return Color::CLEAR }
+        let alpha = self.3 as f64 / 255.0;
+        Color(to_u8(self.0 as f64 / alpha), to_u8(self.1 as f64 / alpha), to_u8(self.2 as f64 / alpha), self.3)
+    }
+
+    /// Blends `color` over this one with straight alpha.
     pub fn blend(&mut self, color : Color) {
-        //Blending A over B where A is the other color and B is current Color
-        let alpha_a : f64 = color.3 as f64 / 255.0;
-        let alpha_b : f64 = self.3 as f64 / 255.0;
-        let alpha = alpha_a + alpha_b * (1.0 - alpha_a);
+        self.blend_with(color, BlendMode::Alpha)
+    }
 
-        let r : u8 = ((color.0 as f64 * alpha_a + self.0 as f64 * alpha_b * (1.0 - alpha_a)) / alpha) as u8;
-        let g : u8 = ((color.1 as f64 * alpha_a + self.1 as f64 * alpha_b * (1.0 - alpha_a)) / alpha) as u8;
-        let b : u8 = ((color.2 as f64 * alpha_a + self.2 as f64 * alpha_b * (1.0 - alpha_a)) / alpha) as u8;
-        let a = alpha_a + alpha_b * (1.0 - alpha_a);
+    pub fn blend_with(&mut self, color : Color, mode : BlendMode) {
+        *self = Color::blended(*self, color, mode);
+    }
+
+    /// The result of blending `src` onto `dst` with `mode`. Works in fixed point: every channel
+    /// is the exact blend equation in units of 1/255, rounded once at the end.
+    pub fn blended(dst : Color, src : Color, mode : BlendMode) -> Color {
+        let (sa, da) = (src.3 as u32, dst.3 as u32);
+        let inverse = 255 - sa;
+        // Alpha of "over" in units of 1/255²
+        let over_alpha = sa * 255 + da * inverse;
+        let alpha = div_round(over_alpha, 255);
+
+        let channels = |f : &dyn Fn(u32, u32) -> u8| -> (u8, u8, u8) {
+            (f(dst.0 as u32, src.0 as u32), f(dst.1 as u32, src.1 as u32), f(dst.2 as u32, src.2 as u32))
+        };
+
+        match mode {
+            BlendMode::Replace => src,
+            BlendMode::Alpha => {
+                if over_alpha == 0 { return Color::CLEAR }
+                let (r, g, b) = channels(&|d, s| div_round(s * sa * 255 + d * da * inverse, over_alpha));
+                Color(r, g, b, alpha)
+            }
+            BlendMode::PremultipliedAlpha => {
+                let (r, g, b) = channels(&|d, s| div_round(s * 255 + d * inverse, 255));
+                Color(r, g, b, alpha)
+            }
+            BlendMode::Add => {
+                let (r, g, b) = channels(&|d, s| div_round(d * 255 + s * sa, 255));
+                Color(r, g, b, dst.3.saturating_add(src.3))
+            }
+            BlendMode::Subtract => {
+                let (r, g, b) = channels(&|d, s| div_round((d * 255).saturating_sub(s * sa), 255));
+                Color(r, g, b, dst.3)
+            }
+            // Separable modes fade the blended channel in over `d` by source alpha, which works
+            // out to these in units of 1/255².
+            BlendMode::Multiply => {
+                let (r, g, b) = channels(&|d, s| div_round(d * (65025 - (255 - s) * sa), 65025));
+                Color(r, g, b, alpha)
+            }
+            BlendMode::Screen => {
+                let (r, g, b) = channels(&|d, s| div_round(d * 65025 + (255 - d) * s * sa, 65025));
+                Color(r, g, b, alpha)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DST : Color = Color(100, 150, 200, 255);
+    const SRC : Color = Color(200, 100, 50, 128);
+
+    #[test]
+    fn replace_copies_source() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Replace), SRC);
+        assert_eq!(Color::blended(DST, Color::CLEAR, BlendMode::Replace), Color::CLEAR);
+    }
+
+    #[test]
+    fn alpha_over_opaque() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Alpha), Color(150, 125, 125, 255));
+    }
 
+    #[test]
+    fn alpha_over_transparent_keeps_source() {
+        assert_eq!(Color::blended(Color::CLEAR, SRC, BlendMode::Alpha), SRC);
+    }
+
+    #[test]
+    fn alpha_between_translucent_colors() {
+        // a = 0.5 + 0.5 * 0.5, r = (255 * 0.5) / 0.75, b = (255 * 0.5 * 0.5) / 0.75
+        let dst = Color(0, 0, 255, 128);
+        let src = Color(255, 0, 0, 128);
+        assert_eq!(Color::blended(dst, src, BlendMode::Alpha), Color(170, 0, 85, 192));
+    }
+
+    #[test]
+    fn alpha_extremes() {
+        assert_eq!(Color::blended(DST, Color(1, 2, 3, 255), BlendMode::Alpha), Color(1, 2, 3, 255));
+        assert_eq!(Color::blended(DST, Color(1, 2, 3, 0), BlendMode::Alpha), DST);
+        assert_eq!(Color::blended(Color::CLEAR, Color::CLEAR, BlendMode::Alpha), Color::CLEAR);
+    }
+
+    #[test]
+    fn premultiplied_matches_straight_alpha() {
+        let premultiplied = SRC.premultiply();
+        assert_eq!(premultiplied, Color(100, 50, 25, 128));
+        assert_eq!(Color::blended(DST, premultiplied, BlendMode::PremultipliedAlpha), Color(150, 125, 125, 255));
+    }
+
+    #[test]
+    fn premultiply_round_trip() {
+        assert_eq!(SRC.premultiply().unpremultiply(), Color(199, 100, 50, 128));
+        assert_eq!(Color(10, 20, 30, 0).premultiply().unpremultiply(), Color::CLEAR);
+    }
+
+    #[test]
+    fn add_saturates() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Add), Color(200, 200, 225, 255));
+        assert_eq!(Color::blended(DST, Color(255, 255, 255, 255), BlendMode::Add), Color(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn multiply() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Multiply), Color(89, 104, 119, 255));
+        assert_eq!(Color::blended(DST, Color(255, 255, 255, 255), BlendMode::Multiply), DST);
+        assert_eq!(Color::blended(DST, Color(0, 0, 0, 255), BlendMode::Multiply), Color(0, 0, 0, 255));
+    }
+
+    #[test]
+    fn screen() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Screen), Color(161, 171, 205, 255));
+        assert_eq!(Color::blended(DST, Color(0, 0, 0, 255), BlendMode::Screen), DST);
+        assert_eq!(Color::blended(DST, Color(255, 255, 255, 255), BlendMode::Screen), Color(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn subtract_saturates_and_keeps_alpha() {
+        assert_eq!(Color::blended(DST, SRC, BlendMode::Subtract), Color(0, 100, 175, 255));
+        assert_eq!(Color::blended(Color(10, 10, 10, 64), Color(5, 20, 0, 255), BlendMode::Subtract), Color(5, 0, 10, 64));
+    }
+
+    /// The blend equations in floating point, as documented on `BlendMode`.
+    fn reference(dst : Color, src : Color, mode : BlendMode) -> Color {
+        let (sa, da) = (src.3 as f64 / 255.0, dst.3 as f64 / 255.0);
+        let over_alpha = sa + da * (1.0 - sa);
+        let channels = |f : &dyn Fn(f64, f64) -> f64| Color(
+            to_u8(f(dst.0 as f64, src.0 as f64)),
+            to_u8(f(dst.1 as f64, src.1 as f64)),
+            to_u8(f(dst.2 as f64, src.2 as f64)),
+            to_u8(over_alpha * 255.0)
+        );
+        let fade = |f : &dyn Fn(f64, f64) -> f64| channels(&|d, s| d + (f(d, s) - d) * sa);
+        match mode {
+            BlendMode::Replace => src,
+            BlendMode::Alpha if over_alpha <= 0.0 => Color::CLEAR,
+            BlendMode::Alpha => channels(&|d, s| (s * sa + d * da * (1.0 - sa)) / over_alpha),
+            BlendMode::PremultipliedAlpha => channels(&|d, s| s + d * (1.0 - sa)),
+            BlendMode::Add => Color { 3 : dst.3.saturating_add(src.3), ..channels(&|d, s| d + s * sa) },
+            BlendMode::Subtract => Color { 3 : dst.3, ..channels(&|d, s| d - s * sa) },
+            BlendMode::Multiply => fade(&|d, s| d * s / 255.0),
+            BlendMode::Screen => fade(&|d, s| 255.0 - (255.0 - d) * (255.0 - s) / 255.0)
+        }
+    }
+
+    #[test]
+    fn fixed_point_matches_float_equations() {
+        let modes = [BlendMode::Replace, BlendMode::Alpha, BlendMode::PremultipliedAlpha, BlendMode::Add,
+            BlendMode::Subtract, BlendMode::Multiply, BlendMode::Screen];
+        let values = (0..=255).step_by(15).chain([1, 127, 128, 254]).collect::<Vec<u8>>();
+        for mode in modes {
+            for &sa in &values {
+                for &da in &values {
+                    for &s in &values {
+                        for &d in &values {
+                            let (dst, src) = (Color(d, 255 - d, d / 2, da), Color(s, s / 3, 255 - s, sa));
+                            let (fixed, float) = (Color::blended(dst, src, mode), reference(dst, src, mode));
+                            let off = |a : u8, b : u8| (a as i32 - b as i32).abs() <= 1;
+                            assert!(off(fixed.0, float.0) && off(fixed.1, float.1) && off(fixed.2, float.2) && fixed.3 == float.3,
+                                "{:?} of {:?} over {:?}: {:?} vs {:?}", mode, src, dst, fixed, float);
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-        self.0 = r;
-        self.1 = g;
-        self.2 = b;
-        self.0 = (alpha * 255.0) as u8;
+    #[test]
+    fn blend_is_alpha_over() {
+        let mut color = DST;
+        color.blend(SRC);
+        assert_eq!(color, Color(150, 125, 125, 255));
     }
-}
\ No newline at end of file
+}
@@ -1,41 +1,206 @@
-use crate::image_buffer::{ImageBuffer, CamBuffer, SingleImageBuffer};
+use crate::image_buffer::{ImageBuffer, ImageSource, ImageView, CamBuffer, DrawParams};
+use crate::sprite_cache::SpriteCache;
+use crate::color::BlendMode;
+use std::cell::RefCell;
+use std::rc::Rc;
 use crate::comps::object::{GameComponent, GameObject};
 use std::any::Any;
 use crate::frame::FrameInfo;
 use crate::input::InputInfo;
 use crate::comps::transform::TransformComponent;
+use crate::math::{Rect, Vec2, Vec2f};
+use crate::dirty;
 
-pub struct ImageBufferRenderComponent<T: ImageBuffer> {
+/// How a sprite is resampled when its transform has a rotation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RotationMode {
+    /// Samples the source directly while blitting. Cheap, but jaggy at odd angles.
+    Nearest,
+    /// Blits a RotSprite result from the component's `SpriteCache`, quantizing the angle to the
+    /// cache's steps.
+    RotSprite
+}
+
+pub struct ImageBufferRenderComponent<T: ImageSource> {
     buffer : T,
-    transform: Option<Box<TransformComponent>>,
-    rotation: f64
+    pivot : Vec2f,
+    flip_x : bool,
+    flip_y : bool,
+    rotation_mode : RotationMode,
+    blend_mode : BlendMode,
+    cache : Rc<RefCell<SpriteCache>>
 }
 
-impl<T: ImageBuffer> ImageBufferRenderComponent<T> {
+impl<T: ImageSource> ImageBufferRenderComponent<T> {
     pub fn new(buffer : T) -> ImageBufferRenderComponent<T> {
         ImageBufferRenderComponent {
             buffer,
-            transform: Option::None,
-            rotation: 0.0
+            pivot : Vec2f::zero(),
+            flip_x : false,
+            flip_y : false,
+            rotation_mode : RotationMode::Nearest,
+            blend_mode : BlendMode::Alpha,
+            cache : SpriteCache::shared(256 * 1024, 128)
+        }
+    }
+
+    /// The point, in source pixels, the sprite is positioned, scaled and rotated around.
+    pub fn with_pivot(mut self, x : f64, y : f64) -> ImageBufferRenderComponent<T> {
+        self.pivot.set_xy(x, y);
+        self
+    }
+
+    /// Pivots around the middle of the image.
+    pub fn centered(self) -> ImageBufferRenderComponent<T> {
+        let (width, height) = self.buffer.get_size();
+        self.with_pivot(width as f64 / 2.0, height as f64 / 2.0)
+    }
+
+    pub fn with_rotation_mode(mut self, rotation_mode : RotationMode) -> ImageBufferRenderComponent<T> {
+        self.rotation_mode = rotation_mode;
+        self
+    }
+
+    pub fn with_blend_mode(mut self, blend_mode : BlendMode) -> ImageBufferRenderComponent<T> {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    /// Shares a cache between sprites, usually `GameState::sprite_cache`. Components otherwise
+    /// get a small cache of their own.
+    pub fn with_cache(mut self, cache : Rc<RefCell<SpriteCache>>) -> ImageBufferRenderComponent<T> {
+        self.cache = cache;
+        self
+    }
+
+    pub fn set_flip(&mut self, flip_x : bool, flip_y : bool) {
+        self.flip_x = flip_x;
+        self.flip_y = flip_y;
+    }
+
+    /// Draws one frame of the source, going through the sprite cache for RotSprite rotations.
+    fn draw_view(&self, source : ImageView, main_buffer : &mut CamBuffer, mut params : DrawParams) {
+        if self.rotation_mode == RotationMode::Nearest || params.rotation % 360.0 == 0.0 {
+            source.draw(main_buffer, &params);
+            return
         }
+
+        // Recording only needs where the sprite lands, so it leaves the cache alone. RotSprite can
+        // put pixels one past the direct transform's bounds.
+        if main_buffer.is_recording() {
+            if let Some(bounds) = params.bounds(source.get_dimensions()) {
+                let bounds = Rect::new(bounds.x - 1, bounds.y - 1, bounds.width + 2, bounds.height + 2);
+                main_buffer.record_draw(bounds, dirty::draw_key(&(source.get_id(), source.get_rect(), params, "rotsprite")));
+            }
+            return
+        }
+
+        let (width, height) = source.get_dimensions();
+        let (scale_x, scale_y) = params.scale.get_xy();
+        let mut cache = self.cache.borrow_mut();
+        let key = cache.key(&source, params.rotation, scale_x, scale_y, self.flip_x, self.flip_y);
+        let mut angle = cache.step_angle(key.angle_step);
+        if self.flip_x != self.flip_y { angle = -angle }
+        let sprite = cache.get_or_insert(key, &source);
+
+        // Carry the pivot through the same scale, rotate and flip the cached sprite went through.
+        let (px, py) = self.pivot.get_xy();
+        let (cx, cy) = ((px - width as f64 / 2.0) * scale_x.abs(), (py - height as f64 / 2.0) * scale_y.abs());
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let mut rx = cx * cos - cy * sin;
+        let mut ry = cx * sin + cy * cos;
+        if self.flip_x { rx = -rx }
+        if self.flip_y { ry = -ry }
+
+        params.pivot.set_xy(sprite.center.0 + rx, sprite.center.1 + ry);
+        params.scale.set_xy(1.0, 1.0);
+        params.rotation = 0.0;
+        params.flip_x = false;
+        params.flip_y = false;
+        sprite.image.draw(main_buffer, &params);
     }
 }
 
-impl<T: 'static + ImageBuffer> GameComponent for ImageBufferRenderComponent<T> {
+impl<T: 'static + ImageSource> GameComponent for ImageBufferRenderComponent<T> {
     fn on_attach(&mut self, obj: &mut GameObject) -> bool {
         true
     }
 
-    fn render(&mut self, main_buffer: &mut CamBuffer) {
-        let adj = self.buffer.rotate_safe(self.rotation, SingleImageBuffer::new(0,0));
-        adj.blend(main_buffer, 60, 30)
-    }
+    fn render(&mut self, main_buffer: &mut CamBuffer, frame_info: &FrameInfo, transform: Option<&TransformComponent>) {
+        let (cam_x, cam_y) = main_buffer.get_offset().get_xy();
+        let mut params = DrawParams::at(-cam_x as f64, -cam_y as f64);
+        params.pivot = self.pivot;
+        params.flip_x = self.flip_x;
+        params.flip_y = self.flip_y;
+        params.blend = self.blend_mode;
 
-    fn update(&mut self, frame_info: &FrameInfo, input_info: &InputInfo) {
-        self.rotation += 0.8
+        if let Some(t) = transform {
+            params.pos.add_vec(&t.interpolated_pos(frame_info));
+            params.scale = t.interpolated_scale(frame_info);
+            params.rotation = t.interpolated_rotation(frame_info);
+        }
+
+        self.buffer.with_view(|source| self.draw_view(source, main_buffer, params));
     }
 
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::image_buffer::SingleImageBuffer;
+
+    /// The smallest rect holding every pixel `mode` draws of an 8x4 sprite turned by `rotation`
+    /// and scaled by `scale`.
+    fn covered(mode : RotationMode, rotation : f64, scale : (f64, f64), pivot : (f64, f64)) -> Rect {
+        let sprite = SingleImageBuffer::from_colors(vec![Color(255, 255, 255, 255); 32], 8, 4);
+        let mut component = ImageBufferRenderComponent::new(sprite).with_pivot(pivot.0, pivot.1).with_rotation_mode(mode);
+        let mut transform = TransformComponent::from(24, 24);
+        transform.rotation = rotation;
+        transform.scale.set_xy(scale.0, scale.1);
+        transform.snapshot();
+
+        let mut buffer = CamBuffer::new(48, 48);
+        component.render(&mut buffer, &FrameInfo::new(0.0), Some(&transform));
+        let drawn = (0..48 * 48).filter(|i| buffer.get_pixel(i % 48, i / 48) != Color::CLEAR);
+        drawn.fold(Rect::default(), |rect, i| rect.union(&Rect::new((i % 48) as i32, (i / 48) as i32, 1, 1)))
+    }
+
+    #[test]
+    fn rotsprite_scales_before_rotating_like_nearest() {
+        // RotSprite resamples differently, so edges may land a pixel apart.
+        let close = |a : Rect, b : Rect| [(a.x, b.x), (a.y, b.y), (a.right(), b.right()), (a.bottom(), b.bottom())].iter().all(|(a, b)| (a - b).abs() <= 1);
+        for (rotation, scale, pivot) in [(90.0, (2.0, 0.5), (4.0, 2.0)), (270.0, (2.0, 0.5), (4.0, 2.0)),
+            (90.0, (3.0, 1.0), (0.0, 0.0)), (180.0, (0.5, 2.0), (8.0, 4.0)), (45.0, (2.0, 0.5), (4.0, 2.0))] {
+            let (nearest, rotsprite) = (covered(RotationMode::Nearest, rotation, scale, pivot), covered(RotationMode::RotSprite, rotation, scale, pivot));
+            assert!(close(nearest, rotsprite), "{} degrees at {:?}: {:?} vs {:?}", rotation, scale, nearest, rotsprite);
+        }
+        let turned = covered(RotationMode::RotSprite, 90.0, (2.0, 0.5), (4.0, 2.0));
+        assert!(turned.height >= 15 && turned.width <= 3, "{:?}", turned);
+    }
+
+    #[test]
+    fn recording_rotsprite_skips_the_cache() {
+        let sprite = SingleImageBuffer::from_colors(vec![Color(255, 255, 255, 255); 32], 8, 4);
+        let cache = SpriteCache::shared(64 * 1024, 16);
+        let mut component = ImageBufferRenderComponent::new(sprite).centered().with_rotation_mode(RotationMode::RotSprite)
+            .with_cache(cache.clone());
+        let mut transform = TransformComponent::from(24, 24);
+        transform.rotation = 90.0;
+        transform.snapshot();
+
+        let mut buffer = CamBuffer::new(48, 48);
+        buffer.start_recording();
+        component.render(&mut buffer, &FrameInfo::new(0.0), Some(&transform));
+        let record = buffer.take_record().unwrap();
+        assert!(cache.borrow().is_empty());
+        assert_eq!(cache.borrow().get_stats().misses, 0);
+
+        let drawn = covered(RotationMode::RotSprite, 90.0, (1.0, 1.0), (4.0, 2.0));
+        assert_eq!(record.bounds.union(&drawn), record.bounds);
+    }
+}
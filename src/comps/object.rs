@@ -2,13 +2,18 @@ use std::any::Any;
 use imgui::Ui;
 use crate::frame::FrameInfo;
 use crate::input::InputInfo;
-use crate::buffer::Buffer;
 use crate::image_buffer::CamBuffer;
 use std::any::type_name;
+use crate::comps::transform::TransformComponent;
 
 pub struct GameObject {
     components : Vec<Box<dyn GameComponent>>,
+    transform : Option<TransformComponent>,
     pub active: bool,
+    /// Objects on unscaled time ignore the `GameState` time scale and keep updating while paused.
+    pub unscaled_time: bool,
+    /// Objects render from the lowest layer up. Objects on the same layer render by name.
+    pub layer: i32,
     pub name : String
 }
 
@@ -16,12 +21,25 @@ impl GameObject {
     pub fn new(name : &str) -> GameObject {
         GameObject {
             active: true,
+            unscaled_time: false,
+            layer: 0,
             components: Vec::new(),
+            transform: Option::None,
             name : String::from(name)
         }
     }
 
+    pub fn with_layer(mut self, layer : i32) -> GameObject {
+        self.layer = layer;
+        self
+    }
+
     pub fn get_comp<T>(&self) -> Option<&T> where T: 'static + GameComponent {
+        if let Some(transform) = &self.transform {
+            let o : Option<&T> = (transform as &dyn Any).downcast_ref::<T>();
+            if o.is_some() { return o }
+        }
+
         for i in self.components.iter() {
             let o : Option<&T> = i.as_ref().as_any().downcast_ref::<T>();
             if let Some(comp) = o {
@@ -37,34 +55,59 @@ impl GameObject {
     }
 
     pub fn has_comp<T>(&self) -> bool where T: 'static + GameComponent {
-        for i in self.components.iter() {
-            let o : Option<&T> = i.as_ref().as_any().downcast_ref::<T>();
-            if o.is_some() { return true }
-        };
+        self.get_comp::<T>().is_some()
+    }
+
+    /// The transform is kept out of the component list so that every other component can be
+    /// handed a reference to it while they are being iterated.
+    pub fn get_transform(&self) -> Option<&TransformComponent> {
+        self.transform.as_ref()
+    }
 
-        false
+    pub fn get_transform_mut(&mut self) -> Option<&mut TransformComponent> {
+        self.transform.as_mut()
     }
 
     pub fn add_comp<T>(&mut self, mut gc: T) where T: 'static + GameComponent {
         if !gc.on_attach(self) {return}
+        if let Some(transform) = (&mut gc as &mut dyn Any).downcast_mut::<TransformComponent>() {
+            self.transform = Option::Some(*transform);
+            return
+        }
         self.components.push(Box::new(gc));
     }
 
     pub fn update(&mut self, frame_info: &FrameInfo, input_info : &InputInfo) {
         if !self.active {return}
+        if let Some(transform) = &mut self.transform {
+            transform.snapshot();
+        }
         for i in self.components.iter_mut() {
-            i.update(frame_info, input_info)
+            i.update(frame_info, input_info, self.transform.as_mut())
         }
     }
 
-    pub fn render(&mut self, main_buffer : &mut CamBuffer) {
+    /// Called instead of `update` while the game is paused so the renderer stops interpolating.
+    pub fn freeze(&mut self) {
+        if let Some(transform) = &mut self.transform {
+            transform.snapshot();
+        }
+        for i in self.components.iter_mut() {
+            i.freeze()
+        }
+    }
+
+    pub fn render(&mut self, main_buffer : &mut CamBuffer, frame_info: &FrameInfo) {
         if !self.active {return}
         for i in self.components.iter_mut() {
-            i.render(main_buffer)
+            i.render(main_buffer, frame_info, self.transform.as_ref())
         }
     }
 
     pub fn debug_objects(&mut self, ui : &Ui) {
+        if let Some(transform) = &mut self.transform {
+            transform.object_debug(ui)
+        }
         for i in self.components.iter_mut() {
             i.object_debug(ui)
         }
@@ -73,8 +116,11 @@ impl GameObject {
 
 pub trait GameComponent {
     fn on_attach(&mut self, obj : &mut GameObject) -> bool {true}
-    fn render(&mut self, main_buffer : &mut CamBuffer) {}
-    fn update(&mut self, frame_info: &FrameInfo, input_info : &InputInfo) {}
+    fn render(&mut self, main_buffer : &mut CamBuffer, frame_info: &FrameInfo, transform : Option<&TransformComponent>) {}
+    fn update(&mut self, frame_info: &FrameInfo, input_info : &InputInfo, transform : Option<&mut TransformComponent>) {}
+    /// Called instead of `update` while the game is paused. Components that interpolate their own
+    /// state forget the previous update here, so the paused frame stands still.
+    fn freeze(&mut self) {}
     fn object_debug(&mut self, ui : &Ui) {}
     fn priority(&self) -> u32 {u32::MAX}
     fn as_any(&self) -> &dyn Any;
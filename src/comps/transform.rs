@@ -1,22 +1,63 @@
 use crate::math::{Vec2i, Vec2f, Vec2};
 use crate::comps::object::{GameComponent, GameObject};
+use crate::frame::FrameInfo;
 use std::any::Any;
 use imgui::{Ui, Slider, im_str, InputInt2, InputInt, InputFloat};
 
+#[derive(Copy, Clone)]
 pub struct TransformComponent {
     pub pos: Vec2i,
     pub scale: Vec2f,
-    pub rotation: f64
+    pub rotation: f64,
+    prev_pos: Vec2i,
+    prev_scale: Vec2f,
+    prev_rotation: f64
 }
 
 impl TransformComponent {
     pub fn new() -> TransformComponent {
         TransformComponent {
             pos: Vec2i::new(0, 0),
-            scale: Vec2f::zero(),
+            scale: Vec2f::new(1.0, 1.0),
             rotation: 0.0,
+            prev_pos: Vec2i::new(0, 0),
+            prev_scale: Vec2f::new(1.0, 1.0),
+            prev_rotation: 0.0
         }
     }
+
+    pub fn from(x : i32, y : i32) -> TransformComponent {
+        let mut transform = TransformComponent::new();
+        transform.pos.set_xy(x, y);
+        transform.snapshot();
+        transform
+    }
+
+    /// Remembers the current state as the previous update's state. `GameObject` calls this
+    /// before every update so renderers can blend between the two.
+    pub fn snapshot(&mut self) {
+        self.prev_pos = self.pos;
+        self.prev_scale = self.scale;
+        self.prev_rotation = self.rotation;
+    }
+
+    pub fn interpolated_pos(&self, frame_info : &FrameInfo) -> Vec2f {
+        let (x, y) = self.pos.get_xy();
+        let (px, py) = self.prev_pos.get_xy();
+        Vec2f::new(frame_info.interpolate(px as f64, x as f64), frame_info.interpolate(py as f64, y as f64))
+    }
+
+    pub fn interpolated_scale(&self, frame_info : &FrameInfo) -> Vec2f {
+        let (x, y) = self.scale.get_xy();
+        let (px, py) = self.prev_scale.get_xy();
+        Vec2f::new(frame_info.interpolate(px, x), frame_info.interpolate(py, y))
+    }
+
+    /// Blends along the shorter arc so a rotation that wraps from 359 to 0 doesn't spin back.
+    pub fn interpolated_rotation(&self, frame_info : &FrameInfo) -> f64 {
+        let delta = (self.rotation - self.prev_rotation + 180.0).rem_euclid(360.0) - 180.0;
+        frame_info.interpolate(self.rotation - delta, self.rotation)
+    }
 }
 
 impl GameComponent for TransformComponent {
@@ -35,4 +76,45 @@ impl GameComponent for TransformComponent {
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(rotation : (f64, f64), pos : (i32, i32)) -> TransformComponent {
+        let mut transform = TransformComponent::new();
+        transform.rotation = rotation.0;
+        transform.snapshot();
+        transform.rotation = rotation.1;
+        transform.pos.set_xy(pos.0, pos.1);
+        transform
+    }
+
+    fn at(alpha : f64) -> FrameInfo {
+        let mut frame_info = FrameInfo::new(1.0 / 60.0);
+        frame_info.alpha = alpha;
+        frame_info
+    }
+
+    #[test]
+    fn position_blends_from_the_previous_update() {
+        let transform = moved((0.0, 0.0), (10, -4));
+        let (x, y) = transform.interpolated_pos(&at(0.5)).get_xy();
+        assert_eq!((x, y), (5.0, -2.0));
+        let (x, y) = transform.interpolated_pos(&at(0.0)).get_xy();
+        assert_eq!((x, y), (0.0, 0.0));
+    }
+
+    #[test]
+    fn rotation_takes_the_short_way_around() {
+        let angle = |rotation : (f64, f64), alpha| moved(rotation, (0, 0)).interpolated_rotation(&at(alpha)).rem_euclid(360.0);
+        // 350 to 10 is 20 degrees forward, not 340 back.
+        assert_eq!(angle((350.0, 10.0), 0.5), 0.0);
+        assert_eq!(angle((350.0, 10.0), 0.25), 355.0);
+        assert_eq!(angle((10.0, 350.0), 0.75), 355.0);
+        assert_eq!(angle((10.0, 90.0), 0.5), 50.0);
+        assert_eq!(angle((10.0, 90.0), 1.0), 90.0);
+        assert_eq!(angle((10.0, 90.0), 0.0), 10.0);
+    }
+}
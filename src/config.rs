@@ -0,0 +1,75 @@
+use crate::color::Color;
+use crate::screen::ScalingMode;
+use crate::window::WindowMode;
+use log::{error, info};
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const CONFIG_PATH : &str = "./config.ron";
+
+/// Display settings read at startup. Missing fields take their defaults, so a config file only
+/// needs the settings it changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub title : String,
+    /// The logical resolution everything is drawn at.
+    pub width : u32,
+    pub height : u32,
+    /// How many times the logical resolution the window opens at.
+    pub window_scale : u32,
+    pub scaling : ScalingMode,
+    /// Fills the parts of the window the game doesn't cover.
+    pub letterbox : Color,
+    pub window_mode : WindowMode,
+    pub vsync : bool
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            title : String::from("Project Blueberry"),
+            width : 240,
+            height : 160,
+            window_scale : 4,
+            scaling : ScalingMode::Integer,
+            letterbox : Color(0, 0, 0, 255),
+            window_mode : WindowMode::Windowed,
+            vsync : true
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn load(path : &Path) -> io::Result<GameConfig> {
+        let contents = fs::read_to_string(path)?;
+        let config : GameConfig = ron::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if config.width == 0 || config.height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The resolution can't be zero"))
+        }
+        Ok(config)
+    }
+
+    /// Like `load`, but falls back to the defaults when the file is missing or broken.
+    pub fn load_or_default(path : &Path) -> GameConfig {
+        match GameConfig::load(path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No config at {}, using the defaults", path.display());
+                GameConfig::default()
+            }
+            Err(e) => {
+                error!("Unable to load config {}, using the defaults: {}", path.display(), e);
+                GameConfig::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::comps::object::GameObject;
+use crate::math::Rect;
+
+/// Hashes whatever identifies a draw, for `ImageBuffer::record_draw`.
+pub fn draw_key(parts : &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What one object drew in a frame: the area it covered and a key that changes whenever any of
+/// its draws would put different pixels there.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DrawRecord {
+    pub bounds : Rect,
+    pub key : u64
+}
+
+/// Collects draws while a `CamBuffer` is recording, see `CamBuffer::start_recording`.
+pub struct DrawRecorder {
+    bounds : Rect,
+    hasher : DefaultHasher
+}
+
+impl DrawRecorder {
+    pub fn new() -> DrawRecorder {
+        DrawRecorder {
+            bounds : Rect::default(),
+            hasher : DefaultHasher::new()
+        }
+    }
+
+    /// Adds a draw covering `rect`. Draws that cover nothing don't count.
+    pub fn record(&mut self, rect : Rect, key : u64) {
+        if rect.is_empty() { return }
+        self.bounds = self.bounds.union(&rect);
+        rect.hash(&mut self.hasher);
+        self.hasher.write_u64(key);
+    }
+
+    /// Everything recorded since the last call.
+    pub fn finish(&mut self) -> DrawRecord {
+        let record = DrawRecord { bounds : self.bounds, key : self.hasher.finish() };
+        *self = DrawRecorder::new();
+        record
+    }
+}
+
+impl Default for DrawRecorder {
+    fn default() -> Self {
+        DrawRecorder::new()
+    }
+}
+
+/// Rects that need redrawing. Touching or overlapping rects are merged, and past `max_regions`
+/// everything collapses into one bounding rect.
+#[derive(Debug, Clone)]
+pub struct DirtyRegions {
+    rects : Vec<Rect>,
+    max_regions : usize
+}
+
+impl DirtyRegions {
+    pub fn new(max_regions : usize) -> DirtyRegions {
+        DirtyRegions { rects : Vec::new(), max_regions : max_regions.max(1) }
+    }
+
+    pub fn mark(&mut self, rect : Rect) {
+        if rect.is_empty() { return }
+        let mut rect = rect;
+        // Merging can make a rect reach others it didn't touch before, so go until none do.
+        while let Some(i) = self.rects.iter().position(|r| r.touches(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(i));
+        }
+        self.rects.push(rect);
+
+        if self.rects.len() > self.max_regions {
+            let bounds = self.rects.iter().fold(Rect::default(), |a, r| a.union(r));
+            self.rects = vec![bounds];
+        }
+    }
+
+    pub fn get_rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+/// What `GameState::render` has to do for a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Redraw {
+    /// Clear and draw the whole buffer.
+    Full,
+    /// Clear and draw only these rects, one clipped pass each.
+    Regions(Vec<Rect>),
+    /// Nothing changed.
+    None
+}
+
+/// Dirty rectangle rendering. Off by default, in which case every frame is drawn from scratch.
+///
+/// When on, the `CamBuffer` keeps its pixels between frames. Each frame every object is first
+/// rendered in recording mode, which writes nothing but notes where each draw lands and what it
+/// draws. Objects whose record differs from last frame mark both their old and new bounds dirty,
+/// and only those rects are cleared and redrawn. A frame with nothing dirty is not drawn at all.
+///
+/// Draws are told apart by image id and draw params, so images edited in place need a `mark`.
+/// Lighting and enabled post processing rework the whole frame, so they force full redraws.
+pub struct DirtyRenderer {
+    pub enabled : bool,
+    regions : DirtyRegions,
+    full : bool,
+    objects : HashMap<String, DrawRecord>,
+    last_size : (usize, usize),
+    last_offset : (i32, i32),
+    last_palette : Option<u64>,
+    last_redraw : Redraw,
+    skipped_frames : u64,
+    was_forced : bool
+}
+
+impl DirtyRenderer {
+    pub fn new() -> DirtyRenderer {
+        DirtyRenderer {
+            enabled : false,
+            regions : DirtyRegions::new(8),
+            full : true,
+            objects : HashMap::new(),
+            last_size : (0, 0),
+            last_offset : (0, 0),
+            last_palette : Option::None,
+            last_redraw : Redraw::Full,
+            skipped_frames : 0,
+            was_forced : false
+        }
+    }
+
+    pub fn with_max_regions(mut self, max_regions : usize) -> DirtyRenderer {
+        self.regions = DirtyRegions::new(max_regions);
+        self
+    }
+
+    /// Redraws `rect` next frame, in screen pixels.
+    pub fn mark(&mut self, rect : Rect) {
+        self.regions.mark(rect);
+    }
+
+    /// Redraws everything next frame.
+    pub fn mark_all(&mut self) {
+        self.full = true;
+    }
+
+    /// Whether the `CamBuffer` has to keep its pixels between frames.
+    pub fn retains_frame(&self) -> bool {
+        self.enabled
+    }
+
+    /// Forces a full redraw if the buffer was resized, the camera moved or `forced`. Whatever
+    /// forced it stays baked into the kept pixels, so the frame after it stops is drawn in full too.
+    pub fn check_frame(&mut self, size : (usize, usize), offset : (i32, i32), forced : bool) {
+        if !self.enabled || forced || self.was_forced || size != self.last_size || offset != self.last_offset {
+            self.full = true;
+        }
+        self.was_forced = forced;
+        self.last_size = size;
+        self.last_offset = offset;
+    }
+
+    /// Compares what the object `name` drew this frame with last frame.
+    pub fn track(&mut self, name : &str, record : DrawRecord) {
+        match self.objects.get(name) {
+            Some(old) if *old == record => return,
+            Some(old) => self.regions.mark(old.bounds),
+            None => {}
+        }
+        self.regions.mark(record.bounds);
+        self.objects.insert(name.to_string(), record);
+    }
+
+    /// Where the object `name` drew in the last recorded frame, if it was tracked.
+    pub fn get_bounds(&self, name : &str) -> Option<Rect> {
+        self.objects.get(name).map(|record| record.bounds)
+    }
+
+    /// Marks where objects that are gone were last drawn.
+    pub fn forget_removed(&mut self, objects : &HashMap<String, GameObject>) {
+        let regions = &mut self.regions;
+        self.objects.retain(|name, record| {
+            let alive = objects.contains_key(name);
+            if !alive {
+                regions.mark(record.bounds);
+            }
+            alive
+        });
+    }
+
+    /// What to draw this frame, resetting the dirty state for the next one.
+    pub fn take_redraw(&mut self) -> Redraw {
+        let bounds = Rect::new(0, 0, self.last_size.0 as i32, self.last_size.1 as i32);
+        let redraw = if self.full {
+            Redraw::Full
+        } else {
+            let rects : Vec<Rect> = self.regions.get_rects().iter().map(|r| r.intersect(&bounds)).filter(|r| !r.is_empty()).collect();
+            if rects.is_empty() { Redraw::None } else { Redraw::Regions(rects) }
+        };
+        if redraw == Redraw::None {
+            self.skipped_frames += 1;
+        }
+        self.full = false;
+        self.regions.clear();
+        self.last_redraw = redraw.clone();
+        redraw
+    }
+
+    /// Whether the palette looks different than last frame. Indexed pixels are looked up when the
+    /// buffer is dumped, so a cycling or swapped palette needs a new upload but no redraw.
+    pub fn palette_changed(&mut self, palette : Option<u64>) -> bool {
+        let changed = palette != self.last_palette;
+        self.last_palette = palette;
+        changed
+    }
+
+    pub fn get_last_redraw(&self) -> &Redraw {
+        &self.last_redraw
+    }
+
+    /// How many frames didn't need drawing so far.
+    pub fn get_skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+}
+
+impl Default for DirtyRenderer {
+    fn default() -> Self {
+        DirtyRenderer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_merge_touching_rects() {
+        let mut regions = DirtyRegions::new(8);
+        regions.mark(Rect::new(0, 0, 4, 4));
+        regions.mark(Rect::new(10, 0, 2, 2));
+        regions.mark(Rect::new(4, 0, 6, 1));
+        assert_eq!(regions.get_rects(), &[Rect::new(0, 0, 12, 4)]);
+        regions.mark(Rect::new(0, 20, 1, 1));
+        assert_eq!(regions.get_rects().len(), 2);
+    }
+
+    #[test]
+    fn regions_collapse_past_the_limit() {
+        let mut regions = DirtyRegions::new(2);
+        for i in 0..3 {
+            regions.mark(Rect::new(i * 10, i * 10, 2, 2));
+        }
+        assert_eq!(regions.get_rects(), &[Rect::new(0, 0, 22, 22)]);
+    }
+
+    #[test]
+    fn only_changed_objects_are_redrawn() {
+        let mut dirty = DirtyRenderer::new();
+        dirty.enabled = true;
+        let record = |x, key| DrawRecord { bounds : Rect::new(x, 0, 4, 4), key };
+
+        dirty.check_frame((64, 64), (0, 0), false);
+        dirty.track("a", record(0, 1));
+        dirty.track("b", record(30, 2));
+        assert_eq!(dirty.take_redraw(), Redraw::Full);
+
+        dirty.check_frame((64, 64), (0, 0), false);
+        dirty.track("a", record(0, 1));
+        dirty.track("b", record(30, 2));
+        assert_eq!(dirty.take_redraw(), Redraw::None);
+        assert_eq!(dirty.get_skipped_frames(), 1);
+
+        dirty.check_frame((64, 64), (0, 0), false);
+        dirty.track("a", record(0, 1));
+        dirty.track("b", record(40, 2));
+        assert_eq!(dirty.take_redraw(), Redraw::Regions(vec![Rect::new(30, 0, 4, 4), Rect::new(40, 0, 4, 4)]));
+
+        dirty.check_frame((64, 64), (0, 0), true);
+        assert_eq!(dirty.take_redraw(), Redraw::Full);
+        dirty.check_frame((64, 64), (0, 0), false);
+        assert_eq!(dirty.take_redraw(), Redraw::Full);
+        dirty.check_frame((64, 64), (1, 0), false);
+        assert_eq!(dirty.take_redraw(), Redraw::Full);
+    }
+}
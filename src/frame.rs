@@ -1,3 +1,209 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Copy, Clone)]
 pub struct FrameInfo {
-    pub(crate) update_delta: f64
-}
\ No newline at end of file
+    /// The fixed simulation step every `GameComponent::update` is advanced by, multiplied by
+    /// the `GameState` time scale unless the object runs on unscaled time.
+    pub update_delta: f64,
+    /// The fixed simulation step before time scaling. Use this for UI that must keep moving
+    /// during slow-mo.
+    pub(crate) unscaled_delta: f64,
+    /// The time scale `update_delta` was produced with.
+    pub(crate) time_scale: f64,
+    /// Real time the last rendered frame took. Only meaningful for diagnostics.
+    pub render_delta: f64,
+    /// Simulated time at the start of the current update. Scaled objects see scaled game time.
+    pub(crate) elapsed: f64,
+    /// Number of updates that have completed.
+    pub(crate) frame: u64,
+    /// How far the renderer is between the previous and current update, in `[0, 1)`.
+    pub alpha: f64
+}
+
+impl FrameInfo {
+    pub fn new(update_delta : f64) -> FrameInfo {
+        FrameInfo {
+            update_delta,
+            unscaled_delta: update_delta,
+            time_scale: 1.0,
+            render_delta: 0.0,
+            elapsed: 0.0,
+            frame: 0,
+            alpha: 0.0
+        }
+    }
+
+    /// Moves the clock forward by one fixed step. Called once after every update.
+    pub fn advance(&mut self) {
+        self.elapsed += self.update_delta;
+        self.frame += 1;
+    }
+
+    /// A copy of this frame as seen by objects running on game time.
+    pub fn scaled(&self, time_scale : f64, game_time : f64) -> FrameInfo {
+        FrameInfo {
+            update_delta: self.update_delta * time_scale,
+            unscaled_delta: self.update_delta,
+            time_scale,
+            elapsed: game_time,
+            ..*self
+        }
+    }
+
+    /// The fixed simulation step before time scaling.
+    pub fn get_unscaled_delta(&self) -> f64 {
+        self.unscaled_delta
+    }
+
+    pub fn get_time_scale(&self) -> f64 {
+        self.time_scale
+    }
+
+    /// Simulated time at the start of the current update.
+    pub fn get_elapsed(&self) -> f64 {
+        self.elapsed
+    }
+
+    /// Number of updates that have completed.
+    pub fn get_frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Linearly interpolates between the value of the previous update and the current one.
+    pub fn interpolate(&self, previous : f64, current : f64) -> f64 {
+        previous + (current - previous) * self.alpha
+    }
+}
+
+/// The user facing part of `TimeControls`, as kept in input recordings. Game time follows from
+/// these, so it is left out.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSettings {
+    pub time_scale: f64,
+    pub paused: bool,
+    pub pending_steps: u32
+}
+
+/// Global clock controls owned by the `GameState`.
+pub struct TimeControls {
+    pub time_scale: f64,
+    pub paused: bool,
+    pending_steps: u32,
+    game_time: f64
+}
+
+impl TimeControls {
+    pub fn new() -> TimeControls {
+        TimeControls {
+            time_scale: 1.0,
+            paused: false,
+            pending_steps: 0,
+            game_time: 0.0
+        }
+    }
+
+    /// Lets `frames` more updates through while paused.
+    pub fn step(&mut self, frames : u32) {
+        self.pending_steps += frames;
+    }
+
+    pub fn get_game_time(&self) -> f64 {
+        self.game_time
+    }
+
+    pub fn get_settings(&self) -> TimeSettings {
+        TimeSettings { time_scale: self.time_scale, paused: self.paused, pending_steps: self.pending_steps }
+    }
+
+    /// Takes over recorded settings, keeping the game time.
+    pub fn apply(&mut self, settings : TimeSettings) {
+        self.time_scale = settings.time_scale;
+        self.paused = settings.paused;
+        self.pending_steps = settings.pending_steps;
+    }
+
+    /// Decides whether game-time objects update this tick, consuming a pending step if paused.
+    pub(crate) fn tick(&mut self) -> bool {
+        if !self.paused { return true }
+        if self.pending_steps == 0 { return false }
+        self.pending_steps -= 1;
+        true
+    }
+
+    pub(crate) fn advance(&mut self, frame_info : &FrameInfo) {
+        self.game_time += frame_info.update_delta * self.time_scale;
+    }
+}
+
+impl Default for TimeControls {
+    fn default() -> Self {
+        TimeControls::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_blends_by_alpha() {
+        let mut frame_info = FrameInfo::new(0.5);
+        assert_eq!(frame_info.interpolate(2.0, 6.0), 2.0);
+        frame_info.alpha = 0.25;
+        assert_eq!(frame_info.interpolate(2.0, 6.0), 3.0);
+        assert_eq!(frame_info.interpolate(6.0, 2.0), 5.0);
+        frame_info.alpha = 1.0;
+        assert_eq!(frame_info.interpolate(2.0, 6.0), 6.0);
+    }
+
+    #[test]
+    fn advance_counts_fixed_steps() {
+        let mut frame_info = FrameInfo::new(0.25);
+        for _ in 0..3 { frame_info.advance() }
+        assert_eq!(frame_info.get_elapsed(), 0.75);
+        assert_eq!(frame_info.get_frame(), 3);
+    }
+
+    #[test]
+    fn scaled_frames_keep_the_real_step() {
+        let mut frame_info = FrameInfo::new(0.5);
+        frame_info.alpha = 0.75;
+        let scaled = frame_info.scaled(0.25, 3.0);
+        assert_eq!(scaled.update_delta, 0.125);
+        assert_eq!(scaled.get_unscaled_delta(), 0.5);
+        assert_eq!(scaled.get_time_scale(), 0.25);
+        assert_eq!(scaled.get_elapsed(), 3.0);
+        assert_eq!(scaled.alpha, 0.75);
+    }
+
+    #[test]
+    fn pausing_stops_ticks_until_stepped() {
+        let mut time = TimeControls::new();
+        assert!(time.tick());
+        time.paused = true;
+        assert!(!time.tick());
+        time.step(2);
+        assert!(time.tick());
+        assert!(time.tick());
+        assert!(!time.tick());
+        // Steps queued while running are kept for the next pause.
+        time.paused = false;
+        time.step(1);
+        assert!(time.tick());
+        time.paused = true;
+        assert!(time.tick());
+        assert!(!time.tick());
+    }
+
+    #[test]
+    fn game_time_follows_the_time_scale() {
+        let mut time = TimeControls::new();
+        let frame_info = FrameInfo::new(0.5);
+        time.advance(&frame_info);
+        time.time_scale = 0.5;
+        time.advance(&frame_info);
+        time.time_scale = 0.0;
+        time.advance(&frame_info);
+        assert_eq!(time.get_game_time(), 0.75);
+    }
+}
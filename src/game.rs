@@ -1,22 +1,33 @@
-use crate::math::Transform;
-use crate::buffer::Buffer;
+use crate::math::{Rect, Vec2};
 use std::path::Component;
 use std::collections::HashMap;
-use crate::imgui::Gui;
-use std::borrow::BorrowMut;
+use crate::imgui::{Gui, GuiContext};
 use imgui::{Ui};
 use crate::input::InputInfo;
 use pixels::Pixels;
-use crate::FrameInfo;
+use crate::frame::FrameInfo;
+use crate::frame::TimeControls;
+use crate::task::Scheduler;
+use crate::sprite_cache::SpriteCache;
+use crate::assets::{Assets, Reload};
+use crate::capture::{self, Capture};
+use crate::postprocess::PostChain;
+use crate::lighting::Lighting;
+use crate::dirty::{DirtyRenderer, Redraw};
+use std::cell::RefCell;
+use std::rc::Rc;
 use winit::window::Window;
-use log::error;
-use winit::event::{Event, WindowEvent};
-use winit::dpi::PhysicalSize;
-use crate::window::WindowInfo;
+use log::{error, info};
+use winit::event::{ElementState, Event, WindowEvent};
+use winit::dpi::{LogicalSize, PhysicalSize};
+use crate::window::{WindowInfo, WindowMode};
+use crate::config::GameConfig;
+use crate::screen::{self, ScreenRenderer};
 use crate::comps::object::GameObject;
 use std::collections::hash_map::IterMut;
 use crate::image_buffer::{CamBuffer, ImageBuffer};
 use std::io::Empty;
+use crate::replay::{InputEvent, InputRecorder, InputPlayback};
 
 pub struct Game {
     pub gs : GameState,
@@ -25,16 +36,53 @@ pub struct Game {
     pub main_buffer : CamBuffer,
     pub input_info : InputInfo,
     pub window_info : WindowInfo,
-    pub frame_info : FrameInfo
+    pub frame_info : FrameInfo,
+    pub recorder : Option<InputRecorder>,
+    pub playback : Option<InputPlayback>,
+    pub screen : ScreenRenderer,
+    /// The display settings to show. Edits are applied at the end of the next rendered frame.
+    pub config : GameConfig,
+    /// The display settings the window currently reflects.
+    pub applied_config : GameConfig
 }
 
 impl Game {
     pub fn update(&mut self) {
+        if let Some(playback) = &mut self.playback {
+            match playback.next_frame(&mut self.input_info) {
+                Some(frame) => {
+                    self.frame_info.update_delta = frame.update_delta;
+                    if let Some(time) = frame.time { self.gs.time.apply(time) }
+                }
+                None => {
+                    info!("Replay finished after {} frames, returning to live input.", playback.frame_count());
+                    self.playback = None;
+                }
+            }
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            recorder.end_frame(self.frame_info.update_delta, self.gs.time.get_settings());
+        }
+
         self.gs.update(&self.frame_info, &self.input_info);
         self.input_info.update();
+        self.frame_info.advance();
+    }
+
+    fn push_input(&mut self, event : InputEvent) {
+        // While a replay is running, live input would desync it from the recording.
+        if self.playback.is_some() { return }
+
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(event);
+        }
+        event.apply(&mut self.input_info);
     }
 
-    pub fn render(&mut self, window : &Window) {
+    /// Draws and presents a frame. Gives whether anything was presented; when nothing was, there's
+    /// nothing to show until the next update.
+    pub fn render(&mut self, window : &Window) -> bool {
         self.imgui.prepare(window);
 
         let imgui = &mut self.imgui;
@@ -42,24 +90,86 @@ impl Game {
         let window_info = &self.window_info;
         let frame_info = &self.frame_info;
         let main_bufer = &mut self.main_buffer;
+        let screen = &self.screen;
+        let config = &mut self.config;
+
+        let changed = gs.render(main_bufer, frame_info);
+        gs.post.apply(main_bufer, frame_info.render_delta);
+        gs.capture.capture(main_bufer, frame_info.render_delta);
 
-        gs.render(main_bufer);
-        main_bufer.dump(self.pixels.get_frame());
+        let presented = changed || imgui.visible;
+        if presented {
+            if changed {
+                main_bufer.dump(self.pixels.get_frame());
+            }
+
+            let results =  self.pixels.render_with(|encoder, render_target, context| {
+                screen.render(encoder, render_target);
+                if !imgui.visible { return }
+                if let Err(e) = imgui.render(window, encoder, render_target, context, GuiContext { gs, window_info, cam_buffer : main_bufer, config }, frame_info.render_delta) {
+                    error!("imgui.render() failed: {}", e);
+                }
+            });
+
+            if results
+                .map_err(|e| error!("pixels.render() failed: {}", e))
+                .is_err()
+            {
+                return false;
+            }
+
+            self.pixels.resize_surface(window.inner_size().width, window.inner_size().height);
+            self.screen.resize(window.inner_size().width, window.inner_size().height);
+        }
 
-        let results =  self.pixels.render_with(|encoder, render_target, context| {
-            context.scaling_renderer.render(encoder, render_target);
-            imgui.render(&window, encoder, render_target, context, gs, frame_info.update_delta, window_info, main_bufer);
-        });
+        if self.config != self.applied_config {
+            self.apply_config(window);
+        }
+        presented
+    }
 
-        if results
-            .map_err(|e| error!("pixels.render() failed: {}", e))
-            .is_err()
-        {
+    /// Brings the window, pixels and `CamBuffer` in line with `config`.
+    pub fn apply_config(&mut self, window : &Window) {
+        let (old, new) = (&self.applied_config, &self.config);
+        if new.width == 0 || new.height == 0 {
+            error!("Ignoring a {}x{} resolution", new.width, new.height);
+            self.config = self.applied_config.clone();
+            self.imgui.reset_edits();
             return;
         }
 
-        self.pixels.resize_surface(window.inner_size().width, window.inner_size().height);
-        main_bufer.clear();
+        if new.title != old.title {
+            window.set_title(&new.title);
+        }
+        if new.window_mode != old.window_mode {
+            window.set_fullscreen(new.window_mode.fullscreen(window));
+        }
+        if (new.width, new.height, new.window_scale) != (old.width, old.height, old.window_scale) && new.window_mode == WindowMode::Windowed {
+            window.set_min_inner_size(Some(LogicalSize::new(new.width, new.height)));
+            window.set_inner_size(LogicalSize::new(new.width * new.window_scale.max(1), new.height * new.window_scale.max(1)));
+        }
+
+        // Vsync is fixed when the surface is created, so pixels and everything built on its device
+        // start over.
+        if new.vsync != old.vsync {
+            match screen::create_pixels(window, new) {
+                Ok(pixels) => {
+                    self.pixels = pixels;
+                    self.imgui = Gui::new(window, &self.pixels);
+                    self.screen = ScreenRenderer::new(&self.pixels, window, new.scaling, new.letterbox);
+                }
+                Err(e) => error!("Unable to switch vsync: {}", e)
+            }
+        } else if (new.width, new.height) != (old.width, old.height) {
+            self.pixels.resize_buffer(new.width, new.height);
+            self.screen.rebind(&self.pixels);
+        }
+
+        self.main_buffer.resize(new.width as usize, new.height as usize);
+        self.screen.mode = new.scaling;
+        self.screen.letterbox = new.letterbox;
+        self.applied_config = self.config.clone();
+        self.imgui.reset_edits();
     }
 
     pub fn handler(&mut self, window : &Window, event : Event<()>) -> bool{
@@ -79,26 +189,34 @@ impl Game {
                     WindowEvent::ReceivedCharacter(_) => {}
                     WindowEvent::Focused(_) => {}
                     WindowEvent::KeyboardInput { device_id, input, is_synthetic } => {
-                        if input.virtual_keycode.is_some() {
-                            self.input_info.set_key(input.virtual_keycode.unwrap(), input.state)
+                        match input.virtual_keycode {
+                            Some(capture::SCREENSHOT_KEY) if input.state == ElementState::Pressed => self.gs.capture.request_screenshot(),
+                            Some(capture::RECORD_KEY) if input.state == ElementState::Pressed => self.gs.capture.toggle_recording(),
+                            Some(crate::imgui::OVERLAY_KEY) if input.state == ElementState::Pressed => self.imgui.visible = !self.imgui.visible,
+                            Some(capture::SCREENSHOT_KEY) | Some(capture::RECORD_KEY) | Some(crate::imgui::OVERLAY_KEY) => {}
+                            Some(key) => self.push_input(InputEvent::Key(key, input.state)),
+                            None => {}
                         }
                     }
                     WindowEvent::ModifiersChanged(_) => {}
                     WindowEvent::CursorMoved { device_id, position, modifiers } => {
-                        self.input_info.update_mouse_pos(position.x, position.y);
-                        let (x, y) = self.pixels.window_pos_to_pixel((position.x.floor() as f32, position.y.floor() as f32)).unwrap_or_else(|pos|self.pixels.clamp_pixel_pos(pos));
-                        self.input_info.update_mouse_pixel_pos(x as u32, y as u32)
+                        self.push_input(InputEvent::MousePos(position.x, position.y));
+                        let (x, y) = self.screen.window_to_pixel(position.x, position.y);
+                        self.push_input(InputEvent::MousePixelPos(x, y))
                     }
                     WindowEvent::CursorEntered { .. } => {}
                     WindowEvent::CursorLeft { .. } => {}
                     WindowEvent::MouseWheel { .. } => {}
                     WindowEvent::MouseInput { device_id, state, button, modifiers } => {
-                        self.input_info.set_mouse_button(button, state)
+                        self.push_input(InputEvent::MouseButton(button, state))
                     }
                     WindowEvent::TouchpadPressure { .. } => {}
                     WindowEvent::AxisMotion { .. } => {}
                     WindowEvent::Touch(_) => {}
-                    WindowEvent::ScaleFactorChanged { .. } => {}
+                    WindowEvent::ScaleFactorChanged { scale_factor, new_inner_size } => {
+                        self.window_info.scale_factor = scale_factor;
+                        self.onResize(*new_inner_size);
+                    }
                     WindowEvent::ThemeChanged(_) => {}
                 }
             }
@@ -118,40 +236,193 @@ impl Game {
     fn onResize(&mut self, size : PhysicalSize<u32>) {
         if size.width > 0 && size.height > 0 {
             self.pixels.resize_surface(size.width, size.height);
+            self.screen.resize(size.width, size.height);
         }
 
         self.window_info.width = size.width;
         self.window_info.height = size.height;
+        self.gs.dirty.mark_all();
 
     }
 
-    fn onCloseRequested(&mut self) {}
+    fn onCloseRequested(&mut self) {
+        self.gs.capture.stop_recording();
+        if let Some(recorder) = &mut self.recorder {
+            let path = recorder.get_path().map(|p| p.display().to_string()).unwrap_or_default();
+            match recorder.flush() {
+                Ok(_) => info!("Saved {} recorded frames to {}", recorder.frame_count(), path),
+                Err(e) => error!("Unable to save input recording to {}: {}", path, e)
+            }
+        }
+    }
 }
 
 pub struct GameState {
-    gameobjects: HashMap<String, GameObject>
+    gameobjects: HashMap<String, GameObject>,
+    /// Object names in the order they render, see `GameObject::layer`.
+    render_order: Vec<String>,
+    pub time: TimeControls,
+    pub scheduler: Scheduler,
+    pub sprite_cache: Rc<RefCell<SpriteCache>>,
+    pub assets: Rc<RefCell<Assets>>,
+    pub capture: Capture,
+    pub post: PostChain,
+    pub lighting: Rc<RefCell<Lighting>>,
+    pub dirty: DirtyRenderer
 }
 
 impl GameState {
     pub fn new() -> GameState {
         GameState {
-            gameobjects: HashMap::new()
+            gameobjects: HashMap::new(),
+            render_order: Vec::new(),
+            time: TimeControls::new(),
+            scheduler: Scheduler::new(),
+            sprite_cache: SpriteCache::shared(16 * 1024 * 1024, 128),
+            assets: Assets::shared(),
+            capture: Capture::new(),
+            post: PostChain::standard(),
+            lighting: Lighting::shared(),
+            dirty: DirtyRenderer::new()
         }
     }
 
+    /// Adds the GameObject. One with the same name is replaced, and the tasks it owned are
+    /// cancelled along with it.
     pub fn add_gameobject(&mut self, gb : GameObject) {
-        self.gameobjects.insert(gb.name.clone(), gb);
+        let name = gb.name.clone();
+        if self.gameobjects.insert(name.clone(), gb).is_some() {
+            self.scheduler.cancel_owned(&name);
+        }
+    }
+
+    /// Removes the GameObject and cancels every task it owns.
+    pub fn remove_gameobject(&mut self, name : &str) -> Option<GameObject> {
+        self.scheduler.cancel_owned(name);
+        self.gameobjects.remove(name)
+    }
+
+    pub fn get_gameobject(&self, name : &str) -> Option<&GameObject> {
+        self.gameobjects.get(name)
+    }
+
+    pub fn get_gameobject_mut(&mut self, name : &str) -> Option<&mut GameObject> {
+        self.gameobjects.get_mut(name)
     }
 
     pub fn update(&mut self, frame_info: &FrameInfo, input_info : &InputInfo) {
+        let reloads = self.assets.borrow_mut().poll(frame_info.elapsed);
+        self.forget_reloaded(reloads);
+
+        let running = self.time.tick();
+        let scaled_frame = frame_info.scaled(self.time.time_scale, self.time.get_game_time());
+
         for (name, i) in self.gameobjects.iter_mut() {
-            i.update(frame_info, input_info);
+            if i.unscaled_time {
+                i.update(frame_info, input_info);
+            } else if running {
+                i.update(&scaled_frame, input_info);
+            } else {
+                i.freeze();
+            }
+        }
+
+        if running {
+            self.scheduler.update(&scaled_frame);
+            for command in self.scheduler.take_commands() {
+                command(self);
+            }
+            self.time.advance(frame_info);
         }
     }
 
-    pub fn render(&mut self, main_buffer : &mut CamBuffer) {
-        for (name, i) in self.gameobjects.iter_mut() {
-            i.render(main_buffer)
+    /// Reloads every changed asset right away instead of waiting for the next poll.
+    pub fn reload_assets(&mut self) {
+        let reloads = self.assets.borrow_mut().reload_changed();
+        self.forget_reloaded(reloads);
+    }
+
+    /// Drops cached copies of images whose pixels were just replaced.
+    fn forget_reloaded(&mut self, reloads : Vec<Reload>) {
+        // Reloaded images keep their ids, so the dirty tracking can't tell they changed.
+        if !reloads.is_empty() {
+            self.dirty.mark_all();
+        }
+        let mut cache = self.sprite_cache.borrow_mut();
+        for id in reloads.into_iter().flat_map(|r| r.image_ids) {
+            cache.invalidate(id);
+        }
+    }
+
+    /// Draws the frame into `main_buffer`, all of it or only what `dirty` finds changed. Gives
+    /// whether the shown pixels changed, so an unchanged frame can skip the upload.
+    pub fn render(&mut self, main_buffer : &mut CamBuffer, frame_info: &FrameInfo) -> bool {
+        // Palette cycles run on game time, so they pause and slow down with everything else.
+        main_buffer.set_palette_time(self.time.get_game_time());
+
+        self.sort_render_order();
+        let forced = self.lighting.borrow().enabled || self.post.is_active();
+        self.dirty.check_frame(main_buffer.get_dimensions(), main_buffer.get_offset().get_xy(), forced);
+        if self.dirty.enabled {
+            main_buffer.start_recording();
+            for name in self.render_order.iter() {
+                let i = self.gameobjects.get_mut(name).expect("render order out of date");
+                i.render(main_buffer, frame_info);
+                if let Some(record) = main_buffer.take_record() {
+                    self.dirty.track(name, record);
+                }
+            }
+            main_buffer.stop_recording();
+            self.dirty.forget_removed(&self.gameobjects);
+            // Lights were submitted during the recording too; they get submitted again below.
+            self.lighting.borrow_mut().discard();
+        }
+
+        let redraw = self.dirty.take_redraw();
+        match &redraw {
+            Redraw::Full => {
+                main_buffer.clear();
+                self.render_objects(main_buffer, frame_info, Option::None);
+            }
+            Redraw::Regions(rects) => for rect in rects {
+                main_buffer.push_clip(*rect);
+                main_buffer.clear_rect(*rect);
+                self.render_objects(main_buffer, frame_info, Some(*rect));
+                main_buffer.pop_clip();
+            }
+            Redraw::None => {}
+        }
+
+        // Lights were submitted while the objects rendered, so the scene is lit once it's complete.
+        let mut lighting = self.lighting.borrow_mut();
+        lighting.set_time(self.time.get_game_time());
+        lighting.apply(main_buffer);
+
+        let palette_changed = self.dirty.palette_changed(main_buffer.palette_key());
+        redraw != Redraw::None || palette_changed
+    }
+
+    /// Brings `render_order` up to date with the objects and their layers. Sorting an order that
+    /// is already sorted is one pass, so this is cheap when nothing changed.
+    fn sort_render_order(&mut self) {
+        let objects = &self.gameobjects;
+        let order = &mut self.render_order;
+        if order.len() != objects.len() || order.iter().any(|name| !objects.contains_key(name)) {
+            order.clear();
+            order.extend(objects.keys().cloned());
+        }
+        order.sort_by(|a, b| (objects[a].layer, a).cmp(&(objects[b].layer, b)));
+    }
+
+    /// Renders every object, or with `region` only those whose recorded draws reach into it.
+    fn render_objects(&mut self, main_buffer : &mut CamBuffer, frame_info: &FrameInfo, region : Option<Rect>) {
+        for name in self.render_order.iter() {
+            let i = self.gameobjects.get_mut(name).expect("render order out of date");
+            if let Some(region) = region {
+                let touches = self.dirty.get_bounds(name).is_some_and(|bounds| !bounds.intersect(&region).is_empty());
+                if !touches { continue }
+            }
+            i.render(main_buffer, frame_info)
         }
     }
 
@@ -164,4 +435,98 @@ impl GameState {
     pub fn iter_mut(&mut self) -> IterMut<'_, String, GameObject> {
         self.gameobjects.iter_mut()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::comps::object::GameComponent;
+    use crate::comps::transform::TransformComponent;
+    use std::any::Any;
+    use std::cell::Cell;
+
+    /// Draws one pixel at `x` and counts how often it was rendered.
+    struct Dot {
+        x : Rc<Cell<usize>>,
+        renders : Rc<Cell<usize>>
+    }
+
+    impl GameComponent for Dot {
+        fn render(&mut self, main_buffer : &mut CamBuffer, _frame_info : &FrameInfo, _transform : Option<&TransformComponent>) {
+            self.renders.set(self.renders.get() + 1);
+            main_buffer.set_pixel(Color(255, 255, 255, 255), self.x.get(), 1);
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn dot(gs : &mut GameState, name : &str, x : usize) -> (Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let (x, renders) = (Rc::new(Cell::new(x)), Rc::new(Cell::new(0)));
+        let mut object = GameObject::new(name);
+        object.add_comp(Dot { x : x.clone(), renders : renders.clone() });
+        gs.add_gameobject(object);
+        (x, renders)
+    }
+
+    /// Notes its object's name whenever it renders.
+    struct Logged(String, Rc<RefCell<Vec<String>>>);
+
+    impl GameComponent for Logged {
+        fn render(&mut self, _main_buffer : &mut CamBuffer, _frame_info : &FrameInfo, _transform : Option<&TransformComponent>) {
+            self.1.borrow_mut().push(self.0.clone());
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn objects_render_by_layer_then_name() {
+        let mut gs = GameState::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        for (name, layer) in [("player", 0), ("sky", -10), ("hud", 10), ("enemy", 0), ("hills", -5)] {
+            let mut object = GameObject::new(name).with_layer(layer);
+            object.add_comp(Logged(name.to_string(), log.clone()));
+            gs.add_gameobject(object);
+        }
+        let mut buffer = CamBuffer::new(4, 4);
+        gs.render(&mut buffer, &FrameInfo::new(0.0));
+        assert_eq!(*log.borrow(), ["sky", "hills", "enemy", "player", "hud"]);
+
+        log.borrow_mut().clear();
+        gs.get_gameobject_mut("sky").unwrap().layer = 20;
+        gs.remove_gameobject("enemy");
+        gs.render(&mut buffer, &FrameInfo::new(0.0));
+        assert_eq!(*log.borrow(), ["hills", "player", "hud", "sky"]);
+    }
+
+    #[test]
+    fn regions_only_redraw_the_objects_inside_them() {
+        let mut gs = GameState::new();
+        gs.dirty.enabled = true;
+        let (_, still_renders) = dot(&mut gs, "still", 30);
+        let (moving, moving_renders) = dot(&mut gs, "moving", 2);
+        let mut buffer = CamBuffer::new(32, 4);
+        let frame_info = FrameInfo::new(0.0);
+
+        // The first frame is recorded, then drawn in full.
+        assert!(gs.render(&mut buffer, &frame_info));
+        assert_eq!((still_renders.get(), moving_renders.get()), (2, 2));
+
+        moving.set(3);
+        assert!(gs.render(&mut buffer, &frame_info));
+        assert_eq!(gs.dirty.get_last_redraw(), &Redraw::Regions(vec![Rect::new(2, 1, 2, 1)]));
+        // Only the recording pass touched the object that stayed put.
+        assert_eq!((still_renders.get(), moving_renders.get()), (3, 4));
+        assert_eq!(buffer.get_pixel(2, 1), Color::CLEAR);
+        assert_eq!(buffer.get_pixel(3, 1), Color(255, 255, 255, 255));
+        assert_eq!(buffer.get_pixel(30, 1), Color(255, 255, 255, 255));
+
+        assert!(!gs.render(&mut buffer, &frame_info));
+        assert_eq!((still_renders.get(), moving_renders.get()), (4, 5));
+    }
+}
@@ -1,28 +1,370 @@
-use crate::color::Color;
-use crate::math::{Vec2i, Vec2};
-use png::{OutputInfo, Reader};
+use crate::color::{Color, BlendMode};
+use crate::image_loader::{self, Frame};
+use crate::math::{Vec2i, Vec2f, Vec2, Rect};
+use crate::palette::Palette;
+use crate::raster;
+use crate::dirty::{self, DrawRecorder, DrawRecord};
+use std::borrow::Cow;
+use std::cell::{Ref, RefCell};
+use std::hash::{Hash, Hasher};
 use std::fs::File;
+use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
+use png::HasParameters;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-const PATH_TO_SPRITES : &str = "./assets/sprites/";
+pub(crate) const PATH_TO_SPRITES : &str = "./assets/sprites/";
 
-fn read_from_file(filename : &str) -> (OutputInfo, Reader<File>) {
-    let mut path = PathBuf::from(PATH_TO_SPRITES);
-    path.push(filename);
-    let file = File::open(path).unwrap();
-    let decoder = png::Decoder::new(file);
-    decoder.read_info().expect("Unable to encode image! File may be corrupt or not a png!")
+static NEXT_IMAGE_ID : AtomicU64 = AtomicU64::new(0);
+
+/// Hands out a process-wide unique id for every image that gets created, used to key caches.
+pub fn next_image_id() -> u64 {
+    NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Where and how `ImageBuffer::draw` places an image. `pos` is where the pivot lands in the
+/// destination, `pivot` is in source pixels and `rotation` is clockwise in degrees.
+#[derive(Debug, Copy, Clone)]
+pub struct DrawParams {
+    pub pos : Vec2f,
+    pub pivot : Vec2f,
+    pub scale : Vec2f,
+    pub rotation : f64,
+    pub flip_x : bool,
+    pub flip_y : bool,
+    pub blend : BlendMode
+}
+
+impl DrawParams {
+    pub fn at(x : f64, y : f64) -> DrawParams {
+        DrawParams {
+            pos : Vec2f::new(x, y),
+            pivot : Vec2f::zero(),
+            scale : Vec2f::new(1.0, 1.0),
+            rotation : 0.0,
+            flip_x : false,
+            flip_y : false,
+            blend : BlendMode::Alpha
+        }
+    }
+
+    /// The scale with flips applied, which is how the transform uses it.
+    fn signed_scale(&self) -> (f64, f64) {
+        let (sx, sy) = self.scale.get_xy();
+        (if self.flip_x { -sx } else { sx }, if self.flip_y { -sy } else { sy })
+    }
+
+    /// The destination pixels a `source` sized image drawn with these params may cover, or `None`
+    /// if it covers nothing.
+    pub fn bounds(&self, source : (usize, usize)) -> Option<Rect> {
+        let (width, height) = source;
+        let (px, py) = self.pivot.get_xy();
+        let (x, y) = self.pos.get_xy();
+        let (sx, sy) = self.signed_scale();
+        if width == 0 || height == 0 || sx == 0.0 || sy == 0.0 { return Option::None }
+
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        for (cx, cy) in [(0.0, 0.0), (width as f64, 0.0), (0.0, height as f64), (width as f64, height as f64)] {
+            let lx = (cx - px) * sx;
+            let ly = (cy - py) * sy;
+            let dx = x + lx * cos - ly * sin;
+            let dy = y + lx * sin + ly * cos;
+            min_x = min_x.min(dx);
+            min_y = min_y.min(dy);
+            max_x = max_x.max(dx);
+            max_y = max_y.max(dy);
+        }
+
+        // Clamped well inside i32 so far off draws still give a sane rect.
+        let limit = (i32::MAX / 4) as f64;
+        let (left, top) = (min_x.floor().clamp(-limit, limit) as i32, min_y.floor().clamp(-limit, limit) as i32);
+        let (right, bottom) = (max_x.ceil().clamp(-limit, limit) as i32, max_y.ceil().clamp(-limit, limit) as i32);
+        Some(Rect::new(left, top, right - left, bottom - top))
+    }
+
+    /// Calls `plot(x, y, u, v)` for every destination pixel inside `clip` covered by a `source`
+    /// sized image drawn with these params, where `u`, `v` is the source pixel that lands there.
+    pub fn for_each_covered(&self, source : (usize, usize), clip : Rect, mut plot : impl FnMut(usize, usize, usize, usize)) {
+        let covered = match self.bounds(source) {
+            Some(bounds) => bounds.intersect(&clip),
+            None => return
+        };
+        if covered.is_empty() { return }
+
+        let (width, height) = source;
+        let (px, py) = self.pivot.get_xy();
+        let (x, y) = self.pos.get_xy();
+        let (sx, sy) = self.signed_scale();
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (start_x, start_y) = (covered.x as usize, covered.y as usize);
+        let (end_x, end_y) = (covered.right() as usize, covered.bottom() as usize);
+
+        for j in start_y..end_y {
+            let dy = j as f64 + 0.5 - y;
+            for i in start_x..end_x {
+                let dx = i as f64 + 0.5 - x;
+                let u = (dx * cos + dy * sin) / sx + px;
+                let v = (-dx * sin + dy * cos) / sy + py;
+                if u < 0.0 || v < 0.0 || u >= width as f64 || v >= height as f64 { continue }
+
+                plot(i, j, u as usize, v as usize);
+            }
+        }
+    }
+}
+
+impl Hash for DrawParams {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        let (x, y) = self.pos.get_xy();
+        let (px, py) = self.pivot.get_xy();
+        let (sx, sy) = self.scale.get_xy();
+        for value in [x, y, px, py, sx, sy, self.rotation] {
+            value.to_bits().hash(state);
+        }
+        (self.flip_x, self.flip_y, self.blend).hash(state);
+    }
+}
+
+/// Clips a blit of the `src` rect of a `source` sized image to `x`, `y` in a destination that may
+/// only be drawn inside `clip`. Gives the part of `src` that survives and where its top left
+/// corner lands, or `None` if nothing does.
+pub fn clip_blit(src : Rect, source : (usize, usize), x : i32, y : i32, clip : Rect) -> Option<(Rect, i32, i32)> {
+    let visible = src.intersect(&Rect::new(0, 0, source.0 as i32, source.1 as i32));
+    let (x, y) = (x + visible.x - src.x, y + visible.y - src.y);
+    let dest = Rect::new(x, y, visible.width, visible.height).intersect(&clip);
+    if dest.is_empty() { return Option::None }
+    Some((Rect::new(visible.x + dest.x - x, visible.y + dest.y - y, dest.width, dest.height), dest.x, dest.y))
+}
+
+/// A borrowed, read-only rectangle of an image's pixels. Views are how images are read for
+/// drawing, so atlas frames and other sub-images never have to be copied out of their sheet.
+#[derive(Copy, Clone)]
+pub struct ImageView<'a> {
+    id : u64,
+    pixels : &'a [Color],
+    stride : usize,
+    rect : Rect
+}
+
+impl<'a> ImageView<'a> {
+    /// A view over all of `pixels`, which hold `width * height` colors row by row.
+    pub fn new(id : u64, pixels : &'a [Color], width : usize, height : usize) -> ImageView<'a> {
+        debug_assert!(pixels.len() >= width * height);
+        ImageView { id, pixels, stride : width, rect : Rect::new(0, 0, width as i32, height as i32) }
+    }
+
+    /// The id of the image this view borrows from.
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Where this view sits inside the image it borrows from.
+    pub fn get_rect(&self) -> Rect {
+        self.rect
+    }
+
+    pub fn get_dimensions(&self) -> (usize, usize) {
+        (self.rect.width as usize, self.rect.height as usize)
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.rect.width as usize
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.rect.height as usize
+    }
+
+    /// The pixel at `x`, `y` relative to the view.
+    pub fn get_pixel(&self, x : usize, y : usize) -> Color {
+        debug_assert!(x < self.get_width() && y < self.get_height());
+        self.pixels[self.rect.x as usize + x + (self.rect.y as usize + y) * self.stride]
+    }
+
+    /// One row of the view as a contiguous slice.
+    pub fn row(&self, y : usize) -> &'a [Color] {
+        let start = self.rect.x as usize + (self.rect.y as usize + y) * self.stride;
+        &self.pixels[start..start + self.get_width()]
+    }
+
+    /// A sub-rectangle of this view, relative to it and clipped to it.
+    pub fn view(&self, x : usize, y : usize, width : usize, height : usize) -> ImageView<'a> {
+        let x = x.min(self.get_width());
+        let y = y.min(self.get_height());
+        let width = width.min(self.get_width() - x);
+        let height = height.min(self.get_height() - y);
+        ImageView {
+            rect : Rect::new(self.rect.x + x as i32, self.rect.y + y as i32, width as i32, height as i32),
+            ..*self
+        }
+    }
+
+    /// Copies the view out into an image of its own.
+    pub fn to_image(self) -> SingleImageBuffer {
+        let mut buffer = Vec::with_capacity(self.get_width() * self.get_height());
+        for y in 0..self.get_height() {
+            buffer.extend_from_slice(self.row(y));
+        }
+        SingleImageBuffer::from_colors(buffer, self.get_width(), self.get_height())
+    }
+
+    pub fn blit(&self, other : &mut dyn ImageBuffer, x : i32, y : i32, mode : BlendMode) {
+        self.blit_rect(Rect::new(0, 0, self.get_width() as i32, self.get_height() as i32), other, x, y, mode)
+    }
+
+    /// Blits the `src` rectangle of this view, relative to it, with its top left corner at `x`,
+    /// `y` in `other`. Both may be negative or hang off either image; only pixels inside the view
+    /// and inside the destination's clip are touched.
+    pub fn blit_rect(&self, src : Rect, other : &mut dyn ImageBuffer, x : i32, y : i32, mode : BlendMode) {
+        let (src, x, y) = match clip_blit(src, self.get_dimensions(), x, y, other.get_clip()) {
+            Some(clipped) => clipped,
+            None => return
+        };
+        if other.is_recording() {
+            let dest = Rect::new(x, y, src.width, src.height);
+            return other.record_draw(dest, dirty::draw_key(&(self.id, self.rect, src, mode)))
+        }
+        for j in 0..src.height {
+            let row = &self.row((src.y + j) as usize)[src.x as usize..src.right() as usize];
+            other.blend_span(row, x as usize, (y + j) as usize, mode);
+        }
+    }
+
+    /// Rotates with RotSprite into `ib`. RotSprite's upscaling reads a pixel on every side, so
+    /// images under 3 pixels wide or high get a clear border first, which keeps their center.
+    pub fn rotate_safe(&self, angle : f64, mut ib: SingleImageBuffer) -> SingleImageBuffer {
+        let (mut width, height) = self.get_dimensions();
+        if width == 0 || height == 0 {
+            ib.set_buffer(&[], 0, 0);
+            return ib
+        }
+        let mut source = self.to_image().buffer;
+        if width < 3 || height < 3 {
+            let mut padded = vec![Color::CLEAR; (width + 2) * (height + 2)];
+            for (y, row) in source.chunks_exact(width).enumerate() {
+                let start = (y + 1) * (width + 2) + 1;
+                padded[start..start + width].copy_from_slice(row);
+            }
+            source = padded;
+            width += 2;
+        }
+        let (width, height, buffer) : (usize, usize, Vec<Color>) =
+            rotsprite::rotsprite(&source, &Color::CLEAR, width, angle).unwrap();
+        ib.set_buffer(buffer.as_slice(), width, height);
+        ib
+    }
+
+    /// Resamples the image by `scale_x` by `scale_y`. Uniform power of two factors go through
+    /// scale2x so pixel art keeps its edges, anything else falls back to nearest neighbour.
+    pub fn scaled(&self, scale_x : f64, scale_y : f64) -> (usize, usize, Vec<Color>) {
+        let (width, height) = self.get_dimensions();
+        let is_power_of_two = scale_x == scale_y && scale_x >= 2.0 && scale_x.fract() == 0.0 && (scale_x as usize).is_power_of_two();
+
+        // scale2x reads a one pixel border around every pixel, so tiny images can't use it.
+        if is_power_of_two && width >= 3 && height >= 3 {
+            let mut result = (width, height, self.to_image().buffer);
+            for _ in 0..(scale_x as usize).trailing_zeros() {
+                result = rotsprite::scale2x::scale2x(&result.2, result.0, result.1);
+            }
+            return result
+        }
+
+        let new_width = (width as f64 * scale_x).round().max(0.0) as usize;
+        let new_height = (height as f64 * scale_y).round().max(0.0) as usize;
+        let mut buffer = Vec::with_capacity(new_width * new_height);
+        for j in 0..new_height {
+            let y = ((j as f64 / scale_y) as usize).min(height - 1);
+            for i in 0..new_width {
+                let x = ((i as f64 / scale_x) as usize).min(width - 1);
+                buffer.push(self.get_pixel(x, y));
+            }
+        }
+        (new_width, new_height, buffer)
+    }
+
+    /// Draws this image into `other` with the full affine transform described by `params`,
+    /// sampling the source with nearest neighbour for every covered destination pixel.
+    pub fn draw(&self, other : &mut dyn ImageBuffer, params : &DrawParams) {
+        if other.is_recording() {
+            if let Some(bounds) = params.bounds(self.get_dimensions()) {
+                other.record_draw(bounds, dirty::draw_key(&(self.id, self.rect, params)));
+            }
+            return
+        }
+        params.for_each_covered(self.get_dimensions(), other.get_clip(), |i, j, u, v| {
+            other.blend_pixel_with(self.get_pixel(u, v), i, j, params.blend);
+        });
+    }
 }
 
 pub trait ImageBuffer {
     fn get_dimensions(&self) -> (usize, usize);
 
+    /// Unique for the lifetime of the image. Caches keyed by it must be invalidated by hand when
+    /// the pixels are edited in place.
+    fn get_id(&self) -> u64;
+
     fn get_buffer(&self) -> &Vec<Color>;
 
     fn get_buffer_mut(&mut self) -> &mut Vec<Color>;
 
     fn set_buffer(&mut self, buffer : &[Color], width : usize, height : usize);
 
+    /// The clip rects pushed so far, innermost last. Use `push_clip` and `pop_clip` rather than
+    /// editing it directly.
+    fn get_clip_stack(&self) -> &Vec<Rect>;
+
+    fn get_clip_stack_mut(&mut self) -> &mut Vec<Rect>;
+
+    /// Whether draws are only being recorded instead of written, see `CamBuffer::start_recording`.
+    /// Blits and draws check this and hand their destination rect to `record_draw` instead.
+    fn is_recording(&self) -> bool {
+        false
+    }
+
+    /// Notes a draw of `rect` while recording. `key` has to change whenever the draw would put
+    /// different pixels there.
+    fn record_draw(&mut self, _rect : Rect, _key : u64) {}
+
+    /// Limits blits, draws and primitives to `clip` within the current clip, until the matching
+    /// `pop_clip`.
+    fn push_clip(&mut self, clip : Rect) {
+        let clip = match self.get_clip_stack().last() {
+            Some(top) => clip.intersect(top),
+            None => clip
+        };
+        self.get_clip_stack_mut().push(clip);
+    }
+
+    /// Drops the innermost clip rect, restoring the one before it.
+    fn pop_clip(&mut self) -> Option<Rect> {
+        self.get_clip_stack_mut().pop()
+    }
+
+    /// Replaces the whole clip stack with `clip`, or empties it with `None`.
+    fn set_clip(&mut self, clip : Option<Rect>) {
+        self.get_clip_stack_mut().clear();
+        if let Some(clip) = clip {
+            self.push_clip(clip);
+        }
+    }
+
+    /// Where drawing may touch: the innermost clip rect within the image, or the whole image.
+    fn get_clip(&self) -> Rect {
+        let (width, height) = self.get_dimensions();
+        let bounds = Rect::new(0, 0, width as i32, height as i32);
+        self.get_clip_stack().last().map_or(bounds, |clip| clip.intersect(&bounds))
+    }
+
+    /// Runs `f` with `clip` pushed, popping it again afterwards.
+    fn with_clip<R>(&mut self, clip : Rect, f : impl FnOnce(&mut Self) -> R) -> R where Self : Sized {
+        self.push_clip(clip);
+        let result = f(self);
+        self.pop_clip();
+        result
+    }
+
     fn get_width(&self) -> usize {
         self.get_dimensions().0
     }
@@ -31,73 +373,105 @@ pub trait ImageBuffer {
         self.get_dimensions().1
     }
 
+    /// Where the pixel at `x`, `y` sits in the buffer, or `None` outside the image.
+    fn pixel_index(&self, x : usize, y : usize) -> Option<usize> {
+        let (width, height) = self.get_dimensions();
+        if x < width && y < height { Some(x + y * width) } else { Option::None }
+    }
+
+    /// The pixel at `x`, `y`, transparent outside the image.
     fn get_pixel(&self, x : usize, y : usize) -> Color {
-        let buffer = self.get_buffer();
-        let index = ((x + (y * self.get_width())) as usize).clamp(0, buffer.len().saturating_sub(1));
-        buffer[index]
+        self.pixel_index(x, y).and_then(|i| self.get_buffer().get(i).copied()).unwrap_or(Color::CLEAR)
     }
 
+    /// Ignored outside the image.
     fn set_pixel(&mut self, color : Color, x : usize, y : usize) {
-        let width = self.get_width();
-        let mut buffer = self.get_buffer_mut();
-        let index = ((x + (y * width)) as usize).clamp(0, buffer.len().saturating_sub(1));
-        buffer[index] = color;
+        if let Some(pixel) = self.pixel_index(x, y).and_then(|i| self.get_buffer_mut().get_mut(i)) {
+            *pixel = color;
+        }
     }
 
     fn blend_pixel(&mut self, color : Color, x : usize, y : usize) {
-        let width = self.get_width();
-        let mut buffer = self.get_buffer_mut();
-        let index = ((x + (y * width)) as usize).clamp(0, buffer.len().saturating_sub(1));
-        buffer[index].blend(color);
+        self.blend_pixel_with(color, x, y, BlendMode::Alpha)
     }
 
-    fn blend(&self, other : &mut dyn ImageBuffer, x : i32, y : i32) {
-        for i in 0..self.get_width() {
-            for j in 0..self.get_height() {
-                if other.contains(x + i as i32, y + j as i32) {
-                    let color = self.get_pixel(i, j);
-                    other.set_pixel(color, (x + i as i32) as usize, (y + j as i32) as usize);
-                }
-            }
+    /// Ignored outside the image.
+    fn blend_pixel_with(&mut self, color : Color, x : usize, y : usize, mode : BlendMode) {
+        if let Some(pixel) = self.pixel_index(x, y).and_then(|i| self.get_buffer_mut().get_mut(i)) {
+            pixel.blend_with(color, mode);
         }
     }
 
+    /// Blends `colors` into row `y` starting at `x`, as `blend_pixel_with` would one by one. Whatever
+    /// falls outside the image is ignored.
+    fn blend_span(&mut self, colors : &[Color], x : usize, y : usize, mode : BlendMode) {
+        let (width, height) = self.get_dimensions();
+        if x >= width || y >= height { return }
+        let start = x + y * width;
+        let end = start + colors.len().min(width - x);
+        raster::blend_span(&mut self.get_buffer_mut()[start..end], colors, mode);
+    }
+
+    /// Alpha blends this image onto `other`.
+    fn blend(&self, other : &mut dyn ImageBuffer, x : i32, y : i32) {
+        self.blit(other, x, y, BlendMode::Alpha)
+    }
+
+    fn blit(&self, other : &mut dyn ImageBuffer, x : i32, y : i32, mode : BlendMode) {
+        let (width, height) = self.get_dimensions();
+        self.blit_rect(Rect::new(0, 0, width as i32, height as i32), other, x, y, mode)
+    }
+
+    /// Blits the `src` part of this image with its top left corner at `x`, `y`. See
+    /// `ImageView::blit_rect`.
+    fn blit_rect(&self, src : Rect, other : &mut dyn ImageBuffer, x : i32, y : i32, mode : BlendMode) {
+        self.as_view().blit_rect(src, other, x, y, mode)
+    }
+
+    /// Borrows the whole image as an `ImageView`.
+    fn as_view(&self) -> ImageView<'_> {
+        let (width, height) = self.get_dimensions();
+        ImageView::new(self.get_id(), self.get_buffer(), width, height)
+    }
+
+    /// Borrows a sub-rectangle of the image without copying. The rectangle is clipped to the image.
+    fn view(&self, x : usize, y : usize, width : usize, height : usize) -> ImageView<'_> {
+        self.as_view().view(x, y, width, height)
+    }
+
     fn rotate(&mut self, angle: f64) {
         let (width, height, buffer) : (usize, usize, Vec<Color>) =
             rotsprite::rotsprite(self.get_buffer(), &Color::CLEAR, self.get_width(), angle).unwrap();
         self.set_buffer(buffer.as_slice(), width, height)
     }
 
-    fn rotate_safe(&self, angle : f64, mut ib: SingleImageBuffer) -> SingleImageBuffer {
-        let (width, height, buffer) : (usize, usize, Vec<Color>) =
-            rotsprite::rotsprite(self.get_buffer(), &Color::CLEAR, self.get_width(), angle).unwrap();
-        ib.set_buffer(buffer.as_slice(), width, height);
-        ib
+    fn rotate_safe(&self, angle : f64, ib: SingleImageBuffer) -> SingleImageBuffer {
+        self.as_view().rotate_safe(angle, ib)
     }
 
     fn scale(&mut self, scale : f64) {
-        let new_width: usize = (self.get_width() as f64 * scale) as usize;
-        let new_height: usize = (self.get_height() as f64 * scale) as usize;
-
-        let (width, height, buffer) : (usize, usize, Vec<Color>) =
-            rotsprite::scale2x::scale2x(&self.get_buffer(), new_width, new_height);
-
+        let (width, height, buffer) = self.scaled(scale, scale);
         self.set_buffer(&buffer, width, height)
     }
 
     fn scale_safe(&mut self, scale : f64, mut ib : SingleImageBuffer) -> SingleImageBuffer {
-        let new_width: usize = (self.get_width() as f64 * scale) as usize;
-        let new_height: usize = (self.get_height() as f64 * scale) as usize;
-
-        let (width, height, buffer) : (usize, usize, Vec<Color>) =
-            rotsprite::scale2x::scale2x(&self.get_buffer(), new_width, new_height);
-
+        let (width, height, buffer) = self.scaled(scale, scale);
         ib.set_buffer(buffer.as_slice(), width, height);
         ib
     }
 
+    /// Resamples the image by `scale_x` by `scale_y`. See `ImageView::scaled`.
+    fn scaled(&self, scale_x : f64, scale_y : f64) -> (usize, usize, Vec<Color>) {
+        self.as_view().scaled(scale_x, scale_y)
+    }
+
+    /// Draws this image into `other` with the transform described by `params`.
+    fn draw(&self, other : &mut dyn ImageBuffer, params : &DrawParams) {
+        self.as_view().draw(other, params)
+    }
+
     fn contains(&self, x : i32, y : i32) -> bool {
-        x > 0 && x < self.get_width() as i32 && y > 0 && y < self.get_height() as i32
+        x >= 0 && x < self.get_width() as i32 && y >= 0 && y < self.get_height() as i32
     }
 
     fn clear(&mut self) {
@@ -109,30 +483,146 @@ pub trait ImageBuffer {
 
 
 
+/// Anything a renderer can read pixels from, either owned or borrowed from somewhere shared.
+pub trait ImageSource {
+    /// Width and height of the current pixels.
+    fn get_size(&self) -> (usize, usize);
+
+    /// Calls `f` with a view of the current pixels.
+    fn with_view<R>(&self, f : impl FnOnce(ImageView) -> R) -> R;
+}
+
+impl<T : ImageBuffer> ImageSource for T {
+    fn get_size(&self) -> (usize, usize) {
+        self.get_dimensions()
+    }
+
+    fn with_view<R>(&self, f : impl FnOnce(ImageView) -> R) -> R {
+        f(self.as_view())
+    }
+}
+
+/// The screen the scene is drawn into. Besides RGBA it can hold palette indices: with a palette
+/// set, `IndexedImage`s write indices that stay live until `dump`, so cycling and swapping the
+/// palette recolors them. Drawing RGBA over an indexed pixel flattens it to its current color.
+///
+/// Unlike other images, single pixel and span writes respect the clip too, so everything drawn
+/// into the screen stays inside it.
 pub struct CamBuffer {
+    id : u64,
     buffer : Vec<Color>,
     width : usize,
     height : usize,
-    offset : Vec2i
+    offset : Vec2i,
+    clips : Vec<Rect>,
+    palette : Option<Palette>,
+    /// One entry per pixel while a palette is set, empty otherwise.
+    indices : Vec<Option<u8>>,
+    /// `palette.lookup()`, built on first use after the palette last changed.
+    lookup : RefCell<Vec<Color>>,
+    recorder : Option<DrawRecorder>
 }
 
 impl CamBuffer {
     pub fn new(width : usize, height : usize) -> CamBuffer {
         CamBuffer {
+            id : next_image_id(),
             width, height,
             buffer : vec![Color::CLEAR; width * height],
-            offset: Vec2i::new(0, 0)
+            offset: Vec2i::new(0, 0),
+            clips : Vec::new(),
+            palette : Option::None,
+            indices : Vec::new(),
+            lookup : RefCell::new(Vec::new()),
+            recorder : Option::None
+        }
+    }
+
+    /// Stops writing pixels: until `stop_recording`, every draw only notes where it would land
+    /// and what it would put there, for `DirtyRenderer` to compare frames with.
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(DrawRecorder::new());
+    }
+
+    /// What was drawn since recording started or since the last call.
+    pub fn take_record(&mut self) -> Option<DrawRecord> {
+        self.recorder.as_mut().map(|r| r.finish())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = Option::None;
+    }
+
+    /// Clears the pixels and palette indices inside `rect`.
+    pub fn clear_rect(&mut self, rect : Rect) {
+        let rect = rect.intersect(&Rect::new(0, 0, self.width as i32, self.height as i32));
+        for y in rect.y..rect.bottom() {
+            let start = y as usize * self.width + rect.x as usize;
+            let end = start + rect.width as usize;
+            self.buffer[start..end].fill(Color::CLEAR);
+            if let Some(indices) = self.indices.get_mut(start..end) {
+                indices.fill(Option::None);
+            }
+        }
+    }
+
+    /// Where a single pixel write at `x`, `y` goes, or `None` outside the buffer or the clip.
+    fn writable(&self, x : usize, y : usize) -> Option<usize> {
+        let index = self.pixel_index(x, y)?;
+        match self.clips.last() {
+            Some(clip) if !clip.contains(x as i32, y as i32) => Option::None,
+            _ => Some(index)
         }
     }
 
+    /// Records a one pixel write while recording. Gives whether it was recorded.
+    fn record_pixel(&mut self, x : usize, y : usize, key : u64) -> bool {
+        if self.recorder.is_none() { return false }
+        self.record_draw(Rect::new(x as i32, y as i32, 1, 1), key);
+        true
+    }
+
     pub fn dump(&self, arr : &mut [u8]) {
-        for (i, v) in self.buffer.iter().enumerate() {
-            let i = i * 4;
-            arr[i] = v.0;
-            arr[i+1] = v.1;
-            arr[i+2] = v.2;
-            arr[i+3] = v.3;
+        if self.palette.is_none() {
+            let bytes : &[u8] = bytemuck::cast_slice(&self.buffer);
+            arr[..bytes.len()].copy_from_slice(bytes);
+            return
+        }
+
+        let lookup = self.lookup();
+        for (out, (&color, index)) in arr.chunks_exact_mut(4).zip(self.buffer.iter().zip(&self.indices)) {
+            let Color(r, g, b, a) = index.map_or(color, |i| lookup[i as usize]);
+            out.copy_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    /// The pixels as they will be shown, with indexed pixels looked up in the palette.
+    pub fn resolved(&self) -> Cow<'_, [Color]> {
+        if self.palette.is_none() {
+            return Cow::Borrowed(&self.buffer)
         }
+        let lookup = self.lookup();
+        Cow::Owned(self.buffer.iter().zip(&self.indices)
+            .map(|(&color, index)| index.map_or(color, |i| lookup[i as usize]))
+            .collect())
+    }
+
+    /// The current color of every palette entry. Only rebuilt after the palette was changed, so
+    /// looking it up many times a frame is cheap.
+    fn lookup(&self) -> Ref<'_, Vec<Color>> {
+        if self.lookup.borrow().is_empty() {
+            if let Some(palette) = &self.palette {
+                *self.lookup.borrow_mut() = palette.lookup();
+            }
+        }
+        self.lookup.borrow()
+    }
+
+    /// Changes the resolution, leaving the buffer cleared.
+    pub fn resize(&mut self, width : usize, height : usize) {
+        self.width = width;
+        self.height = height;
+        self.clear();
     }
 
     pub fn get_offset(&self) -> &Vec2i {
@@ -142,6 +632,77 @@ impl CamBuffer {
     pub fn set_offset(&mut self, x : i32, y : i32) {
         self.offset.set_xy(x, y);
     }
+
+    /// Switches indexed drawing on with `palette`, or off with `None`. Turning it off flattens
+    /// indexed pixels into their current colors.
+    pub fn set_palette(&mut self, palette : Option<Palette>) {
+        if palette.is_none() {
+            self.buffer = self.resolved().into_owned();
+            self.indices.clear();
+        } else if self.indices.is_empty() {
+            self.indices = vec![Option::None; self.buffer.len()];
+        }
+        self.palette = palette;
+        self.lookup.get_mut().clear();
+    }
+
+    /// Bakes every indexed pixel into its current color, keeping the palette for later draws.
+    pub fn flatten_indices(&mut self) {
+        if self.palette.is_none() { return }
+        self.buffer = self.resolved().into_owned();
+        self.indices.iter_mut().for_each(|i| *i = Option::None);
+    }
+
+    pub fn get_palette(&self) -> Option<&Palette> {
+        self.palette.as_ref()
+    }
+
+    /// The palette, for changing it. The lookup is rebuilt on the next dump or blit.
+    pub fn get_palette_mut(&mut self) -> Option<&mut Palette> {
+        self.lookup.get_mut().clear();
+        self.palette.as_mut()
+    }
+
+    /// Moves the palette's cycles to `time`. The lookup is only rebuilt if one of them turned.
+    pub fn set_palette_time(&mut self, time : f64) {
+        if let Some(palette) = &mut self.palette {
+            if palette.set_time(time) { self.lookup.get_mut().clear() }
+        }
+    }
+
+    /// A hash of the colors the palette shows right now, or `None` without a palette.
+    pub fn palette_key(&self) -> Option<u64> {
+        self.palette.as_ref()?;
+        Some(dirty::draw_key(&*self.lookup()))
+    }
+
+    /// Marks a pixel as showing palette entry `index`. Ignored without a palette or outside the
+    /// buffer.
+    pub fn set_index(&mut self, index : u8, x : usize, y : usize) {
+        if self.indices.is_empty() || self.record_pixel(x, y, dirty::draw_key(&index)) { return }
+        if let Some(i) = self.writable(x, y) {
+            self.indices[i] = Some(index);
+        }
+    }
+
+    pub fn get_index(&self, x : usize, y : usize) -> Option<u8> {
+        if x < self.width && y < self.height { self.indices.get(x + y * self.width).copied().flatten() } else { Option::None }
+    }
+
+    /// The pixels, the palette indices (empty without a palette), the palette and the width, borrowed
+    /// apart so they can be split between threads.
+    pub(crate) fn parts_mut(&mut self) -> (&mut [Color], &mut [Option<u8>], Option<&Palette>, usize) {
+        (&mut self.buffer, &mut self.indices, self.palette.as_ref(), self.width)
+    }
+
+    /// Turns the indexed pixel behind `index` into plain RGBA before something is drawn over it.
+    fn flatten(&mut self, index : usize) {
+        if let (Some(palette), Some(entry)) = (&self.palette, self.indices.get_mut(index)) {
+            if let Some(i) = entry.take() {
+                self.buffer[index] = palette.color_at(i);
+            }
+        }
+    }
 }
 
 impl ImageBuffer for CamBuffer {
@@ -149,6 +710,10 @@ impl ImageBuffer for CamBuffer {
         (self.width, self.height)
     }
 
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
     fn get_buffer(&self) -> &Vec<Color> {
         &self.buffer
     }
@@ -161,53 +726,162 @@ impl ImageBuffer for CamBuffer {
         self.buffer.copy_from_slice(buffer);
         self.width = width;
         self.height = height;
+        self.indices.iter_mut().for_each(|i| *i = Option::None);
     }
 
-    fn blend(&self, other: &mut dyn ImageBuffer, x: i32, y: i32) {
-        for i in 0..self.get_width() {
-            for j in 0..self.get_height() {
-                if other.contains(x + i as i32, y + j as i32) {
-                    let color = self.get_pixel(i, j);
-                    let (ox, oy) = self.offset.get_xy();
-                    other.blend_pixel(color, (ox + x + i as i32) as usize, (oy + y + j as i32) as usize);
-                }
-            }
+    fn get_clip_stack(&self) -> &Vec<Rect> {
+        &self.clips
+    }
+
+    fn get_clip_stack_mut(&mut self) -> &mut Vec<Rect> {
+        &mut self.clips
+    }
+
+    fn get_pixel(&self, x : usize, y : usize) -> Color {
+        let index = match self.pixel_index(x, y) {
+            Some(index) => index,
+            None => return Color::CLEAR
+        };
+        match (&self.palette, self.indices.get(index).copied().flatten()) {
+            (Some(palette), Some(i)) => palette.color_at(i),
+            _ => self.buffer[index]
+        }
+    }
+
+    fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn record_draw(&mut self, rect : Rect, key : u64) {
+        let clip = self.get_clip();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(rect.intersect(&clip), key);
+        }
+    }
+
+    fn set_pixel(&mut self, color : Color, x : usize, y : usize) {
+        if self.record_pixel(x, y, dirty::draw_key(&(color, BlendMode::Replace))) { return }
+        if let Some(index) = self.writable(x, y) {
+            self.flatten(index);
+            self.buffer[index] = color;
+        }
+    }
+
+    fn blend_pixel_with(&mut self, color : Color, x : usize, y : usize, mode : BlendMode) {
+        if self.record_pixel(x, y, dirty::draw_key(&(color, mode))) { return }
+        if let Some(index) = self.writable(x, y) {
+            self.flatten(index);
+            self.buffer[index].blend_with(color, mode);
+        }
+    }
+
+    fn blend_span(&mut self, colors : &[Color], x : usize, y : usize, mode : BlendMode) {
+        let span = Rect::new(x.min(i32::MAX as usize) as i32, y.min(i32::MAX as usize) as i32, colors.len().min(i32::MAX as usize) as i32, 1);
+        if self.is_recording() {
+            return self.record_draw(span, dirty::draw_key(&(colors, mode)))
+        }
+        let span = span.intersect(&self.get_clip());
+        if span.is_empty() { return }
+        let colors = &colors[(span.x as usize - x)..];
+        let start = span.x as usize + y * self.width;
+        let end = start + span.width as usize;
+        if self.palette.is_some() {
+            (start..end).for_each(|index| self.flatten(index));
+        }
+        raster::blend_span(&mut self.buffer[start..end], colors, mode);
+    }
+
+    /// Blits the resolved colors, shifted by the camera offset. With a palette only the part of
+    /// `src` inside the buffer is resolved.
+    fn blit_rect(&self, src : Rect, other : &mut dyn ImageBuffer, x : i32, y : i32, mode : BlendMode) {
+        let (x, y) = (x + self.offset.get_x(), y + self.offset.get_y());
+        if self.palette.is_none() {
+            return ImageView::new(self.id, &self.buffer, self.width, self.height).blit_rect(src, other, x, y, mode)
+        }
+
+        let area = src.intersect(&Rect::new(0, 0, self.width as i32, self.height as i32));
+        if area.is_empty() { return }
+        let lookup = self.lookup();
+        let pixels : Vec<Color> = (area.y..area.bottom())
+            .flat_map(|j| (area.x..area.right()).map(move |i| i as usize + j as usize * self.width))
+            .map(|index| self.indices[index].map_or(self.buffer[index], |i| lookup[i as usize]))
+            .collect();
+        let (width, height) = (area.width as usize, area.height as usize);
+        let (x, y) = (x + area.x - src.x, y + area.y - src.y);
+        ImageView::new(self.id, &pixels, width, height).blit_rect(Rect::new(0, 0, area.width, area.height), other, x, y, mode)
+    }
+
+    fn clear(&mut self) {
+        self.buffer.clear();
+        self.buffer.resize(self.width * self.height, Color::CLEAR);
+        if self.palette.is_some() {
+            self.indices.clear();
+            self.indices.resize(self.width * self.height, Option::None);
         }
     }
 }
 
 
 pub struct SingleImageBuffer {
+    id : u64,
     buffer : Vec<Color>,
     width : usize,
-    height : usize
+    height : usize,
+    clips : Vec<Rect>
 }
 
 impl SingleImageBuffer {
     pub fn new(width : usize, height : usize) -> SingleImageBuffer {
         SingleImageBuffer {
+            id : next_image_id(),
             width, height,
-            buffer : vec![Color::CLEAR; width * height]
+            buffer : vec![Color::CLEAR; width * height],
+            clips : Vec::new()
         }
     }
 
-    pub fn from(filename : &str) -> SingleImageBuffer {
-        let (info, mut reader) = read_from_file(filename);
+    pub fn from_colors(buffer : Vec<Color>, width : usize, height : usize) -> SingleImageBuffer {
+        SingleImageBuffer {
+            id : next_image_id(),
+            width, height,
+            buffer,
+            clips : Vec::new()
+        }
+    }
 
-        let mut frame : Vec<u8> = vec![0u8; info.buffer_size()];
-        reader.next_frame(&mut frame).unwrap();
+    /// Loads `filename` from the sprite folder, panicking if it can't. The image isn't watched
+    /// for changes; load it through `Assets` to have it hot reloaded.
+    #[deprecated(note = "use `Assets::image` or `Assets::load_image`, which hot reload and fall back to the missing texture")]
+    pub fn from(filename : &str) -> SingleImageBuffer {
+        let mut path = PathBuf::from(PATH_TO_SPRITES);
+        path.push(filename);
+        SingleImageBuffer::load(&path).expect("Unable to encode image! File may be corrupt or not a png!")
+    }
 
-        let mut buffer : Vec<Color> = Vec::new();
+    /// Decodes the image at `path`, which is not relative to the sprite folder. Any format
+    /// `image_loader` knows is normalized to 8 bit RGBA; animated files give their first frame.
+    pub fn load(path : &Path) -> io::Result<SingleImageBuffer> {
+        image_loader::load(path)
+    }
 
-        for c in frame.chunks_exact(4) {
-            buffer.push(Color(c[0], c[1], c[2], c[3]))
+    /// Makes every pixel whose red, green and blue match `key` fully transparent, for sprites
+    /// drawn with a background color standing in for alpha.
+    pub fn apply_color_key(&mut self, key : Color) {
+        for color in self.buffer.iter_mut() {
+            if (color.0, color.1, color.2) == (key.0, key.1, key.2) {
+                *color = Color::CLEAR;
+            }
         }
+    }
 
-        SingleImageBuffer {
-            width: info.width as usize,
-            height: info.height as usize,
-            buffer
-        }
+    /// Encodes the image as an 8 bit RGBA png.
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width as u32, self.height as u32);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+
+        let data : Vec<u8> = self.buffer.iter().flat_map(|c| [c.0, c.1, c.2, c.3]).collect();
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
     }
 }
 
@@ -216,6 +890,10 @@ impl ImageBuffer for SingleImageBuffer {
         (self.width, self.height)
     }
 
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
     fn get_buffer(&self) -> &Vec<Color> {
         &self.buffer
     }
@@ -230,8 +908,541 @@ impl ImageBuffer for SingleImageBuffer {
         self.clear();
         self.buffer.copy_from_slice(buffer);
     }
+
+    fn get_clip_stack(&self) -> &Vec<Rect> {
+        &self.clips
+    }
+
+    fn get_clip_stack_mut(&mut self) -> &mut Vec<Rect> {
+        &mut self.clips
+    }
+}
+
+#[macro_export]
+macro_rules! image_atlas {
+    ( $n:literal | $({$x:expr, $y:expr, $w:expr, $h:expr}),* ) => {
+        {
+            let mut atlas = AtlasImageBuffer::from($n);
+            $(
+                atlas.add($x, $y, $w, $h);
+            )*
+            atlas
+        }
+    };
+    ( $n:literal | $w:expr, $h:expr ) => {
+        {
+            let mut atlas = AtlasImageBuffer::from($n);
+            atlas.slice($w, $h);
+            atlas
+        }
+    }
 }
 
+/// One image holding several sub-images. Regions are handed out as `ImageView`s into the shared
+/// pixels, so frames are never copied. Sheets decoded from animated files also know how long each
+/// frame is shown.
 pub struct AtlasImageBuffer {
+    image : SingleImageBuffer,
+    regions : Vec<Rect>,
+    /// One entry per region for sheets from animated files, empty otherwise.
+    delays : Vec<Option<f64>>
+}
+
+impl AtlasImageBuffer {
+    pub fn new(image : SingleImageBuffer) -> AtlasImageBuffer {
+        AtlasImageBuffer {
+            image,
+            regions : Vec::new(),
+            delays : Vec::new()
+        }
+    }
+
+    /// Like `SingleImageBuffer::from`, and not hot reloaded either.
+    #[deprecated(note = "use `Assets::load_sheet`, which hot reloads the sheet")]
+    #[allow(deprecated)]
+    pub fn from(filename : &str) -> AtlasImageBuffer {
+        AtlasImageBuffer::new(SingleImageBuffer::from(filename))
+    }
+
+    /// Lays `frames` out left to right in one strip, with a region for each.
+    pub fn from_frames(frames : &[SingleImageBuffer]) -> AtlasImageBuffer {
+        let mut atlas = AtlasImageBuffer::new(SingleImageBuffer::new(0, 0));
+        atlas.set_frames(frames);
+        atlas
+    }
+
+    /// A strip of decoded frames that keeps each frame's delay.
+    pub fn animated(frames : Vec<Frame>) -> AtlasImageBuffer {
+        let mut atlas = AtlasImageBuffer::new(SingleImageBuffer::new(0, 0));
+        atlas.set_animation(frames);
+        atlas
+    }
+
+    /// Loads every frame of an image file, keeping the delays of animated ones. Stills give a
+    /// sheet with a single region.
+    pub fn load_animated(path : &Path) -> io::Result<AtlasImageBuffer> {
+        Ok(AtlasImageBuffer::animated(image_loader::load_frames(path)?))
+    }
+
+    /// Adds a region. It is clipped to the image.
+    pub fn add(&mut self, x : usize, y : usize, width : usize, height : usize) {
+        let rect = self.image.view(x, y, width, height).get_rect();
+        self.regions.push(rect);
+    }
+
+    /// Cuts the whole image into `width` by `height` regions, column by column. Leftover pixels
+    /// on the right and bottom edges are ignored.
+    pub fn slice(&mut self, width : usize, height : usize) {
+        let (texture_width, texture_height) = self.image.get_dimensions();
+
+        for i in 0..(texture_width / width) {
+            for j in 0..(texture_height / height) {
+                self.add(i * width, j * height, width, height);
+            }
+        }
+    }
+
+    pub fn get(&self, index : usize) -> ImageView<'_> {
+        let rect = self.regions.get(index)
+            .unwrap_or_else(|| panic!("{} is out of bounds. Length of AtlasImageBuffer: {}", index, self.regions.len()));
+        self.image.view(rect.x as usize, rect.y as usize, rect.width as usize, rect.height as usize)
+    }
+
+    pub fn get_image(&self) -> &SingleImageBuffer {
+        &self.image
+    }
+
+    /// How long region `index` is shown, in seconds, if the sheet came from an animated file.
+    pub fn get_delay(&self, index : usize) -> Option<f64> {
+        self.delays.get(index).copied().flatten()
+    }
+
+    /// Whether the sheet came from an animated file, even if none of its frames set a delay.
+    pub fn is_animated(&self) -> bool {
+        !self.delays.is_empty()
+    }
+
+    /// Swaps in new pixels, keeping the regions but clipping them to the new image. The atlas
+    /// keeps its id so views and caches keyed by it see one image that changed.
+    pub fn set_image(&mut self, image : SingleImageBuffer) {
+        let (width, height) = image.get_dimensions();
+        self.image.set_buffer(image.get_buffer(), width, height);
+
+        let bounds = Rect::new(0, 0, width as i32, height as i32);
+        for region in self.regions.iter_mut() {
+            *region = region.intersect(&bounds);
+        }
+    }
+
+    /// Replaces the pixels and regions with a strip of `frames`, keeping the id like `set_image`.
+    pub fn set_frames(&mut self, frames : &[SingleImageBuffer]) {
+        let width = frames.iter().map(|f| f.get_width()).sum();
+        let height = frames.iter().map(|f| f.get_height()).max().unwrap_or(0);
+        let mut strip = SingleImageBuffer::new(width, height);
+        self.regions.clear();
+
+        let mut x = 0;
+        for frame in frames {
+            let (frame_width, frame_height) = frame.get_dimensions();
+            for j in 0..frame_height {
+                for i in 0..frame_width {
+                    strip.set_pixel(frame.get_pixel(i, j), x + i, j);
+                }
+            }
+            self.regions.push(Rect::new(x as i32, 0, frame_width as i32, frame_height as i32));
+            x += frame_width;
+        }
+
+        self.image.set_buffer(strip.get_buffer(), width, height);
+        self.delays.clear();
+    }
+
+    /// Like `set_frames`, also taking each frame's delay.
+    pub fn set_animation(&mut self, frames : Vec<Frame>) {
+        let (images, delays) : (Vec<SingleImageBuffer>, Vec<Option<f64>>) = frames.into_iter()
+            .map(|f| (f.image, f.delay))
+            .unzip();
+        self.set_frames(&images);
+        self.delays = delays;
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width` by `height` image where every pixel has its own color.
+    fn numbered(width : usize, height : usize, base : u8) -> SingleImageBuffer {
+        let colors = (0..width * height).map(|i| Color(base, i as u8, (i / width) as u8, 255)).collect();
+        SingleImageBuffer::from_colors(colors, width, height)
+    }
+
+    /// Blits pixel by pixel, checking each one against the source, the destination and `clip`.
+    fn reference_blit(source : &SingleImageBuffer, src : Rect, dest : &mut SingleImageBuffer, x : i32, y : i32, clip : Option<Rect>) {
+        for j in src.y..src.bottom() {
+            for i in src.x..src.right() {
+                let (dx, dy) = (x + i - src.x, y + j - src.y);
+                let inside_clip = clip.map_or(true, |clip| clip.contains(dx, dy));
+                if source.contains(i, j) && dest.contains(dx, dy) && inside_clip {
+                    let color = source.get_pixel(i as usize, j as usize);
+                    dest.set_pixel(color, dx as usize, dy as usize);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn contains_includes_edges_and_corners() {
+        let image = SingleImageBuffer::new(4, 3);
+        for y in -2..5 {
+            for x in -2..6 {
+                assert_eq!(image.contains(x, y), (0..4).contains(&x) && (0..3).contains(&y), "{}, {}", x, y);
+            }
+        }
+        assert!(!SingleImageBuffer::new(0, 0).contains(0, 0));
+    }
+
+    #[test]
+    fn out_of_range_pixels_are_ignored() {
+        let mut image = numbered(3, 2, 10);
+        let before = image.get_buffer().clone();
+        for (x, y) in [(3, 0), (0, 2), (3, 1), (2, 2), (usize::MAX, 0), (0, usize::MAX)] {
+            image.set_pixel(Color(1, 2, 3, 255), x, y);
+            image.blend_pixel_with(Color(1, 2, 3, 255), x, y, BlendMode::Replace);
+            assert_eq!(image.get_pixel(x, y), Color::CLEAR);
+        }
+        assert_eq!(image.get_buffer(), &before);
+    }
+
+    #[test]
+    fn pixels_on_every_edge_and_corner_are_reachable() {
+        let (width, height) = (4, 3);
+        let mut image = SingleImageBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set_pixel(Color(x as u8, y as u8, 0, 255), x, y);
+            }
+        }
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(image.get_pixel(x, y), Color(x as u8, y as u8, 0, 255));
+            }
+        }
+    }
+
+    #[test]
+    fn blit_matches_reference_at_every_position() {
+        let source = numbered(3, 2, 200);
+        let sources = [
+            Rect::new(0, 0, 3, 2),
+            Rect::new(1, 0, 2, 2),
+            Rect::new(0, 1, 3, 1),
+            Rect::new(-1, -1, 3, 3),
+            Rect::new(2, 1, 5, 5),
+            Rect::new(-4, -4, 10, 10),
+            Rect::new(0, 0, 0, 2),
+            Rect::new(3, 0, 1, 1),
+            Rect::new(0, -2, 3, 2)
+        ];
+        let clips = [
+            Option::None,
+            Some(Rect::new(1, 1, 3, 2)),
+            Some(Rect::new(-2, -2, 3, 3)),
+            Some(Rect::new(4, 3, 5, 5)),
+            Some(Rect::new(0, 0, 0, 0)),
+            Some(Rect::new(-10, -10, 30, 30))
+        ];
+
+        for src in sources.iter() {
+            for clip in clips.iter() {
+                for y in -5..7 {
+                    for x in -6..8 {
+                        let mut actual = numbered(5, 4, 0);
+                        actual.set_clip(*clip);
+                        source.blit_rect(*src, &mut actual, x, y, BlendMode::Replace);
+
+                        let mut expected = numbered(5, 4, 0);
+                        reference_blit(&source, *src, &mut expected, x, y, *clip);
+                        assert_eq!(actual.get_buffer(), expected.get_buffer(), "src {:?}, clip {:?}, at {}, {}", src, clip, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blit_from_view_reads_only_the_view() {
+        let sheet = numbered(4, 4, 100);
+        let view = sheet.view(1, 1, 2, 2);
+        let mut dest = SingleImageBuffer::new(4, 4);
+        view.blit_rect(Rect::new(-1, -1, 4, 4), &mut dest, 0, 0, BlendMode::Replace);
+
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = if (1..3).contains(&x) && (1..3).contains(&y) { sheet.get_pixel(x, y) } else { Color::CLEAR };
+                assert_eq!(dest.get_pixel(x, y), expected, "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn larger_source_covers_whole_destination() {
+        let source = numbered(6, 6, 50);
+        let mut dest = SingleImageBuffer::new(2, 2);
+        source.blit(&mut dest, -3, -2, BlendMode::Replace);
+        assert_eq!(dest.get_pixel(0, 0), source.get_pixel(3, 2));
+        assert_eq!(dest.get_pixel(1, 0), source.get_pixel(4, 2));
+        assert_eq!(dest.get_pixel(0, 1), source.get_pixel(3, 3));
+        assert_eq!(dest.get_pixel(1, 1), source.get_pixel(4, 3));
+    }
+
+    #[test]
+    fn clip_blit_handles_degenerate_rects() {
+        let bounds = Rect::new(0, 0, 4, 4);
+        assert_eq!(clip_blit(Rect::new(0, 0, 2, 2), (0, 0), 0, 0, bounds), Option::None);
+        assert_eq!(clip_blit(Rect::new(0, 0, -2, 2), (4, 4), 0, 0, bounds), Option::None);
+        assert_eq!(clip_blit(Rect::new(0, 0, 2, 2), (4, 4), 4, 0, bounds), Option::None);
+        assert_eq!(clip_blit(Rect::new(0, 0, 2, 2), (4, 4), -2, -2, bounds), Option::None);
+        assert_eq!(clip_blit(Rect::new(0, 0, 2, 2), (4, 4), -1, 3, bounds), Some((Rect::new(1, 0, 1, 1), 0, 3)));
+        assert_eq!(clip_blit(Rect::new(-1, -1, 2, 2), (4, 4), 0, 0, bounds), Some((Rect::new(0, 0, 1, 1), 1, 1)));
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn clip_stack_intersects_and_restores() {
+        let mut image = SingleImageBuffer::new(8, 6);
+        assert_eq!(image.get_clip(), Rect::new(0, 0, 8, 6));
+
+        image.push_clip(Rect::new(-2, 1, 6, 10));
+        assert_eq!(image.get_clip(), Rect::new(0, 1, 4, 5));
+        image.push_clip(Rect::new(2, -3, 10, 6));
+        assert_eq!(image.get_clip(), Rect::new(2, 1, 2, 2));
+        image.push_clip(Rect::new(6, 0, 2, 2));
+        assert!(image.get_clip().is_empty());
+
+        assert!(image.pop_clip().map_or(false, |clip| clip.is_empty()));
+        assert_eq!(image.get_clip(), Rect::new(2, 1, 2, 2));
+        image.pop_clip();
+        assert_eq!(image.get_clip(), Rect::new(0, 1, 4, 5));
+        image.pop_clip();
+        assert_eq!(image.get_clip(), Rect::new(0, 0, 8, 6));
+        assert_eq!(image.pop_clip(), Option::None);
+    }
+
+    #[test]
+    fn set_clip_replaces_the_stack() {
+        let mut image = SingleImageBuffer::new(8, 6);
+        image.push_clip(Rect::new(0, 0, 2, 2));
+        image.push_clip(Rect::new(1, 1, 2, 2));
+        image.set_clip(Some(Rect::new(4, 4, 2, 2)));
+        assert_eq!(image.get_clip(), Rect::new(4, 4, 2, 2));
+        assert_eq!(image.get_clip_stack().len(), 1);
+        image.set_clip(Option::None);
+        assert_eq!(image.get_clip(), Rect::new(0, 0, 8, 6));
+    }
+
+    #[test]
+    fn with_clip_pops_afterwards() {
+        let source = numbered(4, 4, 9);
+        let mut dest = SingleImageBuffer::new(4, 4);
+        let clip = dest.with_clip(Rect::new(1, 1, 2, 2), |dest| {
+            source.blit(dest, 0, 0, BlendMode::Replace);
+            dest.get_clip()
+        });
+        assert_eq!(clip, Rect::new(1, 1, 2, 2));
+        assert_eq!(dest.get_clip(), Rect::new(0, 0, 4, 4));
+        assert_eq!(dest.get_pixel(0, 0), Color::CLEAR);
+        assert_eq!(dest.get_pixel(1, 1), source.get_pixel(1, 1));
+        assert_eq!(dest.get_pixel(2, 2), source.get_pixel(2, 2));
+        assert_eq!(dest.get_pixel(3, 3), Color::CLEAR);
+    }
+
+    #[test]
+    fn draw_respects_clip() {
+        let source = numbered(4, 4, 7);
+        let mut dest = SingleImageBuffer::new(4, 4);
+        let mut params = DrawParams::at(-1.0, -1.0);
+        params.blend = BlendMode::Replace;
+        dest.push_clip(Rect::new(0, 0, 2, 4));
+        source.draw(&mut dest, &params);
+
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = if x < 2 && y < 3 { source.get_pixel(x + 1, y + 1) } else { Color::CLEAR };
+                assert_eq!(dest.get_pixel(x, y), expected, "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn cam_buffer_blit_applies_offset_before_clipping() {
+        let mut camera = CamBuffer::new(3, 3);
+        for y in 0..3 {
+            for x in 0..3 {
+                camera.set_pixel(Color(x as u8, y as u8, 1, 255), x, y);
+            }
+        }
+        camera.set_offset(2, 1);
+
+        let mut dest = SingleImageBuffer::new(3, 3);
+        camera.blit(&mut dest, -3, -2, BlendMode::Replace);
+        // Lands at -1, -1, so only the bottom right 2x2 shows, starting in the corner.
+        assert_eq!(dest.get_pixel(0, 0), Color(1, 1, 1, 255));
+        assert_eq!(dest.get_pixel(1, 1), Color(2, 2, 1, 255));
+        assert_eq!(dest.get_pixel(2, 0), Color::CLEAR);
+        assert_eq!(dest.get_pixel(0, 2), Color::CLEAR);
+    }
+
+    #[test]
+    fn cam_buffer_ignores_out_of_range_pixels() {
+        let mut camera = CamBuffer::new(2, 2);
+        camera.set_pixel(Color(9, 9, 9, 255), 1, 1);
+        camera.set_pixel(Color(1, 1, 1, 255), 2, 1);
+        camera.blend_pixel_with(Color(1, 1, 1, 255), 1, 2, BlendMode::Replace);
+        assert_eq!(camera.get_pixel(1, 1), Color(9, 9, 9, 255));
+        assert_eq!(camera.get_pixel(0, 2), Color::CLEAR);
+    }
+
+    #[test]
+    fn cam_buffer_raw_writes_respect_clip() {
+        let mut camera = CamBuffer::new(4, 4);
+        camera.push_clip(Rect::new(1, 1, 2, 2));
+        camera.set_pixel(Color(9, 9, 9, 255), 0, 0);
+        camera.set_pixel(Color(9, 9, 9, 255), 1, 1);
+        camera.blend_span(&[Color(5, 5, 5, 255); 4], 0, 2, BlendMode::Replace);
+        assert_eq!(camera.get_pixel(0, 0), Color::CLEAR);
+        assert_eq!(camera.get_pixel(1, 1), Color(9, 9, 9, 255));
+        assert_eq!(camera.get_pixel(0, 2), Color::CLEAR);
+        assert_eq!(camera.get_pixel(1, 2), Color(5, 5, 5, 255));
+        assert_eq!(camera.get_pixel(2, 2), Color(5, 5, 5, 255));
+        assert_eq!(camera.get_pixel(3, 2), Color::CLEAR);
+    }
+
+    #[test]
+    fn recording_writes_nothing_and_tells_draws_apart() {
+        let sprite = numbered(3, 3, 7);
+        let mut camera = CamBuffer::new(8, 8);
+        let mut record = |params : &DrawParams| {
+            camera.start_recording();
+            sprite.blit(&mut camera, 2, 2, BlendMode::Alpha);
+            sprite.draw(&mut camera, params);
+            camera.blend_pixel_with(Color(1, 2, 3, 255), 0, 0, BlendMode::Alpha);
+            let record = camera.take_record().unwrap();
+            camera.stop_recording();
+            assert!(camera.get_buffer().iter().all(|c| *c == Color::CLEAR));
+            record
+        };
+
+        let first = record(&DrawParams::at(4.0, 4.0));
+        assert_eq!(first.bounds, Rect::new(0, 0, 7, 7));
+        assert_eq!(record(&DrawParams::at(4.0, 4.0)), first);
+        let mut flipped = DrawParams::at(4.0, 4.0);
+        flipped.flip_x = true;
+        let moved = record(&flipped);
+        assert_ne!(moved.key, first.key);
+        // Hanging off the edge only counts the part inside the buffer.
+        assert_eq!(record(&DrawParams::at(6.0, 6.0)).bounds, Rect::new(0, 0, 8, 8));
+    }
+
+    #[test]
+    fn clear_rect_stays_in_bounds() {
+        let mut camera = CamBuffer::new(3, 3);
+        camera.get_buffer_mut().iter_mut().for_each(|c| *c = Color(1, 1, 1, 255));
+        camera.clear_rect(Rect::new(1, -1, 5, 2));
+        assert_eq!(camera.get_pixel(1, 0), Color::CLEAR);
+        assert_eq!(camera.get_pixel(2, 0), Color::CLEAR);
+        assert_eq!(camera.get_pixel(0, 0), Color(1, 1, 1, 255));
+        assert_eq!(camera.get_pixel(1, 1), Color(1, 1, 1, 255));
+    }
+
+    /// Draws a numbered `width` by `height` image into a clear 6x6 image with `params`.
+    fn transformed(width : usize, height : usize, params : &mut DrawParams) -> (SingleImageBuffer, SingleImageBuffer) {
+        let sprite = numbered(width, height, 9);
+        let mut target = SingleImageBuffer::new(6, 6);
+        params.blend = BlendMode::Replace;
+        sprite.draw(&mut target, params);
+        (sprite, target)
+    }
+
+    /// Checks every target pixel against `expected`, which maps it to a source pixel or nothing.
+    fn assert_mapping(sprite : &SingleImageBuffer, target : &SingleImageBuffer, expected : impl Fn(usize, usize) -> Option<(usize, usize)>) {
+        for y in 0..6 {
+            for x in 0..6 {
+                let want = expected(x, y).map_or(Color::CLEAR, |(u, v)| sprite.get_pixel(u, v));
+                assert_eq!(target.get_pixel(x, y), want, "at {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn draw_places_the_pivot_at_pos() {
+        let (sprite, target) = transformed(2, 3, &mut DrawParams::at(2.0, 1.0));
+        assert_mapping(&sprite, &target, |x, y| if (2..4).contains(&x) && (1..4).contains(&y) { Some((x - 2, y - 1)) } else { Option::None });
+
+        let mut params = DrawParams::at(4.0, 4.0);
+        params.pivot = Vec2f::new(1.0, 1.0);
+        let (sprite, target) = transformed(2, 3, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if (3..5).contains(&x) && (3..6).contains(&y) { Some((x - 3, y - 3)) } else { Option::None });
+    }
+
+    #[test]
+    fn draw_flips_around_the_pivot() {
+        let mut params = DrawParams::at(2.0, 1.0);
+        params.pivot = Vec2f::new(2.0, 1.0);
+        params.flip_x = true;
+        let (sprite, target) = transformed(4, 2, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 4 && y < 2 { Some((3 - x, y)) } else { Option::None });
+
+        params.flip_x = false;
+        params.flip_y = true;
+        let (sprite, target) = transformed(4, 2, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 4 && y < 2 { Some((x, 1 - y)) } else { Option::None });
+    }
+
+    #[test]
+    fn draw_rotates_clockwise() {
+        // A quarter turn around the top left corner, shifted so the result starts at 0.
+        let mut params = DrawParams::at(3.0, 0.0);
+        params.rotation = 90.0;
+        let (sprite, target) = transformed(2, 3, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 3 && y < 2 { Some((y, 2 - x)) } else { Option::None });
+
+        params = DrawParams::at(2.0, 3.0);
+        params.rotation = 180.0;
+        let (sprite, target) = transformed(2, 3, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 2 && y < 3 { Some((1 - x, 2 - y)) } else { Option::None });
+    }
+
+    #[test]
+    fn draw_scales_each_axis_on_its_own() {
+        let mut params = DrawParams::at(0.0, 0.0);
+        params.scale = Vec2f::new(2.0, 1.0);
+        let (sprite, target) = transformed(2, 3, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 4 && y < 3 { Some((x / 2, y)) } else { Option::None });
+
+        params.scale = Vec2f::new(1.0, -2.0);
+        params.pos = Vec2f::new(0.0, 6.0);
+        let (sprite, target) = transformed(2, 3, &mut params);
+        assert_mapping(&sprite, &target, |x, y| if x < 2 { Some((x, (5 - y) / 2)) } else { Option::None });
+    }
+
+    #[test]
+    fn animation_delays_stay_on_their_own_frames() {
+        let frame = |delay| Frame { image : numbered(2, 1, 0), delay };
+        let mut atlas = AtlasImageBuffer::animated(vec![frame(Some(0.2)), frame(None), frame(Some(0.3))]);
+        assert!(atlas.is_animated());
+        assert_eq!(atlas.len(), 3);
+        assert_eq!((atlas.get_delay(0), atlas.get_delay(1), atlas.get_delay(2)), (Some(0.2), None, Some(0.3)));
+        assert_eq!(atlas.get_delay(3), None);
+
+        atlas.set_frames(&[numbered(2, 1, 0)]);
+        assert!(!atlas.is_animated());
+        assert_eq!(atlas.get_delay(0), None);
+    }
+}
@@ -0,0 +1,745 @@
+use crate::color::Color;
+use crate::image_buffer::{ImageBuffer, SingleImageBuffer};
+use png::HasParameters;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+
+/// GIFs that don't set a delay are shown at the rate browsers use for them.
+const DEFAULT_GIF_DELAY : f64 = 0.1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+    Tga,
+    Qoi,
+    Gif
+}
+
+impl ImageFormat {
+    /// Picks the format from the file's magic bytes. TGA has none, so it falls back to the
+    /// extension.
+    pub fn detect(path : &Path, bytes : &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(b"\x89PNG") { return Some(ImageFormat::Png) }
+        if bytes.starts_with(b"BM") { return Some(ImageFormat::Bmp) }
+        if bytes.starts_with(b"qoif") { return Some(ImageFormat::Qoi) }
+        if bytes.starts_with(b"GIF8") { return Some(ImageFormat::Gif) }
+
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "tga" => Some(ImageFormat::Tga),
+            _ => None
+        }
+    }
+}
+
+/// One frame of an image file and how long it is shown, in seconds. Stills have no delay.
+pub struct Frame {
+    pub image : SingleImageBuffer,
+    pub delay : Option<f64>
+}
+
+/// Decodes any supported image into RGBA. Animated files give their first frame.
+pub fn load(path : &Path) -> io::Result<SingleImageBuffer> {
+    Ok(load_frames(path)?.swap_remove(0).image)
+}
+
+/// Decodes every frame of an image file, fully composited. Stills give exactly one frame and
+/// anything else gives at least one.
+pub fn load_frames(path : &Path) -> io::Result<Vec<Frame>> {
+    let bytes = fs::read(path)?;
+    let still = |image : SingleImageBuffer| vec![Frame { image, delay : None }];
+
+    let frames = match ImageFormat::detect(path, &bytes) {
+        Some(ImageFormat::Png) => decode_png(&bytes).map(still),
+        Some(ImageFormat::Bmp) => decode_bmp(&bytes).map(still),
+        Some(ImageFormat::Tga) => decode_tga(&bytes).map(still),
+        Some(ImageFormat::Qoi) => decode_qoi(&bytes).map(still),
+        Some(ImageFormat::Gif) => decode_gif(&bytes),
+        None => Err(invalid("Unknown image format"))
+    }?;
+
+    if frames.is_empty() {
+        return Err(invalid("The image has no frames"))
+    }
+    Ok(frames)
+}
+
+fn invalid(message : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// `width * height`, refusing sizes no sprite has so a corrupt header can't allocate gigabytes.
+fn pixel_count(width : usize, height : usize) -> io::Result<usize> {
+    width.checked_mul(height).filter(|&c| c <= 1 << 28).ok_or_else(|| invalid("The image is too large"))
+}
+
+/// Reads little endian integers, failing on a truncated file instead of panicking.
+struct Bytes<'a> {
+    bytes : &'a [u8]
+}
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, at : usize, len : usize) -> io::Result<&'a [u8]> {
+        self.bytes.get(at..at.checked_add(len).ok_or_else(|| invalid("Offset overflows"))?)
+            .ok_or_else(|| invalid("The file is truncated"))
+    }
+
+    fn u8(&self, at : usize) -> io::Result<u8> {
+        Ok(self.slice(at, 1)?[0])
+    }
+
+    fn u16(&self, at : usize) -> io::Result<u16> {
+        let b = self.slice(at, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, at : usize) -> io::Result<u32> {
+        let b = self.slice(at, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+/// Every color type and bit depth normalized to 8 bit RGBA, with tRNS chunks turned into alpha.
+fn decode_png(bytes : &[u8]) -> io::Result<SingleImageBuffer> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+
+    let mut frame : Vec<u8> = vec![0u8; reader.output_buffer_size()];
+    reader.next_frame(&mut frame)?;
+
+    let buffer : Vec<Color> = match reader.output_color_type() {
+        (png::ColorType::RGBA, png::BitDepth::Eight) => frame.chunks_exact(4).map(|c| Color(c[0], c[1], c[2], c[3])).collect(),
+        (png::ColorType::RGB, png::BitDepth::Eight) => frame.chunks_exact(3).map(|c| Color(c[0], c[1], c[2], 255)).collect(),
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight) => frame.chunks_exact(2).map(|c| Color(c[0], c[0], c[0], c[1])).collect(),
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => frame.iter().map(|&g| Color(g, g, g, 255)).collect(),
+        (color_type, bit_depth) => {
+            let message = format!("{:?} at bit depth {:?} isn't supported", color_type, bit_depth);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message))
+        }
+    };
+
+    Ok(SingleImageBuffer::from_colors(buffer, info.width as usize, info.height as usize))
+}
+
+/// Scales a channel of `mask`'s width up to 8 bits.
+fn mask_channel(pixel : u32, mask : u32) -> Option<u8> {
+    if mask == 0 { return None }
+    let max = (mask >> mask.trailing_zeros()) as u64;
+    Some(((((pixel & mask) >> mask.trailing_zeros()) as u64) * 255 / max) as u8)
+}
+
+/// Uncompressed, RLE4 and RLE8 bitmaps at 1, 4, 8, 16, 24 and 32 bits, including bitfield masks.
+fn decode_bmp(bytes : &[u8]) -> io::Result<SingleImageBuffer> {
+    let b = Bytes { bytes };
+    let data_offset = b.u32(10)? as usize;
+    let header_size = b.u32(14)? as usize;
+
+    let (width, height, bpp, compression) = if header_size == 12 {
+        (b.u16(18)? as i32, b.u16(20)? as i16 as i32, b.u16(24)?, 0)
+    } else {
+        (b.u32(18)? as i32, b.u32(22)? as i32, b.u16(28)?, b.u32(30)?)
+    };
+    if width <= 0 || height == 0 {
+        return Err(invalid("The bitmap has no pixels"))
+    }
+    let top_down = height < 0;
+    let (width, height) = (width as usize, height.unsigned_abs() as usize);
+
+    let palette : Vec<Color> = if bpp <= 8 {
+        let entry = if header_size == 12 { 3 } else { 4 };
+        let used = if header_size >= 40 { b.u32(46)? as usize } else { 0 };
+        let count = if used == 0 { 1 << bpp } else { used.min(256) };
+        (0..count).map(|i| {
+            let at = 14 + header_size + i * entry;
+            Ok(Color(b.u8(at + 2)?, b.u8(at + 1)?, b.u8(at)?, 255))
+        }).collect::<io::Result<_>>()?
+    } else {
+        Vec::new()
+    };
+
+    // Bitfield masks follow the info header, or are part of the larger V4 and V5 headers.
+    let (mut red, mut green, mut blue, mut alpha) = match bpp {
+        16 => (0x7C00, 0x03E0, 0x001F, 0),
+        _ => (0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0)
+    };
+    if compression == 3 || compression == 6 {
+        red = b.u32(54)?;
+        green = b.u32(58)?;
+        blue = b.u32(62)?;
+        if compression == 6 || header_size >= 56 { alpha = b.u32(66)? }
+    } else if bpp == 32 && header_size >= 56 {
+        alpha = b.u32(66)?;
+    }
+
+    let mut buffer = vec![Color::CLEAR; pixel_count(width, height)?];
+    let row_index = |y : usize| if top_down { y } else { height - 1 - y };
+
+    match compression {
+        1 | 2 => decode_bmp_rle(&b, data_offset, compression == 2, &palette, width, height, |x, y, c| buffer[x + row_index(y) * width] = c)?,
+        0 | 3 | 6 => {
+            let stride = (width * bpp as usize).div_ceil(32) * 4;
+            for y in 0..height {
+                let row = b.slice(data_offset + y * stride, stride)?;
+                for x in 0..width {
+                    let color = match bpp {
+                        1 | 2 | 4 | 8 => {
+                            let bit = x * bpp as usize;
+                            let index = (row[bit / 8] >> (8 - bpp as usize - bit % 8)) & ((1 << bpp) - 1) as u8;
+                            *palette.get(index as usize).unwrap_or(&Color::CLEAR)
+                        }
+                        24 => Color(row[x * 3 + 2], row[x * 3 + 1], row[x * 3], 255),
+                        16 | 32 => {
+                            let pixel = if bpp == 16 {
+                                u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]) as u32
+                            } else {
+                                u32::from_le_bytes([row[x * 4], row[x * 4 + 1], row[x * 4 + 2], row[x * 4 + 3]])
+                            };
+                            Color(
+                                mask_channel(pixel, red).unwrap_or(0),
+                                mask_channel(pixel, green).unwrap_or(0),
+                                mask_channel(pixel, blue).unwrap_or(0),
+                                mask_channel(pixel, alpha).unwrap_or(255)
+                            )
+                        }
+                        _ => return Err(invalid("Unsupported bitmap bit depth"))
+                    };
+                    buffer[x + row_index(y) * width] = color;
+                }
+            }
+
+            // Plenty of writers leave the alpha byte of 32 bit bitmaps zeroed; those are opaque.
+            if bpp == 32 && alpha != 0 && buffer.iter().all(|c| c.3 == 0) {
+                buffer.iter_mut().for_each(|c| c.3 = 255);
+            }
+        }
+        _ => return Err(invalid("Unsupported bitmap compression"))
+    }
+
+    Ok(SingleImageBuffer::from_colors(buffer, width, height))
+}
+
+/// Run length encoded bitmaps. Pixels skipped by delta codes stay transparent.
+fn decode_bmp_rle(b : &Bytes, mut at : usize, rle4 : bool, palette : &[Color], width : usize, height : usize,
+                  mut put : impl FnMut(usize, usize, Color)) -> io::Result<()> {
+    let color = |index : u8| *palette.get(index as usize).unwrap_or(&Color::CLEAR);
+    let (mut x, mut y) = (0, 0);
+    let mut write = |x : &mut usize, y : usize, index : u8| {
+        if *x < width && y < height { put(*x, y, color(index)) }
+        *x += 1;
+    };
+
+    loop {
+        let (count, value) = (b.u8(at)?, b.u8(at + 1)?);
+        at += 2;
+
+        if count > 0 {
+            for i in 0..count {
+                let index = if !rle4 { value } else if i % 2 == 0 { value >> 4 } else { value & 0x0F };
+                write(&mut x, y, index);
+            }
+            continue
+        }
+
+        match value {
+            0 => { x = 0; y += 1 }
+            1 => return Ok(()),
+            2 => {
+                x += b.u8(at)? as usize;
+                y += b.u8(at + 1)? as usize;
+                at += 2;
+            }
+            literal => {
+                let literal = literal as usize;
+                let len = if rle4 { literal.div_ceil(2) } else { literal };
+                let data = b.slice(at, len)?;
+                for i in 0..literal {
+                    let index = if !rle4 { data[i] } else if i % 2 == 0 { data[i / 2] >> 4 } else { data[i / 2] & 0x0F };
+                    write(&mut x, y, index);
+                }
+                // Literal runs are padded to a whole number of 16 bit words.
+                at += len.div_ceil(2) * 2;
+            }
+        }
+
+        if y >= height { return Ok(()) }
+    }
+}
+
+/// Color mapped, true color and grayscale targas, raw or run length encoded.
+fn decode_tga(bytes : &[u8]) -> io::Result<SingleImageBuffer> {
+    let b = Bytes { bytes };
+    let id_length = b.u8(0)? as usize;
+    let color_map_type = b.u8(1)?;
+    let image_type = b.u8(2)?;
+    let (map_first, map_length, map_entry_bits) = (b.u16(3)? as usize, b.u16(5)? as usize, b.u8(7)? as usize);
+    let (width, height) = (b.u16(12)? as usize, b.u16(14)? as usize);
+    let depth = b.u8(16)? as usize;
+    let descriptor = b.u8(17)?;
+    let alpha_bits = descriptor & 0x0F;
+
+    let rle = image_type & 8 != 0;
+    let kind = image_type & !8;
+    if !(1..=3).contains(&kind) {
+        return Err(invalid("Unsupported targa image type"))
+    }
+
+    // Everything below indexes pixels by these sizes, so a corrupt header has to stop here.
+    let depth_ok = match kind {
+        1 => depth == 8 || depth == 16,
+        2 => matches!(depth, 15 | 16 | 24 | 32),
+        _ => depth == 8 || depth == 16
+    };
+    if !depth_ok {
+        return Err(invalid("Unsupported targa pixel depth for its image type"))
+    }
+    if kind == 1 && color_map_type != 1 {
+        return Err(invalid("Color mapped targa has no color map"))
+    }
+    if color_map_type == 1 && !matches!(map_entry_bits, 15 | 16 | 24 | 32) {
+        return Err(invalid("Unsupported targa color map entry size"))
+    }
+
+    // Turns one stored pixel of `bits` into a color, straight from the bytes.
+    let to_color = |p : &[u8], bits : usize| -> Color {
+        match bits {
+            8 => Color(p[0], p[0], p[0], 255),
+            15 | 16 => {
+                let v = u16::from_le_bytes([p[0], p[1]]);
+                let scale = |c : u16| ((c & 0x1F) * 255 / 31) as u8;
+                let a = if bits == 16 && alpha_bits > 0 && v & 0x8000 == 0 { 0 } else { 255 };
+                Color(scale(v >> 10), scale(v >> 5), scale(v), a)
+            }
+            24 => Color(p[2], p[1], p[0], 255),
+            _ => Color(p[2], p[1], p[0], if alpha_bits > 0 { p[3] } else { 255 })
+        }
+    };
+
+    let mut at = 18 + id_length;
+    let mut palette = Vec::new();
+    if color_map_type == 1 {
+        let entry = map_entry_bits.div_ceil(8);
+        for i in 0..map_length {
+            palette.push(to_color(b.slice(at + i * entry, entry)?, map_entry_bits));
+        }
+        at += map_length * entry;
+    }
+
+    let pixel_bytes = depth.div_ceil(8);
+    let decode = |p : &[u8]| -> Color {
+        match kind {
+            1 => {
+                let index = if pixel_bytes == 2 { u16::from_le_bytes([p[0], p[1]]) as usize } else { p[0] as usize };
+                *palette.get(index.wrapping_sub(map_first)).unwrap_or(&Color::CLEAR)
+            }
+            3 if depth == 16 => Color(p[0], p[0], p[0], p[1]),
+            3 => Color(p[0], p[0], p[0], 255),
+            _ => to_color(p, depth)
+        }
+    };
+
+    let count = pixel_count(width, height)?;
+    let mut pixels = Vec::with_capacity(count);
+    while pixels.len() < count {
+        if !rle {
+            pixels.push(decode(b.slice(at, pixel_bytes)?));
+            at += pixel_bytes;
+            continue
+        }
+
+        let header = b.u8(at)?;
+        at += 1;
+        let run = (header & 0x7F) as usize + 1;
+        if header & 0x80 != 0 {
+            let color = decode(b.slice(at, pixel_bytes)?);
+            at += pixel_bytes;
+            pixels.extend(std::iter::repeat_n(color, run));
+        } else {
+            for _ in 0..run {
+                pixels.push(decode(b.slice(at, pixel_bytes)?));
+                at += pixel_bytes;
+            }
+        }
+    }
+    pixels.truncate(count);
+
+    // Targas are stored bottom up and left to right unless the descriptor says otherwise.
+    let right_to_left = descriptor & 0x10 != 0;
+    let top_down = descriptor & 0x20 != 0;
+    let mut buffer = vec![Color::CLEAR; count];
+    for y in 0..height {
+        for x in 0..width {
+            let dst_x = if right_to_left { width - 1 - x } else { x };
+            let dst_y = if top_down { y } else { height - 1 - y };
+            buffer[dst_x + dst_y * width] = pixels[x + y * width];
+        }
+    }
+
+    Ok(SingleImageBuffer::from_colors(buffer, width, height))
+}
+
+/// The "Quite OK Image" format, see https://qoiformat.org/qoi-specification.pdf.
+fn decode_qoi(bytes : &[u8]) -> io::Result<SingleImageBuffer> {
+    let b = Bytes { bytes };
+    let header = b.slice(4, 8)?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let count = pixel_count(width, height)?;
+
+    let hash = |c : Color| (c.0 as usize * 3 + c.1 as usize * 5 + c.2 as usize * 7 + c.3 as usize * 11) % 64;
+    let mut seen = [Color::CLEAR; 64];
+    let mut pixel = Color(0, 0, 0, 255);
+    let mut pixels = Vec::with_capacity(count);
+    let mut at = 14;
+
+    while pixels.len() < count {
+        let tag = b.u8(at)?;
+        at += 1;
+
+        match tag {
+            0xFE => {
+                let rgb = b.slice(at, 3)?;
+                pixel = Color(rgb[0], rgb[1], rgb[2], pixel.3);
+                at += 3;
+            }
+            0xFF => {
+                let rgba = b.slice(at, 4)?;
+                pixel = Color(rgba[0], rgba[1], rgba[2], rgba[3]);
+                at += 4;
+            }
+            _ => match tag >> 6 {
+                0 => pixel = seen[(tag & 0x3F) as usize],
+                1 => {
+                    let d = |shift : u8| ((tag >> shift) & 0x03).wrapping_sub(2);
+                    pixel = Color(pixel.0.wrapping_add(d(4)), pixel.1.wrapping_add(d(2)), pixel.2.wrapping_add(d(0)), pixel.3);
+                }
+                2 => {
+                    let next = b.u8(at)?;
+                    at += 1;
+                    let green = (tag & 0x3F).wrapping_sub(32);
+                    let red = green.wrapping_add(next >> 4).wrapping_sub(8);
+                    let blue = green.wrapping_add(next & 0x0F).wrapping_sub(8);
+                    pixel = Color(pixel.0.wrapping_add(red), pixel.1.wrapping_add(green), pixel.2.wrapping_add(blue), pixel.3);
+                }
+                _ => {
+                    let run = (tag & 0x3F) as usize;
+                    pixels.extend(std::iter::repeat_n(pixel, run));
+                }
+            }
+        }
+
+        seen[hash(pixel)] = pixel;
+        pixels.push(pixel);
+    }
+    pixels.truncate(count);
+
+    Ok(SingleImageBuffer::from_colors(pixels, width, height))
+}
+
+/// Every frame composited onto the logical screen, honouring each frame's disposal method.
+fn decode_gif(bytes : &[u8]) -> io::Result<Vec<Frame>> {
+    let gif_error = |e : gif::DecodingError| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(bytes)).map_err(gif_error)?;
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+
+    let mut canvas = SingleImageBuffer::new(width, height);
+    let mut frames = Vec::new();
+
+    while let Some(frame) = decoder.read_next_frame().map_err(gif_error)? {
+        let previous = if frame.dispose == gif::DisposalMethod::Previous { Some(canvas.get_buffer().clone()) } else { None };
+        let (left, top) = (frame.left as usize, frame.top as usize);
+        let (frame_width, frame_height) = (frame.width as usize, frame.height as usize);
+
+        for (i, c) in frame.buffer.chunks_exact(4).enumerate() {
+            let (x, y) = (left + i % frame_width, top + i / frame_width);
+            if c[3] != 0 && x < width && y < height {
+                canvas.set_pixel(Color(c[0], c[1], c[2], c[3]), x, y);
+            }
+        }
+
+        let delay = if frame.delay == 0 { DEFAULT_GIF_DELAY } else { frame.delay as f64 / 100.0 };
+        frames.push(Frame { image : SingleImageBuffer::from_colors(canvas.get_buffer().clone(), width, height), delay : Some(delay) });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in top..(top + frame_height).min(height) {
+                    for x in left..(left + frame_width).min(width) {
+                        canvas.set_pixel(Color::CLEAR, x, y);
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => {
+                if let Some(previous) = previous {
+                    canvas.set_buffer(&previous, width, height);
+                }
+            }
+            _ => ()
+        }
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a png of `color_type` at `bit_depth`, with `chunks` written between the header
+    /// and the pixels.
+    fn png(width : u32, height : u32, color_type : png::ColorType, bit_depth : png::BitDepth, chunks : &[([u8; 4], &[u8])], data : &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, width, height);
+            encoder.set(color_type).set(bit_depth);
+            let mut writer = encoder.write_header().unwrap();
+            for (name, chunk) in chunks {
+                writer.write_chunk(*name, chunk).unwrap();
+            }
+            writer.write_image_data(data).unwrap();
+        }
+        bytes
+    }
+
+    fn pixels(image : &SingleImageBuffer) -> Vec<Color> {
+        image.get_buffer().clone()
+    }
+
+    #[test]
+    fn png_palettes_expand_with_their_transparency() {
+        let palette = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+        // Two bits per pixel: 0, 1, 2, 1.
+        let bytes = png(4, 1, png::ColorType::Indexed, png::BitDepth::Two, &[(*b"PLTE", &palette), (*b"tRNS", &[0, 128])], &[0b00_01_10_01]);
+        let image = decode_png(&bytes).unwrap();
+        assert_eq!(image.get_dimensions(), (4, 1));
+        assert_eq!(pixels(&image), vec![Color(10, 20, 30, 0), Color(40, 50, 60, 128), Color(70, 80, 90, 255), Color(40, 50, 60, 128)]);
+    }
+
+    #[test]
+    fn png_grayscale_becomes_gray_rgba() {
+        let bytes = png(2, 1, png::ColorType::Grayscale, png::BitDepth::Eight, &[], &[0, 200]);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(0, 0, 0, 255), Color(200, 200, 200, 255)]);
+
+        let bytes = png(2, 1, png::ColorType::GrayscaleAlpha, png::BitDepth::Eight, &[], &[50, 100, 150, 255]);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(50, 50, 50, 100), Color(150, 150, 150, 255)]);
+
+        // A tRNS chunk on grayscale makes that one gray level transparent.
+        let bytes = png(2, 1, png::ColorType::Grayscale, png::BitDepth::Eight, &[(*b"tRNS", &[0, 200])], &[0, 200]);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(0, 0, 0, 255), Color(200, 200, 200, 0)]);
+    }
+
+    #[test]
+    fn png_sixteen_bit_keeps_the_high_byte() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0x00];
+        let bytes = png(1, 1, png::ColorType::RGBA, png::BitDepth::Sixteen, &[], &data);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(0x12, 0x56, 0x9a, 0xff)]);
+
+        let bytes = png(2, 1, png::ColorType::Grayscale, png::BitDepth::Sixteen, &[], &[0xab, 0xcd, 0x01, 0x02]);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(0xab, 0xab, 0xab, 255), Color(1, 1, 1, 255)]);
+    }
+
+    #[test]
+    fn png_rgb_gets_opaque_alpha() {
+        let bytes = png(2, 1, png::ColorType::RGB, png::BitDepth::Eight, &[], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(pixels(&decode_png(&bytes).unwrap()), vec![Color(1, 2, 3, 255), Color(4, 5, 6, 255)]);
+        assert!(decode_png(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    /// A bitmap with a 40 byte info header. `extra` is the palette or bitfield masks.
+    fn bmp(width : i32, height : i32, bpp : u16, compression : u32, extra : &[u8], data : &[u8]) -> Vec<u8> {
+        let data_offset = 14 + 40 + extra.len() as u32;
+        let mut bytes = b"BM".to_vec();
+        for v in [data_offset + data.len() as u32, 0, data_offset, 40] { bytes.extend(v.to_le_bytes()) }
+        bytes.extend(width.to_le_bytes());
+        bytes.extend(height.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(bpp.to_le_bytes());
+        let colors_used = if bpp <= 8 { extra.len() as u32 / 4 } else { 0 };
+        for v in [compression, data.len() as u32, 0, 0, colors_used, 0] { bytes.extend(v.to_le_bytes()) }
+        bytes.extend(extra);
+        bytes.extend(data);
+        bytes
+    }
+
+    /// An 18 byte targa header followed by `body`.
+    fn tga(image_type : u8, map : Option<(u16, u8)>, width : u16, height : u16, depth : u8, descriptor : u8, body : &[u8]) -> Vec<u8> {
+        let (map_length, map_bits) = map.unwrap_or((0, 0));
+        let mut bytes = vec![0, map.is_some() as u8, image_type];
+        for v in [0, map_length] { bytes.extend(v.to_le_bytes()) }
+        bytes.push(map_bits);
+        for v in [0, 0, width, height] { bytes.extend(v.to_le_bytes()) }
+        bytes.extend([depth, descriptor]);
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn bmp_true_color_rows_are_bottom_up_and_padded() {
+        // Two 24 bit rows of two pixels, each padded to eight bytes, bottom row first.
+        let data = [1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0];
+        let image = decode_bmp(&bmp(2, 2, 24, 0, &[], &data)).unwrap();
+        assert_eq!(pixels(&image), vec![Color(9, 8, 7, 255), Color(12, 11, 10, 255), Color(3, 2, 1, 255), Color(6, 5, 4, 255)]);
+
+        // Negative heights are stored top down.
+        let image = decode_bmp(&bmp(2, -2, 24, 0, &[], &data)).unwrap();
+        assert_eq!(image.get_pixel(0, 0), Color(3, 2, 1, 255));
+    }
+
+    #[test]
+    fn bmp_palettes_and_bitfields() {
+        // Palette entries are stored blue, green, red, unused.
+        let palette = [0, 0, 255, 0, 255, 0, 0, 0];
+        let image = decode_bmp(&bmp(3, 1, 1, 0, &palette, &[0b101_00000, 0, 0, 0])).unwrap();
+        assert_eq!(pixels(&image), vec![Color(0, 0, 255, 255), Color(255, 0, 0, 255), Color(0, 0, 255, 255)]);
+
+        let masks : Vec<u8> = [0xFF00_0000u32, 0x00FF_0000, 0x0000_FF00].iter().flat_map(|m| m.to_le_bytes()).collect();
+        let image = decode_bmp(&bmp(1, 1, 32, 3, &masks, &[9, 30, 20, 10])).unwrap();
+        assert_eq!(pixels(&image), vec![Color(10, 20, 30, 255)]);
+    }
+
+    #[test]
+    fn bmp_rle8_runs_literals_and_end_of_line() {
+        let palette = [0, 0, 0, 0, 50, 60, 70, 0];
+        // Bottom row: three pixels of color 1. Top row: the literal 0, 1, 0, padded to a word.
+        let data = [3, 1, 0, 0, 0, 3, 0, 1, 0, 0, 0, 1];
+        let image = decode_bmp(&bmp(4, 2, 8, 1, &palette, &data)).unwrap();
+        let (black, color) = (Color(0, 0, 0, 255), Color(70, 60, 50, 255));
+        assert_eq!(pixels(&image), vec![black, color, black, Color::CLEAR, color, color, color, Color::CLEAR]);
+    }
+
+    #[test]
+    fn broken_bitmaps_are_errors() {
+        let good = bmp(2, 2, 24, 0, &[], &[0; 16]);
+        assert!(decode_bmp(&good).is_ok());
+        for len in [0, 10, 20, 40, good.len() - 1] {
+            assert!(decode_bmp(&good[..len]).is_err(), "truncated to {}", len);
+        }
+        assert!(decode_bmp(&bmp(0, 2, 24, 0, &[], &[0; 16])).is_err());
+        assert!(decode_bmp(&bmp(2, 0, 24, 0, &[], &[0; 16])).is_err());
+        assert!(decode_bmp(&bmp(2, 2, 24, 9, &[], &[0; 16])).is_err());
+        assert!(decode_bmp(&bmp(2, 2, 12, 0, &[], &[0; 16])).is_err());
+        assert!(decode_bmp(&bmp(2, 2, 1, 2, &[0; 8], &[3, 1])).is_err());
+    }
+
+    #[test]
+    fn tga_true_color_raw_and_run_length() {
+        let image = decode_tga(&tga(2, None, 2, 2, 24, 0, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])).unwrap();
+        assert_eq!(pixels(&image), vec![Color(9, 8, 7, 255), Color(12, 11, 10, 255), Color(3, 2, 1, 255), Color(6, 5, 4, 255)]);
+
+        // Top down with eight alpha bits: a run of two, then one raw pixel.
+        let body = [0x81, 1, 2, 3, 128, 0x00, 4, 5, 6, 0];
+        let image = decode_tga(&tga(10, None, 3, 1, 32, 0x28, &body)).unwrap();
+        assert_eq!(pixels(&image), vec![Color(3, 2, 1, 128), Color(3, 2, 1, 128), Color(6, 5, 4, 0)]);
+
+        // 16 bit pixels: pure red with the attribute bit set, and blue with it clear.
+        let body = [0x00, 0xFC, 0x1F, 0x00];
+        let image = decode_tga(&tga(2, None, 2, 1, 16, 0x21, &body)).unwrap();
+        assert_eq!(pixels(&image), vec![Color(255, 0, 0, 255), Color(0, 0, 255, 0)]);
+    }
+
+    #[test]
+    fn tga_color_maps_and_grayscale() {
+        let body = [10, 20, 30, 40, 50, 60, 1, 0];
+        let image = decode_tga(&tga(1, Some((2, 24)), 2, 1, 8, 0x20, &body)).unwrap();
+        assert_eq!(pixels(&image), vec![Color(60, 50, 40, 255), Color(30, 20, 10, 255)]);
+
+        let image = decode_tga(&tga(3, None, 2, 1, 8, 0x20, &[0, 200])).unwrap();
+        assert_eq!(pixels(&image), vec![Color(0, 0, 0, 255), Color(200, 200, 200, 255)]);
+        let image = decode_tga(&tga(3, None, 1, 1, 16, 0x20, &[90, 40])).unwrap();
+        assert_eq!(pixels(&image), vec![Color(90, 90, 90, 40)]);
+    }
+
+    #[test]
+    fn corrupt_tga_headers_are_errors() {
+        let body = [0; 64];
+        for depth in [0, 1, 12, 33, 255] {
+            assert!(decode_tga(&tga(2, None, 2, 2, depth, 0, &body)).is_err(), "depth {}", depth);
+        }
+        // Depths that exist, but not for that image type.
+        assert!(decode_tga(&tga(2, None, 2, 2, 8, 0, &body)).is_err());
+        assert!(decode_tga(&tga(3, None, 2, 2, 24, 0, &body)).is_err());
+        assert!(decode_tga(&tga(1, Some((2, 24)), 2, 2, 32, 0, &body)).is_err());
+        // Color maps need one and it needs a real entry size.
+        assert!(decode_tga(&tga(1, None, 2, 2, 8, 0, &body)).is_err());
+        for bits in [0, 8, 12] {
+            assert!(decode_tga(&tga(1, Some((2, bits)), 2, 2, 8, 0, &body)).is_err(), "map entry bits {}", bits);
+        }
+        assert!(decode_tga(&tga(4, None, 2, 2, 24, 0, &body)).is_err());
+        assert!(decode_tga(&tga(2, None, 2, 2, 24, 0, &body[..11])).is_err());
+        assert!(decode_tga(&tga(10, None, 2, 2, 24, 0, &[0x83, 1, 2])).is_err());
+        assert!(decode_tga(&[0; 17]).is_err());
+    }
+
+    #[test]
+    fn qoi_decodes_every_op() {
+        let mut bytes = b"qoif".to_vec();
+        bytes.extend(7u32.to_be_bytes());
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend([4, 0]);
+        // RGB, diff, luma, RGBA, index of the first pixel, then a run of two.
+        bytes.extend([0xFE, 10, 20, 30, 0x76, 0xA4, 0x96, 0xFF, 1, 2, 3, 4, 9, 0xC1]);
+        bytes.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let image = decode_qoi(&bytes).unwrap();
+        let first = Color(10, 20, 30, 255);
+        assert_eq!(pixels(&image), vec![first, Color(11, 19, 30, 255), Color(16, 23, 32, 255), Color(1, 2, 3, 4), first, first, first]);
+
+        assert!(decode_qoi(&bytes[..20]).is_err());
+        assert!(decode_qoi(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn gif_frames_are_composited_with_their_delays() {
+        let mut bytes = Vec::new();
+        {
+            let palette = [255, 0, 0, 0, 0, 255, 0, 255, 0, 0, 0, 0];
+            let mut encoder = gif::Encoder::new(&mut bytes, 2, 1, &palette).unwrap();
+            let mut frame = gif::Frame::from_indexed_pixels(2, 1, vec![0, 1], None);
+            frame.delay = 20;
+            encoder.write_frame(&frame).unwrap();
+
+            // Only the left pixel changes; the right one is transparent and keeps the blue.
+            let mut frame = gif::Frame::from_indexed_pixels(2, 1, vec![2, 3], Some(3));
+            frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&frame).unwrap();
+
+            let mut frame = gif::Frame::from_indexed_pixels(1, 1, vec![0], None);
+            frame.left = 1;
+            frame.delay = 5;
+            encoder.write_frame(&frame).unwrap();
+        }
+
+        let frames = decode_gif(&bytes).unwrap();
+        let (red, blue, green) = (Color(255, 0, 0, 255), Color(0, 0, 255, 255), Color(0, 255, 0, 255));
+        let delays : Vec<Option<f64>> = frames.iter().map(|f| f.delay).collect();
+        assert_eq!(delays, vec![Some(0.2), Some(DEFAULT_GIF_DELAY), Some(0.05)]);
+        assert_eq!(pixels(&frames[0].image), vec![red, blue]);
+        assert_eq!(pixels(&frames[1].image), vec![green, blue]);
+        assert_eq!(pixels(&frames[2].image), vec![Color::CLEAR, red]);
+
+        assert!(decode_gif(&bytes[..8]).is_err());
+        assert!(decode_gif(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn formats_are_detected_by_magic_then_extension() {
+        let path = Path::new("sprite.TGA");
+        assert_eq!(ImageFormat::detect(path, b"\x89PNG...."), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(path, b"BM"), Some(ImageFormat::Bmp));
+        assert_eq!(ImageFormat::detect(path, b"qoif"), Some(ImageFormat::Qoi));
+        assert_eq!(ImageFormat::detect(path, b"GIF89a"), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::detect(path, b""), Some(ImageFormat::Tga));
+        assert_eq!(ImageFormat::detect(Path::new("sprite.png"), b"not a png"), None);
+    }
+
+    #[test]
+    fn color_keys_clear_matching_pixels_whatever_their_alpha() {
+        let bytes = png(3, 1, png::ColorType::RGBA, png::BitDepth::Eight, &[], &[255, 0, 255, 255, 255, 0, 255, 10, 255, 0, 254, 255]);
+        let mut image = decode_png(&bytes).unwrap();
+        image.apply_color_key(Color(255, 0, 255, 255));
+        assert_eq!(pixels(&image), vec![Color::CLEAR, Color::CLEAR, Color(255, 0, 254, 255)]);
+    }
+}
@@ -1,27 +1,68 @@
 use pixels::{wgpu, PixelsContext};
 use std::time::Instant;
-use imgui::{ImStr, Ui, Window, Condition, im_str, CollapsingHeader, WindowFlags, PlotLines, TreeNode, InputInt2, ImGuiInputTextFlags, Drag, DragRange};
+use imgui::{ImStr, Ui, Window, Condition, im_str, CollapsingHeader, WindowFlags, PlotLines, TreeNode, InputInt2, Slider, ImGuiInputTextFlags, Drag, DragRange};
+use imgui::ColorEdit;
 use crate::game::GameState;
 use winit::event::VirtualKeyCode::W;
-use crate::window::WindowInfo;
-use crate::buffer::Buffer;
 use crate::math::Vec2;
 use crate::image_buffer::CamBuffer;
+use crate::capture::CaptureFormat;
+use crate::color::Color;
+use crate::config::{GameConfig, CONFIG_PATH};
+use crate::screen::ScalingMode;
+use crate::window::{WindowInfo, WindowMode};
+use log::{error, info};
+use std::path::Path;
+use crate::dirty::Redraw;
+use winit::event::VirtualKeyCode;
+
+/// Shows and hides the overlay. While it's hidden, frames where nothing changed aren't presented.
+pub const OVERLAY_KEY : VirtualKeyCode = VirtualKeyCode::F1;
+
+/// A color picker for `color`, returning whether it was changed. Without `alpha` only the color
+/// channels are shown and the alpha is kept.
+pub fn color_edit(ui : &Ui, label : &ImStr, color : &mut Color, alpha : bool) -> bool {
+    let channel = |c : f32| (c * 255.0).round() as u8;
+    let mut rgba = [color.0 as f32 / 255.0, color.1 as f32 / 255.0, color.2 as f32 / 255.0, color.3 as f32 / 255.0];
+    let changed = if alpha {
+        ColorEdit::new(label, &mut rgba).build(ui)
+    } else {
+        let mut rgb = [rgba[0], rgba[1], rgba[2]];
+        let changed = ColorEdit::new(label, &mut rgb).build(ui);
+        rgba[..3].copy_from_slice(&rgb);
+        changed
+    };
+    if changed {
+        *color = Color(channel(rgba[0]), channel(rgba[1]), channel(rgba[2]), channel(rgba[3]));
+    }
+    changed
+}
+
+/// The parts of the game the overlay inspects and edits each frame.
+pub(crate) struct GuiContext<'a> {
+    pub gs : &'a mut GameState,
+    pub window_info : &'a WindowInfo,
+    pub cam_buffer : &'a mut CamBuffer,
+    pub config : &'a mut GameConfig
+}
 
 /// Manages all state required for rendering Dear ImGui over `Pixels`.
 pub struct Gui {
+    pub visible: bool,
     imgui: imgui::Context,
     platform: imgui_winit_support::WinitPlatform,
     renderer: imgui_wgpu::Renderer,
     last_frame: Instant,
     last_cursor: Option<imgui::MouseCursor>,
     about_open: bool,
-    deltas : Vec<f32>
+    deltas : Vec<f32>,
+    /// The resolution being typed in, only applied on request.
+    resolution : Option<[i32; 2]>
 }
 
 impl Gui {
     /// Create Dear ImGui.
-    pub(crate) fn new(window: &winit::window::Window, pixels: &pixels::Pixels) -> Self {
+    pub fn new(window: &winit::window::Window, pixels: &pixels::Pixels) -> Self {
         // Create Dear ImGui context
         let mut imgui = imgui::Context::create();
         imgui.set_ini_filename(None);
@@ -60,16 +101,23 @@ impl Gui {
 
         // Return GUI context
         Self {
+            visible: true,
             imgui,
             platform,
             renderer,
             last_frame: Instant::now(),
             last_cursor: None,
             about_open: false,
-            deltas: Vec::new()
+            deltas: Vec::new(),
+            resolution: None
         }
     }
 
+    /// Drops any half typed settings, so the overlay shows the config in use again.
+    pub fn reset_edits(&mut self) {
+        self.resolution = None;
+    }
+
     /// Prepare Dear ImGui.
     pub(crate) fn prepare(
         &mut self,
@@ -89,11 +137,11 @@ impl Gui {
         encoder: &mut wgpu::CommandEncoder,
         render_target: &wgpu::TextureView,
         context: &PixelsContext,
-        gs : &mut GameState,
-        delta : f64,
-        window_info: &WindowInfo,
-        cam_buffer: &mut CamBuffer
+        game: GuiContext,
+        delta : f64
     ) -> imgui_wgpu::RendererResult<()> {
+        let GuiContext { gs, window_info, cam_buffer, config } = game;
+
         // Start a new Dear ImGui frame and update the cursor
         let ui = self.imgui.frame();
 
@@ -119,6 +167,7 @@ impl Gui {
         }
 
         let mut deltas = &mut self.deltas;
+        let resolution = &mut self.resolution;
 
         deltas.push(delta as f32);
         while deltas.len() >= 20 {
@@ -138,6 +187,23 @@ impl Gui {
                     .build();
             });
 
+        let assets = gs.assets.borrow();
+        let reloads = assets.get_recent_reloads();
+        if !reloads.is_empty() {
+            Window::new(im_str!("Asset Reloads"))
+                .flags(WindowFlags::NO_DECORATION | WindowFlags::ALWAYS_AUTO_RESIZE | WindowFlags::NO_SAVED_SETTINGS | WindowFlags::NO_FOCUS_ON_APPEARING | WindowFlags::NO_NAV)
+                .position([window_info.width as f32 - 315.0, 80.0], Condition::Always)
+                .build(&ui, || {
+                    for reload in reloads {
+                        match &reload.error {
+                            Some(e) => ui.text_colored([1.0, 0.4, 0.4, 1.0], format!("Failed to reload {}: {}", reload.name, e)),
+                            None => ui.text(format!("Reloaded {}", reload.name))
+                        }
+                    }
+                });
+        }
+        drop(assets);
+
         Window::new(im_str!("Blueberry Main"))
             .flags(WindowFlags::NO_RESIZE | WindowFlags::NO_MOVE)
             .position([0.0, 20.0], Condition::FirstUseEver)
@@ -147,9 +213,149 @@ impl Gui {
                 let mut cam_pos = [x, y];
                 InputInt2::new(&ui, im_str!("Cam pos"), &mut cam_pos);
                 ui.input_int2(im_str!("Cam pos"), &mut cam_pos).build();
+                if CollapsingHeader::new(im_str!("Time")).default_open(true).build(&ui) {
+                    let time = &mut gs.time;
+                    ui.checkbox(im_str!("Paused"), &mut time.paused);
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Step"), [0.0, 0.0]) {
+                        time.paused = true;
+                        time.step(1);
+                    }
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Step 10"), [0.0, 0.0]) {
+                        time.paused = true;
+                        time.step(10);
+                    }
+
+                    let mut time_scale = time.time_scale as f32;
+                    if Slider::new(im_str!("Time Scale")).range(0.0..=4.0).build(&ui, &mut time_scale) {
+                        time.time_scale = time_scale as f64;
+                    }
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Reset")) {
+                        time.time_scale = 1.0;
+                    }
+                    ui.text(format!("Game Time: {:.2}s", time.get_game_time()));
+                }
+                if CollapsingHeader::new(im_str!("Sprite Cache")).build(&ui) {
+                    let mut cache = gs.sprite_cache.borrow_mut();
+                    let stats = cache.get_stats();
+                    ui.text(format!("Entries: {}", cache.len()));
+                    ui.text(format!("Memory: {} / {} KiB", cache.get_used() / 1024, cache.get_budget() / 1024));
+                    ui.text(format!("Hits: {}  Misses: {}  Evictions: {}", stats.hits, stats.misses, stats.evictions));
+                    if ui.small_button(im_str!("Clear")) {
+                        cache.clear();
+                    }
+                }
+                if CollapsingHeader::new(im_str!("Assets")).build(&ui) {
+                    let mut assets = gs.assets.borrow_mut();
+                    ui.text(format!("Loaded: {}", assets.len()));
+                    ui.checkbox(im_str!("Watch for Changes"), &mut assets.watch);
+                    drop(assets);
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Reload Now")) {
+                        gs.reload_assets();
+                    }
+                }
+                if CollapsingHeader::new(im_str!("Display")).build(&ui) {
+                    let size = resolution.get_or_insert([config.width as i32, config.height as i32]);
+                    ui.input_int2(im_str!("Resolution"), size).build();
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Apply")) {
+                        if size[0] > 0 && size[1] > 0 {
+                            config.width = size[0] as u32;
+                            config.height = size[1] as u32;
+                        }
+                        // Show what's actually in use again, whether or not it was accepted.
+                        *resolution = None;
+                    }
+
+                    ui.text("Scaling");
+                    ui.radio_button(im_str!("Integer"), &mut config.scaling, ScalingMode::Integer);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("Fit"), &mut config.scaling, ScalingMode::Fit);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("Stretch"), &mut config.scaling, ScalingMode::Stretch);
+
+                    color_edit(&ui, im_str!("Letterbox"), &mut config.letterbox, false);
+
+                    ui.text("Window");
+                    ui.radio_button(im_str!("Windowed"), &mut config.window_mode, WindowMode::Windowed);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("Borderless"), &mut config.window_mode, WindowMode::Borderless);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("Fullscreen"), &mut config.window_mode, WindowMode::Fullscreen);
+                    let mut window_scale = config.window_scale as i32;
+                    if Slider::new(im_str!("Window Scale")).range(1..=8).build(&ui, &mut window_scale) {
+                        config.window_scale = window_scale as u32;
+                    }
+                    ui.checkbox(im_str!("VSync"), &mut config.vsync);
+
+                    if ui.small_button(im_str!("Save Config")) {
+                        match config.save(Path::new(CONFIG_PATH)) {
+                            Ok(_) => info!("Saved config to {}", CONFIG_PATH),
+                            Err(e) => error!("Unable to save config to {}: {}", CONFIG_PATH, e)
+                        }
+                    }
+                }
+                if CollapsingHeader::new(im_str!("Lighting")).build(&ui) {
+                    let mut lighting = gs.lighting.borrow_mut();
+                    ui.checkbox(im_str!("Enabled"), &mut lighting.enabled);
+                    ui.same_line(0.0);
+                    ui.checkbox(im_str!("Show Light Map"), &mut lighting.show_light_map);
+
+                    color_edit(&ui, im_str!("Ambient"), &mut lighting.ambient, false);
+                    ui.text(format!("Lights: {}  Occluders: {}", lighting.get_light_count(), if lighting.occluders.is_some() { "yes" } else { "none" }));
+                }
+                if CollapsingHeader::new(im_str!("Dirty Rendering")).build(&ui) {
+                    let dirty = &mut gs.dirty;
+                    ui.checkbox(im_str!("Enabled##dirty"), &mut dirty.enabled);
+                    let redraw = match dirty.get_last_redraw() {
+                        Redraw::Full => "full".to_string(),
+                        Redraw::Regions(rects) => format!("{} regions", rects.len()),
+                        Redraw::None => "nothing".to_string()
+                    };
+                    ui.text(format!("Last frame: {}  Skipped: {}", redraw, dirty.get_skipped_frames()));
+                    ui.text_disabled("Frames are presented while the overlay is up, F1 hides it.");
+                }
+                if CollapsingHeader::new(im_str!("Post Processing")).build(&ui) {
+                    for (i, stage) in gs.post.get_stages_mut().iter_mut().enumerate() {
+                        let id = ui.push_id(i as i32);
+                        ui.checkbox(&im_str!("{}", stage.effect.name()), &mut stage.enabled);
+                        if stage.enabled {
+                            ui.indent();
+                            stage.effect.debug(&ui);
+                            ui.unindent();
+                        }
+                        id.pop(&ui);
+                    }
+                }
+                if CollapsingHeader::new(im_str!("Capture")).build(&ui) {
+                    let capture = &mut gs.capture;
+                    let mut scale = capture.scale as i32;
+                    if Slider::new(im_str!("Scale")).range(1..=8).build(&ui, &mut scale) {
+                        capture.scale = scale as usize;
+                    }
+                    ui.radio_button(im_str!("GIF"), &mut capture.format, CaptureFormat::Gif);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("PNG Sequence"), &mut capture.format, CaptureFormat::PngSequence);
+                    if ui.small_button(im_str!("Screenshot (F12)")) {
+                        capture.request_screenshot();
+                    }
+                    ui.same_line(0.0);
+                    if capture.is_recording() {
+                        if ui.small_button(im_str!("Stop (F10)")) {
+                            capture.stop_recording();
+                        }
+                        ui.text(format!("Recording: {} frames, {:.1}s", capture.get_frame_count(), capture.get_recorded_time()));
+                    } else if ui.small_button(im_str!("Record (F10)")) {
+                        capture.start_recording();
+                    }
+                }
                 if CollapsingHeader::new(im_str!("Game Objects")).default_open(true).build(&ui) {
                     for (name, go) in gs.iter_mut() {
                         TreeNode::new(&im_str!("{}", name)).build(&ui, || {
+                            ui.checkbox(im_str!("Unscaled Time"), &mut go.unscaled_time);
                             go.debug_objects(&ui);
                         });
                         ui.same_line(280.0);
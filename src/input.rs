@@ -2,7 +2,6 @@ use crate::math::{Vec2u, Vec2f, Vec2, Vec2i};
 use game_loop::winit::event::{VirtualKeyCode, ElementState, MouseButton};
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use crate::{FrameInfo};
 
 pub struct InputInfo {
     keymap : HashMap<VirtualKeyCode, bool>,
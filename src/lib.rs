@@ -0,0 +1,33 @@
+#![deny(clippy::all)]
+
+pub mod buffer;
+pub mod math;
+pub mod game;
+pub mod imgui;
+pub mod input;
+pub mod window;
+pub mod animation;
+pub mod frame;
+pub mod image_buffer;
+pub mod color;
+pub mod comps;
+pub mod replay;
+pub mod task;
+pub mod tween;
+pub mod sprite_cache;
+pub mod atlas;
+pub mod assets;
+pub mod image_loader;
+pub mod capture;
+pub mod config;
+pub mod screen;
+pub mod palette;
+pub mod postprocess;
+pub mod lighting;
+pub mod particles;
+pub mod parallax;
+pub mod primitives;
+pub mod raster;
+pub mod dirty;
+
+extern crate num_traits;
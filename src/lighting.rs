@@ -0,0 +1,461 @@
+use crate::color::Color;
+use crate::comps::object::GameComponent;
+use crate::comps::transform::TransformComponent;
+use crate::frame::FrameInfo;
+use crate::image_buffer::{CamBuffer, ImageBuffer};
+use crate::math::{Vec2, Vec2f};
+use imgui::{im_str, Slider, Ui};
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_LIGHT_SEED : AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightShape {
+    /// Shines the same way in every direction.
+    Point,
+    /// Shines `angle` degrees wide around `direction`, clockwise from the x axis. The owner's
+    /// rotation turns it further.
+    Cone { direction : f64, angle : f64 }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Light {
+    pub shape : LightShape,
+    pub color : Color,
+    /// Distance in pixels at which the light is gone.
+    pub radius : f64,
+    pub intensity : f64,
+    /// How sharply light drops off, the exponent of `1 - distance / radius`. 1 is linear.
+    pub falloff : f64,
+    /// How much of the intensity flickering can take away, in `[0, 1]`.
+    pub flicker : f64,
+    /// Flicker changes per second.
+    pub flicker_speed : f64,
+    pub casts_shadows : bool
+}
+
+impl Light {
+    pub fn point(radius : f64, color : Color) -> Light {
+        Light {
+            shape : LightShape::Point,
+            color,
+            radius,
+            intensity : 1.0,
+            falloff : 1.0,
+            flicker : 0.0,
+            flicker_speed : 8.0,
+            casts_shadows : true
+        }
+    }
+
+    pub fn cone(radius : f64, color : Color, direction : f64, angle : f64) -> Light {
+        Light { shape : LightShape::Cone { direction, angle }, ..Light::point(radius, color) }
+    }
+
+    pub fn with_intensity(mut self, intensity : f64) -> Light {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_falloff(mut self, falloff : f64) -> Light {
+        self.falloff = falloff;
+        self
+    }
+
+    pub fn with_flicker(mut self, amount : f64, speed : f64) -> Light {
+        self.flicker = amount;
+        self.flicker_speed = speed;
+        self
+    }
+
+    pub fn without_shadows(mut self) -> Light {
+        self.casts_shadows = false;
+        self
+    }
+}
+
+/// A grid of solid tiles in world pixels, the walls that block light.
+pub struct TileOccluders {
+    tile_size : usize,
+    width : usize,
+    height : usize,
+    solid : Vec<bool>
+}
+
+impl TileOccluders {
+    pub fn new(width : usize, height : usize, tile_size : usize) -> TileOccluders {
+        TileOccluders { tile_size : tile_size.max(1), width, height, solid : vec![false; width * height] }
+    }
+
+    /// One string per row, `#` marking solid tiles.
+    pub fn from_rows(rows : &[&str], tile_size : usize) -> TileOccluders {
+        let width = rows.iter().map(|r| r.chars().count()).max().unwrap_or(0);
+        let mut occluders = TileOccluders::new(width, rows.len(), tile_size);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                occluders.set(x, y, c == '#');
+            }
+        }
+        occluders
+    }
+
+    pub fn set(&mut self, x : usize, y : usize, solid : bool) {
+        if x < self.width && y < self.height {
+            self.solid[x + y * self.width] = solid;
+        }
+    }
+
+    /// Whether tile `x`, `y` blocks light. Everything outside the grid is open.
+    pub fn is_solid(&self, x : i64, y : i64) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height && self.solid[x as usize + y as usize * self.width]
+    }
+
+    pub fn get_tile_size(&self) -> usize {
+        self.tile_size
+    }
+
+    /// Whether a solid tile lies between `from` and `to`. The tiles holding either end don't
+    /// count, so lights inside walls still shine out and wall faces still get lit.
+    pub fn blocks(&self, from : (f64, f64), to : (f64, f64)) -> bool {
+        let size = self.tile_size as f64;
+        let (x0, y0) = (from.0 / size, from.1 / size);
+        let (x1, y1) = (to.0 / size, to.1 / size);
+        let (mut tx, mut ty) = (x0.floor() as i64, y0.floor() as i64);
+        let (end_x, end_y) = (x1.floor() as i64, y1.floor() as i64);
+
+        // Walks every tile the segment passes through, crossing whichever tile edge is nearer.
+        let axis = |start : f64, delta : f64, tile : i64| -> (i64, f64, f64) {
+            if delta > 0.0 {
+                (1, (tile as f64 + 1.0 - start) / delta, 1.0 / delta)
+            } else if delta < 0.0 {
+                (-1, (start - tile as f64) / -delta, -1.0 / delta)
+            } else {
+                (0, f64::INFINITY, f64::INFINITY)
+            }
+        };
+        let (step_x, mut next_x, delta_x) = axis(x0, x1 - x0, tx);
+        let (step_y, mut next_y, delta_y) = axis(y0, y1 - y0, ty);
+
+        let steps = (end_x - tx).abs() + (end_y - ty).abs();
+        for _ in 0..steps {
+            if next_x < next_y {
+                tx += step_x;
+                next_x += delta_x;
+            } else {
+                ty += step_y;
+                next_y += delta_y;
+            }
+            if (tx, ty) == (end_x, end_y) { return false }
+            if self.is_solid(tx, ty) { return true }
+        }
+        false
+    }
+}
+
+/// Smooth noise in `[0, 1]` for flickering, different for every `seed`.
+fn flicker_noise(seed : u64, t : f64) -> f64 {
+    let hash = |i : i64| {
+        let mut h = (i as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ seed.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        h ^= h >> 31;
+        h = h.wrapping_mul(0x94D0_49BB_1331_11EB);
+        h ^= h >> 29;
+        (h >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let i = t.floor();
+    let f = t - i;
+    let f = f * f * (3.0 - 2.0 * f);
+    hash(i as i64) * (1.0 - f) + hash(i as i64 + 1) * f
+}
+
+struct SubmittedLight {
+    light : Light,
+    pos : Vec2f,
+    rotation : f64,
+    seed : u64
+}
+
+/// Lights the scene in software. `LightComponent`s submit their lights while rendering; once the
+/// scene is drawn, `apply` adds them up on top of the ambient level into a light map the size of
+/// the `CamBuffer` and multiplies the scene by it.
+pub struct Lighting {
+    pub enabled : bool,
+    /// Light everywhere gets, dark lights left out. White leaves unlit areas as they are.
+    pub ambient : Color,
+    /// Shows the light map instead of the lit scene.
+    pub show_light_map : bool,
+    pub occluders : Option<TileOccluders>,
+    lights : Vec<SubmittedLight>,
+    light_map : Vec<[f32; 3]>,
+    time : f64,
+    last_light_count : usize
+}
+
+impl Lighting {
+    pub fn new() -> Lighting {
+        Lighting {
+            enabled : false,
+            ambient : Color(40, 40, 56, 255),
+            show_light_map : false,
+            occluders : Option::None,
+            lights : Vec::new(),
+            light_map : Vec::new(),
+            time : 0.0,
+            last_light_count : 0
+        }
+    }
+
+    pub fn shared() -> Rc<RefCell<Lighting>> {
+        Rc::new(RefCell::new(Lighting::new()))
+    }
+
+    /// Adds `light` at world position `pos` to the next `apply`.
+    pub fn submit(&mut self, light : Light, pos : Vec2f, rotation : f64, seed : u64) {
+        self.lights.push(SubmittedLight { light, pos, rotation, seed });
+    }
+
+    /// Where flickering is. Set from game time so pausing freezes it.
+    pub fn set_time(&mut self, time : f64) {
+        self.time = time;
+    }
+
+    /// Lights submitted for the last applied frame.
+    pub fn get_light_count(&self) -> usize {
+        self.last_light_count
+    }
+
+    /// Forgets the lights submitted so far without lighting anything.
+    pub fn discard(&mut self) {
+        self.lights.clear();
+    }
+
+    /// Lights `buffer` with everything submitted since the last call, then forgets the lights.
+    pub fn apply(&mut self, buffer : &mut CamBuffer) {
+        self.last_light_count = self.lights.len();
+        if !self.enabled {
+            self.lights.clear();
+            return
+        }
+
+        let (width, height) = buffer.get_dimensions();
+        let (cam_x, cam_y) = buffer.get_offset().get_xy();
+        let ambient = [self.ambient.0 as f32 / 255.0, self.ambient.1 as f32 / 255.0, self.ambient.2 as f32 / 255.0];
+        self.light_map.clear();
+        self.light_map.resize(width * height, ambient);
+
+        for submitted in self.lights.drain(..) {
+            let light = &submitted.light;
+            let flicker = 1.0 - light.flicker * flicker_noise(submitted.seed, self.time * light.flicker_speed);
+            let strength = light.intensity * flicker;
+            if strength <= 0.0 || light.radius <= 0.0 { continue }
+            let color = [light.color.0 as f64 / 255.0 * strength, light.color.1 as f64 / 255.0 * strength, light.color.2 as f64 / 255.0 * strength];
+
+            // The light in screen space, and the screen pixels its radius reaches.
+            let (lx, ly) = submitted.pos.get_xy();
+            let (sx, sy) = (lx - cam_x as f64, ly - cam_y as f64);
+            let start_x = (sx - light.radius).floor().max(0.0) as usize;
+            let start_y = (sy - light.radius).floor().max(0.0) as usize;
+            let end_x = (sx + light.radius).ceil().clamp(0.0, width as f64) as usize;
+            let end_y = (sy + light.radius).ceil().clamp(0.0, height as f64) as usize;
+
+            let cone = match light.shape {
+                LightShape::Point => Option::None,
+                LightShape::Cone { direction, angle } => Some(((direction + submitted.rotation).to_radians(), (angle / 2.0).to_radians()))
+            };
+            let occluders = self.occluders.as_ref().filter(|_| light.casts_shadows);
+
+            for y in start_y..end_y {
+                for x in start_x..end_x {
+                    let (dx, dy) = (x as f64 + 0.5 - sx, y as f64 + 0.5 - sy);
+                    let distance = (dx * dx + dy * dy).sqrt();
+                    if distance >= light.radius { continue }
+
+                    let mut amount = (1.0 - distance / light.radius).powf(light.falloff);
+                    if let Some((direction, half_angle)) = cone {
+                        let off = (dy.atan2(dx) - direction + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI;
+                        if off.abs() > half_angle { continue }
+                        // The outer fifth of the cone fades out instead of cutting off.
+                        amount *= ((half_angle - off.abs()) / (half_angle * 0.2)).min(1.0);
+                    }
+                    if let Some(occluders) = occluders {
+                        if occluders.blocks((lx, ly), (dx + lx, dy + ly)) { continue }
+                    }
+
+                    let cell = &mut self.light_map[x + y * width];
+                    for c in 0..3 {
+                        cell[c] += (color[c] * amount) as f32;
+                    }
+                }
+            }
+        }
+
+        buffer.flatten_indices();
+        for (pixel, light) in buffer.get_buffer_mut().iter_mut().zip(&self.light_map) {
+            let channel = |c : u8, l : f32| (c as f32 * l.min(1.0)).round() as u8;
+            *pixel = if self.show_light_map {
+                Color(channel(255, light[0]), channel(255, light[1]), channel(255, light[2]), 255)
+            } else {
+                Color(channel(pixel.0, light[0]), channel(pixel.1, light[1]), channel(pixel.2, light[2]), pixel.3)
+            };
+        }
+    }
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Lighting::new()
+    }
+}
+
+/// Puts a `Light` at the object's position, turned by its rotation.
+pub struct LightComponent {
+    lighting : Rc<RefCell<Lighting>>,
+    pub light : Light,
+    /// Where the light sits relative to the object.
+    pub offset : Vec2f,
+    seed : u64
+}
+
+impl LightComponent {
+    pub fn new(lighting : Rc<RefCell<Lighting>>, light : Light) -> LightComponent {
+        LightComponent { lighting, light, offset : Vec2f::zero(), seed : NEXT_LIGHT_SEED.fetch_add(1, Ordering::Relaxed) }
+    }
+
+    pub fn with_offset(mut self, x : f64, y : f64) -> LightComponent {
+        self.offset.set_xy(x, y);
+        self
+    }
+}
+
+impl GameComponent for LightComponent {
+    fn render(&mut self, _main_buffer : &mut CamBuffer, frame_info : &FrameInfo, transform : Option<&TransformComponent>) {
+        let mut pos = self.offset;
+        let mut rotation = 0.0;
+        if let Some(t) = transform {
+            pos.add_vec(&t.interpolated_pos(frame_info));
+            rotation = t.interpolated_rotation(frame_info);
+        }
+        self.lighting.borrow_mut().submit(self.light, pos, rotation, self.seed);
+    }
+
+    fn object_debug(&mut self, ui : &Ui) {
+        let light = &mut self.light;
+        let mut values = [light.radius as f32, light.intensity as f32, light.falloff as f32, light.flicker as f32];
+        let mut changed = Slider::new(im_str!("Radius")).range(0.0..=256.0).build(ui, &mut values[0]);
+        changed |= Slider::new(im_str!("Intensity")).range(0.0..=4.0).build(ui, &mut values[1]);
+        changed |= Slider::new(im_str!("Falloff")).range(0.1..=4.0).build(ui, &mut values[2]);
+        changed |= Slider::new(im_str!("Flicker")).range(0.0..=1.0).build(ui, &mut values[3]);
+        if changed {
+            light.radius = values[0] as f64;
+            light.intensity = values[1] as f64;
+            light.falloff = values[2] as f64;
+            light.flicker = values[3] as f64;
+        }
+        ui.checkbox(im_str!("Shadows"), &mut light.casts_shadows);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walls() -> TileOccluders {
+        TileOccluders::from_rows(&[
+            ".....",
+            ".#...",
+            ".....",
+            "...#.",
+            "..#.."
+        ], 4)
+    }
+
+    #[test]
+    fn walls_block_along_straight_lines() {
+        let walls = walls();
+        // Zero delta on one axis, through the wall and beside it.
+        assert!(walls.blocks((2.0, 6.0), (18.0, 6.0)));
+        assert!(walls.blocks((6.0, 2.0), (6.0, 10.0)));
+        assert!(!walls.blocks((2.0, 2.0), (2.0, 18.0)));
+        assert!(!walls.blocks((2.0, 2.0), (18.0, 2.0)));
+        assert!(!walls.blocks((18.0, 6.0), (18.0, 6.0)));
+        // Going the other way, and off the grid where everything is open.
+        assert!(walls.blocks((18.0, 6.0), (2.0, 6.0)));
+        assert!(!walls.blocks((-10.0, -10.0), (40.0, -2.0)));
+    }
+
+    #[test]
+    fn the_end_tiles_do_not_count() {
+        let walls = walls();
+        // A light inside a wall shines out, and the wall it hits is lit.
+        assert!(!walls.blocks((6.0, 6.0), (18.0, 6.0)));
+        assert!(!walls.blocks((2.0, 6.0), (6.0, 6.0)));
+        assert!(!walls.blocks((5.0, 5.0), (7.0, 7.0)));
+        // Leaving one wall doesn't let light through the next.
+        assert!(walls.blocks((14.0, 14.0), (2.0, 6.0)));
+        assert!(!walls.blocks((10.0, 18.0), (10.0, 2.0)));
+    }
+
+    #[test]
+    fn diagonals_cross_corners() {
+        let walls = walls();
+        assert!(walls.blocks((2.0, 2.0), (10.0, 10.0)));
+        assert!(walls.blocks((10.0, 10.0), (2.0, 2.0)));
+        // Tiles 3, 3 and 2, 4 only touch at a corner, which light doesn't slip through.
+        assert!(walls.blocks((10.0, 14.0), (14.0, 18.0)));
+        assert!(walls.blocks((14.0, 18.0), (10.0, 14.0)));
+        // Passing just above that corner only crosses open tiles.
+        assert!(!walls.blocks((9.0, 9.0), (11.5, 13.0)));
+    }
+
+    /// A white 16x16 scene lit by `light` in its middle, with no ambient light.
+    fn lit(light : Light, rotation : f64) -> CamBuffer {
+        let mut buffer = CamBuffer::new(16, 16);
+        for pixel in buffer.get_buffer_mut().iter_mut() {
+            *pixel = Color(255, 255, 255, 255);
+        }
+        let mut lighting = Lighting::new();
+        lighting.enabled = true;
+        lighting.ambient = Color(0, 0, 0, 255);
+        lighting.submit(light, Vec2f::new(8.0, 8.0), rotation, 0);
+        lighting.apply(&mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn cones_wrap_around_half_a_turn() {
+        let white = Color(255, 255, 255, 255);
+        // 150 to 190 degrees, across the seam of atan2.
+        for (light, rotation) in [(Light::cone(8.0, white, 170.0, 40.0), 0.0), (Light::cone(8.0, white, -10.0, 40.0), 180.0),
+            (Light::cone(8.0, white, 530.0, 40.0), 0.0), (Light::cone(8.0, white, 80.0, 40.0), 90.0)] {
+            let buffer = lit(light, rotation);
+            assert!(buffer.get_pixel(2, 8).0 > 0);
+            assert!(buffer.get_pixel(2, 7).0 > 0);
+            assert_eq!(buffer.get_pixel(13, 8), Color(0, 0, 0, 255));
+            assert_eq!(buffer.get_pixel(8, 2), Color(0, 0, 0, 255));
+            assert_eq!(buffer.get_pixel(8, 13), Color(0, 0, 0, 255));
+        }
+        let point = lit(Light::point(8.0, white), 0.0);
+        assert!(point.get_pixel(13, 8).0 > 0 && point.get_pixel(8, 2).0 > 0);
+    }
+
+    #[test]
+    fn flicker_stays_in_range() {
+        for seed in 0..32 {
+            let mut last = flicker_noise(seed, -50.0);
+            for i in -5000..5000 {
+                let noise = flicker_noise(seed, i as f64 * 0.01);
+                assert!((0.0..=1.0).contains(&noise), "{} at {}", noise, i);
+                // Smooth, so small steps in time only move it a little.
+                assert!((noise - last).abs() < 0.05);
+                last = noise;
+            }
+        }
+        assert_ne!(flicker_noise(1, 0.5), flicker_noise(2, 0.5));
+    }
+}
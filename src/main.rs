@@ -1,106 +1,201 @@
 #![deny(clippy::all)]
 
-use std::collections::HashMap;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use game_loop::game_loop;
-use game_loop::winit::event::{Event, VirtualKeyCode, WindowEvent};
+use game_loop::{GameLoop, Time, TimeTrait};
+use game_loop::winit::event::Event;
 use game_loop::winit::event_loop::{ControlFlow, EventLoop};
-use log::error;
-use pixels::{Pixels, SurfaceTexture};
-use winit::dpi::{LogicalSize, PhysicalSize};
+use log::{error, info};
+use winit::dpi::LogicalSize;
 use winit::window::{Window, WindowBuilder};
 
-use comps::draw::ImageBufferRenderComponent;
-use comps::object::*;
-
-use crate::animation::{Animation, AnimationComponent};
-use crate::buffer::{Buffer, BufferAtlas};
-use crate::color::Color;
-use crate::frame::FrameInfo;
-use crate::game::{Game, GameState};
-use crate::image_buffer::{CamBuffer, ImageBuffer, SingleImageBuffer};
-use crate::imgui::Gui;
-use crate::input::InputInfo;
-use crate::math::{Vec2, Vec2i};
-use crate::window::WindowInfo;
-
-mod buffer;
-mod math;
-mod game;
-mod imgui;
-mod input;
-mod window;
-mod animation;
-mod frame;
-mod image_buffer;
-mod color;
-mod comps;
-
-extern crate num_traits;
-
-const WIDTH : u32 = 240;
-const HEIGHT : u32 = 160;
+use project_blueberry::comps::draw::{ImageBufferRenderComponent, RotationMode};
+use project_blueberry::comps::object::*;
+use project_blueberry::comps::transform::TransformComponent;
+
+use project_blueberry::animation::{Animation, AnimationComponent};
+use project_blueberry::frame::FrameInfo;
+use project_blueberry::game::{Game, GameState};
+use project_blueberry::image_buffer::CamBuffer;
+use project_blueberry::imgui::Gui;
+use project_blueberry::input::InputInfo;
+use project_blueberry::tween::{Tween, TweenComponent, LoopMode};
+use project_blueberry::window::WindowInfo;
+use project_blueberry::replay::{InputPlayback, InputRecorder};
+use project_blueberry::atlas::AtlasBuilder;
+use project_blueberry::assets::AssetImage;
+use project_blueberry::capture::CaptureFormat;
+use project_blueberry::config::{GameConfig, CONFIG_PATH};
+use project_blueberry::screen::ScreenRenderer;
+use project_blueberry::particles::{ParticleEmitterComponent, PATH_TO_PARTICLES};
+use project_blueberry::{go, replay, screen};
+use std::path::{Path, PathBuf};
+
+
+const UPDATES_PER_SECOND : u32 = 60;
+
+/// Command line options. `--record <file>` captures the session's input, `--replay <file>` plays
+/// one back, and `--headless` runs the replay without opening a window. `--pack-atlas <dir> <out>`
+/// packs the pngs in `dir` into `<out>.png` and `<out>.ron` and exits. `--capture <file>` records
+/// the screen from the first frame into a GIF, or a png sequence for other paths.
+struct LaunchOptions {
+    record : Option<PathBuf>,
+    replay : Option<PathBuf>,
+    headless : bool,
+    pack_atlas : Option<(PathBuf, PathBuf)>,
+    capture : Option<PathBuf>
+}
+
+impl LaunchOptions {
+    fn from_args() -> LaunchOptions {
+        let mut options = LaunchOptions { record: None, replay: None, headless: false, pack_atlas: None, capture: None };
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => options.record = args.next().map(PathBuf::from),
+                "--replay" => options.replay = args.next().map(PathBuf::from),
+                "--headless" => options.headless = true,
+                "--capture" => options.capture = args.next().map(PathBuf::from),
+                "--pack-atlas" => options.pack_atlas = args.next().zip(args.next()).map(|(dir, out)| (PathBuf::from(dir), PathBuf::from(out))),
+                _ => error!("Unknown argument '{}'", arg)
+            }
+        }
+
+        options
+    }
+}
+
+fn build_scene(gs : &mut GameState, options : &LaunchOptions) {
+    if let Some(path) = &options.capture {
+        gs.capture.start_recording_to(path.clone(), CaptureFormat::for_path(path));
+    }
+
+    let sheet = gs.assets.borrow_mut().load_sheet("dungeon_sheet.png").expect("Unable to load the dungeon sheet");
+    {
+        let mut assets = gs.assets.borrow_mut();
+        let frames = assets.get_mut(sheet);
+        for x in [64, 80, 96, 112] {
+            frames.add(x, 112, 16, 16);
+        }
+    }
+
+    let anim = Animation::from_asset(gs.assets.clone(), sheet, 0.25);
+
+    let gear = gs.assets.borrow_mut().image("gear.png");
+    let ib = AssetImage::new(gs.assets.clone(), gear);
+    let spin = Tween::rotation(360.0, 4.0).looping(LoopMode::Loop(None));
+    let go = go!("test_1"| TransformComponent::from(60, 30), ImageBufferRenderComponent::new(ib).centered().with_rotation_mode(RotationMode::RotSprite).with_cache(gs.sprite_cache.clone()), TweenComponent::new().with(spin));
+
+    gs.add_gameobject(go);
+    gs.add_gameobject(go!("anim_1"| TransformComponent::from(120, 60), AnimationComponent::new(anim)));
+
+    let embers_path = Path::new(PATH_TO_PARTICLES).join("embers.ron");
+    match ParticleEmitterComponent::load(&embers_path) {
+        Ok(embers) => gs.add_gameobject(go!("embers_1"| TransformComponent::from(180, 130), embers)),
+        Err(e) => error!("Unable to load particle preset {}: {}", embers_path.display(), e)
+    }
+}
 
 fn main() {
+    env_logger::init();
+    let options = LaunchOptions::from_args();
+    let config = GameConfig::load_or_default(Path::new(CONFIG_PATH));
+
+    if let Some((dir, out)) = &options.pack_atlas {
+        let mut builder = AtlasBuilder::new();
+        builder.add_dir(dir).unwrap_or_else(|e| panic!("Unable to read sprites from {}: {}", dir.display(), e));
+        let atlas = builder.build().unwrap_or_else(|e| panic!("Unable to pack {}: {}", dir.display(), e));
+        atlas.save(&out.with_extension("png"), &out.with_extension("ron"))
+            .unwrap_or_else(|e| panic!("Unable to save atlas {}: {}", out.display(), e));
+        info!("Packed {} sprites into {}", atlas.len(), out.display());
+        return;
+    }
+
+    let playback = options.replay.as_ref().map(|path| {
+        InputPlayback::load(path).unwrap_or_else(|e| panic!("Unable to load replay {}: {}", path.display(), e))
+    });
+
+    if options.headless {
+        let playback = playback.expect("--headless requires a recording passed with --replay");
+        let mut gs = GameState::new();
+        build_scene(&mut gs, &options);
+        replay::play_headless(&mut gs, playback, &mut CamBuffer::new(config.width as usize, config.height as usize));
+        return;
+    }
+
     let event_loop = EventLoop::new();
 
     let window = {
-        let size = LogicalSize::new((WIDTH * 4) as f64, (HEIGHT * 4) as f64);
+        let scale = config.window_scale.max(1);
         WindowBuilder::new()
-            .with_title("Project Blueberry")
-            .with_inner_size(size)
-            .with_min_inner_size(size)
+            .with_title(&config.title)
+            .with_inner_size(LogicalSize::new(config.width * scale, config.height * scale))
+            .with_min_inner_size(LogicalSize::new(config.width, config.height))
             .build(&event_loop)
             .expect("Unable to Build window.")
     };
+    window.set_fullscreen(config.window_mode.fullscreen(&window));
 
     let mut game = {
-        let mut pixels = {
-            let window_size = window.inner_size();
-            let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-            Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
-        };
-
-        let mut imgui = Gui::new(&window, &pixels);
+        let pixels = screen::create_pixels(&window, &config).expect("Unable to create the pixel surface");
+        let imgui = Gui::new(&window, &pixels);
+        let screen = ScreenRenderer::new(&pixels, &window, config.scaling, config.letterbox);
+        let window_size = window.inner_size();
 
         Game {
             gs: GameState::new(),
             pixels: pixels,
             imgui,
-            main_buffer: CamBuffer::new(WIDTH as usize, HEIGHT as usize),
+            main_buffer: CamBuffer::new(config.width as usize, config.height as usize),
             input_info: InputInfo::new(),
-            window_info : WindowInfo{ width : WIDTH * 4, height : HEIGHT * 4, scale_factor: 1.0},
-            frame_info : FrameInfo { update_delta: 0.0 }
+            window_info : WindowInfo{ width : window_size.width, height : window_size.height, scale_factor: window.scale_factor()},
+            frame_info : FrameInfo::new(1.0 / UPDATES_PER_SECOND as f64),
+            recorder : options.record.clone().map(|path| InputRecorder::to_file(path, 5 * UPDATES_PER_SECOND as usize)),
+            playback,
+            screen,
+            config : config.clone(),
+            applied_config : config
         }
     };
 
-    let buffer_atlas = buffer_atlas!("dungeon_sheet.png" |
-        {64, 112, 16, 16},
-        {80, 112, 16, 16},
-        {96, 112, 16, 16},
-        {112, 112, 16, 16}
-    );
-
-    let anim = Animation::new(buffer_atlas, 0.25);
-
-    let mut ib = SingleImageBuffer::from("gear.png");
-    let mut ib2 = SingleImageBuffer::from("gear.png");
-    let mut go = go!("test_1"| ImageBufferRenderComponent::new(ib));
-
-    game.gs.add_gameobject(go);
-
-
-    game_loop(event_loop, window, game, 60, 0.1,
-              |g| {
-                  g.game.frame_info.update_delta = g.last_frame_time();
-                  g.game.update();
-              }, |g| {
-                g.game.frame_info.update_delta = g.last_frame_time();
-                g.game.render(&g.window)
-              }, |g, event| {
-                g.game.frame_info.update_delta = g.last_frame_time();
+    build_scene(&mut game.gs, &options);
+
+
+    run(event_loop, window, game);
+}
+
+/// Runs `game` the way `game_loop::game_loop` does, except that after a frame that presented
+/// nothing the event loop sleeps until the next update is due instead of polling.
+fn run(event_loop : EventLoop<()>, window : Window, game : Game) -> ! {
+    let mut g = GameLoop::new(game, UPDATES_PER_SECOND, 0.1, window);
+    let mut idle = false;
+    event_loop.run(move |event, _, control_flow| {
+        match event {
+            Event::RedrawRequested(_) => {
+                let running = g.next_frame(|g| {
+                    g.game.frame_info.update_delta = g.fixed_time_step();
+                    g.game.update();
+                }, |g| {
+                    g.game.frame_info.render_delta = g.last_frame_time();
+                    g.game.frame_info.alpha = g.blending_factor();
+                    idle = !g.game.render(&g.window);
+                });
+                if !running { *control_flow = ControlFlow::Exit }
+            }
+            Event::MainEventsCleared => {
+                // Nothing new can show up before the next update.
+                let wait = g.fixed_time_step() - g.accumulated_time() - Time::now().sub(&g.previous_instant());
+                if idle && wait > 0.0 && !g.exit_next_iteration {
+                    *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_secs_f64(wait));
+                } else {
+                    *control_flow = ControlFlow::Poll;
+                    g.window.request_redraw();
+                }
+            }
+            event => {
                 if !g.game.handler(&g.window, event) { g.exit() }
             }
-    );
+        }
+    })
 }
\ No newline at end of file
@@ -5,6 +5,7 @@ use num_traits::Num;
 use crate::comps::object::GameComponent;
 use std::any::Any;
 use imgui::Ui;
+use serde::{Serialize, Deserialize};
 
 pub trait Vec2<T: Num> {
 
@@ -165,6 +166,64 @@ impl Vec2<f64> for Vec2f {
 }
 
 
+/// An axis aligned rectangle in pixels. `x`, `y` is the top left corner.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Rect {
+    pub x : i32,
+    pub y : i32,
+    pub width : i32,
+    pub height : i32
+}
+
+impl Rect {
+    pub fn new(x : i32, y : i32, width : i32, height : i32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    /// One past the rightmost column.
+    pub fn right(&self) -> i32 {
+        self.x + self.width
+    }
+
+    /// One past the bottom row.
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn contains(&self, x : i32, y : i32) -> bool {
+        x >= self.x && y >= self.y && x < self.right() && y < self.bottom()
+    }
+
+    /// The overlap of both rectangles, empty if they don't touch.
+    pub fn intersect(&self, other : &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        Rect::new(x, y, (right - x).max(0), (bottom - y).max(0))
+    }
+
+    /// The smallest rectangle holding both. Empty rectangles are left out.
+    pub fn union(&self, other : &Rect) -> Rect {
+        if self.is_empty() { return *other }
+        if other.is_empty() { return *self }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(x, y, self.right().max(other.right()) - x, self.bottom().max(other.bottom()) - y)
+    }
+
+    /// Whether the rectangles overlap or share an edge.
+    pub fn touches(&self, other : &Rect) -> bool {
+        !self.is_empty() && !other.is_empty()
+            && self.x <= other.right() && other.x <= self.right() && self.y <= other.bottom() && other.y <= self.bottom()
+    }
+}
+
+
 
 pub struct Transform {
     x : i32,
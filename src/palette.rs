@@ -0,0 +1,451 @@
+use crate::color::Color;
+use crate::comps::object::GameComponent;
+use crate::comps::transform::TransformComponent;
+use crate::frame::FrameInfo;
+use crate::image_buffer::{next_image_id, CamBuffer, DrawParams, ImageBuffer, SingleImageBuffer};
+use crate::math::Vec2;
+use imgui::Ui;
+use std::any::Any;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Rotates the colors of `len` entries starting at `start` by `rate` entries a second, the
+/// classic way to animate water, lava and conveyor belts without touching any pixels. A negative
+/// rate cycles the other way.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PaletteCycle {
+    pub start : u8,
+    pub len : u8,
+    pub rate : f64
+}
+
+impl PaletteCycle {
+    pub fn new(start : u8, len : u8, rate : f64) -> PaletteCycle {
+        PaletteCycle { start, len, rate }
+    }
+
+    /// The entry whose base color `index` shows at `time`.
+    fn source(&self, index : u8, time : f64) -> u8 {
+        let (start, len) = (self.start as i64, self.len as i64);
+        let index = index as i64;
+        if len == 0 || index < start || index >= start + len {
+            return index as u8
+        }
+        (start + (index - start - self.phase(time)).rem_euclid(len)) as u8
+    }
+
+    /// How many steps the cycle is turned at `time`, within one turn.
+    fn phase(&self, time : f64) -> i64 {
+        let steps = (time * self.rate).floor() as i64;
+        if self.len == 0 { 0 } else { steps.rem_euclid(self.len as i64) }
+    }
+}
+
+/// Up to 256 colors for indexed images, plus the cycles animating them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    colors : Vec<Color>,
+    cycles : Vec<PaletteCycle>,
+    time : f64
+}
+
+impl Palette {
+    /// Keeps the first 256 of `colors`.
+    pub fn new(mut colors : Vec<Color>) -> Palette {
+        colors.truncate(256);
+        Palette { colors, cycles : Vec::new(), time : 0.0 }
+    }
+
+    /// Reads a palette image, one entry per pixel, row by row. Fully transparent pixels are
+    /// kept as entries so indices line up with the image.
+    pub fn load(path : &Path) -> io::Result<Palette> {
+        let image = SingleImageBuffer::load(path)?;
+        if image.get_buffer().len() > 256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "A palette holds at most 256 colors"))
+        }
+        Ok(Palette::new(image.get_buffer().clone()))
+    }
+
+    pub fn with_cycle(mut self, cycle : PaletteCycle) -> Palette {
+        self.cycles.push(cycle);
+        self
+    }
+
+    /// The base color of `index`, ignoring cycles. Out of range entries are transparent.
+    pub fn get(&self, index : u8) -> Color {
+        self.colors.get(index as usize).copied().unwrap_or(Color::CLEAR)
+    }
+
+    pub fn set(&mut self, index : u8, color : Color) {
+        let index = index as usize;
+        if index >= self.colors.len() {
+            self.colors.resize(index + 1, Color::CLEAR);
+        }
+        self.colors[index] = color;
+    }
+
+    /// The color `index` shows right now, with every cycle applied.
+    pub fn color_at(&self, index : u8) -> Color {
+        let source = self.cycles.iter().fold(index, |i, cycle| cycle.source(i, self.time));
+        self.get(source)
+    }
+
+    /// Every entry as it looks right now, padded to 256 so any index can be looked up.
+    pub fn lookup(&self) -> Vec<Color> {
+        (0..=255).map(|i| self.color_at(i)).collect()
+    }
+
+    /// The index of the entry closest to `color`, skipping `skip`.
+    pub fn nearest(&self, color : Color, skip : Option<u8>) -> u8 {
+        let distance = |c : &Color| {
+            let d = |a : u8, b : u8| (a as i32 - b as i32).pow(2);
+            d(c.0, color.0) + d(c.1, color.1) + d(c.2, color.2)
+        };
+        self.colors.iter().enumerate()
+            .filter(|(i, _)| Some(*i as u8) != skip)
+            .min_by_key(|(_, c)| distance(c))
+            .map_or(0, |(i, _)| i as u8)
+    }
+
+    /// Where cycles are. Set from game time every frame so pausing freezes them. Gives whether
+    /// any cycle turned, which is when `lookup` changes.
+    pub fn set_time(&mut self, time : f64) -> bool {
+        let turned = self.cycles.iter().any(|cycle| cycle.phase(self.time) != cycle.phase(time));
+        self.time = time;
+        turned
+    }
+
+    pub fn get_cycles_mut(&mut self) -> &mut Vec<PaletteCycle> {
+        &mut self.cycles
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+}
+
+/// Swaps indices while drawing, so one sprite can show up in several color variants.
+#[derive(Copy, Clone)]
+pub struct PaletteMap {
+    table : [u8; 256]
+}
+
+impl PaletteMap {
+    pub fn identity() -> PaletteMap {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = i as u8;
+        }
+        PaletteMap { table }
+    }
+
+    /// Draws entry `from` as entry `to`.
+    pub fn with(mut self, from : u8, to : u8) -> PaletteMap {
+        self.table[from as usize] = to;
+        self
+    }
+
+    /// Draws the `len` entries from `from` as the `len` entries from `to`, e.g. one 16 color bank
+    /// as another.
+    pub fn with_range(mut self, from : u8, to : u8, len : u8) -> PaletteMap {
+        for i in 0..len {
+            self.table[from.wrapping_add(i) as usize] = to.wrapping_add(i);
+        }
+        self
+    }
+
+    pub fn get(&self, index : u8) -> u8 {
+        self.table[index as usize]
+    }
+}
+
+impl Default for PaletteMap {
+    fn default() -> Self {
+        PaletteMap::identity()
+    }
+}
+
+/// A sprite stored as palette indices. Drawn into a `CamBuffer` with a palette it stays indices
+/// until the buffer is dumped, so palette cycling and swaps of the screen palette reach it.
+pub struct IndexedImage {
+    id : u64,
+    indices : Vec<u8>,
+    width : usize,
+    height : usize,
+    /// Pixels with this index are never drawn.
+    pub transparent : Option<u8>
+}
+
+impl IndexedImage {
+    pub fn new(indices : Vec<u8>, width : usize, height : usize) -> IndexedImage {
+        assert_eq!(indices.len(), width * height, "An indexed image needs one index per pixel");
+        IndexedImage { id : next_image_id(), indices, width, height, transparent : Some(0) }
+    }
+
+    /// Matches every pixel of `image` to the nearest entry of `palette`. Fully transparent
+    /// pixels become index 0, which no opaque pixel is matched to.
+    pub fn from_image(image : &SingleImageBuffer, palette : &Palette) -> IndexedImage {
+        let indices = image.get_buffer().iter()
+            .map(|&c| if c.3 == 0 { 0 } else { palette.nearest(c, Some(0)) })
+            .collect();
+        IndexedImage::new(indices, image.get_width(), image.get_height())
+    }
+
+    pub fn load(path : &Path, palette : &Palette) -> io::Result<IndexedImage> {
+        Ok(IndexedImage::from_image(&SingleImageBuffer::load(path)?, palette))
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn get_index(&self, x : usize, y : usize) -> u8 {
+        self.indices[x + y * self.width]
+    }
+
+    /// Writes remapped indices into `target`, which shows them through its palette.
+    pub fn draw(&self, target : &mut CamBuffer, params : &DrawParams, map : &PaletteMap) {
+        params.for_each_covered(self.get_dimensions(), target.get_clip(), |x, y, u, v| {
+            let index = self.get_index(u, v);
+            if Some(index) != self.transparent {
+                target.set_index(map.get(index), x, y);
+            }
+        });
+    }
+
+    /// Draws into any RGBA image, looking colors up in `palette` right away. Cycles are applied
+    /// as they are at this moment.
+    pub fn draw_rgba(&self, target : &mut dyn ImageBuffer, params : &DrawParams, palette : &Palette, map : &PaletteMap) {
+        let lookup = palette.lookup();
+        params.for_each_covered(self.get_dimensions(), target.get_clip(), |x, y, u, v| {
+            let index = self.get_index(u, v);
+            if Some(index) != self.transparent {
+                target.blend_pixel_with(lookup[map.get(index) as usize], x, y, params.blend);
+            }
+        });
+    }
+
+    /// Converts to RGBA through `palette`.
+    pub fn to_image(&self, palette : &Palette) -> SingleImageBuffer {
+        let lookup = palette.lookup();
+        let buffer = self.indices.iter()
+            .map(|&i| if Some(i) == self.transparent { Color::CLEAR } else { lookup[i as usize] })
+            .collect();
+        SingleImageBuffer::from_colors(buffer, self.width, self.height)
+    }
+}
+
+/// Renders an `IndexedImage` with its own palette swap. Into a `CamBuffer` without a palette it
+/// falls back to `fallback`, so indexed sprites still show up in an RGBA scene.
+pub struct IndexedRenderComponent {
+    image : Rc<IndexedImage>,
+    pub map : PaletteMap,
+    pub fallback : Option<Palette>,
+    pub centered : bool
+}
+
+impl IndexedRenderComponent {
+    pub fn new(image : Rc<IndexedImage>) -> IndexedRenderComponent {
+        IndexedRenderComponent { image, map : PaletteMap::identity(), fallback : Option::None, centered : false }
+    }
+
+    pub fn with_map(mut self, map : PaletteMap) -> IndexedRenderComponent {
+        self.map = map;
+        self
+    }
+
+    pub fn with_fallback(mut self, palette : Palette) -> IndexedRenderComponent {
+        self.fallback = Some(palette);
+        self
+    }
+
+    pub fn centered(mut self) -> IndexedRenderComponent {
+        self.centered = true;
+        self
+    }
+}
+
+impl GameComponent for IndexedRenderComponent {
+    fn render(&mut self, main_buffer : &mut CamBuffer, frame_info : &FrameInfo, transform : Option<&TransformComponent>) {
+        let (cam_x, cam_y) = main_buffer.get_offset().get_xy();
+        let mut params = DrawParams::at(-cam_x as f64, -cam_y as f64);
+
+        if let Some(t) = transform {
+            params.pos.add_vec(&t.interpolated_pos(frame_info));
+            params.scale = t.interpolated_scale(frame_info);
+            params.rotation = t.interpolated_rotation(frame_info);
+        }
+        if self.centered {
+            let (width, height) = self.image.get_dimensions();
+            params.pivot.set_xy(width as f64 / 2.0, height as f64 / 2.0);
+        }
+
+        match (main_buffer.get_palette().is_some(), &self.fallback) {
+            (true, _) => self.image.draw(main_buffer, &params, &self.map),
+            (false, Some(palette)) => self.image.draw_rgba(main_buffer, &params, palette, &self.map),
+            (false, None) => ()
+        }
+    }
+
+    fn object_debug(&mut self, ui : &Ui) {
+        let (width, height) = self.image.get_dimensions();
+        ui.text(format!("Indexed {}x{}", width, height));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::BlendMode;
+    use crate::math::Rect;
+
+    /// Entry `i` is `Color(i, 0, 0, 255)`.
+    fn ramp(len : u8) -> Palette {
+        Palette::new((0..len).map(|i| Color(i, 0, 0, 255)).collect())
+    }
+
+    fn sources(cycle : &PaletteCycle, time : f64) -> Vec<u8> {
+        (2..8).map(|i| cycle.source(i, time)).collect()
+    }
+
+    #[test]
+    fn positive_rates_move_colors_up_the_range() {
+        let cycle = PaletteCycle::new(3, 4, 2.0);
+        assert_eq!(sources(&cycle, 0.0), vec![2, 3, 4, 5, 6, 7]);
+        assert_eq!(sources(&cycle, 0.49), vec![2, 3, 4, 5, 6, 7]);
+        // One step in, entry 4 shows what entry 3 held and the last color wraps to the front.
+        assert_eq!(sources(&cycle, 0.5), vec![2, 6, 3, 4, 5, 7]);
+        assert_eq!(sources(&cycle, 1.0), vec![2, 5, 6, 3, 4, 7]);
+        // Whole turns come back around, however far along.
+        assert_eq!(sources(&cycle, 2.0), sources(&cycle, 0.0));
+        assert_eq!(sources(&cycle, 1000.5), sources(&cycle, 0.5));
+    }
+
+    #[test]
+    fn negative_rates_and_times_wrap_the_other_way() {
+        let cycle = PaletteCycle::new(3, 4, -2.0);
+        assert_eq!(sources(&cycle, 0.5), vec![2, 4, 5, 6, 3, 7]);
+        assert_eq!(sources(&cycle, 0.5), sources(&PaletteCycle::new(3, 4, 2.0), -0.5));
+        assert_eq!(sources(&cycle, 1000.5), sources(&cycle, 0.5));
+        assert_eq!(sources(&PaletteCycle::new(3, 0, 2.0), 0.5), vec![2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn cycles_reach_colors_and_lookups() {
+        let mut palette = ramp(8).with_cycle(PaletteCycle::new(4, 4, 1.0));
+        palette.set_time(1.0);
+        assert_eq!(palette.color_at(4), Color(7, 0, 0, 255));
+        assert_eq!(palette.color_at(5), Color(4, 0, 0, 255));
+        assert_eq!(palette.get(4), Color(4, 0, 0, 255));
+
+        let lookup = palette.lookup();
+        assert_eq!(lookup.len(), 256);
+        assert_eq!(&lookup[2..8], &[2, 3, 7, 4, 5, 6].map(|i| Color(i, 0, 0, 255)));
+        assert_eq!(lookup[200], Color::CLEAR);
+
+        assert_eq!(palette.nearest(Color(0, 0, 0, 255), Some(0)), 1);
+        assert_eq!(palette.nearest(Color(2, 2, 2, 255), Option::None), 2);
+        palette.set(10, Color(1, 2, 3, 255));
+        assert_eq!((palette.len(), palette.get(9)), (11, Color::CLEAR));
+        assert_eq!(palette.nearest(Color(2, 2, 2, 255), Option::None), 10);
+    }
+
+    #[test]
+    fn lookups_only_change_when_a_cycle_turns() {
+        let mut palette = ramp(8).with_cycle(PaletteCycle::new(4, 4, 2.0));
+        assert!(!palette.set_time(0.4));
+        assert!(palette.set_time(0.5));
+        assert!(!palette.set_time(0.9));
+        // Two whole turns later the colors are back where they were.
+        assert!(!palette.set_time(4.5));
+
+        let mut buffer = CamBuffer::new(1, 1);
+        buffer.set_palette(Some(palette));
+        let key = buffer.palette_key();
+        buffer.set_palette_time(4.9);
+        assert_eq!(buffer.palette_key(), key);
+        buffer.set_palette_time(5.0);
+        assert_ne!(buffer.palette_key(), key);
+        assert_eq!(CamBuffer::new(1, 1).palette_key(), Option::None);
+    }
+
+    #[test]
+    fn palette_maps_swap_ranges_and_wrap_around() {
+        let map = PaletteMap::identity().with_range(16, 32, 4).with(1, 2);
+        let mapped : Vec<u8> = [0, 1, 15, 16, 19, 20].iter().map(|&i| map.get(i)).collect();
+        assert_eq!(mapped, vec![0, 2, 15, 32, 35, 20]);
+
+        let map = PaletteMap::identity().with_range(254, 10, 4);
+        let mapped : Vec<u8> = [253, 254, 255, 0, 1, 2].iter().map(|&i| map.get(i)).collect();
+        assert_eq!(mapped, vec![253, 10, 11, 12, 13, 2]);
+
+        let map = PaletteMap::identity().with_range(0, 254, 3);
+        assert_eq!((map.get(0), map.get(1), map.get(2), map.get(3)), (254, 255, 0, 3));
+    }
+
+    #[test]
+    fn indexed_pixels_follow_the_palette_until_flattened() {
+        let mut buffer = CamBuffer::new(3, 1);
+        buffer.set_palette(Some(ramp(8).with_cycle(PaletteCycle::new(4, 4, 1.0))));
+        buffer.set_index(4, 0, 0);
+        buffer.set_index(5, 1, 0);
+        buffer.set_pixel(Color(9, 9, 9, 255), 2, 0);
+
+        let dumped = |buffer : &CamBuffer| {
+            let mut bytes = vec![0; 12];
+            buffer.dump(&mut bytes);
+            bytes.chunks_exact(4).map(|c| c[0]).collect::<Vec<u8>>()
+        };
+        assert_eq!(dumped(&buffer), vec![4, 5, 9]);
+        buffer.get_palette_mut().unwrap().set_time(1.0);
+        assert_eq!(dumped(&buffer), vec![7, 4, 9]);
+        assert_eq!(buffer.get_pixel(0, 0), Color(7, 0, 0, 255));
+
+        // Drawing RGBA over an indexed pixel bakes it first.
+        buffer.blend_pixel_with(Color(0, 0, 0, 0), 1, 0, BlendMode::Alpha);
+        assert_eq!(buffer.get_index(1, 0), Option::None);
+        buffer.get_palette_mut().unwrap().set_time(2.0);
+        assert_eq!(dumped(&buffer), vec![6, 4, 9]);
+
+        buffer.flatten_indices();
+        buffer.get_palette_mut().unwrap().set_time(3.0);
+        assert_eq!(dumped(&buffer), vec![6, 4, 9]);
+        assert!(buffer.get_palette().is_some());
+
+        buffer.set_index(4, 0, 0);
+        buffer.set_palette(Option::None);
+        assert_eq!(dumped(&buffer), vec![5, 4, 9]);
+        buffer.set_index(1, 0, 0);
+        assert_eq!(buffer.get_index(0, 0), Option::None);
+    }
+
+    #[test]
+    fn blits_resolve_indices_of_the_copied_area() {
+        let mut buffer = CamBuffer::new(4, 1);
+        buffer.set_palette(Some(ramp(8)));
+        for x in 0..4 {
+            buffer.set_index(x as u8 + 2, x, 0);
+        }
+        buffer.set_offset(1, 0);
+
+        let mut target = SingleImageBuffer::new(4, 1);
+        buffer.blit_rect(Rect::new(-1, 0, 3, 1), &mut target, 0, 0, BlendMode::Replace);
+        let reds : Vec<u8> = target.get_buffer().iter().map(|c| c.0).collect();
+        // Source x -1 is outside, so the destination starts one pixel further, plus the offset.
+        assert_eq!(reds, vec![0, 0, 2, 3]);
+        assert_eq!(target.get_pixel(0, 0), Color::CLEAR);
+    }
+}
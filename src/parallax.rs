@@ -0,0 +1,296 @@
+use crate::color::BlendMode;
+use crate::comps::object::GameComponent;
+use crate::comps::transform::TransformComponent;
+use crate::frame::FrameInfo;
+use crate::image_buffer::{CamBuffer, ImageBuffer, ImageSource};
+use crate::input::InputInfo;
+use crate::math::{Vec2, Vec2f};
+use crate::raster::TileRenderer;
+use imgui::{im_str, Slider, Ui};
+use std::any::Any;
+use std::iter::StepBy;
+use std::ops::Range;
+
+/// Which screen edge a layer lines up with vertically, before its offset and scrolling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerticalAnchor {
+    Top,
+    Center,
+    Bottom
+}
+
+impl VerticalAnchor {
+    /// Where the top of an `image` tall layer goes on a `screen` tall buffer.
+    pub fn top(&self, screen : usize, image : usize) -> f64 {
+        match self {
+            VerticalAnchor::Top => 0.0,
+            VerticalAnchor::Center => (screen as f64 - image as f64) / 2.0,
+            VerticalAnchor::Bottom => screen as f64 - image as f64
+        }
+    }
+}
+
+/// Where copies of a `size` long image go along a `screen` long axis when the first one is at
+/// `start`. Without `repeat` that's just `start`, otherwise every tile that shows.
+pub fn tile_starts(start : i32, size : usize, screen : usize, repeat : bool) -> StepBy<Range<i32>> {
+    if !repeat || size == 0 {
+        return (start..start.saturating_add(1)).step_by(1)
+    }
+    let size = size.min(i32::MAX as usize) as i32;
+    let first = start.rem_euclid(size);
+    let first = if first > 0 { first - size } else { first };
+    (first..screen.min(i32::MAX as usize) as i32).step_by(size as usize)
+}
+
+/// A background layer that scrolls with the camera by `scroll_factor`: 0 stays put on screen
+/// like a sky, 1 moves with the world, in between reads as depth. It can also drift on its own
+/// and tile in either direction.
+///
+/// Components of one object render in the order they were added, so a multi-layer background is
+/// one object with its layers added back to front, put on a low `GameObject::layer` to stay
+/// behind everything else. The transform, if any, moves every layer.
+pub struct ParallaxLayerComponent<T : ImageSource> {
+    image : T,
+    scroll_factor : Vec2f,
+    velocity : Vec2f,
+    scroll : Vec2f,
+    /// `scroll` before the last update, not wrapped, so drawing in between never jumps back a tile.
+    prev_scroll : Vec2f,
+    offset : Vec2f,
+    repeat_x : bool,
+    repeat_y : bool,
+    anchor : VerticalAnchor,
+    blend_mode : BlendMode
+}
+
+impl<T : ImageSource> ParallaxLayerComponent<T> {
+    /// A layer that moves with the world and tiles horizontally.
+    pub fn new(image : T) -> ParallaxLayerComponent<T> {
+        ParallaxLayerComponent {
+            image,
+            scroll_factor : Vec2f::new(1.0, 1.0),
+            velocity : Vec2f::zero(),
+            scroll : Vec2f::zero(),
+            prev_scroll : Vec2f::zero(),
+            offset : Vec2f::zero(),
+            repeat_x : true,
+            repeat_y : false,
+            anchor : VerticalAnchor::Top,
+            blend_mode : BlendMode::Alpha
+        }
+    }
+
+    /// How far the layer moves per pixel the camera moves, on each axis.
+    pub fn with_scroll_factor(mut self, x : f64, y : f64) -> ParallaxLayerComponent<T> {
+        self.scroll_factor.set_xy(x, y);
+        self
+    }
+
+    /// Drift in pixels per second of game time, like clouds or fog.
+    pub fn with_velocity(mut self, x : f64, y : f64) -> ParallaxLayerComponent<T> {
+        self.velocity.set_xy(x, y);
+        self
+    }
+
+    pub fn with_repeat(mut self, repeat_x : bool, repeat_y : bool) -> ParallaxLayerComponent<T> {
+        self.repeat_x = repeat_x;
+        self.repeat_y = repeat_y;
+        self
+    }
+
+    pub fn with_anchor(mut self, anchor : VerticalAnchor) -> ParallaxLayerComponent<T> {
+        self.anchor = anchor;
+        self
+    }
+
+    /// Shifts the layer from where its anchor and transform put it.
+    pub fn with_offset(mut self, x : f64, y : f64) -> ParallaxLayerComponent<T> {
+        self.offset.set_xy(x, y);
+        self
+    }
+
+    pub fn with_blend_mode(mut self, blend_mode : BlendMode) -> ParallaxLayerComponent<T> {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    pub fn set_velocity(&mut self, x : f64, y : f64) {
+        self.velocity.set_xy(x, y);
+    }
+
+    /// How far the layer has drifted so far.
+    pub fn get_scroll(&self) -> &Vec2f {
+        &self.scroll
+    }
+
+    /// Where the first copy of the layer goes on a `screen` sized buffer looking from `camera`,
+    /// having drifted by `scroll`.
+    fn origin(&self, screen : (usize, usize), camera : (i32, i32), pos : Vec2f, scroll : Vec2f) -> (f64, f64) {
+        let (width, height) = self.image.get_size();
+        let (fx, fy) = self.scroll_factor.get_xy();
+        let (sx, sy) = scroll.get_xy();
+        let (x, y) = pos.get_xy();
+        let left = x - camera.0 as f64 * fx + sx;
+        let top = self.anchor.top(screen.1, height) + y - camera.1 as f64 * fy + sy;
+        // Only the phase matters along tiled axes, and it keeps far off cameras in range.
+        let wrap = |v : f64, size : usize, repeat : bool| if repeat && size > 0 { v.rem_euclid(size as f64) } else { v };
+        (wrap(left, width, self.repeat_x), wrap(top, height, self.repeat_y))
+    }
+}
+
+impl<T : 'static + ImageSource> GameComponent for ParallaxLayerComponent<T> {
+    fn update(&mut self, frame_info : &FrameInfo, _input_info : &InputInfo, _transform : Option<&mut TransformComponent>) {
+        let (width, height) = self.image.get_size();
+        let (vx, vy) = self.velocity.get_xy();
+        let (dx, dy) = (vx * frame_info.update_delta, vy * frame_info.update_delta);
+        let (mut x, mut y) = self.scroll.get_xy();
+        x += dx;
+        y += dy;
+        if self.repeat_x && width > 0 { x = x.rem_euclid(width as f64) }
+        if self.repeat_y && height > 0 { y = y.rem_euclid(height as f64) }
+        self.scroll.set_xy(x, y);
+        self.prev_scroll.set_xy(x - dx, y - dy);
+    }
+
+    fn freeze(&mut self) {
+        self.prev_scroll = self.scroll;
+    }
+
+    fn render(&mut self, main_buffer : &mut CamBuffer, frame_info : &FrameInfo, transform : Option<&TransformComponent>) {
+        let mut pos = self.offset;
+        if let Some(t) = transform {
+            pos.add_vec(&t.interpolated_pos(frame_info));
+        }
+        let (x, y) = self.scroll.get_xy();
+        let (prev_x, prev_y) = self.prev_scroll.get_xy();
+        let scroll = Vec2f::new(frame_info.interpolate(prev_x, x), frame_info.interpolate(prev_y, y));
+        let screen = main_buffer.get_dimensions();
+        let (left, top) = self.origin(screen, main_buffer.get_offset().get_xy(), pos, scroll);
+        let (width, height) = self.image.get_size();
+        let columns = tile_starts(left.floor() as i32, width, screen.0, self.repeat_x);
+        let rows = tile_starts(top.floor() as i32, height, screen.1, self.repeat_y);
+
+        // A layer usually covers the whole screen, so its tiles are drawn a strip per thread.
+        let mode = self.blend_mode;
+        self.image.with_view(|view| {
+            let mut renderer = TileRenderer::new();
+            for y in rows {
+                for x in columns.clone() {
+                    renderer.blit(view, x, y, mode);
+                }
+            }
+            renderer.render(main_buffer);
+        });
+    }
+
+    fn object_debug(&mut self, ui : &Ui) {
+        let (fx, fy) = self.scroll_factor.get_xy();
+        let (vx, vy) = self.velocity.get_xy();
+        let mut values = [fx as f32, fy as f32, vx as f32, vy as f32];
+        let mut changed = Slider::new(im_str!("Factor X")).range(0.0..=2.0).build(ui, &mut values[0]);
+        changed |= Slider::new(im_str!("Factor Y")).range(0.0..=2.0).build(ui, &mut values[1]);
+        changed |= Slider::new(im_str!("Velocity X")).range(-256.0..=256.0).build(ui, &mut values[2]);
+        changed |= Slider::new(im_str!("Velocity Y")).range(-256.0..=256.0).build(ui, &mut values[3]);
+        if changed {
+            self.scroll_factor.set_xy(values[0] as f64, values[1] as f64);
+            self.velocity.set_xy(values[2] as f64, values[3] as f64);
+        }
+
+        ui.checkbox(im_str!("Repeat X"), &mut self.repeat_x);
+        ui.same_line(0.0);
+        ui.checkbox(im_str!("Repeat Y"), &mut self.repeat_y);
+        ui.radio_button(im_str!("Top"), &mut self.anchor, VerticalAnchor::Top);
+        ui.same_line(0.0);
+        ui.radio_button(im_str!("Center"), &mut self.anchor, VerticalAnchor::Center);
+        ui.same_line(0.0);
+        ui.radio_button(im_str!("Bottom"), &mut self.anchor, VerticalAnchor::Bottom);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::comps::object::GameObject;
+    use crate::image_buffer::SingleImageBuffer;
+
+    #[test]
+    fn tiles_cover_the_screen_from_any_start() {
+        assert_eq!(tile_starts(0, 10, 25, true).collect::<Vec<_>>(), vec![0, 10, 20]);
+        assert_eq!(tile_starts(7, 10, 25, true).collect::<Vec<_>>(), vec![-3, 7, 17]);
+        assert_eq!(tile_starts(-13, 10, 25, true).collect::<Vec<_>>(), vec![-3, 7, 17]);
+        assert_eq!(tile_starts(-13, 10, 25, false).collect::<Vec<_>>(), vec![-13]);
+        assert_eq!(tile_starts(4, 0, 25, true).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn anchors_line_up_with_screen_edges() {
+        assert_eq!(VerticalAnchor::Top.top(100, 40), 0.0);
+        assert_eq!(VerticalAnchor::Center.top(100, 40), 30.0);
+        assert_eq!(VerticalAnchor::Bottom.top(100, 40), 60.0);
+    }
+
+    /// The columns of the bottom row that are red after drawing `layer` at `alpha`.
+    fn drawn(layer : &mut ParallaxLayerComponent<SingleImageBuffer>, frame_info : &FrameInfo, alpha : f64) -> Vec<usize> {
+        let mut buffer = CamBuffer::new(10, 6);
+        buffer.set_offset(4, 100);
+        layer.render(&mut buffer, &FrameInfo { alpha, ..*frame_info }, Option::None);
+        (0..10).filter(|&x| buffer.get_pixel(x, 4) == Color(255, 0, 0, 255)).collect()
+    }
+
+    #[test]
+    fn layer_scrolls_by_its_factor_and_drifts() {
+        let mut image = SingleImageBuffer::new(4, 2);
+        image.set_pixel(Color(255, 0, 0, 255), 0, 0);
+        let mut layer = ParallaxLayerComponent::new(image).with_scroll_factor(0.5, 0.0).with_velocity(8.0, 0.0)
+            .with_anchor(VerticalAnchor::Bottom);
+        let mut frame_info = FrameInfo::new(0.25);
+        let mut buffer = CamBuffer::new(10, 6);
+        buffer.set_offset(4, 100);
+
+        // Camera at 4 with half the speed puts the tiles 2 to the left, the bottom row is 4.
+        layer.render(&mut buffer, &frame_info, Option::None);
+        let red : Vec<usize> = (0..10).filter(|&x| buffer.get_pixel(x, 4) == Color(255, 0, 0, 255)).collect();
+        assert_eq!(red, vec![2, 6]);
+        assert!((0..10).all(|x| buffer.get_pixel(x, 0) == Color::CLEAR));
+
+        // A quarter second at 8 pixels per second drifts it 2 to the right, drawn in steps between
+        // updates.
+        layer.update(&frame_info, &InputInfo::new(), Option::None);
+        frame_info.advance();
+        assert_eq!(drawn(&mut layer, &frame_info, 0.0), vec![2, 6]);
+        assert_eq!(drawn(&mut layer, &frame_info, 0.5), vec![3, 7]);
+        assert_eq!(drawn(&mut layer, &frame_info, 1.0), vec![0, 4, 8]);
+
+        // Drifting past the end of a tile wraps the scroll, but in between it keeps going forward.
+        layer.update(&frame_info, &InputInfo::new(), Option::None);
+        assert_eq!(layer.get_scroll().get_xy(), (0.0, 0.0));
+        assert_eq!(drawn(&mut layer, &frame_info, 0.0), vec![0, 4, 8]);
+        assert_eq!(drawn(&mut layer, &frame_info, 0.5), vec![1, 5, 9]);
+        assert_eq!(drawn(&mut layer, &frame_info, 1.0), vec![2, 6]);
+    }
+
+    #[test]
+    fn paused_layers_stop_drifting() {
+        let mut image = SingleImageBuffer::new(4, 2);
+        image.set_pixel(Color(255, 0, 0, 255), 0, 0);
+        let mut object = GameObject::new("sky");
+        object.add_comp(ParallaxLayerComponent::new(image).with_scroll_factor(0.5, 0.0).with_velocity(8.0, 0.0)
+            .with_anchor(VerticalAnchor::Bottom));
+        let frame_info = FrameInfo::new(0.25);
+        object.update(&frame_info, &InputInfo::new());
+        object.freeze();
+
+        for alpha in [0.0, 0.5, 1.0] {
+            let mut buffer = CamBuffer::new(10, 6);
+            buffer.set_offset(4, 100);
+            object.render(&mut buffer, &FrameInfo { alpha, ..frame_info });
+            let red : Vec<usize> = (0..10).filter(|&x| buffer.get_pixel(x, 4) == Color(255, 0, 0, 255)).collect();
+            assert_eq!(red, vec![0, 4, 8], "alpha {}", alpha);
+        }
+    }
+}
@@ -0,0 +1,709 @@
+#[allow(deprecated)]
+use crate::buffer::BufferAtlas;
+use crate::color::{Color, BlendMode};
+use crate::comps::object::GameComponent;
+use crate::comps::transform::TransformComponent;
+use crate::frame::FrameInfo;
+use crate::image_buffer::{AtlasImageBuffer, CamBuffer, DrawParams, ImageBuffer, ImageView};
+use crate::imgui::color_edit;
+use crate::input::InputInfo;
+use crate::math::{Vec2, Vec2f};
+use imgui::{im_str, Slider, Ui};
+use log::{error, info};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const PATH_TO_PARTICLES : &str = "./assets/particles/";
+
+static NEXT_EMITTER_SEED : AtomicU64 = AtomicU64::new(1);
+
+/// Where live particles are kept.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParticleSpace {
+    /// Particles stay where they were spawned, leaving a trail behind a moving emitter.
+    World,
+    /// Particles move along with the emitter.
+    Local
+}
+
+/// Which atlas frame a sprite particle shows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameMode {
+    Fixed(usize),
+    /// Plays through every frame once over the particle's life.
+    OverLife,
+    /// A random frame, picked at spawn.
+    Random
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParticleLook {
+    /// A filled square `size` pixels wide.
+    Pixel,
+    /// A frame of the emitter's atlas, scaled by `size` and tinted by the color.
+    Sprite(FrameMode)
+}
+
+/// Everything that decides how an emitter behaves, saved to and loaded from RON files. Missing
+/// fields take their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParticlePreset {
+    /// The pool size. No more particles are alive at once.
+    pub max_particles : usize,
+    /// Particles per second while emitting.
+    pub rate : f64,
+    /// Particles spawned at once when emission starts, and again every `burst_interval`.
+    pub burst : u32,
+    /// Seconds between bursts, 0 for one burst only.
+    pub burst_interval : f64,
+    /// Seconds the emitter runs before stopping on its own, 0 to run until stopped.
+    pub duration : f64,
+    /// Shortest and longest life in seconds.
+    pub lifetime : (f64, f64),
+    /// Slowest and fastest starting speed in pixels per second.
+    pub speed : (f64, f64),
+    /// Degrees clockwise from the x axis, turned by the owner's rotation.
+    pub direction : f64,
+    /// Degrees either side of `direction` a particle may start moving in.
+    pub spread : f64,
+    /// Pixels from the emitter particles start at.
+    pub spawn_radius : f64,
+    /// Acceleration in pixels per second squared.
+    pub gravity : (f64, f64),
+    /// The share of velocity lost per second.
+    pub drag : f64,
+    /// Colors spaced evenly over a particle's life. Alpha fades particles out.
+    pub colors : Vec<Color>,
+    /// Size at birth and at death.
+    pub size : (f64, f64),
+    pub space : ParticleSpace,
+    pub look : ParticleLook,
+    pub blend : BlendMode
+}
+
+impl Default for ParticlePreset {
+    fn default() -> Self {
+        ParticlePreset {
+            max_particles : 256,
+            rate : 20.0,
+            burst : 0,
+            burst_interval : 0.0,
+            duration : 0.0,
+            lifetime : (0.5, 1.0),
+            speed : (20.0, 40.0),
+            direction : -90.0,
+            spread : 30.0,
+            spawn_radius : 0.0,
+            gravity : (0.0, 0.0),
+            drag : 0.0,
+            colors : vec![Color(255, 255, 255, 255), Color(255, 255, 255, 0)],
+            size : (1.0, 1.0),
+            space : ParticleSpace::World,
+            look : ParticleLook::Pixel,
+            blend : BlendMode::Alpha
+        }
+    }
+}
+
+impl ParticlePreset {
+    /// Reads a preset from `PATH_TO_PARTICLES`.
+    pub fn from(filename : &str) -> io::Result<ParticlePreset> {
+        let mut path = PathBuf::from(PATH_TO_PARTICLES);
+        path.push(filename);
+        ParticlePreset::load(&path)
+    }
+
+    pub fn load(path : &Path) -> io::Result<ParticlePreset> {
+        let contents = fs::read_to_string(path)?;
+        ron::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)
+    }
+
+    /// The color `t` of the way through a life.
+    pub fn color_at(&self, t : f64) -> Color {
+        match self.colors.len() {
+            0 => Color(255, 255, 255, 255),
+            1 => self.colors[0],
+            len => {
+                let position = t.clamp(0.0, 1.0) * (len - 1) as f64;
+                let i = (position.floor() as usize).min(len - 2);
+                Color::lerp(self.colors[i], self.colors[i + 1], position - i as f64)
+            }
+        }
+    }
+
+    pub fn size_at(&self, t : f64) -> f64 {
+        self.size.0 + (self.size.1 - self.size.0) * t.clamp(0.0, 1.0)
+    }
+}
+
+/// xorshift64*, enough randomness for effects and the same every run for replays.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, range : (f64, f64)) -> f64 {
+        range.0 + (range.1 - range.0) * self.next()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Particle {
+    pos : (f64, f64),
+    /// Where the particle was before the last update, drawn from with the frame's alpha.
+    prev_pos : (f64, f64),
+    velocity : (f64, f64),
+    age : f64,
+    lifetime : f64,
+    frame : usize
+}
+
+/// Spawns and draws particles described by a `ParticlePreset`. Particles live in a pool sized
+/// `max_particles`: the live ones are kept at the front and dead ones are swapped behind them, so
+/// nothing is allocated once the pool is full.
+pub struct ParticleEmitterComponent {
+    preset : ParticlePreset,
+    /// Where the preset came from and is saved back to.
+    path : Option<PathBuf>,
+    sprites : Option<AtlasImageBuffer>,
+    pool : Vec<Particle>,
+    alive : usize,
+    pub offset : Vec2f,
+    emitting : bool,
+    elapsed : f64,
+    spawn_debt : f64,
+    next_burst : f64,
+    /// The emitter's world position and rotation at the last update.
+    origin : Vec2f,
+    prev_origin : Vec2f,
+    rotation : f64,
+    rng : Rng
+}
+
+impl ParticleEmitterComponent {
+    pub fn new(preset : ParticlePreset) -> ParticleEmitterComponent {
+        let seed = NEXT_EMITTER_SEED.fetch_add(1, Ordering::Relaxed);
+        ParticleEmitterComponent {
+            pool : Vec::with_capacity(preset.max_particles),
+            preset,
+            path : Option::None,
+            sprites : Option::None,
+            alive : 0,
+            offset : Vec2f::zero(),
+            emitting : true,
+            elapsed : 0.0,
+            spawn_debt : 0.0,
+            next_burst : 0.0,
+            origin : Vec2f::zero(),
+            prev_origin : Vec2f::zero(),
+            rotation : 0.0,
+            rng : Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+        }
+    }
+
+    /// Uses the preset at `path`, which the overlay's save button writes back to.
+    pub fn load(path : &Path) -> io::Result<ParticleEmitterComponent> {
+        let mut emitter = ParticleEmitterComponent::new(ParticlePreset::load(path)?);
+        emitter.path = Some(path.to_path_buf());
+        Ok(emitter)
+    }
+
+    /// Draws `Sprite` particles with the frames of `atlas`.
+    #[deprecated(note = "use `with_sheet` with an `AtlasImageBuffer` instead")]
+    #[allow(deprecated)]
+    pub fn with_atlas(self, atlas : &BufferAtlas) -> ParticleEmitterComponent {
+        self.with_sheet(atlas.into())
+    }
+
+    pub fn with_sheet(mut self, sheet : AtlasImageBuffer) -> ParticleEmitterComponent {
+        self.sprites = Some(sheet);
+        self
+    }
+
+    pub fn with_offset(mut self, x : f64, y : f64) -> ParticleEmitterComponent {
+        self.offset.set_xy(x, y);
+        self
+    }
+
+    /// Starts out not emitting, for emitters that only fire on `burst` or `play`.
+    pub fn stopped(mut self) -> ParticleEmitterComponent {
+        self.emitting = false;
+        self
+    }
+
+    pub fn get_preset(&self) -> &ParticlePreset {
+        &self.preset
+    }
+
+    pub fn set_preset(&mut self, preset : ParticlePreset) {
+        self.preset = preset;
+        self.resize_pool();
+    }
+
+    /// Restarts emission from the beginning, burst included.
+    pub fn play(&mut self) {
+        self.emitting = true;
+        self.elapsed = 0.0;
+        self.spawn_debt = 0.0;
+        self.next_burst = 0.0;
+    }
+
+    /// Stops spawning. Live particles play out.
+    pub fn stop(&mut self) {
+        self.emitting = false;
+    }
+
+    /// Removes every live particle.
+    pub fn clear(&mut self) {
+        self.alive = 0;
+    }
+
+    pub fn is_emitting(&self) -> bool {
+        self.emitting
+    }
+
+    /// Whether the emitter has stopped and its last particle died.
+    pub fn is_finished(&self) -> bool {
+        !self.emitting && self.alive == 0
+    }
+
+    pub fn get_alive(&self) -> usize {
+        self.alive
+    }
+
+    /// Spawns `count` particles right away, as many as the pool has room for.
+    pub fn burst(&mut self, count : u32) {
+        for _ in 0..count {
+            self.spawn();
+        }
+    }
+
+    fn resize_pool(&mut self) {
+        let max = self.preset.max_particles;
+        self.alive = self.alive.min(max);
+        self.pool.truncate(max);
+        self.pool.reserve(max - self.pool.len());
+    }
+
+    fn spawn(&mut self) {
+        if self.alive >= self.preset.max_particles { return }
+
+        let preset = &self.preset;
+        let rng = &mut self.rng;
+        let angle = (preset.direction + self.rotation + rng.range((-preset.spread, preset.spread))).to_radians();
+        let speed = rng.range(preset.speed);
+        let (offset_angle, offset) = (rng.next() * std::f64::consts::TAU, rng.next().sqrt() * preset.spawn_radius);
+
+        let (mut x, mut y) = (offset_angle.cos() * offset, offset_angle.sin() * offset);
+        if preset.space == ParticleSpace::World {
+            let (origin_x, origin_y) = self.origin.get_xy();
+            x += origin_x;
+            y += origin_y;
+        }
+        let frame_count = self.sprites.as_ref().map_or(1, |s| s.len().max(1));
+        let frame = match preset.look {
+            ParticleLook::Sprite(FrameMode::Fixed(frame)) => frame.min(frame_count - 1),
+            ParticleLook::Sprite(FrameMode::Random) => ((rng.next() * frame_count as f64) as usize).min(frame_count - 1),
+            _ => 0
+        };
+
+        let particle = Particle {
+            pos : (x, y),
+            prev_pos : (x, y),
+            velocity : (angle.cos() * speed, angle.sin() * speed),
+            age : 0.0,
+            lifetime : rng.range(preset.lifetime).max(f64::EPSILON),
+            frame
+        };
+        if self.alive < self.pool.len() {
+            self.pool[self.alive] = particle;
+        } else {
+            self.pool.push(particle);
+        }
+        self.alive += 1;
+    }
+
+    fn simulate(&mut self, delta : f64) {
+        let preset = &self.preset;
+        let drag = (1.0 - preset.drag * delta).max(0.0);
+        let mut i = 0;
+        while i < self.alive {
+            let particle = &mut self.pool[i];
+            particle.age += delta;
+            if particle.age >= particle.lifetime {
+                // The last live particle takes the dead one's slot.
+                self.alive -= 1;
+                self.pool.swap(i, self.alive);
+                continue
+            }
+            particle.prev_pos = particle.pos;
+            let (vx, vy) = particle.velocity;
+            particle.velocity = ((vx + preset.gravity.0 * delta) * drag, (vy + preset.gravity.1 * delta) * drag);
+            particle.pos.0 += particle.velocity.0 * delta;
+            particle.pos.1 += particle.velocity.1 * delta;
+            i += 1;
+        }
+    }
+
+    fn emit(&mut self, delta : f64) {
+        if !self.emitting { return }
+
+        if self.preset.burst > 0 && self.elapsed >= self.next_burst {
+            self.burst(self.preset.burst);
+            self.next_burst = if self.preset.burst_interval > 0.0 { self.next_burst + self.preset.burst_interval } else { f64::INFINITY };
+        }
+
+        // Time past the end of the duration doesn't spawn anything.
+        let delta = if self.preset.duration > 0.0 { delta.min(self.preset.duration - self.elapsed) } else { delta };
+        self.spawn_debt += self.preset.rate * delta;
+        while self.spawn_debt >= 1.0 {
+            self.spawn_debt -= 1.0;
+            self.spawn();
+        }
+
+        self.elapsed += delta;
+        if self.preset.duration > 0.0 && self.elapsed >= self.preset.duration {
+            self.emitting = false;
+        }
+    }
+
+    fn draw_pixel(&self, main_buffer : &mut CamBuffer, x : f64, y : f64, size : f64, color : Color) {
+        let size = size.round().max(1.0) as i32;
+        let (left, top) = ((x - size as f64 / 2.0).round() as i32, (y - size as f64 / 2.0).round() as i32);
+        for py in top..top + size {
+            for px in left..left + size {
+                if px >= 0 && py >= 0 && (px as usize) < main_buffer.get_width() && (py as usize) < main_buffer.get_height() {
+                    main_buffer.blend_pixel_with(color, px as usize, py as usize, self.preset.blend);
+                }
+            }
+        }
+    }
+
+    fn draw_sprite(&self, main_buffer : &mut CamBuffer, view : ImageView, x : f64, y : f64, size : f64, color : Color) {
+        let (width, height) = view.get_dimensions();
+        let mut params = DrawParams::at(x, y);
+        params.pivot.set_xy(width as f64 / 2.0, height as f64 / 2.0);
+        params.scale.set_xy(size, size);
+
+        let tint = |c : u8, t : u8| ((c as u16 * t as u16 + 127) / 255) as u8;
+        params.for_each_covered(view.get_dimensions(), main_buffer.get_clip(), |i, j, u, v| {
+            let Color(r, g, b, a) = view.get_pixel(u, v);
+            main_buffer.blend_pixel_with(Color(tint(r, color.0), tint(g, color.1), tint(b, color.2), tint(a, color.3)), i, j, self.preset.blend);
+        });
+    }
+
+    fn save_preset(&self) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(PATH_TO_PARTICLES).join("preset.ron")
+        };
+        match self.preset.save(&path) {
+            Ok(_) => info!("Saved particle preset {}", path.display()),
+            Err(e) => error!("Unable to save particle preset {}: {}", path.display(), e)
+        }
+    }
+}
+
+impl GameComponent for ParticleEmitterComponent {
+    fn update(&mut self, frame_info : &FrameInfo, _input_info : &InputInfo, transform : Option<&mut TransformComponent>) {
+        let mut origin = self.offset;
+        self.rotation = 0.0;
+        if let Some(t) = transform {
+            let (x, y) = t.pos.get_xy();
+            origin.add_vec(&Vec2f::new(x as f64, y as f64));
+            self.rotation = t.rotation;
+        }
+        self.prev_origin = self.origin;
+        self.origin = origin;
+
+        let delta = frame_info.update_delta;
+        self.simulate(delta);
+        self.emit(delta);
+    }
+
+    fn freeze(&mut self) {
+        self.prev_origin = self.origin;
+        for particle in self.pool[..self.alive].iter_mut() {
+            particle.prev_pos = particle.pos;
+        }
+    }
+
+    fn render(&mut self, main_buffer : &mut CamBuffer, frame_info : &FrameInfo, _transform : Option<&TransformComponent>) {
+        let (cam_x, cam_y) = main_buffer.get_offset().get_xy();
+        let (mut base_x, mut base_y) = (-cam_x as f64, -cam_y as f64);
+        if self.preset.space == ParticleSpace::Local {
+            let (origin_x, origin_y) = self.origin.get_xy();
+            let (prev_x, prev_y) = self.prev_origin.get_xy();
+            base_x += frame_info.interpolate(prev_x, origin_x);
+            base_y += frame_info.interpolate(prev_y, origin_y);
+        }
+
+        let sprites = match self.preset.look {
+            ParticleLook::Sprite(_) => self.sprites.as_ref().filter(|s| !s.is_empty()),
+            ParticleLook::Pixel => Option::None
+        };
+        for particle in &self.pool[..self.alive] {
+            let t = particle.age / particle.lifetime;
+            let color = self.preset.color_at(t);
+            let (x, y) = (base_x + frame_info.interpolate(particle.prev_pos.0, particle.pos.0),
+                base_y + frame_info.interpolate(particle.prev_pos.1, particle.pos.1));
+            match sprites {
+                Some(sprites) => {
+                    let frame = match self.preset.look {
+                        ParticleLook::Sprite(FrameMode::OverLife) => ((t * sprites.len() as f64) as usize).min(sprites.len() - 1),
+                        _ => particle.frame
+                    };
+                    self.draw_sprite(main_buffer, sprites.get(frame), x, y, self.preset.size_at(t), color)
+                }
+                None => self.draw_pixel(main_buffer, x, y, self.preset.size_at(t), color)
+            }
+        }
+    }
+
+    fn object_debug(&mut self, ui : &Ui) {
+        ui.text(format!("Alive: {} / {}", self.alive, self.preset.max_particles));
+        if ui.small_button(im_str!("Burst")) {
+            self.burst(self.preset.burst.max(10));
+        }
+        ui.same_line(0.0);
+        if self.emitting {
+            if ui.small_button(im_str!("Stop")) { self.stop() }
+        } else if ui.small_button(im_str!("Play")) {
+            self.play()
+        }
+
+        let preset = &mut self.preset;
+        let mut max = preset.max_particles as i32;
+        if Slider::new(im_str!("Max Particles")).range(1..=4096).build(ui, &mut max) {
+            preset.max_particles = max as usize;
+        }
+        let mut burst = preset.burst as i32;
+        if Slider::new(im_str!("Burst Count")).range(0..=512).build(ui, &mut burst) {
+            preset.burst = burst as u32;
+        }
+
+        let edit = |label : &imgui::ImStr, value : &mut f64, min : f32, max : f32| {
+            let mut v = *value as f32;
+            if Slider::new(label).range(min..=max).build(ui, &mut v) {
+                *value = v as f64;
+            }
+        };
+        edit(im_str!("Rate"), &mut preset.rate, 0.0, 500.0);
+        edit(im_str!("Burst Interval"), &mut preset.burst_interval, 0.0, 10.0);
+        edit(im_str!("Duration"), &mut preset.duration, 0.0, 10.0);
+        edit(im_str!("Min Life"), &mut preset.lifetime.0, 0.0, 10.0);
+        edit(im_str!("Max Life"), &mut preset.lifetime.1, 0.0, 10.0);
+        edit(im_str!("Min Speed"), &mut preset.speed.0, 0.0, 300.0);
+        edit(im_str!("Max Speed"), &mut preset.speed.1, 0.0, 300.0);
+        edit(im_str!("Direction"), &mut preset.direction, -180.0, 180.0);
+        edit(im_str!("Spread"), &mut preset.spread, 0.0, 180.0);
+        edit(im_str!("Spawn Radius"), &mut preset.spawn_radius, 0.0, 64.0);
+        edit(im_str!("Gravity X"), &mut preset.gravity.0, -300.0, 300.0);
+        edit(im_str!("Gravity Y"), &mut preset.gravity.1, -300.0, 300.0);
+        edit(im_str!("Drag"), &mut preset.drag, 0.0, 10.0);
+        edit(im_str!("Start Size"), &mut preset.size.0, 0.0, 8.0);
+        edit(im_str!("End Size"), &mut preset.size.1, 0.0, 8.0);
+
+        for (i, color) in preset.colors.iter_mut().enumerate() {
+            color_edit(ui, &im_str!("Color {}", i), color, true);
+        }
+        if ui.small_button(im_str!("Add Color")) {
+            let last = preset.colors.last().copied().unwrap_or(Color(255, 255, 255, 255));
+            preset.colors.push(last);
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Remove Color")) && preset.colors.len() > 1 {
+            preset.colors.pop();
+        }
+
+        ui.radio_button(im_str!("World"), &mut preset.space, ParticleSpace::World);
+        ui.same_line(0.0);
+        ui.radio_button(im_str!("Local"), &mut preset.space, ParticleSpace::Local);
+        ui.radio_button(im_str!("Alpha"), &mut preset.blend, BlendMode::Alpha);
+        ui.same_line(0.0);
+        ui.radio_button(im_str!("Add"), &mut preset.blend, BlendMode::Add);
+
+        self.resize_pool();
+        if ui.small_button(im_str!("Save Preset")) {
+            self.save_preset();
+        }
+        if let Some(path) = &self.path {
+            ui.same_line(0.0);
+            if ui.small_button(im_str!("Reload Preset")) {
+                match ParticlePreset::load(path) {
+                    Ok(preset) => self.set_preset(preset),
+                    Err(e) => error!("Unable to load particle preset {}: {}", path.display(), e)
+                }
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embers() -> ParticlePreset {
+        ParticlePreset::from("embers.ron").unwrap()
+    }
+
+    /// Particles that live forever and fly right at 10 pixels per second.
+    fn steady() -> ParticlePreset {
+        ParticlePreset {
+            rate : 0.0,
+            lifetime : (100.0, 100.0),
+            speed : (10.0, 10.0),
+            direction : 0.0,
+            spread : 0.0,
+            ..ParticlePreset::default()
+        }
+    }
+
+    #[test]
+    fn colors_and_sizes_run_over_a_life() {
+        let preset = embers();
+        assert_eq!(preset.color_at(0.0), Color(255, 240, 160, 255));
+        assert_eq!(preset.color_at(0.5), Color(255, 140, 40, 230));
+        assert_eq!(preset.color_at(1.0), Color(180, 40, 20, 0));
+        assert_eq!(preset.color_at(0.25), Color(255, 190, 100, 243));
+        assert_eq!(preset.color_at(-1.0), preset.color_at(0.0));
+        assert_eq!(preset.color_at(3.0), preset.color_at(1.0));
+        assert_eq!(preset.size_at(0.5), 1.5);
+        assert_eq!((preset.size_at(-1.0), preset.size_at(2.0)), (2.0, 1.0));
+
+        let single = ParticlePreset { colors : vec![Color(1, 2, 3, 4)], ..ParticlePreset::default() };
+        assert_eq!(single.color_at(0.7), Color(1, 2, 3, 4));
+        let none = ParticlePreset { colors : Vec::new(), ..ParticlePreset::default() };
+        assert_eq!(none.color_at(0.7), Color(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn dead_particles_are_recycled_in_place() {
+        let mut emitter = ParticleEmitterComponent::new(ParticlePreset { max_particles : 4, ..steady() }).stopped();
+        emitter.burst(3);
+        emitter.pool[0].lifetime = 0.5;
+        emitter.pool[2].velocity = (0.0, 5.0);
+        let pool = emitter.pool.as_ptr();
+
+        emitter.simulate(1.0);
+        assert_eq!(emitter.get_alive(), 2);
+        // The last live particle moved into the dead one's slot.
+        assert_eq!(emitter.pool[0].velocity, (0.0, 5.0));
+        assert_eq!(emitter.pool[0].pos, (0.0, 5.0));
+
+        emitter.burst(10);
+        assert_eq!(emitter.get_alive(), 4);
+        assert_eq!(emitter.pool.len(), 4);
+        assert_eq!(emitter.pool.as_ptr(), pool);
+        assert!(emitter.pool.iter().all(|p| p.age < p.lifetime));
+
+        emitter.simulate(200.0);
+        assert!(emitter.is_finished());
+    }
+
+    #[test]
+    fn emission_stops_after_its_duration() {
+        let mut emitter = ParticleEmitterComponent::new(ParticlePreset { rate : 8.0, duration : 1.0, ..steady() });
+        for _ in 0..6 {
+            emitter.emit(0.25);
+        }
+        assert_eq!(emitter.get_alive(), 8);
+        assert!(!emitter.is_emitting());
+
+        // A long step past the end only spawns for the time that was left.
+        emitter.clear();
+        emitter.play();
+        emitter.emit(0.75);
+        assert_eq!(emitter.get_alive(), 6);
+        emitter.emit(0.75);
+        assert_eq!(emitter.get_alive(), 8);
+
+        let mut bursts = ParticleEmitterComponent::new(ParticlePreset { burst : 3, burst_interval : 0.5, duration : 1.2, ..steady() });
+        while bursts.is_emitting() {
+            bursts.emit(0.25);
+        }
+        assert_eq!(bursts.get_alive(), 9);
+    }
+
+    #[test]
+    fn presets_round_trip_through_ron() {
+        let preset = embers();
+        assert_eq!(preset.max_particles, 128);
+        assert_eq!(preset.gravity, (0.0, -12.0));
+        assert_eq!(preset.blend, BlendMode::Add);
+        assert_eq!(preset.colors.len(), 3);
+
+        let text = ron::ser::to_string_pretty(&preset, ron::ser::PrettyConfig::default()).unwrap();
+        assert_eq!(ron::from_str::<ParticlePreset>(&text).unwrap(), preset);
+        let path = std::env::temp_dir().join(format!("blueberry_particles_{}", std::process::id())).join("embers.ron");
+        preset.save(&path).unwrap();
+        let loaded = ParticlePreset::load(&path);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(loaded.unwrap(), preset);
+
+        let partial = ron::from_str::<ParticlePreset>("(rate: 5.0, look: Sprite(Fixed(2)))").unwrap();
+        assert_eq!(partial, ParticlePreset { rate : 5.0, look : ParticleLook::Sprite(FrameMode::Fixed(2)), ..ParticlePreset::default() });
+    }
+
+    #[test]
+    fn rendering_interpolates_between_updates() {
+        let preset = ParticlePreset { blend : BlendMode::Replace, colors : vec![Color(255, 0, 0, 255)], ..steady() };
+        let mut emitter = ParticleEmitterComponent::new(preset).stopped().with_offset(2.0, 4.0);
+        let mut frame_info = FrameInfo::new(0.0);
+        emitter.update(&frame_info, &InputInfo::new(), Option::None);
+        emitter.burst(1);
+        frame_info.update_delta = 0.4;
+        emitter.update(&frame_info, &InputInfo::new(), Option::None);
+
+        let drawn = |emitter : &mut ParticleEmitterComponent, alpha : f64| {
+            let mut buffer = CamBuffer::new(16, 8);
+            emitter.render(&mut buffer, &FrameInfo { alpha, ..frame_info }, Option::None);
+            (0..16).filter(|&x| buffer.get_pixel(x, 4) == Color(255, 0, 0, 255)).collect::<Vec<usize>>()
+        };
+        assert_eq!(drawn(&mut emitter, 0.0), vec![2]);
+        assert_eq!(drawn(&mut emitter, 0.5), vec![4]);
+        assert_eq!(drawn(&mut emitter, 1.0), vec![6]);
+    }
+
+    #[test]
+    fn frozen_particles_stand_still() {
+        let preset = ParticlePreset { blend : BlendMode::Replace, colors : vec![Color(255, 0, 0, 255)], ..steady() };
+        let mut emitter = ParticleEmitterComponent::new(preset).stopped().with_offset(2.0, 4.0);
+        let mut frame_info = FrameInfo::new(0.0);
+        emitter.update(&frame_info, &InputInfo::new(), Option::None);
+        emitter.burst(1);
+        frame_info.update_delta = 0.4;
+        emitter.update(&frame_info, &InputInfo::new(), Option::None);
+        emitter.freeze();
+
+        for alpha in [0.0, 0.5, 1.0] {
+            let mut buffer = CamBuffer::new(16, 8);
+            emitter.render(&mut buffer, &FrameInfo { alpha, ..frame_info }, Option::None);
+            let drawn = (0..16).filter(|&x| buffer.get_pixel(x, 4) == Color(255, 0, 0, 255)).collect::<Vec<usize>>();
+            assert_eq!(drawn, vec![6], "alpha {}", alpha);
+        }
+    }
+}
@@ -0,0 +1,698 @@
+use crate::color::Color;
+use crate::image_buffer::{CamBuffer, ImageBuffer, SingleImageBuffer};
+use crate::imgui::color_edit;
+use crate::palette::Palette;
+use imgui::{im_str, Slider, Ui};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// 4x4 Bayer matrix, the thresholds ordered dithering compares against.
+const BAYER : [u8; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
+
+/// The Bayer threshold for a pixel, in `(0, 1)`.
+fn threshold(x : usize, y : usize) -> f64 {
+    (BAYER[(x & 3) + (y & 3) * 4] as f64 + 0.5) / 16.0
+}
+
+/// Moves the color channels of `c` towards `target` by `t`, keeping its alpha.
+fn mix_rgb(c : Color, target : Color, t : f64) -> Color {
+    let Color(r, g, b, _) = Color::lerp(c, target, t);
+    Color(r, g, b, c.3)
+}
+
+fn slider(ui : &Ui, label : &imgui::ImStr, value : &mut f64, max : f32) {
+    let mut v = *value as f32;
+    if Slider::new(label).range(0.0..=max).build(ui, &mut v) {
+        *value = v as f64;
+    }
+}
+
+/// One full screen pass run over the finished frame, before it is captured and shown.
+pub trait PostProcess {
+    fn name(&self) -> &str;
+    /// Rewrites `pixels`, `width` by `height` row by row. `delta` is how long the last frame took.
+    fn apply(&mut self, pixels : &mut [Color], width : usize, height : usize, delta : f64);
+    fn debug(&mut self, _ui : &Ui) {}
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+pub struct PostStage {
+    pub enabled : bool,
+    pub effect : Box<dyn PostProcess>
+}
+
+/// The passes run over the `CamBuffer` every rendered frame, in order. Disabled passes are skipped
+/// entirely, timers included.
+pub struct PostChain {
+    stages : Vec<PostStage>
+}
+
+impl PostChain {
+    pub fn new() -> PostChain {
+        PostChain { stages : Vec::new() }
+    }
+
+    /// Every built-in pass, all disabled: grading, flash and fade, then color reduction, then the
+    /// CRT look on top.
+    pub fn standard() -> PostChain {
+        PostChain::new()
+            .with(ColorGrade::new(Lut::identity(16)), false)
+            .with(Flash::new(), false)
+            .with(Fade::new(), false)
+            .with(Dither::new(8), false)
+            .with(Quantize::new(Palette::new(PICO8.to_vec())), false)
+            .with(Crt::new(), false)
+    }
+
+    pub fn with<T : PostProcess + 'static>(mut self, effect : T, enabled : bool) -> PostChain {
+        self.push(effect, enabled);
+        self
+    }
+
+    pub fn push<T : PostProcess + 'static>(&mut self, effect : T, enabled : bool) {
+        self.stages.push(PostStage { enabled, effect : Box::new(effect) });
+    }
+
+    /// The first pass of type `T`.
+    pub fn get_mut<T : PostProcess + 'static>(&mut self) -> Option<&mut T> {
+        self.stages.iter_mut().find_map(|s| s.effect.as_any_mut().downcast_mut::<T>())
+    }
+
+    pub fn set_enabled<T : PostProcess + 'static>(&mut self, enabled : bool) {
+        for stage in self.stages.iter_mut() {
+            if stage.effect.as_any_mut().is::<T>() {
+                stage.enabled = enabled;
+                return
+            }
+        }
+    }
+
+    pub fn get_stages_mut(&mut self) -> &mut Vec<PostStage> {
+        &mut self.stages
+    }
+
+    /// Flashes the screen `color`, fading back over `duration` seconds.
+    pub fn flash(&mut self, color : Color, duration : f64) {
+        self.set_enabled::<Flash>(true);
+        if let Some(flash) = self.get_mut::<Flash>() {
+            flash.trigger(color, duration);
+        }
+    }
+
+    /// Fades the screen out to `color` over `duration` seconds.
+    pub fn fade_out(&mut self, color : Color, duration : f64) {
+        self.set_enabled::<Fade>(true);
+        if let Some(fade) = self.get_mut::<Fade>() {
+            fade.fade_out(color, duration);
+        }
+    }
+
+    /// Fades back in from whatever the screen is faded to.
+    pub fn fade_in(&mut self, duration : f64) {
+        self.set_enabled::<Fade>(true);
+        if let Some(fade) = self.get_mut::<Fade>() {
+            fade.fade_in(duration);
+        }
+    }
+
+    /// Whether any stage is enabled, so `apply` would change the frame.
+    pub fn is_active(&self) -> bool {
+        self.stages.iter().any(|s| s.enabled)
+    }
+
+    pub fn apply(&mut self, buffer : &mut CamBuffer, delta : f64) {
+        if !self.is_active() { return }
+
+        // Passes work on plain colors, so palette indices are resolved first.
+        buffer.flatten_indices();
+        let (width, height) = buffer.get_dimensions();
+        let pixels = buffer.get_buffer_mut();
+        for stage in self.stages.iter_mut().filter(|s| s.enabled) {
+            stage.effect.apply(pixels, width, height, delta);
+        }
+    }
+}
+
+impl Default for PostChain {
+    fn default() -> Self {
+        PostChain::standard()
+    }
+}
+
+/// A 3D color lookup table, `size` entries per channel.
+pub struct Lut {
+    size : usize,
+    table : Vec<Color>
+}
+
+impl Lut {
+    /// Builds a table by running `f` on every entry's color.
+    pub fn from_fn(size : usize, f : impl Fn(Color) -> Color) -> Lut {
+        let size = size.max(2);
+        let step = |i : usize| (i * 255 / (size - 1)) as u8;
+        let mut table = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    table.push(f(Color(step(r), step(g), step(b), 255)));
+                }
+            }
+        }
+        Lut { size, table }
+    }
+
+    /// Leaves every color as it is. Graded screenshots of it are the starting point for new LUTs.
+    pub fn identity(size : usize) -> Lut {
+        Lut::from_fn(size, |c| c)
+    }
+
+    /// Reads a strip of `size` squares side by side, `size * size` wide and `size` high. Red runs
+    /// along x within a square, green down y, and blue goes up one per square.
+    pub fn load(path : &Path) -> io::Result<Lut> {
+        Lut::from_image(&SingleImageBuffer::load(path)?)
+    }
+
+    pub fn from_image(image : &SingleImageBuffer) -> io::Result<Lut> {
+        let (width, size) = image.get_dimensions();
+        if size < 2 || width != size * size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("A {}x{} image is not a LUT strip", width, size)))
+        }
+        let mut table = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    table.push(image.get_pixel(b * size + r, g));
+                }
+            }
+        }
+        Ok(Lut { size, table })
+    }
+
+    /// The strip `load` reads.
+    pub fn to_image(&self) -> SingleImageBuffer {
+        let size = self.size;
+        let mut image = SingleImageBuffer::new(size * size, size);
+        for (i, &color) in self.table.iter().enumerate() {
+            let (r, g, b) = (i % size, i / size % size, i / (size * size));
+            image.set_pixel(color, b * size + r, g);
+        }
+        image
+    }
+
+    fn entry(&self, r : usize, g : usize, b : usize) -> Color {
+        self.table[r + g * self.size + b * self.size * self.size]
+    }
+
+    /// Trilinearly interpolates `color` through the table. Alpha is left alone.
+    pub fn sample(&self, color : Color) -> Color {
+        let scale = (self.size - 1) as f64 / 255.0;
+        let split = |c : u8| {
+            let v = c as f64 * scale;
+            let i = (v.floor() as usize).min(self.size - 2);
+            (i, v - i as f64)
+        };
+        let ((r, fr), (g, fg), (b, fb)) = (split(color.0), split(color.1), split(color.2));
+
+        let along_r = |g, b| Color::lerp(self.entry(r, g, b), self.entry(r + 1, g, b), fr);
+        let along_g = |b| Color::lerp(along_r(g, b), along_r(g + 1, b), fg);
+        let Color(r, g, b, _) = Color::lerp(along_g(b), along_g(b + 1), fb);
+        Color(r, g, b, color.3)
+    }
+}
+
+/// Color grading through a `Lut`, blended in by `strength`.
+pub struct ColorGrade {
+    pub lut : Lut,
+    pub strength : f64
+}
+
+impl ColorGrade {
+    pub fn new(lut : Lut) -> ColorGrade {
+        ColorGrade { lut, strength : 1.0 }
+    }
+}
+
+impl PostProcess for ColorGrade {
+    fn name(&self) -> &str {
+        "Color Grade"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], _width : usize, _height : usize, _delta : f64) {
+        for pixel in pixels.iter_mut() {
+            *pixel = mix_rgb(*pixel, self.lut.sample(*pixel), self.strength);
+        }
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        slider(ui, im_str!("Strength"), &mut self.strength, 1.0);
+        type Preset = (&'static imgui::ImStr, fn(Color) -> Color);
+        let presets : [Preset; 4] = [
+            (im_str!("Identity"), |c| c),
+            (im_str!("Grayscale"), |c| {
+                let l = (c.0 as f64 * 0.299 + c.1 as f64 * 0.587 + c.2 as f64 * 0.114).round() as u8;
+                Color(l, l, l, 255)
+            }),
+            (im_str!("Sepia"), |c| {
+                let (r, g, b) = (c.0 as f64, c.1 as f64, c.2 as f64);
+                let channel = |v : f64| v.round().min(255.0) as u8;
+                Color(channel(r * 0.393 + g * 0.769 + b * 0.189), channel(r * 0.349 + g * 0.686 + b * 0.168), channel(r * 0.272 + g * 0.534 + b * 0.131), 255)
+            }),
+            (im_str!("Night"), |c| Color(c.0 / 3, c.1 / 2, (c.2 as u16 * 3 / 4 + 40).min(255) as u8, 255))
+        ];
+        for (i, (label, f)) in presets.iter().enumerate() {
+            if i > 0 { ui.same_line(0.0) }
+            if ui.small_button(label) {
+                self.lut = Lut::from_fn(self.lut.size, f);
+            }
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Fades the screen to and from a solid color.
+pub struct Fade {
+    pub color : Color,
+    /// How far faded the screen is, 0 untouched and 1 fully `color`.
+    pub amount : f64,
+    target : f64,
+    speed : f64
+}
+
+impl Fade {
+    pub fn new() -> Fade {
+        Fade { color : Color(0, 0, 0, 255), amount : 0.0, target : 0.0, speed : 0.0 }
+    }
+
+    pub fn fade_out(&mut self, color : Color, duration : f64) {
+        self.color = color;
+        self.fade_towards(1.0, duration);
+    }
+
+    pub fn fade_in(&mut self, duration : f64) {
+        self.fade_towards(0.0, duration);
+    }
+
+    fn fade_towards(&mut self, target : f64, duration : f64) {
+        self.target = target;
+        self.speed = if duration > 0.0 { 1.0 / duration } else { f64::INFINITY };
+    }
+
+    /// Whether the last fade has finished.
+    pub fn is_done(&self) -> bool {
+        self.amount == self.target
+    }
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Fade::new()
+    }
+}
+
+impl PostProcess for Fade {
+    fn name(&self) -> &str {
+        "Fade"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], _width : usize, _height : usize, delta : f64) {
+        let step = self.speed * delta;
+        self.amount = if self.amount < self.target { (self.amount + step).min(self.target) } else { (self.amount - step).max(self.target) };
+        if self.amount <= 0.0 { return }
+
+        for pixel in pixels.iter_mut() {
+            *pixel = mix_rgb(*pixel, self.color, self.amount);
+        }
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        color_edit(ui, im_str!("Color"), &mut self.color, false);
+        if ui.small_button(im_str!("Fade Out")) {
+            self.fade_out(self.color, 1.0);
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Fade In")) {
+            self.fade_in(1.0);
+        }
+        ui.text(format!("Amount: {:.2}", self.amount));
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Ordered dithering down to `levels` shades per channel.
+pub struct Dither {
+    pub levels : u8
+}
+
+impl Dither {
+    pub fn new(levels : u8) -> Dither {
+        Dither { levels }
+    }
+}
+
+impl PostProcess for Dither {
+    fn name(&self) -> &str {
+        "Dither"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], width : usize, _height : usize, _delta : f64) {
+        let steps = self.levels.max(2) as f64 - 1.0;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let t = threshold(i % width, i / width);
+            let channel = |c : u8| ((c as f64 * steps / 255.0 + t).floor().min(steps) * 255.0 / steps).round() as u8;
+            *pixel = Color(channel(pixel.0), channel(pixel.1), channel(pixel.2), pixel.3);
+        }
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        let mut levels = self.levels as i32;
+        if Slider::new(im_str!("Levels")).range(2..=32).build(ui, &mut levels) {
+            self.levels = levels as u8;
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The PICO-8 palette, the default for `Quantize`.
+pub const PICO8 : [Color; 16] = [
+    Color(0, 0, 0, 255), Color(29, 43, 83, 255), Color(126, 37, 83, 255), Color(0, 135, 81, 255),
+    Color(171, 82, 54, 255), Color(95, 87, 79, 255), Color(194, 195, 199, 255), Color(255, 241, 232, 255),
+    Color(255, 0, 77, 255), Color(255, 163, 0, 255), Color(255, 236, 39, 255), Color(0, 228, 54, 255),
+    Color(41, 173, 255, 255), Color(131, 118, 156, 255), Color(255, 119, 168, 255), Color(255, 204, 170, 255)
+];
+
+/// Snaps every pixel to the nearest color of a palette, optionally dithered by `spread`, the
+/// largest offset in channel steps added before matching.
+pub struct Quantize {
+    palette : Palette,
+    pub spread : f64,
+    nearest : HashMap<(u8, u8, u8), Color>
+}
+
+impl Quantize {
+    /// Past this many remembered matches the cache starts over.
+    const CACHE_LIMIT : usize = 1 << 16;
+
+    pub fn new(palette : Palette) -> Quantize {
+        Quantize { palette, spread : 0.0, nearest : HashMap::new() }
+    }
+
+    pub fn with_spread(mut self, spread : f64) -> Quantize {
+        self.spread = spread;
+        self
+    }
+
+    pub fn get_palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette : Palette) {
+        self.palette = palette;
+        self.nearest.clear();
+    }
+}
+
+impl PostProcess for Quantize {
+    fn name(&self) -> &str {
+        "Quantize"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], width : usize, _height : usize, _delta : f64) {
+        if self.palette.is_empty() { return }
+        if self.nearest.len() > Quantize::CACHE_LIMIT {
+            self.nearest.clear();
+        }
+
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let offset = (threshold(i % width, i / width) - 0.5) * self.spread;
+            let channel = |c : u8| (c as f64 + offset).round().clamp(0.0, 255.0) as u8;
+            let key = (channel(pixel.0), channel(pixel.1), channel(pixel.2));
+
+            let palette = &self.palette;
+            let Color(r, g, b, _) = *self.nearest.entry(key)
+                .or_insert_with(|| palette.get(palette.nearest(Color(key.0, key.1, key.2, 255), Option::None)));
+            *pixel = Color(r, g, b, pixel.3);
+        }
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        ui.text(format!("Colors: {}", self.palette.len()));
+        slider(ui, im_str!("Dither Spread"), &mut self.spread, 128.0);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Decays a full screen tint, for hits and explosions.
+pub struct Flash {
+    pub color : Color,
+    remaining : f64,
+    duration : f64
+}
+
+impl Flash {
+    pub fn new() -> Flash {
+        Flash { color : Color(255, 255, 255, 255), remaining : 0.0, duration : 0.0 }
+    }
+
+    /// Tints the screen with `color`, its alpha as the starting strength, fading over `duration`.
+    pub fn trigger(&mut self, color : Color, duration : f64) {
+        self.color = color;
+        self.remaining = duration;
+        self.duration = duration;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining > 0.0
+    }
+}
+
+impl Default for Flash {
+    fn default() -> Self {
+        Flash::new()
+    }
+}
+
+impl PostProcess for Flash {
+    fn name(&self) -> &str {
+        "Flash"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], _width : usize, _height : usize, delta : f64) {
+        if !self.is_active() { return }
+
+        let strength = self.remaining / self.duration * self.color.3 as f64 / 255.0;
+        for pixel in pixels.iter_mut() {
+            *pixel = mix_rgb(*pixel, self.color, strength);
+        }
+        self.remaining -= delta;
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        color_edit(ui, im_str!("Color"), &mut self.color, false);
+        if ui.small_button(im_str!("Trigger")) {
+            self.trigger(self.color, 0.3);
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A cheap CRT look: darkened scanlines, an aperture grille mask and a vignette.
+pub struct Crt {
+    /// How much every second row is darkened.
+    pub scanlines : f64,
+    /// How much the two channels a column doesn't carry are darkened.
+    pub mask : f64,
+    /// How much the corners are darkened.
+    pub vignette : f64
+}
+
+impl Crt {
+    pub fn new() -> Crt {
+        Crt { scanlines : 0.3, mask : 0.15, vignette : 0.3 }
+    }
+}
+
+impl Default for Crt {
+    fn default() -> Self {
+        Crt::new()
+    }
+}
+
+impl PostProcess for Crt {
+    fn name(&self) -> &str {
+        "CRT"
+    }
+
+    fn apply(&mut self, pixels : &mut [Color], width : usize, height : usize, _delta : f64) {
+        let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let (dx, dy) = ((x as f64 + 0.5 - cx) / cx, (y as f64 + 0.5 - cy) / cy);
+            let mut shade = 1.0 - self.vignette * (dx * dx + dy * dy) / 2.0;
+            if y % 2 == 1 {
+                shade *= 1.0 - self.scanlines;
+            }
+
+            let mut channels = [pixel.0, pixel.1, pixel.2];
+            for (c, value) in channels.iter_mut().enumerate() {
+                let mask = if c == x % 3 { 1.0 } else { 1.0 - self.mask };
+                *value = (*value as f64 * shade * mask).round().clamp(0.0, 255.0) as u8;
+            }
+            *pixel = Color(channels[0], channels[1], channels[2], pixel.3);
+        }
+    }
+
+    fn debug(&mut self, ui : &Ui) {
+        slider(ui, im_str!("Scanlines"), &mut self.scanlines, 1.0);
+        slider(ui, im_str!("Mask"), &mut self.mask, 1.0);
+        slider(ui, im_str!("Vignette"), &mut self.vignette, 1.0);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES : [Color; 5] = [Color(0, 0, 0, 255), Color(255, 255, 255, 10), Color(12, 200, 77, 255), Color(128, 128, 128, 0), Color(255, 1, 254, 90)];
+
+    fn applied(effect : &mut dyn PostProcess, pixels : &[Color], width : usize, delta : f64) -> Vec<Color> {
+        let mut pixels = pixels.to_vec();
+        let height = pixels.len() / width;
+        effect.apply(&mut pixels, width, height, delta);
+        pixels
+    }
+
+    #[test]
+    fn identity_luts_leave_colors_alone() {
+        for size in [2, 4, 16] {
+            let lut = Lut::identity(size);
+            for color in SAMPLES {
+                assert_eq!(lut.sample(color), color, "size {}", size);
+            }
+        }
+        let mut grade = ColorGrade::new(Lut::identity(16));
+        assert_eq!(applied(&mut grade, &SAMPLES, 5, 0.0), SAMPLES.to_vec());
+    }
+
+    #[test]
+    fn luts_interpolate_between_entries() {
+        let inverted = Lut::from_fn(2, |Color(r, g, b, a)| Color(255 - r, 255 - g, 255 - b, a));
+        assert_eq!(inverted.sample(Color(0, 255, 51, 7)), Color(255, 0, 204, 7));
+        assert_eq!(inverted.sample(Color(128, 128, 128, 255)), Color(127, 127, 127, 255));
+
+        let mut grade = ColorGrade::new(inverted);
+        grade.strength = 0.5;
+        assert_eq!(applied(&mut grade, &[Color(0, 0, 0, 255)], 1, 0.0), vec![Color(128, 128, 128, 255)]);
+    }
+
+    #[test]
+    fn lut_strips_round_trip() {
+        let lut = Lut::from_fn(4, |Color(r, g, b, _)| Color(g, b, r, 255));
+        let image = lut.to_image();
+        assert_eq!(image.get_dimensions(), (16, 4));
+        // Square 1 holds blue 85; red runs along x and green down y inside it.
+        assert_eq!(image.get_pixel(4 + 2, 3), Color(255, 85, 170, 255));
+
+        let loaded = Lut::from_image(&image).unwrap();
+        assert_eq!(loaded.size, 4);
+        assert_eq!(loaded.table, lut.table);
+        assert!(Lut::from_image(&SingleImageBuffer::new(5, 2)).is_err());
+        assert!(Lut::from_image(&SingleImageBuffer::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn dither_spreads_mid_tones_over_the_bayer_matrix() {
+        let gray = vec![Color(128, 128, 128, 200); 16];
+        let dithered = applied(&mut Dither::new(2), &gray, 4, 0.0);
+        assert!(dithered.iter().all(|c| (c.0 == 0 || c.0 == 255) && c.0 == c.1 && c.1 == c.2 && c.3 == 200));
+        assert_eq!(dithered.iter().filter(|c| c.0 == 255).count(), 8);
+        // The brightest threshold sits in the first row, the darkest in the top left corner.
+        assert_eq!((dithered[0].0, dithered[2 + 4].0), (0, 255));
+
+        // Colors already on a level stay put.
+        let levels = [Color(0, 85, 170, 255), Color(255, 255, 0, 255)];
+        assert_eq!(applied(&mut Dither::new(4), &levels, 2, 0.0), levels.to_vec());
+        assert_eq!(applied(&mut Dither::new(4), &gray, 4, 0.0), applied(&mut Dither::new(4), &gray, 4, 0.0));
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_nearest_palette_color() {
+        let mut quantize = Quantize::new(Palette::new(PICO8.to_vec()));
+        assert_eq!(applied(&mut quantize, &PICO8, 4, 0.0), PICO8.to_vec());
+
+        let near = [Color(250, 5, 80, 17), Color(3, 2, 1, 255)];
+        assert_eq!(applied(&mut quantize, &near, 2, 0.0), vec![Color(255, 0, 77, 17), Color(0, 0, 0, 255)]);
+
+        quantize.set_palette(Palette::new(vec![Color(0, 0, 0, 255), Color(255, 255, 255, 255)]));
+        let gray = vec![Color(128, 128, 128, 255); 16];
+        let spread = applied(&mut quantize.with_spread(64.0), &gray, 4, 0.0);
+        assert_eq!(spread.iter().filter(|c| c.0 == 255).count(), 8);
+        assert!(applied(&mut Quantize::new(Palette::new(Vec::new())), &gray, 4, 0.0) == gray);
+    }
+
+    #[test]
+    fn fades_and_flashes_run_on_frame_time() {
+        let screen = [Color(100, 100, 100, 255)];
+        let mut fade = Fade::default();
+        fade.fade_out(Color(0, 0, 0, 255), 1.0);
+        assert_eq!(applied(&mut fade, &screen, 1, 0.5), vec![Color(50, 50, 50, 255)]);
+        assert!(!fade.is_done());
+        assert_eq!(applied(&mut fade, &screen, 1, 0.75), vec![Color(0, 0, 0, 255)]);
+        assert!(fade.is_done());
+        fade.fade_in(0.0);
+        assert_eq!(applied(&mut fade, &screen, 1, 0.01), screen.to_vec());
+
+        let mut flash = Flash::default();
+        flash.trigger(Color(200, 0, 0, 255), 0.5);
+        assert_eq!(applied(&mut flash, &screen, 1, 0.25), vec![Color(200, 0, 0, 255)]);
+        assert_eq!(applied(&mut flash, &screen, 1, 0.25), vec![Color(150, 50, 50, 255)]);
+        assert!(!flash.is_active());
+        assert_eq!(applied(&mut flash, &screen, 1, 0.25), screen.to_vec());
+    }
+
+    #[test]
+    fn a_crt_with_nothing_turned_up_changes_nothing() {
+        let mut crt = Crt { scanlines : 0.0, mask : 0.0, vignette : 0.0 };
+        assert_eq!(applied(&mut crt, &SAMPLES[..4], 2, 0.0), SAMPLES[..4].to_vec());
+        let darkened = applied(&mut Crt::default(), &[Color(200, 200, 200, 255); 4], 2, 0.0);
+        assert!(darkened.iter().all(|c| c.0 < 200 && c.3 == 255));
+        assert!(darkened[2].1 < darkened[0].1);
+    }
+
+    #[test]
+    fn chains_only_run_enabled_stages() {
+        let mut chain = PostChain::standard();
+        assert!(!chain.is_active());
+        let mut buffer = CamBuffer::new(2, 1);
+        buffer.set_pixel(Color(100, 100, 100, 255), 0, 0);
+        chain.apply(&mut buffer, 0.1);
+        assert_eq!(buffer.get_pixel(0, 0), Color(100, 100, 100, 255));
+
+        chain.flash(Color(0, 0, 255, 255), 1.0);
+        assert!(chain.is_active() && chain.get_mut::<Flash>().unwrap().is_active());
+        chain.apply(&mut buffer, 0.1);
+        assert_eq!(buffer.get_pixel(0, 0), Color(0, 0, 255, 255));
+        chain.set_enabled::<Flash>(false);
+        assert!(!chain.is_active());
+    }
+}
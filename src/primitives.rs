@@ -0,0 +1,403 @@
+use crate::color::{Color, BlendMode};
+use crate::dirty;
+use crate::image_buffer::ImageBuffer;
+use crate::math::Rect;
+
+/// `a / b` rounded down and up, for positive `b`.
+fn floor_div(a : i128, b : i128) -> i128 {
+    a.div_euclid(b)
+}
+
+fn ceil_div(a : i128, b : i128) -> i128 {
+    -(-a).div_euclid(b)
+}
+
+/// Calls `plot` for every pixel of the Bresenham line from `x0`, `y0` to `x1`, `y1` that lies in
+/// `clip`, leaving out the last one unless `include_end`.
+///
+/// Step `i` along the major axis is `floor((2 * minor * i + major) / (2 * major))` pixels along
+/// the minor one, which is what stepping the error term gives. The steps inside `clip` are solved
+/// for directly, so only visible pixels are visited and clipping never moves a pixel.
+fn line_points(x0 : i32, y0 : i32, x1 : i32, y1 : i32, include_end : bool, clip : Rect, mut plot : impl FnMut(i32, i32)) {
+    if clip.is_empty() { return }
+    let (dx, dy) = (x1 as i128 - x0 as i128, y1 as i128 - y0 as i128);
+    let x_major = dx.abs() >= dy.abs();
+    let ((a0, da, a_lo, a_hi), (b0, db, b_lo, b_hi)) = {
+        let x = (x0 as i128, dx, clip.x as i128, clip.right() as i128 - 1);
+        let y = (y0 as i128, dy, clip.y as i128, clip.bottom() as i128 - 1);
+        if x_major { (x, y) } else { (y, x) }
+    };
+    let (major, minor) = (da.abs(), db.abs());
+    let (sa, sb) = (da.signum(), db.signum());
+    let last = if include_end { major } else { major - 1 };
+
+    // Steps whose offset along an axis, starting from `start` in direction `step`, stays in `lo..=hi`.
+    let offsets = |start : i128, step : i128, lo : i128, hi : i128| if step < 0 { (start - hi, start - lo) } else { (lo - start, hi - start) };
+    let (mut first, mut end) = (0, last);
+    let (major_lo, major_hi) = offsets(a0, sa, a_lo, a_hi);
+    if major == 0 {
+        if major_lo > 0 || major_hi < 0 { return }
+    } else {
+        first = first.max(major_lo);
+        end = end.min(major_hi);
+    }
+    let (minor_lo, minor_hi) = offsets(b0, sb, b_lo, b_hi);
+    if minor == 0 {
+        if minor_lo > 0 || minor_hi < 0 { return }
+    } else {
+        first = first.max(ceil_div(2 * major * minor_lo - major, 2 * minor));
+        end = end.min(floor_div(2 * major * (minor_hi + 1) - major - 1, 2 * minor));
+    }
+
+    for i in first..=end {
+        let offset = if major == 0 { 0 } else { (2 * minor * i + major) / (2 * major) };
+        let (a, b) = ((a0 + sa * i) as i32, (b0 + sb * offset) as i32);
+        if x_major { plot(a, b) } else { plot(b, a) }
+    }
+}
+
+/// Half the width of each row of a filled ellipse, from the top row to the bottom one. Radii are
+/// padded by half a pixel so small circles come out round instead of diamond shaped.
+fn ellipse_spans(rx : i32, ry : i32) -> Vec<i32> {
+    let (a, b) = (rx as f64 + 0.5, ry as f64 + 0.5);
+    (-ry..=ry).map(|dy| {
+        let t = 1.0 - (dy as f64 / b).powi(2);
+        ((a * t.max(0.0).sqrt()).floor() as i32).min(rx)
+    }).collect()
+}
+
+/// Pixel exact drawing on any `ImageBuffer`. Every shape touches each pixel at most once, so
+/// translucent colors blend evenly, and nothing is drawn outside `ImageBuffer::get_clip`.
+pub trait Primitives : ImageBuffer {
+    /// Blends `color` into one pixel if it is inside the clip rect.
+    fn plot(&mut self, x : i32, y : i32, color : Color, mode : BlendMode) {
+        if self.get_clip().contains(x, y) {
+            self.blend_pixel_with(color, x as usize, y as usize, mode);
+        }
+    }
+
+    /// Blends `color` from `x0` to `x1` inclusive on row `y`.
+    fn hline(&mut self, x0 : i32, x1 : i32, y : i32, color : Color, mode : BlendMode) {
+        let clip = self.get_clip();
+        if y < clip.y || y >= clip.bottom() { return }
+        let (start, end) = (x0.min(x1).max(clip.x), x0.max(x1).min(clip.right() - 1));
+        for x in start..=end {
+            self.blend_pixel_with(color, x as usize, y as usize, mode);
+        }
+    }
+
+    fn line(&mut self, x0 : i32, y0 : i32, x1 : i32, y1 : i32, color : Color) {
+        let clip = self.get_clip();
+        line_points(x0, y0, x1, y1, true, clip, |x, y| self.blend_pixel_with(color, x as usize, y as usize, BlendMode::Alpha));
+    }
+
+    /// The one pixel wide border of `rect`.
+    fn rect(&mut self, rect : Rect, color : Color) {
+        if rect.is_empty() { return }
+        let (right, bottom) = (rect.right() - 1, rect.bottom() - 1);
+        self.hline(rect.x, right, rect.y, color, BlendMode::Alpha);
+        if bottom > rect.y {
+            self.hline(rect.x, right, bottom, color, BlendMode::Alpha);
+        }
+        for y in rect.y + 1..bottom {
+            self.plot(rect.x, y, color, BlendMode::Alpha);
+            if right > rect.x {
+                self.plot(right, y, color, BlendMode::Alpha);
+            }
+        }
+    }
+
+    fn fill_rect(&mut self, rect : Rect, color : Color) {
+        for y in rect.y..rect.bottom() {
+            self.hline(rect.x, rect.right() - 1, y, color, BlendMode::Alpha);
+        }
+    }
+
+    fn circle(&mut self, cx : i32, cy : i32, radius : i32, color : Color) {
+        self.ellipse(cx, cy, radius, radius, color)
+    }
+
+    fn fill_circle(&mut self, cx : i32, cy : i32, radius : i32, color : Color) {
+        self.fill_ellipse(cx, cy, radius, radius, color)
+    }
+
+    /// The outline of the ellipse centered on `cx`, `cy` with radii `rx` and `ry`: the pixels of
+    /// the filled ellipse with a neighbour outside it.
+    fn ellipse(&mut self, cx : i32, cy : i32, rx : i32, ry : i32, color : Color) {
+        if rx < 0 || ry < 0 { return }
+        let spans = ellipse_spans(rx, ry);
+        let span = |row : i32| if row < 0 || row as usize >= spans.len() { -1 } else { spans[row as usize] };
+
+        for row in 0..spans.len() as i32 {
+            let half = span(row);
+            // The inside of this row is what the rows above and below also cover, minus the ends.
+            let inner = span(row - 1).min(span(row + 1)).min(half - 1);
+            let y = cy - ry + row;
+            if inner < 0 {
+                self.hline(cx - half, cx + half, y, color, BlendMode::Alpha);
+            } else {
+                self.hline(cx - half, cx - inner - 1, y, color, BlendMode::Alpha);
+                self.hline(cx + inner + 1, cx + half, y, color, BlendMode::Alpha);
+            }
+        }
+    }
+
+    fn fill_ellipse(&mut self, cx : i32, cy : i32, rx : i32, ry : i32, color : Color) {
+        if rx < 0 || ry < 0 { return }
+        for (row, half) in ellipse_spans(rx, ry).into_iter().enumerate() {
+            self.hline(cx - half, cx + half, cy - ry + row as i32, color, BlendMode::Alpha);
+        }
+    }
+
+    /// Lines between consecutive points, closed back to the first.
+    fn polygon(&mut self, points : &[(i32, i32)], color : Color) {
+        match points.len() {
+            0 => {}
+            1 => self.plot(points[0].0, points[0].1, color, BlendMode::Alpha),
+            2 => self.line(points[0].0, points[0].1, points[1].0, points[1].1, color),
+            len => {
+                let clip = self.get_clip();
+                for i in 0..len {
+                    // Each edge leaves out its end, which is the start of the next.
+                    let ((x0, y0), (x1, y1)) = (points[i], points[(i + 1) % len]);
+                    line_points(x0, y0, x1, y1, false, clip, |x, y| self.blend_pixel_with(color, x as usize, y as usize, BlendMode::Alpha));
+                }
+            }
+        }
+    }
+
+    /// Fills the polygon with the even-odd rule, taking every pixel whose center is inside. Shapes
+    /// sharing an edge don't overlap.
+    fn fill_polygon(&mut self, points : &[(i32, i32)], color : Color) {
+        if points.len() < 3 { return }
+        let clip = self.get_clip();
+        let top = points.iter().map(|p| p.1).min().unwrap_or(0).max(clip.y);
+        let bottom = points.iter().map(|p| p.1).max().unwrap_or(0).min(clip.bottom());
+
+        let mut crossings = Vec::with_capacity(points.len());
+        for y in top..bottom {
+            let center = y as f64 + 0.5;
+            crossings.clear();
+            for i in 0..points.len() {
+                let ((x0, y0), (x1, y1)) = (points[i], points[(i + 1) % points.len()]);
+                let (y0, y1, x0, x1) = (y0 as f64, y1 as f64, x0 as f64, x1 as f64);
+                if (y0 <= center) != (y1 <= center) {
+                    crossings.push(x0 + (center - y0) / (y1 - y0) * (x1 - x0));
+                }
+            }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            for pair in crossings.chunks_exact(2) {
+                let (start, end) = ((pair[0] - 0.5).ceil() as i32, (pair[1] - 0.5).ceil() as i32 - 1);
+                if start <= end {
+                    self.hline(start, end, y, color, BlendMode::Alpha);
+                }
+            }
+        }
+    }
+
+    /// Replaces the 4-connected area of pixels matching the one at `x`, `y` with `color`, without
+    /// leaving the clip rect. While recording, the area depends on pixels that aren't written, so
+    /// the whole clip rect is recorded instead.
+    fn flood_fill(&mut self, x : i32, y : i32, color : Color) {
+        let clip = self.get_clip();
+        if !clip.contains(x, y) { return }
+        if self.is_recording() {
+            return self.record_draw(clip, dirty::draw_key(&(x, y, color)))
+        }
+        let target = self.get_pixel(x as usize, y as usize);
+        if target == color { return }
+
+        let matches = |buffer : &Self, x : i32, y : i32| buffer.get_pixel(x as usize, y as usize) == target;
+        // Fills whole runs of a row at once, queueing the rows above and below.
+        let mut stack = vec![(x, y)];
+        while let Some((x, y)) = stack.pop() {
+            if !matches(self, x, y) { continue }
+            let mut left = x;
+            while left > clip.x && matches(self, left - 1, y) { left -= 1 }
+            let mut right = x;
+            while right < clip.right() - 1 && matches(self, right + 1, y) { right += 1 }
+
+            for i in left..=right {
+                self.set_pixel(color, i as usize, y as usize);
+            }
+            for row in [y - 1, y + 1] {
+                if row < clip.y || row >= clip.bottom() { continue }
+                let mut i = left;
+                while i <= right {
+                    if matches(self, i, row) {
+                        stack.push((i, row));
+                        while i <= right && matches(self, i, row) { i += 1 }
+                    }
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+impl<T : ImageBuffer + ?Sized> Primitives for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_buffer::CamBuffer;
+
+    const INK : Color = Color(255, 255, 255, 255);
+
+    /// The buffer as one string per row, `#` for drawn pixels.
+    fn picture(buffer : &CamBuffer) -> Vec<String> {
+        let (width, height) = buffer.get_dimensions();
+        (0..height).map(|y| (0..width).map(|x| if buffer.get_pixel(x, y) == Color::CLEAR { '.' } else { '#' }).collect()).collect()
+    }
+
+    fn drawn(width : usize, height : usize, draw : impl FnOnce(&mut CamBuffer)) -> Vec<String> {
+        let mut buffer = CamBuffer::new(width, height);
+        draw(&mut buffer);
+        picture(&buffer)
+    }
+
+    /// Bresenham stepped one pixel at a time, without clipping.
+    fn stepped(x0 : i32, y0 : i32, x1 : i32, y1 : i32) -> Vec<(i32, i32)> {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        let mut points = vec![(x, y)];
+        while (x, y) != (x1, y1) {
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += sx;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += sy;
+            }
+            points.push((x, y));
+        }
+        points
+    }
+
+    fn clipped(x0 : i32, y0 : i32, x1 : i32, y1 : i32, include_end : bool, clip : Rect) -> Vec<(i32, i32)> {
+        let mut points = Vec::new();
+        line_points(x0, y0, x1, y1, include_end, clip, |x, y| points.push((x, y)));
+        points
+    }
+
+    #[test]
+    fn lines_step_the_same_in_every_octant() {
+        assert_eq!(drawn(5, 3, |b| b.line(0, 0, 4, 2, INK)), ["#....", ".##..", "...##"]);
+        assert_eq!(drawn(3, 5, |b| b.line(0, 0, 2, 4, INK)), ["#..", ".#.", ".#.", "..#", "..#"]);
+        assert_eq!(drawn(5, 3, |b| b.line(4, 2, 0, 0, INK)), ["##...", "..##.", "....#"]);
+
+        let everywhere = Rect::new(-100, -100, 200, 200);
+        for (x1, y1) in [(12, 9), (9, 12), (3, 12), (0, 9), (0, 3), (3, 0), (9, 0), (12, 3),
+            (12, 6), (6, 12), (0, 6), (6, 0), (12, 12), (0, 0), (12, 0), (0, 12), (6, 6), (7, 6), (5, 7)] {
+            let line = stepped(6, 6, x1, y1);
+            assert_eq!(clipped(6, 6, x1, y1, true, everywhere), line, "to {}, {}", x1, y1);
+            assert_eq!(clipped(6, 6, x1, y1, false, everywhere), line[..line.len() - 1], "to {}, {}", x1, y1);
+        }
+    }
+
+    #[test]
+    fn clipping_never_moves_a_pixel() {
+        let clip = Rect::new(3, 2, 7, 6);
+        let ends = [-9, -1, 0, 2, 3, 5, 8, 9, 10, 14, 31];
+        for &x0 in &ends {
+            for &y0 in &ends {
+                for (x1, y1) in [(5, 4), (-7, 12), (20, -3), (9, 7), (3, 30), (-40, -1)] {
+                    let visible = stepped(x0, y0, x1, y1).into_iter().filter(|&(x, y)| clip.contains(x, y)).collect::<Vec<_>>();
+                    assert_eq!(clipped(x0, y0, x1, y1, true, clip), visible, "{}, {} to {}, {}", x0, y0, x1, y1);
+                }
+            }
+        }
+        assert!(clipped(0, 0, 9, 9, true, Rect::new(2, 2, 0, 5)).is_empty());
+    }
+
+    #[test]
+    fn far_off_lines_only_visit_what_shows() {
+        let mut buffer = CamBuffer::new(16, 16);
+        buffer.line(-1_000_000_000, 5, 1_000_000_000, 6, INK);
+        buffer.line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, INK);
+        buffer.line(i32::MAX, 0, i32::MIN, 0, INK);
+        let rows = picture(&buffer);
+        assert_eq!(rows[0], "################");
+        assert!((0..16).all(|x| buffer.get_pixel(x, 5) == INK || buffer.get_pixel(x, 6) == INK));
+        assert!(rows[1..5].iter().chain(&rows[7..]).all(|row| row.matches('#').count() <= 2));
+        assert!((0..16).all(|i| buffer.get_pixel(i, i) == INK));
+    }
+
+    #[test]
+    fn rect_outlines() {
+        assert_eq!(drawn(6, 5, |b| b.rect(Rect::new(1, 1, 4, 3), INK)), ["......", ".####.", ".#..#.", ".####.", "......"]);
+        assert_eq!(drawn(3, 4, |b| b.rect(Rect::new(1, 0, 1, 3), INK)), [".#.", ".#.", ".#.", "..."]);
+        assert_eq!(drawn(3, 2, |b| b.rect(Rect::new(0, 1, 3, 1), INK)), ["...", "###"]);
+        assert_eq!(drawn(2, 2, |b| b.rect(Rect::new(0, 0, 0, 2), INK)), ["..", ".."]);
+        // Translucent corners are only drawn once.
+        let mut buffer = CamBuffer::new(4, 4);
+        buffer.rect(Rect::new(0, 0, 4, 4), Color(255, 255, 255, 128));
+        assert!([(0, 0), (3, 0), (0, 3), (3, 3), (1, 0), (0, 2)].iter().all(|&(x, y)| buffer.get_pixel(x, y) == Color(255, 255, 255, 128)));
+    }
+
+    #[test]
+    fn small_circles_and_ellipses() {
+        assert_eq!(drawn(3, 3, |b| b.circle(1, 1, 0, INK)), ["...", ".#.", "..."]);
+        assert_eq!(drawn(3, 3, |b| b.circle(1, 1, 1, INK)), ["###", "#.#", "###"]);
+        assert_eq!(drawn(5, 5, |b| b.circle(2, 2, 2, INK)), [".###.", "#...#", "#...#", "#...#", ".###."]);
+        assert_eq!(drawn(5, 5, |b| b.fill_circle(2, 2, 2, INK)), [".###.", "#####", "#####", "#####", ".###."]);
+        assert_eq!(drawn(7, 7, |b| b.circle(3, 3, 3, INK)), ["..###..", ".#...#.", "#.....#", "#.....#", "#.....#", ".#...#.", "..###.."]);
+        assert_eq!(drawn(7, 3, |b| b.ellipse(3, 1, 3, 1, INK)), [".#####.", "#.....#", ".#####."]);
+        assert_eq!(drawn(7, 3, |b| b.fill_ellipse(3, 1, 3, 1, INK)), [".#####.", "#######", ".#####."]);
+        assert_eq!(drawn(3, 3, |b| b.circle(1, 1, -1, INK)), ["...", "...", "..."]);
+    }
+
+    #[test]
+    fn polygons_sharing_an_edge_do_not_overlap() {
+        let half = Color(255, 255, 255, 128);
+        let mut buffer = CamBuffer::new(8, 6);
+        buffer.fill_polygon(&[(1, 1), (7, 1), (7, 5)], half);
+        buffer.fill_polygon(&[(1, 1), (7, 5), (1, 5)], half);
+        for y in 0..6 {
+            for x in 0..8 {
+                let expected = if (1..7).contains(&x) && (1..5).contains(&y) { half } else { Color::CLEAR };
+                assert_eq!(buffer.get_pixel(x, y), expected, "{}, {}", x, y);
+            }
+        }
+
+        assert_eq!(drawn(6, 5, |b| b.fill_polygon(&[(1, 1), (5, 1), (5, 4), (1, 4)], INK)), ["......", ".####.", ".####.", ".####.", "......"]);
+        assert_eq!(drawn(5, 5, |b| b.polygon(&[(0, 0), (4, 0), (4, 4)], INK)), ["#####", ".#..#", "..#.#", "...##", "....#"]);
+    }
+
+    #[test]
+    fn flood_fill_only_records_while_recording() {
+        let mut buffer = CamBuffer::new(6, 4);
+        buffer.start_recording();
+        buffer.push_clip(Rect::new(1, 1, 3, 2));
+        buffer.flood_fill(2, 1, INK);
+        buffer.pop_clip();
+        let record = buffer.take_record().unwrap();
+        buffer.stop_recording();
+        assert_eq!(record.bounds, Rect::new(1, 1, 3, 2));
+        assert_eq!(picture(&buffer), ["......"; 4]);
+
+        buffer.start_recording();
+        buffer.flood_fill(2, 1, INK);
+        assert_ne!(buffer.take_record().unwrap().key, record.key);
+    }
+
+    #[test]
+    fn flood_fill_stays_inside_the_clip() {
+        let mut buffer = CamBuffer::new(6, 4);
+        buffer.line(2, 0, 2, 3, INK);
+        buffer.push_clip(Rect::new(3, 1, 2, 2));
+        buffer.flood_fill(4, 1, Color(255, 0, 0, 255));
+        buffer.flood_fill(0, 0, Color(255, 0, 0, 255));
+        buffer.pop_clip();
+        let filled = (0..4).map(|y| (0..6).map(|x| if buffer.get_pixel(x, y) == Color(255, 0, 0, 255) { 'r' } else { '.' }).collect::<String>()).collect::<Vec<_>>();
+        assert_eq!(filled, ["......", "...rr.", "...rr.", "......"]);
+
+        buffer.flood_fill(0, 0, Color(0, 0, 255, 255));
+        assert_eq!(buffer.get_pixel(1, 3), Color(0, 0, 255, 255));
+        assert_eq!(buffer.get_pixel(3, 0), Color::CLEAR);
+    }
+}
@@ -0,0 +1,374 @@
+use std::convert::TryInto;
+
+use rayon::prelude::*;
+
+use crate::color::{Color, BlendMode};
+use crate::dirty;
+use crate::image_buffer::{CamBuffer, DrawParams, ImageBuffer, ImageView, clip_blit};
+use crate::math::Rect;
+use crate::palette::Palette;
+
+/// Pixels handled together by the straight alpha fast path.
+const LANES : usize = 4;
+
+/// Blends `src` onto the start of `dst`, giving exactly what `Color::blended` gives pixel by
+/// pixel. Replace is a plain copy. Straight alpha copies runs of opaque pixels, skips runs of
+/// transparent ones and blends runs over an opaque destination in 16 bit lanes, which compile to
+/// SIMD.
+pub fn blend_span(dst : &mut [Color], src : &[Color], mode : BlendMode) {
+    let len = dst.len().min(src.len());
+    let (dst, src) = (&mut dst[..len], &src[..len]);
+    match mode {
+        BlendMode::Replace => dst.copy_from_slice(src),
+        BlendMode::Alpha => alpha_span(dst, src),
+        _ => dst.iter_mut().zip(src).for_each(|(d, s)| d.blend_with(*s, mode))
+    }
+}
+
+fn alpha_span(dst : &mut [Color], src : &[Color]) {
+    let mut dst_chunks = dst.chunks_exact_mut(LANES);
+    let mut src_chunks = src.chunks_exact(LANES);
+    for (d, s) in (&mut dst_chunks).zip(&mut src_chunks) {
+        if s.iter().all(|c| c.3 == 255) {
+            d.copy_from_slice(s);
+        } else if s.iter().all(|c| c.3 == 0) && d.iter().all(|c| c.3 != 0) {
+            // Nothing to do. Over a transparent destination the result is `Color::CLEAR`, so
+            // those still go the slow way.
+        } else if d.iter().all(|c| c.3 == 255) {
+            over_opaque(d, s);
+        } else {
+            d.iter_mut().zip(s).for_each(|(d, s)| d.blend(*s));
+        }
+    }
+    for (d, s) in dst_chunks.into_remainder().iter_mut().zip(src_chunks.remainder()) {
+        d.blend(*s);
+    }
+}
+
+/// Straight alpha over `LANES` opaque pixels: `d = (s * sa + d * (255 - sa)) / 255` per byte,
+/// rounded, with the alpha byte staying opaque. Kept out of line, as LLVM stops vectorizing it
+/// once it is inlined into the chunk loop.
+#[inline(never)]
+fn over_opaque(dst : &mut [Color], src : &[Color]) {
+    let d : &mut [u8; LANES * 4] = bytemuck::cast_slice_mut(dst).try_into().expect("one chunk of lanes");
+    let s : &[u8; LANES * 4] = bytemuck::cast_slice(src).try_into().expect("one chunk of lanes");
+
+    // Every byte gets its pixel's alpha, and the alpha bytes blend 255 so they stay opaque.
+    let (mut alpha, mut source) = ([0u16; LANES * 4], [255u16; LANES * 4]);
+    for pixel in 0..LANES {
+        for channel in 0..4 {
+            alpha[pixel * 4 + channel] = s[pixel * 4 + 3] as u16;
+        }
+        for channel in 0..3 {
+            source[pixel * 4 + channel] = s[pixel * 4 + channel] as u16;
+        }
+    }
+    for i in 0..LANES * 4 {
+        // Rounded division by 255 that stays within 16 bits.
+        let x = source[i] * alpha[i] + d[i] as u16 * (255 - alpha[i]) + 128;
+        d[i] = ((x + (x >> 8)) >> 8) as u8;
+    }
+}
+
+/// One drawing operation recorded by `TileRenderer`. Views borrow their pixels, so atlas frames
+/// and assets go in without copying.
+#[derive(Copy, Clone)]
+pub enum DrawCommand<'a> {
+    /// `ImageView::blit_rect`.
+    Blit { view : ImageView<'a>, src : Rect, x : i32, y : i32, mode : BlendMode },
+    /// `ImageView::draw`.
+    Draw { view : ImageView<'a>, params : DrawParams },
+    /// Blends `color` over every pixel of `rect`.
+    Fill { rect : Rect, color : Color, mode : BlendMode }
+}
+
+/// The rows of a `CamBuffer` one task draws into.
+struct Strip<'a> {
+    pixels : &'a mut [Color],
+    /// Empty unless the buffer has a palette.
+    indices : &'a mut [Option<u8>],
+    palette : Option<&'a Palette>,
+    width : usize,
+    top : i32,
+    clip : Rect
+}
+
+impl<'a> Strip<'a> {
+    /// The pixels of `row` from `x` on, `len` long, flattened out of the palette.
+    fn span(&mut self, x : i32, row : i32, len : usize) -> &mut [Color] {
+        let start = (row - self.top) as usize * self.width + x as usize;
+        if let Some(palette) = self.palette {
+            for (pixel, index) in self.pixels[start..start + len].iter_mut().zip(&mut self.indices[start..start + len]) {
+                if let Some(i) = index.take() {
+                    *pixel = palette.color_at(i);
+                }
+            }
+        }
+        &mut self.pixels[start..start + len]
+    }
+
+    fn run(&mut self, command : &DrawCommand) {
+        match *command {
+            DrawCommand::Blit { view, src, x, y, mode } => {
+                if let Some((src, x, y)) = clip_blit(src, view.get_dimensions(), x, y, self.clip) {
+                    for j in 0..src.height {
+                        let colors = &view.row((src.y + j) as usize)[src.x as usize..src.right() as usize];
+                        blend_span(self.span(x, y + j, colors.len()), colors, mode);
+                    }
+                }
+            }
+            DrawCommand::Draw { view, params } => {
+                params.for_each_covered(view.get_dimensions(), self.clip, |i, j, u, v| {
+                    self.span(i as i32, j as i32, 1)[0].blend_with(view.get_pixel(u, v), params.blend);
+                });
+            }
+            DrawCommand::Fill { rect, color, mode } => {
+                let rect = rect.intersect(&self.clip);
+                if rect.is_empty() { return }
+                for y in rect.y..rect.bottom() {
+                    let span = self.span(rect.x, y, rect.width as usize);
+                    match mode {
+                        BlendMode::Replace => span.fill(color),
+                        _ => span.iter_mut().for_each(|pixel| pixel.blend_with(color, mode))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Records draw commands and plays them back into a `CamBuffer` in parallel. The buffer is cut
+/// into strips of whole rows, and every strip runs the full list clipped to itself, so the
+/// result is the same as drawing the commands one after another.
+///
+/// Drawing straight into the `CamBuffer` stays the default. Components that cover a large part of
+/// the screen collect their draws here and call `render` instead, like `ParallaxLayerComponent`.
+pub struct TileRenderer<'a> {
+    commands : Vec<DrawCommand<'a>>,
+    tile_height : usize
+}
+
+impl<'a> TileRenderer<'a> {
+    pub fn new() -> TileRenderer<'a> {
+        TileRenderer {
+            commands : Vec::new(),
+            tile_height : 16
+        }
+    }
+
+    /// Rows per strip. Taller strips mean fewer tasks but coarser load balancing.
+    pub fn with_tile_height(mut self, rows : usize) -> TileRenderer<'a> {
+        self.tile_height = rows.max(1);
+        self
+    }
+
+    pub fn push(&mut self, command : DrawCommand<'a>) {
+        self.commands.push(command);
+    }
+
+    pub fn blit(&mut self, view : ImageView<'a>, x : i32, y : i32, mode : BlendMode) {
+        let (width, height) = view.get_dimensions();
+        self.blit_rect(view, Rect::new(0, 0, width as i32, height as i32), x, y, mode)
+    }
+
+    pub fn blit_rect(&mut self, view : ImageView<'a>, src : Rect, x : i32, y : i32, mode : BlendMode) {
+        self.push(DrawCommand::Blit { view, src, x, y, mode });
+    }
+
+    pub fn draw(&mut self, view : ImageView<'a>, params : &DrawParams) {
+        self.push(DrawCommand::Draw { view, params : *params });
+    }
+
+    pub fn fill(&mut self, rect : Rect, color : Color, mode : BlendMode) {
+        self.push(DrawCommand::Fill { rect, color, mode });
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    /// Draws every command into `target` within its clip, one rayon task per strip.
+    pub fn render(&self, target : &mut CamBuffer) {
+        if target.is_recording() { return self.record(target) }
+        let commands = &self.commands;
+        self.strips(target).into_par_iter().for_each(|mut strip| commands.iter().for_each(|c| strip.run(c)));
+    }
+
+    /// Same as `render`, on the calling thread.
+    pub fn render_serial(&self, target : &mut CamBuffer) {
+        if target.is_recording() { return self.record(target) }
+        for mut strip in self.strips(target) {
+            self.commands.iter().for_each(|c| strip.run(c));
+        }
+    }
+
+    /// Hands every command to a recording `target` the way drawing it directly would.
+    fn record(&self, target : &mut CamBuffer) {
+        for command in &self.commands {
+            match *command {
+                DrawCommand::Blit { view, src, x, y, mode } => view.blit_rect(src, target, x, y, mode),
+                DrawCommand::Draw { view, params } => view.draw(target, &params),
+                DrawCommand::Fill { rect, color, mode } => target.record_draw(rect, dirty::draw_key(&(color, mode)))
+            }
+        }
+    }
+
+    fn strips<'b>(&self, target : &'b mut CamBuffer) -> Vec<Strip<'b>> {
+        let clip = target.get_clip();
+        let (pixels, indices, palette, width) = target.parts_mut();
+        if width == 0 || clip.is_empty() { return Vec::new() }
+
+        let size = width * self.tile_height;
+        let mut index_chunks = indices.chunks_mut(size);
+        pixels.chunks_mut(size).enumerate().map(|(i, pixels)| {
+            let top = (i * self.tile_height) as i32;
+            let rows = Rect::new(0, top, width as i32, (pixels.len() / width) as i32);
+            Strip { pixels, indices : index_chunks.next().unwrap_or_default(), palette, width, top, clip : clip.intersect(&rows) }
+        }).filter(|strip| !strip.clip.is_empty()).collect()
+    }
+}
+
+impl<'a> Default for TileRenderer<'a> {
+    fn default() -> Self {
+        TileRenderer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_buffer::SingleImageBuffer;
+    use crate::primitives::Primitives;
+
+    /// Colors covering opaque, transparent and in between alphas in every lane position.
+    fn pattern(len : usize, seed : usize) -> Vec<Color> {
+        let alphas = [0, 255, 128, 1, 254, 255, 255, 0, 77];
+        (0..len).map(|i| {
+            let n = i * 31 + seed * 17;
+            Color(n as u8, (n * 3) as u8, (n * 7) as u8, alphas[(i + seed) % alphas.len()])
+        }).collect()
+    }
+
+    fn opaque(len : usize, seed : usize) -> Vec<Color> {
+        pattern(len, seed).into_iter().map(|c| Color(c.0, c.1, c.2, 255)).collect()
+    }
+
+    #[test]
+    fn span_matches_per_pixel_blending() {
+        let modes = [BlendMode::Replace, BlendMode::Alpha, BlendMode::PremultipliedAlpha, BlendMode::Add,
+            BlendMode::Subtract, BlendMode::Multiply, BlendMode::Screen];
+        for mode in modes {
+            for len in 0..19 {
+                for dst in [pattern(len, 3), opaque(len, 5)] {
+                    let src = pattern(len, 1);
+                    let mut actual = dst.clone();
+                    blend_span(&mut actual, &src, mode);
+                    let expected : Vec<Color> = dst.iter().zip(&src).map(|(d, s)| Color::blended(*d, *s, mode)).collect();
+                    assert_eq!(actual, expected, "{:?}, {} pixels", mode, len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn over_opaque_matches_blended_for_every_alpha() {
+        for alpha in 0..=255u32 {
+            let src : Vec<Color> = (0..LANES).map(|i| Color((alpha * 3 + i as u32) as u8, 255, 0, alpha as u8)).collect();
+            let dst : Vec<Color> = (0..LANES).map(|i| Color(i as u8 * 60, 0, 255, 255)).collect();
+            let mut actual = dst.clone();
+            over_opaque(&mut actual, &src);
+            let expected : Vec<Color> = dst.iter().zip(&src).map(|(d, s)| Color::blended(*d, *s, BlendMode::Alpha)).collect();
+            assert_eq!(actual, expected, "alpha {}", alpha);
+        }
+    }
+
+    #[test]
+    fn span_stops_at_the_shorter_slice() {
+        let mut dst = vec![Color(1, 1, 1, 255); 3];
+        blend_span(&mut dst, &[Color(9, 9, 9, 255); 5], BlendMode::Replace);
+        assert_eq!(dst, vec![Color(9, 9, 9, 255); 3]);
+        let mut dst = vec![Color(1, 1, 1, 255); 5];
+        blend_span(&mut dst, &[Color(9, 9, 9, 255); 2], BlendMode::Replace);
+        assert_eq!(dst[1..3], [Color(9, 9, 9, 255), Color(1, 1, 1, 255)]);
+    }
+
+    /// The scene drawn command by command straight into a buffer, to compare the tiles with.
+    fn reference(sprite : &SingleImageBuffer, target : &mut CamBuffer) {
+        for (i, mode) in [BlendMode::Alpha, BlendMode::Add, BlendMode::Replace].iter().enumerate() {
+            sprite.blit(target, i as i32 * 9 - 5, i as i32 * 7 - 4, *mode);
+        }
+        sprite.as_view().blit_rect(Rect::new(2, 2, 5, 40), target, 20, 20, BlendMode::Alpha);
+        let mut params = DrawParams::at(16.0, 12.0);
+        params.rotation = 30.0;
+        sprite.draw(target, &params);
+        target.fill_rect(Rect::new(10, 3, 20, 6), Color(200, 0, 0, 100));
+    }
+
+    fn recorded<'a>(sprite : &'a SingleImageBuffer, rows : usize) -> TileRenderer<'a> {
+        let mut renderer = TileRenderer::new().with_tile_height(rows);
+        for (i, mode) in [BlendMode::Alpha, BlendMode::Add, BlendMode::Replace].iter().enumerate() {
+            renderer.blit(sprite.as_view(), i as i32 * 9 - 5, i as i32 * 7 - 4, *mode);
+        }
+        renderer.blit_rect(sprite.as_view(), Rect::new(2, 2, 5, 40), 20, 20, BlendMode::Alpha);
+        let mut params = DrawParams::at(16.0, 12.0);
+        params.rotation = 30.0;
+        renderer.draw(sprite.as_view(), &params);
+        renderer.fill(Rect::new(10, 3, 20, 6), Color(200, 0, 0, 100), BlendMode::Alpha);
+        renderer
+    }
+
+    fn background(width : usize, height : usize) -> CamBuffer {
+        let mut buffer = CamBuffer::new(width, height);
+        for (i, color) in pattern(width * height, 2).into_iter().enumerate() {
+            buffer.set_pixel(color, i % width, i / width);
+        }
+        buffer
+    }
+
+    #[test]
+    fn tiles_match_drawing_in_order() {
+        let sprite = SingleImageBuffer::from_colors(pattern(12 * 10, 4), 12, 10);
+        for rows in [1, 3, 16, 100] {
+            let mut expected = background(37, 29);
+            reference(&sprite, &mut expected);
+
+            let renderer = recorded(&sprite, rows);
+            let (mut parallel, mut serial) = (background(37, 29), background(37, 29));
+            renderer.render(&mut parallel);
+            renderer.render_serial(&mut serial);
+            assert_eq!(parallel.get_buffer(), expected.get_buffer(), "{} rows", rows);
+            assert_eq!(serial.get_buffer(), expected.get_buffer(), "{} rows", rows);
+        }
+    }
+
+    #[test]
+    fn tiles_respect_clip_and_palette() {
+        let sprite = SingleImageBuffer::from_colors(pattern(12 * 10, 4), 12, 10);
+        let palette = Palette::new(vec![Color(0, 0, 0, 255), Color(10, 20, 30, 255), Color(40, 50, 60, 255)]);
+        let setup = || {
+            let mut buffer = background(37, 29);
+            buffer.set_palette(Some(palette.clone()));
+            for x in 0..37 {
+                buffer.set_index(1 + (x % 2) as u8, x, 5);
+            }
+            buffer.push_clip(Rect::new(4, 2, 20, 15));
+            buffer
+        };
+
+        let mut expected = setup();
+        reference(&sprite, &mut expected);
+        let mut actual = setup();
+        recorded(&sprite, 4).render(&mut actual);
+        assert_eq!(actual.resolved(), expected.resolved());
+        // Indexed pixels outside the clip are left indexed.
+        assert_eq!(actual.get_index(30, 5), Some(1));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::input::InputInfo;
+use crate::frame::{FrameInfo, TimeSettings};
+use crate::game::GameState;
+use crate::image_buffer::CamBuffer;
+use game_loop::winit::event::{VirtualKeyCode, ElementState, MouseButton};
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use log::error;
+
+/// A single mutation of the `InputInfo`, as produced by the window event handler.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum InputEvent {
+    Key(VirtualKeyCode, ElementState),
+    MouseButton(MouseButton, ElementState),
+    MousePos(f64, f64),
+    MousePixelPos(u32, u32)
+}
+
+impl InputEvent {
+    pub fn apply(&self, input_info : &mut InputInfo) {
+        match *self {
+            InputEvent::Key(key, state) => input_info.set_key(key, state),
+            InputEvent::MouseButton(mb, state) => input_info.set_mouse_button(mb, state),
+            InputEvent::MousePos(x, y) => input_info.update_mouse_pos(x, y),
+            InputEvent::MousePixelPos(x, y) => input_info.update_mouse_pixel_pos(x, y)
+        }
+    }
+}
+
+/// Everything that happened between two calls to `GameState::update`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub update_delta : f64,
+    /// The time controls the frame started with. Older recordings don't have them and leave the
+    /// time controls alone.
+    #[serde(default)]
+    pub time : Option<TimeSettings>,
+    pub events : Vec<InputEvent>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub frames : Vec<RecordedFrame>
+}
+
+impl Recording {
+    pub fn load(path : &Path) -> io::Result<Recording> {
+        let contents = fs::read_to_string(path)?;
+        ron::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
+
+/// Collects input events as they arrive and closes them off into a frame on every tick.
+///
+/// A recorder with a file saves to it every `autosave_frames` frames and when dropped, so a
+/// crash loses at most the last few seconds.
+#[derive(Default)]
+pub struct InputRecorder {
+    recording : Recording,
+    pending : Vec<InputEvent>,
+    path : Option<PathBuf>,
+    autosave_frames : usize,
+    saved_frames : usize
+}
+
+impl InputRecorder {
+    pub fn new() -> InputRecorder {
+        InputRecorder::default()
+    }
+
+    /// Records to `path`, saving every `autosave_frames` frames, or only when dropped with 0.
+    pub fn to_file(path : PathBuf, autosave_frames : usize) -> InputRecorder {
+        let mut recorder = InputRecorder::new();
+        recorder.path = Some(path);
+        recorder.autosave_frames = autosave_frames;
+        recorder
+    }
+
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn record(&mut self, event : InputEvent) {
+        self.pending.push(event);
+    }
+
+    /// Closes off the frame about to be updated with `update_delta` under the `time` settings.
+    pub fn end_frame(&mut self, update_delta : f64, time : TimeSettings) {
+        let events = std::mem::take(&mut self.pending);
+        self.recording.frames.push(RecordedFrame { update_delta, time : Some(time), events });
+
+        if self.autosave_frames > 0 && self.frame_count() - self.saved_frames >= self.autosave_frames {
+            if let Err(e) = self.flush() {
+                error!("Unable to autosave input recording: {}", e);
+            }
+        }
+    }
+
+    /// Writes everything recorded so far to the recorder's file, if it has one.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.recording.save(path)?;
+            self.saved_frames = self.frame_count();
+        }
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.recording.frames.len()
+    }
+
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        self.recording.save(path)
+    }
+}
+
+impl Drop for InputRecorder {
+    fn drop(&mut self) {
+        if self.path.is_none() || self.saved_frames == self.frame_count() { return }
+        if let Err(e) = self.flush() {
+            error!("Unable to save input recording: {}", e);
+        }
+    }
+}
+
+/// Feeds a `Recording` back one frame per tick.
+pub struct InputPlayback {
+    recording : Recording,
+    current_frame : usize
+}
+
+impl InputPlayback {
+    pub fn new(recording : Recording) -> InputPlayback {
+        InputPlayback {
+            recording,
+            current_frame : 0
+        }
+    }
+
+    pub fn load(path : &Path) -> io::Result<InputPlayback> {
+        Ok(InputPlayback::new(Recording::load(path)?))
+    }
+
+    /// Applies the events of the next recorded frame to `input_info` and returns the frame, or
+    /// `None` once the recording has run out.
+    pub fn next_frame(&mut self, input_info : &mut InputInfo) -> Option<&RecordedFrame> {
+        let frame = self.recording.frames.get(self.current_frame)?;
+        for event in frame.events.iter() {
+            event.apply(input_info);
+        }
+        self.current_frame += 1;
+        Some(frame)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.recording.frames.len()
+    }
+}
+
+/// Replays a recording against `gs` without a window, rendering every frame into `main_buffer`
+/// so that render side effects are exercised exactly like in a windowed session.
+pub fn play_headless(gs : &mut GameState, mut playback : InputPlayback, main_buffer : &mut CamBuffer) {
+    let mut input_info = InputInfo::new();
+    let mut frame_info = FrameInfo::new(0.0);
+
+    while let Some(frame) = playback.next_frame(&mut input_info) {
+        let update_delta = frame.update_delta;
+        if let Some(time) = frame.time { gs.time.apply(time) }
+        frame_info.update_delta = update_delta;
+        gs.update(&frame_info, &input_info);
+        input_info.update();
+        frame_info.advance();
+
+        gs.render(main_buffer, &frame_info);
+        gs.post.apply(main_buffer, update_delta);
+        gs.capture.capture(main_buffer, update_delta);
+    }
+    gs.capture.stop_recording();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{BlendMode, Color};
+    use crate::comps::object::{GameComponent, GameObject};
+    use crate::comps::transform::TransformComponent;
+    use crate::image_buffer::ImageBuffer;
+    use crate::math::Vec2;
+    use std::any::Any;
+
+    const RUNNING : TimeSettings = TimeSettings { time_scale : 1.0, paused : false, pending_steps : 0 };
+
+    /// Walks right while the right arrow is held and paints where it stands.
+    struct Walker;
+
+    impl GameComponent for Walker {
+        fn update(&mut self, _frame_info : &FrameInfo, input_info : &InputInfo, transform : Option<&mut TransformComponent>) {
+            if let Some(t) = transform {
+                let (x, y) = t.pos.get_xy();
+                if input_info.get_key(VirtualKeyCode::Right) { t.pos.set_xy(x + 1, y) }
+                if input_info.get_mouse_button(MouseButton::Left) { t.pos.set_xy(x, y + 1) }
+            }
+        }
+
+        fn render(&mut self, main_buffer : &mut CamBuffer, _frame_info : &FrameInfo, transform : Option<&TransformComponent>) {
+            if let Some(t) = transform {
+                let (x, y) = t.pos.get_xy();
+                main_buffer.blend_pixel_with(Color(255, 0, 0, 255), x as usize, y as usize, BlendMode::Replace);
+            }
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn recorded_session() -> Recording {
+        let mut recorder = InputRecorder::new();
+        recorder.end_frame(0.5, RUNNING);
+        recorder.record(InputEvent::Key(VirtualKeyCode::Right, ElementState::Pressed));
+        for _ in 0..3 { recorder.end_frame(0.5, RUNNING) }
+        recorder.record(InputEvent::Key(VirtualKeyCode::Right, ElementState::Released));
+        recorder.record(InputEvent::MouseButton(MouseButton::Left, ElementState::Pressed));
+        recorder.end_frame(0.25, RUNNING);
+        recorder.record(InputEvent::MouseButton(MouseButton::Left, ElementState::Released));
+        recorder.end_frame(0.5, RUNNING);
+        recorder.recording.clone()
+    }
+
+    /// Runs the recording headless and gives where the walker ended up and what was drawn.
+    fn replay(recording : Recording) -> ((i32, i32), Vec<Color>) {
+        let mut gs = GameState::new();
+        let mut walker = GameObject::new("walker");
+        walker.add_comp(TransformComponent::from(1, 1));
+        walker.add_comp(Walker);
+        gs.add_gameobject(walker);
+
+        let mut buffer = CamBuffer::new(8, 8);
+        play_headless(&mut gs, InputPlayback::new(recording), &mut buffer);
+        let pos = gs.get_gameobject("walker").and_then(|o| o.get_transform()).unwrap().pos.get_xy();
+        (pos, buffer.get_buffer().clone())
+    }
+
+    #[test]
+    fn playback_applies_each_frame_once() {
+        let mut playback = InputPlayback::new(recorded_session());
+        let mut input_info = InputInfo::new();
+        assert_eq!(playback.frame_count(), 6);
+        assert_eq!(playback.next_frame(&mut input_info).map(|f| f.update_delta), Some(0.5));
+        assert!(!input_info.get_key(VirtualKeyCode::Right));
+        assert_eq!(playback.next_frame(&mut input_info).map(|f| f.update_delta), Some(0.5));
+        assert!(input_info.get_key(VirtualKeyCode::Right));
+        for _ in 0..4 { assert!(playback.next_frame(&mut input_info).is_some()) }
+        assert!(playback.next_frame(&mut input_info).is_none());
+    }
+
+    #[test]
+    fn replays_are_deterministic() {
+        let (pos, pixels) = replay(recorded_session());
+        // Three frames holding right, then one with the mouse down.
+        assert_eq!(pos, (4, 2));
+        assert_eq!(pixels[2 * 8 + 4], Color(255, 0, 0, 255));
+        assert_eq!(replay(recorded_session()), (pos, pixels));
+    }
+
+    #[test]
+    fn replays_follow_the_recorded_time_controls() {
+        let mut recorder = InputRecorder::new();
+        recorder.record(InputEvent::Key(VirtualKeyCode::Right, ElementState::Pressed));
+        recorder.end_frame(0.5, RUNNING);
+        let paused = TimeSettings { paused : true, ..RUNNING };
+        for _ in 0..3 { recorder.end_frame(0.5, paused) }
+        // A single step while paused, then it runs again at half speed.
+        recorder.end_frame(0.5, TimeSettings { pending_steps : 1, ..paused });
+        recorder.end_frame(0.5, TimeSettings { time_scale : 0.5, ..RUNNING });
+
+        let mut gs = GameState::new();
+        let mut walker = GameObject::new("walker");
+        walker.add_comp(TransformComponent::from(1, 1));
+        walker.add_comp(Walker);
+        gs.add_gameobject(walker);
+        let mut buffer = CamBuffer::new(8, 8);
+        play_headless(&mut gs, InputPlayback::new(recorder.recording.clone()), &mut buffer);
+        assert_eq!(gs.get_gameobject("walker").and_then(|o| o.get_transform()).unwrap().pos.get_xy(), (4, 1));
+        assert_eq!(gs.time.get_settings(), TimeSettings { time_scale : 0.5, ..RUNNING });
+        assert_eq!(gs.time.get_game_time(), 1.25);
+
+        // Recordings made before time controls were kept replay with whatever is set.
+        let old : Recording = ron::from_str("(frames:[(update_delta:0.5,events:[])])").unwrap();
+        assert_eq!(old.frames[0].time, Option::None);
+        play_headless(&mut gs, InputPlayback::new(old), &mut buffer);
+        assert_eq!(gs.time.get_game_time(), 1.5);
+    }
+
+    #[test]
+    fn recordings_survive_a_round_trip_and_autosave() {
+        let path = std::env::temp_dir().join(format!("blueberry_replay_{}.ron", std::process::id()));
+        let mut recorder = InputRecorder::to_file(path.clone(), 2);
+        recorder.record(InputEvent::MousePos(3.5, 4.0));
+        recorder.end_frame(0.5, RUNNING);
+        assert!(!path.exists());
+        recorder.end_frame(0.5, RUNNING);
+        assert_eq!(Recording::load(&path).unwrap().frames.len(), 2);
+
+        // Whatever wasn't autosaved yet is written when the recorder goes away.
+        recorder.record(InputEvent::MousePixelPos(1, 2));
+        recorder.end_frame(0.25, RUNNING);
+        drop(recorder);
+        let loaded = Recording::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.frames.len(), 3);
+        assert_eq!(loaded.frames[2].update_delta, 0.25);
+        assert!(matches!(loaded.frames[0].events[..], [InputEvent::MousePos(x, y)] if x == 3.5 && y == 4.0));
+
+        let (pos, pixels) = replay(recorded_session());
+        let text = ron::to_string(&recorded_session()).unwrap();
+        assert_eq!(replay(ron::from_str(&text).unwrap()), (pos, pixels));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::color::Color;
+use crate::config::GameConfig;
+use pixels::{wgpu, Pixels, PixelsBuilder, SurfaceTexture};
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use winit::window::Window;
+
+/// How the logical resolution is fit into the window.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScalingMode {
+    /// The largest whole multiple that fits, letterboxed. Every game pixel is the same size.
+    Integer,
+    /// As large as fits while keeping the aspect ratio, letterboxed.
+    Fit,
+    /// Fills the whole window, ignoring the aspect ratio.
+    Stretch
+}
+
+impl ScalingMode {
+    /// Where a `texture` sized image lands in a `surface` sized window, as x, y, width, height in
+    /// physical pixels. Never larger than the surface.
+    pub fn viewport(&self, texture : (u32, u32), surface : (u32, u32)) -> (f32, f32, f32, f32) {
+        let (texture_width, texture_height) = (texture.0.max(1) as f32, texture.1.max(1) as f32);
+        let (surface_width, surface_height) = (surface.0.max(1) as f32, surface.1.max(1) as f32);
+        let fit = (surface_width / texture_width).min(surface_height / texture_height);
+
+        let (width, height) = match self {
+            ScalingMode::Stretch => return (0.0, 0.0, surface_width, surface_height),
+            // A window smaller than the game can't fit a whole multiple, so it shrinks instead.
+            ScalingMode::Integer if fit >= 1.0 => (texture_width * fit.floor(), texture_height * fit.floor()),
+            _ => (texture_width * fit, texture_height * fit)
+        };
+        (((surface_width - width) / 2.0).floor(), ((surface_height - height) / 2.0).floor(), width, height)
+    }
+
+    /// The game pixel under a physical window position, clamped to the screen.
+    pub fn window_to_pixel(&self, texture : (u32, u32), surface : (u32, u32), x : f64, y : f64) -> (u32, u32) {
+        let (vx, vy, vw, vh) = self.viewport(texture, surface);
+        let (width, height) = texture;
+        let px = ((x - vx as f64) * width as f64 / vw as f64).floor().clamp(0.0, width.saturating_sub(1) as f64);
+        let py = ((y - vy as f64) * height as f64 / vh as f64).floor().clamp(0.0, height.saturating_sub(1) as f64);
+        (px as u32, py as u32)
+    }
+}
+
+pub fn create_pixels(window : &Window, config : &GameConfig) -> Result<Pixels, pixels::Error> {
+    let size = window.inner_size();
+    let surface_texture = SurfaceTexture::new(size.width, size.height, window);
+    PixelsBuilder::new(config.width, config.height, surface_texture)
+        .enable_vsync(config.vsync)
+        .build()
+}
+
+const SHADER : &str = r#"
+struct VertexOutput {
+    [[location(0)]] tex_coord: vec2<f32>;
+    [[builtin(position)]] position: vec4<f32>;
+};
+
+// One triangle covering the viewport, its texture coordinates flipped so v runs downwards.
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    let x = f32((index << 1u) & 2u);
+    let y = f32(index & 2u);
+    var out: VertexOutput;
+    out.tex_coord = vec2<f32>(x, 1.0 - y);
+    out.position = vec4<f32>(x * 2.0 - 1.0, y * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+[[group(0), binding(0)]] var r_tex_color: texture_2d<f32>;
+[[group(0), binding(1)]] var r_tex_sampler: sampler;
+
+[[stage(fragment)]]
+fn fs_main([[location(0)]] tex_coord: vec2<f32>) -> [[location(0)]] vec4<f32> {
+    return textureSample(r_tex_color, r_tex_sampler, tex_coord);
+}
+"#;
+
+/// Draws the pixels texture into the window with a `ScalingMode`, filling the rest with the
+/// letterbox color. Replaces pixels' own renderer, which only scales by whole multiples.
+pub struct ScreenRenderer {
+    pipeline : wgpu::RenderPipeline,
+    bind_group_layout : wgpu::BindGroupLayout,
+    sampler : wgpu::Sampler,
+    bind_group : wgpu::BindGroup,
+    srgb : bool,
+    pub mode : ScalingMode,
+    pub letterbox : Color,
+    texture_size : (u32, u32),
+    surface_size : (u32, u32)
+}
+
+impl ScreenRenderer {
+    pub fn new(pixels : &Pixels, window : &Window, mode : ScalingMode, letterbox : Color) -> ScreenRenderer {
+        let device = pixels.device();
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label : Some("screen_shader"),
+            source : wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER))
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label : Some("screen_sampler"),
+            mag_filter : wgpu::FilterMode::Nearest,
+            min_filter : wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label : Some("screen_bind_group_layout"),
+            entries : &[
+                wgpu::BindGroupLayoutEntry {
+                    binding : 0,
+                    visibility : wgpu::ShaderStages::FRAGMENT,
+                    ty : wgpu::BindingType::Texture {
+                        sample_type : wgpu::TextureSampleType::Float { filterable : true },
+                        multisampled : false,
+                        view_dimension : wgpu::TextureViewDimension::D2
+                    },
+                    count : None
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding : 1,
+                    visibility : wgpu::ShaderStages::FRAGMENT,
+                    ty : wgpu::BindingType::Sampler { filtering : true, comparison : false },
+                    count : None
+                }
+            ]
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label : Some("screen_pipeline_layout"),
+            bind_group_layouts : &[&bind_group_layout],
+            push_constant_ranges : &[]
+        });
+        let format = pixels.render_texture_format();
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label : Some("screen_pipeline"),
+            layout : Some(&pipeline_layout),
+            vertex : wgpu::VertexState { module : &module, entry_point : "vs_main", buffers : &[] },
+            primitive : wgpu::PrimitiveState::default(),
+            depth_stencil : None,
+            multisample : wgpu::MultisampleState::default(),
+            fragment : Some(wgpu::FragmentState {
+                module : &module,
+                entry_point : "fs_main",
+                targets : &[wgpu::ColorTargetState {
+                    format,
+                    blend : Some(wgpu::BlendState::REPLACE),
+                    write_mask : wgpu::ColorWrites::ALL
+                }]
+            })
+        });
+
+        let bind_group = ScreenRenderer::create_bind_group(pixels, &bind_group_layout, &sampler);
+        let extent = pixels.context().texture_extent;
+        let size = window.inner_size();
+
+        ScreenRenderer {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            bind_group,
+            srgb : format.describe().srgb,
+            mode,
+            letterbox,
+            texture_size : (extent.width, extent.height),
+            surface_size : (size.width, size.height)
+        }
+    }
+
+    fn create_bind_group(pixels : &Pixels, layout : &wgpu::BindGroupLayout, sampler : &wgpu::Sampler) -> wgpu::BindGroup {
+        let view = pixels.texture().create_view(&wgpu::TextureViewDescriptor::default());
+        pixels.device().create_bind_group(&wgpu::BindGroupDescriptor {
+            label : Some("screen_bind_group"),
+            layout,
+            entries : &[
+                wgpu::BindGroupEntry { binding : 0, resource : wgpu::BindingResource::TextureView(&view) },
+                wgpu::BindGroupEntry { binding : 1, resource : wgpu::BindingResource::Sampler(sampler) }
+            ]
+        })
+    }
+
+    /// Picks up the new texture after `Pixels::resize_buffer`.
+    pub fn rebind(&mut self, pixels : &Pixels) {
+        self.bind_group = ScreenRenderer::create_bind_group(pixels, &self.bind_group_layout, &self.sampler);
+        let extent = pixels.context().texture_extent;
+        self.texture_size = (extent.width, extent.height);
+    }
+
+    pub fn resize(&mut self, width : u32, height : u32) {
+        self.surface_size = (width, height);
+    }
+
+    pub fn viewport(&self) -> (f32, f32, f32, f32) {
+        self.mode.viewport(self.texture_size, self.surface_size)
+    }
+
+    /// The game pixel under a physical window position, clamped to the screen.
+    pub fn window_to_pixel(&self, x : f64, y : f64) -> (u32, u32) {
+        self.mode.window_to_pixel(self.texture_size, self.surface_size, x, y)
+    }
+
+    pub fn render(&self, encoder : &mut wgpu::CommandEncoder, render_target : &wgpu::TextureView) {
+        // Clear colors are linear, so sRGB targets need the letterbox color decoded first.
+        let channel = |c : u8| {
+            let c = c as f64 / 255.0;
+            if !self.srgb { c } else if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        let Color(r, g, b, _) = self.letterbox;
+        let clear = wgpu::Color { r : channel(r), g : channel(g), b : channel(b), a : 1.0 };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label : Some("screen_render_pass"),
+            color_attachments : &[wgpu::RenderPassColorAttachment {
+                view : render_target,
+                resolve_target : None,
+                ops : wgpu::Operations { load : wgpu::LoadOp::Clear(clear), store : true }
+            }],
+            depth_stencil_attachment : None
+        });
+
+        let (x, y, width, height) = self.viewport();
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.set_viewport(x, y, width, height, 0.0, 1.0);
+        rpass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAME : (u32, u32) = (320, 180);
+
+    #[test]
+    fn integer_scaling_uses_whole_multiples() {
+        assert_eq!(ScalingMode::Integer.viewport(GAME, (1280, 720)), (0.0, 0.0, 1280.0, 720.0));
+        // 3.5 times rounds down to 3, centred.
+        assert_eq!(ScalingMode::Integer.viewport(GAME, (1120, 700)), (80.0, 80.0, 960.0, 540.0));
+        assert_eq!(ScalingMode::Integer.viewport(GAME, (1000, 1000)), (20.0, 230.0, 960.0, 540.0));
+    }
+
+    #[test]
+    fn fit_keeps_the_aspect_ratio() {
+        assert_eq!(ScalingMode::Fit.viewport(GAME, (1120, 700)), (0.0, 35.0, 1120.0, 630.0));
+        assert_eq!(ScalingMode::Fit.viewport(GAME, (640, 1000)), (0.0, 320.0, 640.0, 360.0));
+    }
+
+    #[test]
+    fn stretch_fills_the_window() {
+        assert_eq!(ScalingMode::Stretch.viewport(GAME, (1000, 300)), (0.0, 0.0, 1000.0, 300.0));
+        assert_eq!(ScalingMode::Stretch.viewport(GAME, (100, 50)), (0.0, 0.0, 100.0, 50.0));
+    }
+
+    #[test]
+    fn windows_smaller_than_the_game_shrink_it() {
+        for mode in [ScalingMode::Integer, ScalingMode::Fit] {
+            assert_eq!(mode.viewport(GAME, (160, 160)), (0.0, 35.0, 160.0, 90.0), "{:?}", mode);
+        }
+        // A minimized window still gives a viewport that can be divided by.
+        assert_eq!(ScalingMode::Integer.viewport(GAME, (0, 0)), (0.0, 0.0, 1.0, 0.5625));
+        assert_eq!(ScalingMode::Integer.window_to_pixel(GAME, (0, 0), 0.5, 0.5), (160, 160));
+    }
+
+    #[test]
+    fn window_positions_map_to_game_pixels() {
+        let integer = |x, y| ScalingMode::Integer.window_to_pixel(GAME, (1120, 700), x, y);
+        assert_eq!(integer(80.0, 80.0), (0, 0));
+        assert_eq!(integer(82.9, 82.9), (0, 0));
+        assert_eq!(integer(83.0, 83.0), (1, 1));
+        assert_eq!(integer(1039.9, 619.9), (319, 179));
+        // The letterbox clamps to the nearest edge pixel.
+        assert_eq!(integer(0.0, 0.0), (0, 0));
+        assert_eq!(integer(1119.0, 699.0), (319, 179));
+
+        let stretch = |x, y| ScalingMode::Stretch.window_to_pixel(GAME, (640, 720), x, y);
+        assert_eq!(stretch(2.0, 4.0), (1, 1));
+        assert_eq!(stretch(639.0, 719.0), (319, 179));
+
+        // At half size every window pixel covers two game pixels.
+        let small = |x, y| ScalingMode::Fit.window_to_pixel(GAME, (160, 160), x, y);
+        assert_eq!(small(0.0, 35.0), (0, 0));
+        assert_eq!(small(1.0, 36.0), (2, 2));
+        assert_eq!(small(159.0, 124.0), (318, 178));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::color::Color;
+use crate::image_buffer::{ImageBuffer, ImageView, SingleImageBuffer};
+use crate::math::Rect;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Scales are bucketed to this many steps per unit so a tweened scale doesn't fill the cache.
+const SCALE_STEPS : f64 = 64.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SpriteKey {
+    pub image : u64,
+    pub region : Rect,
+    pub angle_step : u32,
+    pub scale : (i32, i32),
+    pub flip_x : bool,
+    pub flip_y : bool
+}
+
+/// A transformed copy of a sprite. `center` is where the centre of the source image ended up.
+pub struct CachedSprite {
+    pub image : SingleImageBuffer,
+    pub center : (f64, f64),
+    last_used : u64,
+    pinned : bool
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SpriteCacheStats {
+    pub hits : u64,
+    pub misses : u64,
+    pub evictions : u64
+}
+
+/// Remembers RotSprite results so rotating sprites only pay for it when the quantized angle,
+/// scale or flip actually changes. Least recently used entries are evicted once the cache holds
+/// more than `budget` bytes of pixels; pre-baked entries are never evicted.
+pub struct SpriteCache {
+    entries : HashMap<SpriteKey, CachedSprite>,
+    angle_steps : u32,
+    budget : usize,
+    used : usize,
+    clock : u64,
+    stats : SpriteCacheStats
+}
+
+impl SpriteCache {
+    /// `angle_steps` is how many distinct angles a full turn is quantized to.
+    pub fn new(budget : usize, angle_steps : u32) -> SpriteCache {
+        SpriteCache {
+            entries : HashMap::new(),
+            angle_steps : angle_steps.max(1),
+            budget,
+            used : 0,
+            clock : 0,
+            stats : SpriteCacheStats::default()
+        }
+    }
+
+    pub fn shared(budget : usize, angle_steps : u32) -> Rc<RefCell<SpriteCache>> {
+        Rc::new(RefCell::new(SpriteCache::new(budget, angle_steps)))
+    }
+
+    /// The key for `source` drawn with the given transform. Views into the same image are told
+    /// apart by their region.
+    pub fn key(&self, source : &ImageView, angle : f64, scale_x : f64, scale_y : f64, flip_x : bool, flip_y : bool) -> SpriteKey {
+        let steps = self.angle_steps as f64;
+        let angle_step = ((angle.rem_euclid(360.0) / 360.0 * steps).round() as u32) % self.angle_steps;
+        let scale = ((scale_x.abs() * SCALE_STEPS).round() as i32, (scale_y.abs() * SCALE_STEPS).round() as i32);
+        SpriteKey { image : source.get_id(), region : source.get_rect(), angle_step, scale, flip_x, flip_y }
+    }
+
+    /// The angle, in degrees, a key's `angle_step` stands for.
+    pub fn step_angle(&self, angle_step : u32) -> f64 {
+        angle_step as f64 * 360.0 / self.angle_steps as f64
+    }
+
+    /// Looks up the sprite for `key`, rendering it from `source` on a miss.
+    pub fn get_or_insert(&mut self, key : SpriteKey, source : &ImageView) -> &CachedSprite {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if self.entries.contains_key(&key) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            let sprite = self.render(key, source);
+            self.insert(key, sprite);
+        }
+
+        let entry = self.entries.get_mut(&key).unwrap();
+        entry.last_used = clock;
+        entry
+    }
+
+    /// Renders `angles` evenly spaced angles of `source` with the given scale and flips up front
+    /// and keeps them for good. Angles still snap to the cache's own steps, so asking for more
+    /// angles than it has steps bakes every step once.
+    pub fn prebake(&mut self, source : &ImageView, angles : u32, scale_x : f64, scale_y : f64, flip_x : bool, flip_y : bool) {
+        for i in 0..angles {
+            let key = self.key(source, i as f64 * 360.0 / angles as f64, scale_x, scale_y, flip_x, flip_y);
+            let mut sprite = self.render(key, source);
+            sprite.pinned = true;
+            self.insert(key, sprite);
+        }
+    }
+
+    /// Drops everything cached for every region of `image`, e.g. after its pixels were replaced.
+    pub fn invalidate(&mut self, image : u64) {
+        let used = &mut self.used;
+        self.entries.retain(|key, entry| {
+            if key.image != image { return true }
+            *used -= entry.image.get_buffer().len() * std::mem::size_of::<Color>();
+            false
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    pub fn get_stats(&self) -> SpriteCacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of pixel data currently held.
+    pub fn get_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn get_budget(&self) -> usize {
+        self.budget
+    }
+
+    fn insert(&mut self, key : SpriteKey, sprite : CachedSprite) {
+        self.used += sprite.image.get_buffer().len() * std::mem::size_of::<Color>();
+        if let Some(old) = self.entries.insert(key, sprite) {
+            self.used -= old.image.get_buffer().len() * std::mem::size_of::<Color>();
+        }
+
+        while self.used > self.budget {
+            let oldest = self.entries.iter()
+                .filter(|(k, e)| !e.pinned && **k != key)
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| *k);
+
+            match oldest {
+                Some(k) => {
+                    let entry = self.entries.remove(&k).unwrap();
+                    self.used -= entry.image.get_buffer().len() * std::mem::size_of::<Color>();
+                    self.stats.evictions += 1;
+                }
+                None => break
+            }
+        }
+    }
+
+    /// Scales, rotates with RotSprite and then flips, the order `DrawParams` transforms in. A
+    /// single mirror reverses the direction of a rotation, so mirrored sprites are rotated the
+    /// other way to match flip-then-rotate.
+    fn render(&self, key : SpriteKey, source : &ImageView) -> CachedSprite {
+        let mut angle = self.step_angle(key.angle_step);
+        if key.flip_x != key.flip_y { angle = -angle }
+
+        let (scale_x, scale_y) = (key.scale.0 as f64 / SCALE_STEPS, key.scale.1 as f64 / SCALE_STEPS);
+        let mut image = if scale_x != 1.0 || scale_y != 1.0 {
+            let (w, h, buffer) = source.scaled(scale_x, scale_y);
+            SingleImageBuffer::from_colors(buffer, w, h)
+        } else {
+            source.to_image()
+        };
+
+        if angle % 360.0 != 0.0 && !image.get_buffer().is_empty() {
+            let rotated = image.as_view().rotate_safe(angle, SingleImageBuffer::new(0, 0));
+            image = rotated;
+        }
+
+        let (w, h) = image.get_dimensions();
+        if key.flip_x || key.flip_y {
+            let buffer = image.get_buffer_mut();
+            if key.flip_x { buffer.chunks_exact_mut(w.max(1)).for_each(|row| row.reverse()) }
+            if key.flip_y {
+                for j in 0..h / 2 {
+                    for i in 0..w {
+                        buffer.swap(i + j * w, i + (h - 1 - j) * w);
+                    }
+                }
+            }
+        }
+
+        CachedSprite { image, center : (w as f64 / 2.0, h as f64 / 2.0), last_used : 0, pinned : false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each 4x4 sprite holds 64 bytes of pixels.
+    const SPRITE : usize = 16 * std::mem::size_of::<Color>();
+
+    fn sprite() -> SingleImageBuffer {
+        SingleImageBuffer::from_colors((0..16).map(|i| Color(i, 0, 0, 255)).collect(), 4, 4)
+    }
+
+    fn flipped(cache : &mut SpriteCache, view : &ImageView, flip_x : bool, flip_y : bool) {
+        let key = cache.key(view, 0.0, 1.0, 1.0, flip_x, flip_y);
+        cache.get_or_insert(key, view);
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_first() {
+        let image = sprite();
+        let view = image.view(0, 0, 4, 4);
+        let mut cache = SpriteCache::new(3 * SPRITE, 4);
+        flipped(&mut cache, &view, false, false);
+        flipped(&mut cache, &view, true, false);
+        flipped(&mut cache, &view, false, true);
+        flipped(&mut cache, &view, false, false);
+        assert_eq!(cache.get_used(), 3 * SPRITE);
+
+        // The x flip is now the oldest and makes room for the xy flip.
+        flipped(&mut cache, &view, true, true);
+        assert_eq!((cache.len(), cache.get_used(), cache.get_stats().evictions), (3, 3 * SPRITE, 1));
+        let misses = cache.get_stats().misses;
+        flipped(&mut cache, &view, false, false);
+        flipped(&mut cache, &view, false, true);
+        assert_eq!(cache.get_stats().misses, misses);
+        flipped(&mut cache, &view, true, false);
+        assert_eq!(cache.get_stats().misses, misses + 1);
+    }
+
+    #[test]
+    fn entries_over_budget_are_kept_while_in_use() {
+        let image = sprite();
+        let view = image.view(0, 0, 4, 4);
+        let mut cache = SpriteCache::new(SPRITE / 2, 4);
+        flipped(&mut cache, &view, false, false);
+        assert_eq!((cache.len(), cache.get_used()), (1, SPRITE));
+        flipped(&mut cache, &view, true, false);
+        assert_eq!((cache.len(), cache.get_used()), (1, SPRITE));
+        assert_eq!(cache.get_stats().evictions, 1);
+    }
+
+    #[test]
+    fn prebaked_entries_are_pinned() {
+        let image = sprite();
+        let view = image.view(0, 0, 4, 4);
+        let mut cache = SpriteCache::new(0, 4);
+        cache.prebake(&view, 2, 1.0, 1.0, true, false);
+        assert_eq!(cache.len(), 2);
+        flipped(&mut cache, &view, false, false);
+        flipped(&mut cache, &view, false, true);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get_stats().evictions, 1);
+
+        let (hits, misses) = (cache.get_stats().hits, cache.get_stats().misses);
+        for angle in [0.0, 180.0] {
+            let key = cache.key(&view, angle, 1.0, 1.0, true, false);
+            cache.get_or_insert(key, &view);
+        }
+        assert_eq!(cache.get_stats().hits, hits + 2);
+        assert_eq!(cache.get_stats().misses, misses);
+    }
+
+    #[test]
+    fn prebake_uses_the_requested_transform() {
+        let image = sprite();
+        let view = image.view(0, 0, 4, 4);
+        let mut cache = SpriteCache::new(0, 8);
+        cache.prebake(&view, 16, 2.0, 0.5, false, true);
+        assert_eq!(cache.len(), 8);
+        let key = cache.key(&view, 0.0, 2.0, 0.5, false, true);
+        assert_eq!(cache.get_or_insert(key, &view).image.get_dimensions(), (8, 2));
+        assert_eq!(cache.get_stats().misses, 0);
+        cache.invalidate(image.get_id());
+        assert_eq!((cache.len(), cache.get_used()), (0, 0));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::frame::FrameInfo;
+use crate::game::GameState;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+pub type Command = Box<dyn FnOnce(&mut GameState)>;
+
+#[derive(Copy, Clone, Default)]
+struct Clock {
+    elapsed : f64,
+    frame : u64
+}
+
+/// State shared between the `Scheduler` and every `TaskContext` it has handed out.
+#[derive(Default)]
+struct Shared {
+    clock : Cell<Clock>,
+    incoming : RefCell<Vec<Task>>,
+    commands : RefCell<Vec<Command>>,
+    next_id : Cell<u64>
+}
+
+struct Task {
+    id : u64,
+    owner : Option<String>,
+    cancelled : Rc<Cell<bool>>,
+    future : Pin<Box<dyn Future<Output = ()>>>
+}
+
+/// Returned from `spawn`, lets the caller stop a task before it finishes.
+#[derive(Clone)]
+pub struct TaskHandle {
+    id : u64,
+    cancelled : Rc<Cell<bool>>
+}
+
+impl TaskHandle {
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Runs async tasks on the game loop. Every task is polled exactly once per `GameState::update`,
+/// after all game objects have been updated, and only while game time is running.
+///
+/// ```ignore
+/// gs.scheduler.spawn_owned("door", |ctx| async move {
+///     ctx.wait_seconds(2.0).await;
+///     ctx.run(|gs| gs.remove_gameobject("door"));
+/// });
+/// ```
+pub struct Scheduler {
+    shared : Rc<Shared>,
+    tasks : Vec<Task>
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            shared : Rc::new(Shared::default()),
+            tasks : Vec::new()
+        }
+    }
+
+    fn context(&self) -> TaskContext {
+        TaskContext { shared : self.shared.clone() }
+    }
+
+    /// Starts a task that lives until it completes or is cancelled through its handle.
+    pub fn spawn<F, Fut>(&mut self, f : F) -> TaskHandle where F: FnOnce(TaskContext) -> Fut, Fut: 'static + Future<Output = ()> {
+        self.context().spawn(f)
+    }
+
+    /// Starts a task that is cancelled as soon as the GameObject named `owner` is removed.
+    pub fn spawn_owned<F, Fut>(&mut self, owner : &str, f : F) -> TaskHandle where F: FnOnce(TaskContext) -> Fut, Fut: 'static + Future<Output = ()> {
+        self.context().spawn_owned(owner, f)
+    }
+
+    pub fn cancel_owned(&mut self, owner : &str) {
+        let owned_by = |task : &Task| task.owner.as_deref() == Some(owner);
+        self.tasks.iter().filter(|t| owned_by(t)).for_each(|t| t.cancelled.set(true));
+        self.shared.incoming.borrow().iter().filter(|t| owned_by(t)).for_each(|t| t.cancelled.set(true));
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len() + self.shared.incoming.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Advances the task clock and polls every live task once.
+    pub fn update(&mut self, frame_info : &FrameInfo) {
+        let mut clock = self.shared.clock.get();
+        clock.elapsed += frame_info.update_delta;
+        clock.frame += 1;
+        self.shared.clock.set(clock);
+
+        self.tasks.append(&mut self.shared.incoming.borrow_mut());
+
+        let mut cx = Context::from_waker(Waker::noop());
+        self.tasks.retain_mut(|task| {
+            if task.cancelled.get() { return false }
+            task.future.as_mut().poll(&mut cx).is_pending()
+        });
+    }
+
+    /// Hands over the commands queued by tasks through `TaskContext::run` this update.
+    pub fn take_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.shared.commands.borrow_mut())
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+/// Passed into every task. Cheap to clone, so it can be moved into nested tasks.
+#[derive(Clone)]
+pub struct TaskContext {
+    shared : Rc<Shared>
+}
+
+impl TaskContext {
+    fn push(&self, owner : Option<String>, future : Pin<Box<dyn Future<Output = ()>>>) -> TaskHandle {
+        let id = self.shared.next_id.get();
+        self.shared.next_id.set(id + 1);
+
+        let cancelled = Rc::new(Cell::new(false));
+        self.shared.incoming.borrow_mut().push(Task { id, owner, cancelled : cancelled.clone(), future });
+        TaskHandle { id, cancelled }
+    }
+
+    pub fn spawn<F, Fut>(&self, f : F) -> TaskHandle where F: FnOnce(TaskContext) -> Fut, Fut: 'static + Future<Output = ()> {
+        self.push(None, Box::pin(f(self.clone())))
+    }
+
+    pub fn spawn_owned<F, Fut>(&self, owner : &str, f : F) -> TaskHandle where F: FnOnce(TaskContext) -> Fut, Fut: 'static + Future<Output = ()> {
+        self.push(Some(String::from(owner)), Box::pin(f(self.clone())))
+    }
+
+    /// Queues `f` to run against the `GameState` once all tasks have been polled this update.
+    pub fn run<F>(&self, f : F) where F: 'static + FnOnce(&mut GameState) {
+        self.shared.commands.borrow_mut().push(Box::new(f));
+    }
+
+    /// Game time the scheduler has been running for, in seconds.
+    pub fn elapsed(&self) -> f64 {
+        self.shared.clock.get().elapsed
+    }
+
+    /// Number of updates the scheduler has been polled for.
+    pub fn frame(&self) -> u64 {
+        self.shared.clock.get().frame
+    }
+
+    pub fn wait_seconds(&self, seconds : f64) -> WaitSeconds {
+        WaitSeconds { shared : self.shared.clone(), seconds, deadline : None }
+    }
+
+    pub fn wait_frames(&self, frames : u64) -> WaitFrames {
+        WaitFrames { shared : self.shared.clone(), frames, deadline : None }
+    }
+
+    pub fn wait_until<P>(&self, predicate : P) -> WaitUntil<P> where P: FnMut() -> bool {
+        WaitUntil { predicate }
+    }
+}
+
+/// Resolves once `seconds` of game time have passed since it was first awaited.
+pub struct WaitSeconds {
+    shared : Rc<Shared>,
+    seconds : f64,
+    deadline : Option<f64>
+}
+
+impl Future for WaitSeconds {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, _cx : &mut Context<'_>) -> Poll<()> {
+        let now = self.shared.clock.get().elapsed;
+        let seconds = self.seconds;
+        let deadline = *self.deadline.get_or_insert(now + seconds);
+        if now >= deadline { Poll::Ready(()) } else { Poll::Pending }
+    }
+}
+
+/// Resolves `frames` updates after it was first awaited. `wait_frames(1)` resumes next update.
+pub struct WaitFrames {
+    shared : Rc<Shared>,
+    frames : u64,
+    deadline : Option<u64>
+}
+
+impl Future for WaitFrames {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, _cx : &mut Context<'_>) -> Poll<()> {
+        let now = self.shared.clock.get().frame;
+        let frames = self.frames;
+        let deadline = *self.deadline.get_or_insert(now + frames);
+        if now >= deadline { Poll::Ready(()) } else { Poll::Pending }
+    }
+}
+
+/// Resolves on the first update where `predicate` returns true.
+pub struct WaitUntil<P> {
+    predicate : P
+}
+
+impl<P> Future for WaitUntil<P> where P: FnMut() -> bool + Unpin {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, _cx : &mut Context<'_>) -> Poll<()> {
+        if (self.predicate)() { Poll::Ready(()) } else { Poll::Pending }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comps::object::GameObject;
+
+    type Log = Rc<RefCell<Vec<&'static str>>>;
+
+    /// Runs `updates` updates a quarter second apart and gives the frames `log` grew on.
+    fn run(scheduler : &mut Scheduler, log : &Log, updates : usize) -> Vec<usize> {
+        let frame_info = FrameInfo::new(0.25);
+        let mut grew = Vec::new();
+        for i in 1..=updates {
+            let before = log.borrow().len();
+            scheduler.update(&frame_info);
+            if log.borrow().len() > before { grew.push(i) }
+        }
+        grew
+    }
+
+    #[test]
+    fn wait_seconds_counts_from_the_first_await() {
+        let mut scheduler = Scheduler::new();
+        let log = Log::default();
+        let task_log = log.clone();
+        scheduler.spawn(|ctx| async move {
+            ctx.wait_seconds(0.5).await;
+            task_log.borrow_mut().push("half");
+            ctx.wait_seconds(0.0).await;
+            task_log.borrow_mut().push("zero");
+        });
+        assert_eq!(run(&mut scheduler, &log, 5), vec![3]);
+        assert_eq!(*log.borrow(), vec!["half", "zero"]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn wait_frames_resumes_that_many_updates_later() {
+        let mut scheduler = Scheduler::new();
+        let log = Log::default();
+        let task_log = log.clone();
+        scheduler.spawn(|ctx| async move {
+            for _ in 0..2 {
+                ctx.wait_frames(2).await;
+                task_log.borrow_mut().push("tick");
+            }
+        });
+        assert_eq!(run(&mut scheduler, &log, 6), vec![3, 5]);
+    }
+
+    #[test]
+    fn wait_until_checks_once_per_update() {
+        let mut scheduler = Scheduler::new();
+        let log = Log::default();
+        let (task_log, open) = (log.clone(), Rc::new(Cell::new(false)));
+        let door = open.clone();
+        scheduler.spawn(|ctx| async move {
+            ctx.wait_until(move || door.get()).await;
+            task_log.borrow_mut().push("open");
+        });
+        assert!(run(&mut scheduler, &log, 3).is_empty());
+        open.set(true);
+        assert_eq!(run(&mut scheduler, &log, 2), vec![1]);
+    }
+
+    #[test]
+    fn cancelled_tasks_stop_running() {
+        let mut scheduler = Scheduler::new();
+        let log = Log::default();
+        let (first, second) = (log.clone(), log.clone());
+        let handle = scheduler.spawn(|ctx| async move {
+            loop {
+                first.borrow_mut().push("handle");
+                ctx.wait_frames(1).await;
+            }
+        });
+        scheduler.spawn_owned("door", |ctx| async move {
+            loop {
+                second.borrow_mut().push("owned");
+                ctx.wait_frames(1).await;
+            }
+        });
+        run(&mut scheduler, &log, 1);
+        handle.cancel();
+        scheduler.cancel_owned("door");
+        assert!(run(&mut scheduler, &log, 2).is_empty());
+        assert!(scheduler.is_empty());
+        assert!(handle.is_cancelled());
+    }
+
+    #[test]
+    fn commands_run_against_the_game_state() {
+        let mut gs = GameState::new();
+        gs.scheduler.spawn(|ctx| async move {
+            ctx.wait_frames(1).await;
+            ctx.run(|gs| gs.add_gameobject(GameObject::new("spawned")));
+        });
+        gs.update(&FrameInfo::new(0.25), &crate::input::InputInfo::new());
+        assert!(gs.get_gameobject("spawned").is_none());
+        gs.update(&FrameInfo::new(0.25), &crate::input::InputInfo::new());
+        assert!(gs.get_gameobject("spawned").is_some());
+    }
+
+    #[test]
+    fn removing_or_replacing_an_object_cancels_its_tasks() {
+        let mut gs = GameState::new();
+        for _ in 0..2 {
+            gs.add_gameobject(GameObject::new("door"));
+            let handle = gs.scheduler.spawn_owned("door", |ctx| async move { ctx.wait_seconds(10.0).await });
+            gs.update(&FrameInfo::new(0.25), &crate::input::InputInfo::new());
+            assert!(!handle.is_cancelled());
+            gs.add_gameobject(GameObject::new("door"));
+            assert!(handle.is_cancelled());
+        }
+        let handle = gs.scheduler.spawn_owned("door", |ctx| async move { ctx.wait_seconds(10.0).await });
+        gs.remove_gameobject("door");
+        assert!(handle.is_cancelled());
+        gs.update(&FrameInfo::new(0.25), &crate::input::InputInfo::new());
+        assert!(gs.scheduler.is_empty());
+    }
+}
@@ -0,0 +1,570 @@
+use crate::color::Color;
+use crate::comps::object::GameComponent;
+use crate::comps::transform::TransformComponent;
+use crate::frame::FrameInfo;
+use crate::input::InputInfo;
+use crate::math::{Vec2, Vec2f, Vec2i};
+use imgui::Ui;
+use std::any::Any;
+use std::f64::consts::PI;
+
+/// Standard easing curves. Every curve maps `0.0 -> 0.0` and `1.0 -> 1.0`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Ease {
+    Linear,
+    QuadIn, QuadOut, QuadInOut,
+    CubicIn, CubicOut, CubicInOut,
+    ElasticIn, ElasticOut, ElasticInOut,
+    BounceIn, BounceOut, BounceInOut,
+    BackIn, BackOut, BackInOut
+}
+
+impl Ease {
+    pub fn apply(&self, t : f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Ease::Linear => t,
+            Ease::QuadIn => t * t,
+            Ease::QuadOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Ease::QuadInOut => if t < 0.5 { 2.0 * t * t } else { 1.0 - (-2.0 * t + 2.0).powi(2) / 2.0 },
+            Ease::CubicIn => t * t * t,
+            Ease::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Ease::CubicInOut => if t < 0.5 { 4.0 * t * t * t } else { 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0 },
+            Ease::ElasticIn => {
+                if t == 0.0 || t == 1.0 { return t }
+                -(2f64.powf(10.0 * t - 10.0)) * ((t * 10.0 - 10.75) * (2.0 * PI / 3.0)).sin()
+            }
+            Ease::ElasticOut => {
+                if t == 0.0 || t == 1.0 { return t }
+                2f64.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * PI / 3.0)).sin() + 1.0
+            }
+            Ease::ElasticInOut => {
+                if t == 0.0 || t == 1.0 { return t }
+                let c = 2.0 * PI / 4.5;
+                if t < 0.5 {
+                    -(2f64.powf(20.0 * t - 10.0) * ((20.0 * t - 11.125) * c).sin()) / 2.0
+                } else {
+                    2f64.powf(-20.0 * t + 10.0) * ((20.0 * t - 11.125) * c).sin() / 2.0 + 1.0
+                }
+            }
+            Ease::BounceIn => 1.0 - Ease::BounceOut.apply(1.0 - t),
+            Ease::BounceOut => {
+                let (n, d) = (7.5625, 2.75);
+                if t < 1.0 / d {
+                    n * t * t
+                } else if t < 2.0 / d {
+                    let t = t - 1.5 / d;
+                    n * t * t + 0.75
+                } else if t < 2.5 / d {
+                    let t = t - 2.25 / d;
+                    n * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / d;
+                    n * t * t + 0.984375
+                }
+            }
+            Ease::BounceInOut => {
+                if t < 0.5 {
+                    (1.0 - Ease::BounceOut.apply(1.0 - 2.0 * t)) / 2.0
+                } else {
+                    (1.0 + Ease::BounceOut.apply(2.0 * t - 1.0)) / 2.0
+                }
+            }
+            Ease::BackIn => {
+                let (c1, c3) = (1.70158, 2.70158);
+                c3 * t * t * t - c1 * t * t
+            }
+            Ease::BackOut => {
+                let (c1, c3) = (1.70158, 2.70158);
+                1.0 + c3 * (t - 1.0).powi(3) + c1 * (t - 1.0).powi(2)
+            }
+            Ease::BackInOut => {
+                let c2 = 1.70158 * 1.525;
+                if t < 0.5 {
+                    (2.0 * t).powi(2) * ((c2 + 1.0) * 2.0 * t - c2) / 2.0
+                } else {
+                    ((2.0 * t - 2.0).powi(2) * ((c2 + 1.0) * (t * 2.0 - 2.0) + c2) + 2.0) / 2.0
+                }
+            }
+        }
+    }
+}
+
+/// How a tween behaves once it reaches its end. `None` repeats forever, `Some(0)` finishes
+/// without playing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoopMode {
+    Once,
+    /// Restarts from the beginning.
+    Loop(Option<u32>),
+    /// Plays forwards then backwards. Each direction counts as one iteration.
+    Yoyo(Option<u32>)
+}
+
+fn lerp(from : f64, to : f64, t : f64) -> f64 {
+    from + (to - from) * t
+}
+
+/// What a `Tween` writes to. Transform targets capture their start value the first time the tween
+/// runs when `from` is `None`.
+pub enum TweenTarget {
+    Position(Option<Vec2i>, Vec2i),
+    Scale(Option<Vec2f>, Vec2f),
+    Rotation(Option<f64>, f64),
+    Color(Color, Color, Box<dyn FnMut(Color)>),
+    Value(f64, f64, Box<dyn FnMut(f64)>)
+}
+
+impl TweenTarget {
+    fn apply(&mut self, t : f64, transform : Option<&mut TransformComponent>) {
+        match self {
+            TweenTarget::Position(from, to) => if let Some(transform) = transform {
+                let (fx, fy) = from.get_or_insert(transform.pos).get_xy();
+                let (tx, ty) = to.get_xy();
+                transform.pos.set_xy(lerp(fx as f64, tx as f64, t).round() as i32, lerp(fy as f64, ty as f64, t).round() as i32);
+            },
+            TweenTarget::Scale(from, to) => if let Some(transform) = transform {
+                let (fx, fy) = from.get_or_insert(transform.scale).get_xy();
+                let (tx, ty) = to.get_xy();
+                transform.scale.set_xy(lerp(fx, tx, t), lerp(fy, ty, t));
+            },
+            TweenTarget::Rotation(from, to) => if let Some(transform) = transform {
+                let from = *from.get_or_insert(transform.rotation);
+                transform.rotation = lerp(from, *to, t);
+            },
+            TweenTarget::Color(from, to, setter) => setter(Color::lerp(*from, *to, t)),
+            TweenTarget::Value(from, to, setter) => setter(lerp(*from, *to, t))
+        }
+    }
+}
+
+/// Anything that can be advanced by a `TweenComponent`.
+pub trait Tweenable {
+    /// Advances by `delta` seconds. Returns the time left over once finished, or `None` while
+    /// still running, so sequences can hand the remainder to their next entry.
+    fn advance(&mut self, delta : f64, transform : Option<&mut TransformComponent>) -> Option<f64>;
+
+    /// Rewinds to the start so the tween can be played again.
+    fn reset(&mut self);
+}
+
+pub struct Tween {
+    target : TweenTarget,
+    duration : f64,
+    ease : Ease,
+    loop_mode : LoopMode,
+    delay : f64,
+    elapsed : f64,
+    iteration : u32,
+    on_complete : Option<Box<dyn FnMut()>>
+}
+
+impl Tween {
+    pub fn new(target : TweenTarget, duration : f64) -> Tween {
+        Tween {
+            target,
+            duration,
+            ease : Ease::Linear,
+            loop_mode : LoopMode::Once,
+            delay : 0.0,
+            elapsed : 0.0,
+            iteration : 0,
+            on_complete : None
+        }
+    }
+
+    pub fn position(to : Vec2i, duration : f64) -> Tween {
+        Tween::new(TweenTarget::Position(None, to), duration)
+    }
+
+    pub fn scale(to : Vec2f, duration : f64) -> Tween {
+        Tween::new(TweenTarget::Scale(None, to), duration)
+    }
+
+    pub fn rotation(to : f64, duration : f64) -> Tween {
+        Tween::new(TweenTarget::Rotation(None, to), duration)
+    }
+
+    pub fn color<F>(from : Color, to : Color, duration : f64, setter : F) -> Tween where F: 'static + FnMut(Color) {
+        Tween::new(TweenTarget::Color(from, to, Box::new(setter)), duration)
+    }
+
+    pub fn value<F>(from : f64, to : f64, duration : f64, setter : F) -> Tween where F: 'static + FnMut(f64) {
+        Tween::new(TweenTarget::Value(from, to, Box::new(setter)), duration)
+    }
+
+    pub fn ease(mut self, ease : Ease) -> Tween {
+        self.ease = ease;
+        self
+    }
+
+    pub fn looping(mut self, loop_mode : LoopMode) -> Tween {
+        self.loop_mode = loop_mode;
+        self
+    }
+
+    pub fn delay(mut self, delay : f64) -> Tween {
+        self.delay = delay;
+        self
+    }
+
+    pub fn on_complete<F>(mut self, f : F) -> Tween where F: 'static + FnMut() {
+        self.on_complete = Some(Box::new(f));
+        self
+    }
+
+    fn is_reversed(&self) -> bool {
+        matches!(self.loop_mode, LoopMode::Yoyo(_)) && self.iteration % 2 == 1
+    }
+
+    fn iterations(&self) -> Option<u32> {
+        match self.loop_mode {
+            LoopMode::Once => Some(1),
+            LoopMode::Loop(count) | LoopMode::Yoyo(count) => count
+        }
+    }
+}
+
+impl Tweenable for Tween {
+    fn advance(&mut self, delta : f64, mut transform : Option<&mut TransformComponent>) -> Option<f64> {
+        if self.iterations() == Some(0) {
+            if let Some(f) = &mut self.on_complete { f() }
+            return Some(delta)
+        }
+
+        self.elapsed += delta;
+        if self.elapsed < self.delay { return None }
+
+        loop {
+            let local = self.elapsed - self.delay;
+            let finished_iteration = local >= self.duration;
+            let t = if self.duration > 0.0 { (local / self.duration).min(1.0) } else { 1.0 };
+            let t = if self.is_reversed() { 1.0 - t } else { t };
+            self.target.apply(self.ease.apply(t), transform.as_deref_mut());
+
+            if !finished_iteration { return None }
+
+            self.iteration += 1;
+            let leftover = local - self.duration;
+            if Some(self.iteration) == self.iterations() {
+                if let Some(f) = &mut self.on_complete { f() }
+                return Some(leftover)
+            }
+            // A zero length looping tween would otherwise spin here forever.
+            if self.duration <= 0.0 { return None }
+            self.elapsed = self.delay + leftover;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.elapsed = 0.0;
+        self.iteration = 0;
+    }
+}
+
+/// Plays its entries one after another.
+pub struct Sequence {
+    entries : Vec<Box<dyn Tweenable>>,
+    current : usize,
+    repeat : Option<u32>,
+    iteration : u32,
+    on_complete : Option<Box<dyn FnMut()>>
+}
+
+impl Sequence {
+    pub fn new() -> Sequence {
+        Sequence { entries : Vec::new(), current : 0, repeat : Some(1), iteration : 0, on_complete : None }
+    }
+
+    pub fn then<T>(mut self, tween : T) -> Sequence where T: 'static + Tweenable {
+        self.entries.push(Box::new(tween));
+        self
+    }
+
+    /// Plays the whole sequence `count` times, or forever with `None`. With `Some(0)` it finishes
+    /// without playing.
+    pub fn repeat(mut self, count : Option<u32>) -> Sequence {
+        self.repeat = count;
+        self
+    }
+
+    pub fn on_complete<F>(mut self, f : F) -> Sequence where F: 'static + FnMut() {
+        self.on_complete = Some(Box::new(f));
+        self
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Sequence::new()
+    }
+}
+
+impl Tweenable for Sequence {
+    fn advance(&mut self, delta : f64, mut transform : Option<&mut TransformComponent>) -> Option<f64> {
+        if self.repeat == Some(0) {
+            if let Some(f) = &mut self.on_complete { f() }
+            return Some(delta)
+        }
+
+        let mut delta = delta;
+        loop {
+            let entry = match self.entries.get_mut(self.current) {
+                Some(entry) => entry,
+                None => {
+                    self.iteration += 1;
+                    if Some(self.iteration) == self.repeat || self.entries.is_empty() {
+                        if let Some(f) = &mut self.on_complete { f() }
+                        return Some(delta)
+                    }
+                    self.entries.iter_mut().for_each(|e| e.reset());
+                    self.current = 0;
+                    continue
+                }
+            };
+
+            delta = entry.advance(delta, transform.as_deref_mut())?;
+            self.current += 1;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.entries.iter_mut().for_each(|e| e.reset());
+        self.current = 0;
+        self.iteration = 0;
+    }
+}
+
+/// Plays its entries at the same time and finishes with the longest one.
+pub struct Parallel {
+    entries : Vec<(Box<dyn Tweenable>, Option<f64>)>,
+    on_complete : Option<Box<dyn FnMut()>>
+}
+
+impl Parallel {
+    pub fn new() -> Parallel {
+        Parallel { entries : Vec::new(), on_complete : None }
+    }
+
+    pub fn with<T>(mut self, tween : T) -> Parallel where T: 'static + Tweenable {
+        self.entries.push((Box::new(tween), None));
+        self
+    }
+
+    pub fn on_complete<F>(mut self, f : F) -> Parallel where F: 'static + FnMut() {
+        self.on_complete = Some(Box::new(f));
+        self
+    }
+}
+
+impl Default for Parallel {
+    fn default() -> Self {
+        Parallel::new()
+    }
+}
+
+impl Tweenable for Parallel {
+    fn advance(&mut self, delta : f64, mut transform : Option<&mut TransformComponent>) -> Option<f64> {
+        // Entries that finished in earlier calls left over time from those calls, which is
+        // already spent, so only the ones finishing now count.
+        let mut remainder = delta;
+        for (entry, leftover) in self.entries.iter_mut().filter(|(_, leftover)| leftover.is_none()) {
+            *leftover = entry.advance(delta, transform.as_deref_mut());
+            if let Some(left) = *leftover {
+                remainder = remainder.min(left);
+            }
+        }
+
+        if self.entries.iter().any(|(_, leftover)| leftover.is_none()) { return None }
+        if let Some(f) = &mut self.on_complete { f() }
+        // The entry that finished last has the smallest remainder.
+        Some(remainder)
+    }
+
+    fn reset(&mut self) {
+        for (entry, leftover) in self.entries.iter_mut() {
+            entry.reset();
+            *leftover = None;
+        }
+    }
+}
+
+/// Drives tweens on its GameObject with the object's `FrameInfo` delta. Finished tweens are dropped.
+pub struct TweenComponent {
+    tweens : Vec<Box<dyn Tweenable>>
+}
+
+impl TweenComponent {
+    pub fn new() -> TweenComponent {
+        TweenComponent { tweens : Vec::new() }
+    }
+
+    pub fn with<T>(mut self, tween : T) -> TweenComponent where T: 'static + Tweenable {
+        self.add(tween);
+        self
+    }
+
+    pub fn add<T>(&mut self, tween : T) where T: 'static + Tweenable {
+        self.tweens.push(Box::new(tween));
+    }
+
+    pub fn clear(&mut self) {
+        self.tweens.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tweens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tweens.is_empty()
+    }
+}
+
+impl Default for TweenComponent {
+    fn default() -> Self {
+        TweenComponent::new()
+    }
+}
+
+impl GameComponent for TweenComponent {
+    fn update(&mut self, frame_info : &FrameInfo, _input_info : &InputInfo, mut transform : Option<&mut TransformComponent>) {
+        self.tweens.retain_mut(|tween| tween.advance(frame_info.update_delta, transform.as_deref_mut()).is_none());
+    }
+
+    fn object_debug(&mut self, ui : &Ui) {
+        ui.text(format!("Active Tweens: {}", self.tweens.len()))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    const EASES : [Ease; 16] = [Ease::Linear, Ease::QuadIn, Ease::QuadOut, Ease::QuadInOut, Ease::CubicIn, Ease::CubicOut,
+        Ease::CubicInOut, Ease::ElasticIn, Ease::ElasticOut, Ease::ElasticInOut, Ease::BounceIn, Ease::BounceOut,
+        Ease::BounceInOut, Ease::BackIn, Ease::BackOut, Ease::BackInOut];
+
+    /// A value tween whose latest value can be read back.
+    fn value(from : f64, to : f64, duration : f64) -> (Tween, Rc<Cell<f64>>) {
+        let out = Rc::new(Cell::new(f64::NAN));
+        let setter = out.clone();
+        (Tween::value(from, to, duration, move |v| setter.set(v)), out)
+    }
+
+    fn close(a : f64, b : f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn every_ease_hits_both_endpoints() {
+        for ease in EASES.iter() {
+            assert!(close(ease.apply(0.0), 0.0), "{:?} at 0", ease);
+            assert!(close(ease.apply(1.0), 1.0), "{:?} at 1", ease);
+            // Out of range input is clamped.
+            assert!(close(ease.apply(-1.0), 0.0) && close(ease.apply(2.0), 1.0), "{:?} clamps", ease);
+        }
+        assert!(close(Ease::QuadInOut.apply(0.5), 0.5));
+        assert!(Ease::BackIn.apply(0.2) < 0.0);
+    }
+
+    #[test]
+    fn tween_reports_leftover_time() {
+        let (mut tween, out) = value(0.0, 10.0, 1.0);
+        assert_eq!(tween.advance(0.25, Option::None), Option::None);
+        assert!(close(out.get(), 2.5));
+        assert!(close(tween.advance(1.0, Option::None).unwrap(), 0.25));
+        assert!(close(out.get(), 10.0));
+    }
+
+    #[test]
+    fn yoyo_runs_back_and_counts_each_direction() {
+        let (tween, out) = value(0.0, 10.0, 1.0);
+        let mut tween = tween.looping(LoopMode::Yoyo(Some(2)));
+        assert_eq!(tween.advance(1.5, Option::None), Option::None);
+        assert!(close(out.get(), 5.0));
+        assert!(close(tween.advance(0.75, Option::None).unwrap(), 0.25));
+        assert!(close(out.get(), 0.0));
+    }
+
+    #[test]
+    fn sequence_hands_leftovers_to_the_next_entry() {
+        let (first, a) = value(0.0, 1.0, 0.5);
+        let (second, b) = value(0.0, 1.0, 1.0);
+        let mut sequence = Sequence::new().then(first).then(second);
+        assert_eq!(sequence.advance(0.75, Option::None), Option::None);
+        assert!(close(a.get(), 1.0));
+        assert!(close(b.get(), 0.25));
+        assert!(close(sequence.advance(1.0, Option::None).unwrap(), 0.25));
+        assert!(close(b.get(), 1.0));
+    }
+
+    #[test]
+    fn sequence_repeats_with_the_leftover() {
+        let (tween, out) = value(0.0, 1.0, 1.0);
+        let done = Rc::new(Cell::new(0));
+        let counter = done.clone();
+        let mut sequence = Sequence::new().then(tween).repeat(Some(2)).on_complete(move || counter.set(counter.get() + 1));
+        assert_eq!(sequence.advance(1.25, Option::None), Option::None);
+        assert!(close(out.get(), 0.25));
+        assert!(close(sequence.advance(1.0, Option::None).unwrap(), 0.25));
+        assert_eq!(done.get(), 1);
+    }
+
+    #[test]
+    fn zero_iterations_finish_right_away() {
+        for mode in [LoopMode::Loop(Some(0)), LoopMode::Yoyo(Some(0))] {
+            let (tween, out) = value(0.0, 10.0, 1.0);
+            let done = Rc::new(Cell::new(0));
+            let counter = done.clone();
+            let mut tween = tween.looping(mode).on_complete(move || counter.set(counter.get() + 1));
+            assert_eq!(tween.advance(0.25, Option::None), Some(0.25), "{:?}", mode);
+            assert!(out.get().is_nan());
+            assert_eq!(done.get(), 1);
+        }
+
+        let (tween, out) = value(0.0, 1.0, 1.0);
+        let mut sequence = Sequence::new().then(tween).repeat(Some(0));
+        assert_eq!(sequence.advance(0.5, Option::None), Some(0.5));
+        assert!(out.get().is_nan());
+    }
+
+    #[test]
+    fn parallel_leftover_only_counts_entries_finishing_now() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let (short, long) = (order.clone(), order.clone());
+        let (a, _) = value(0.0, 1.0, 0.2);
+        let (b, _) = value(0.0, 1.0, 1.1);
+        let mut parallel = Parallel::new()
+            .with(a.on_complete(move || short.borrow_mut().push("a")))
+            .with(b.on_complete(move || long.borrow_mut().push("b")));
+
+        // `a` finishes with 0.3 left, which is spent by the time `b` finishes.
+        assert_eq!(parallel.advance(0.5, Option::None), Option::None);
+        assert_eq!(parallel.advance(0.5, Option::None), Option::None);
+        assert!(close(parallel.advance(0.5, Option::None).unwrap(), 0.4));
+        assert_eq!(*order.borrow(), vec!["a", "b"]);
+
+        parallel.reset();
+        assert!(close(parallel.advance(2.0, Option::None).unwrap(), 0.9));
+    }
+
+    #[test]
+    fn transform_targets_start_from_the_current_value() {
+        let mut transform = TransformComponent::from(10, 0);
+        let mut tween = Tween::position(Vec2i::new(20, 10), 1.0);
+        tween.advance(0.5, Some(&mut transform));
+        assert_eq!(transform.pos.get_xy(), (15, 5));
+        let mut spin = Tween::rotation(90.0, 1.0).ease(Ease::QuadIn).delay(0.5);
+        assert_eq!(spin.advance(0.25, Some(&mut transform)), Option::None);
+        assert_eq!(transform.rotation, 0.0);
+        spin.advance(0.75, Some(&mut transform));
+        assert!(close(transform.rotation, 22.5));
+    }
+}
@@ -1,5 +1,34 @@
+use serde::{Serialize, Deserialize};
+use winit::window::{Fullscreen, Window};
+
 pub struct WindowInfo {
     pub width : u32,
     pub height : u32,
     pub scale_factor : f64
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WindowMode {
+    Windowed,
+    /// A borderless window covering the monitor the game is on.
+    Borderless,
+    /// Exclusive fullscreen at the monitor's largest video mode.
+    Fullscreen
+}
+
+impl WindowMode {
+    pub fn fullscreen(&self, window : &Window) -> Option<Fullscreen> {
+        match self {
+            WindowMode::Windowed => None,
+            WindowMode::Borderless => Some(Fullscreen::Borderless(window.current_monitor())),
+            WindowMode::Fullscreen => {
+                let mode = window.current_monitor()?.video_modes()
+                    .max_by_key(|m| (m.size().width * m.size().height, m.refresh_rate()));
+                match mode {
+                    Some(mode) => Some(Fullscreen::Exclusive(mode)),
+                    None => Some(Fullscreen::Borderless(window.current_monitor()))
+                }
+            }
+        }
+    }
+}